@@ -0,0 +1,986 @@
+//! Block management for the paged KV cache
+//!
+//! This module provides the bookkeeping for the physical blocks of the
+//! key-value cache: which blocks are free, which are in use by running
+//! sequences, and which completed blocks can be reused through prefix caching.
+//! Unreferenced cached blocks are evicted in least-recently-used order when
+//! no empty block is left, unless they are pinned.
+
+use anyhow::Result;
+use common::sequence::Sequence;
+use std::collections::{HashMap, HashSet, VecDeque};
+use xxhash_rust::xxh64::Xxh64;
+
+/// A single physical block in the KV cache
+///
+/// Tracks how many sequences currently reference the block and, for
+/// completely filled blocks, the hash and token IDs used for prefix caching.
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// Index of this block in the physical KV cache
+    pub block_id: usize,
+
+    /// Number of sequences currently referencing this block
+    pub ref_count: usize,
+
+    /// Prefix hash of the block contents, or None if the block is not full
+    pub hash: Option<u64>,
+
+    /// Token IDs stored in this block, used to verify prefix cache hits
+    pub token_ids: Vec<u32>,
+}
+
+impl Block {
+    /// Creates a new, unreferenced block with the given ID
+    pub fn new(block_id: usize) -> Self {
+        Self {
+            block_id,
+            ref_count: 0,
+            hash: None,
+            token_ids: Vec::new(),
+        }
+    }
+
+    /// Records the prefix hash and token IDs of a completely filled block
+    pub fn update(&mut self, hash: u64, token_ids: &[u32]) {
+        self.hash = Some(hash);
+        self.token_ids = token_ids.to_vec();
+    }
+
+    /// Resets the block for a fresh allocation with a single reference
+    pub fn reset(&mut self) {
+        self.ref_count = 1;
+        self.hash = None;
+        self.token_ids.clear();
+    }
+}
+
+/// Manager for the physical blocks of the KV cache
+///
+/// The block manager hands out physical blocks to sequences, fills in their
+/// block tables, and reclaims blocks when sequences finish. Completely filled
+/// blocks are hashed together with their prefix so that sequences sharing a
+/// common prompt prefix can reuse the same physical blocks. Blocks released
+/// with a hash stay in the prefix cache until they are evicted, which only
+/// happens once no empty block is left.
+#[derive(Debug)]
+pub struct BlockManager {
+    /// Number of tokens stored in each block
+    block_size: usize,
+
+    /// All physical blocks, indexed by block ID
+    blocks: Vec<Block>,
+
+    /// Mapping from prefix hash to the block holding that prefix
+    hash_to_block_id: HashMap<u64, usize>,
+
+    /// Unreferenced blocks that hold no cached prefix
+    free_block_ids: VecDeque<usize>,
+
+    /// Unreferenced blocks that hold a cached prefix, least recently used first
+    cached_block_ids: VecDeque<usize>,
+
+    /// Blocks that are referenced by at least one sequence
+    used_block_ids: HashSet<usize>,
+
+    /// Blocks that are never evicted or reused, even when unreferenced
+    pinned_block_ids: HashSet<usize>,
+
+    /// Total number of blocks in the CPU swap pool
+    num_cpu_blocks: usize,
+
+    /// CPU swap blocks not holding a swapped-out block
+    free_cpu_block_ids: VecDeque<usize>,
+}
+
+impl BlockManager {
+    /// Creates a new block manager with all blocks free
+    ///
+    /// # Arguments
+    ///
+    /// * `num_blocks` - Total number of physical blocks in the KV cache
+    /// * `block_size` - Number of tokens stored in each block
+    pub fn new(num_blocks: usize, block_size: usize) -> Self {
+        Self {
+            block_size,
+            blocks: (0..num_blocks).map(Block::new).collect(),
+            hash_to_block_id: HashMap::new(),
+            free_block_ids: (0..num_blocks).collect(),
+            cached_block_ids: VecDeque::new(),
+            used_block_ids: HashSet::new(),
+            pinned_block_ids: HashSet::new(),
+            num_cpu_blocks: 0,
+            free_cpu_block_ids: VecDeque::new(),
+        }
+    }
+
+    /// Returns the block manager with a CPU swap pool of `num_cpu_blocks`
+    ///
+    /// Without a swap pool, every swap-out fails and preempted sequences
+    /// must be recomputed.
+    pub fn with_cpu_blocks(mut self, num_cpu_blocks: usize) -> Self {
+        self.num_cpu_blocks = num_cpu_blocks;
+        self.free_cpu_block_ids = (0..num_cpu_blocks).collect();
+        self
+    }
+
+    /// Returns the number of tokens stored in each block
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns the total number of physical blocks managed
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns the number of blocks not referenced by any sequence
+    ///
+    /// Schedulers use this to judge how much KV headroom remains before
+    /// admitting new sequences. Unreferenced blocks still holding a cached
+    /// prefix are counted, since they are evicted on demand, but pinned
+    /// blocks are not.
+    pub fn num_free_blocks(&self) -> usize {
+        let num_pinned = self.pinned_block_ids.iter().filter(|id| !self.used_block_ids.contains(id)).count();
+        self.free_block_ids.len() + self.cached_block_ids.len() - num_pinned
+    }
+
+    /// Returns the number of unpinned, unreferenced blocks holding a cached prefix
+    pub fn num_evictable_blocks(&self) -> usize {
+        self.cached_block_ids.iter().filter(|id| !self.pinned_block_ids.contains(id)).count()
+    }
+
+    /// Pins blocks so they are never evicted or reused
+    ///
+    /// A pinned block keeps its contents even when no sequence references
+    /// it, so e.g. a shared system prompt warmed once stays in the prefix
+    /// cache for every later request.
+    ///
+    /// # Arguments
+    ///
+    /// * `blocks` - Physical IDs of the blocks to pin
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, pinning nothing, if a block ID is out of range.
+    pub fn pin_blocks(&mut self, blocks: &[usize]) -> Result<()> {
+        if let Some(&block_id) = blocks.iter().find(|&&id| id >= self.num_blocks()) {
+            anyhow::bail!("Cannot pin block {} of a KV cache with {} blocks", block_id, self.num_blocks());
+        }
+        self.pinned_block_ids.extend(blocks);
+        Ok(())
+    }
+
+    /// Unpins blocks, making them evictable again once unreferenced
+    ///
+    /// # Arguments
+    ///
+    /// * `blocks` - Physical IDs of the blocks to unpin
+    pub fn unpin_blocks(&mut self, blocks: &[usize]) {
+        for block_id in blocks {
+            self.pinned_block_ids.remove(block_id);
+        }
+    }
+
+    /// Returns the total number of blocks in the CPU swap pool
+    pub fn num_cpu_blocks(&self) -> usize {
+        self.num_cpu_blocks
+    }
+
+    /// Returns the number of CPU swap blocks that are not in use
+    pub fn num_free_cpu_blocks(&self) -> usize {
+        self.free_cpu_block_ids.len()
+    }
+
+    /// Computes the prefix hash of a block of token IDs
+    ///
+    /// The hash chains in the hash of the previous block so that equal
+    /// blocks only match when their entire prefix is also equal.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_ids` - Token IDs stored in the block
+    /// * `prefix` - Hash of the preceding block, or None for the first block
+    pub fn compute_hash(token_ids: &[u32], prefix: Option<u64>) -> u64 {
+        let mut hasher = Xxh64::new(0);
+        if let Some(prefix) = prefix {
+            hasher.update(&prefix.to_le_bytes());
+        }
+        for token_id in token_ids {
+            hasher.update(&token_id.to_le_bytes());
+        }
+        hasher.digest()
+    }
+
+    /// Checks whether enough free blocks remain to allocate a sequence
+    ///
+    /// Blocks already covered by the sequence's cached tokens do not need
+    /// fresh allocations, so prefix-cached sequences require fewer free blocks.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the sequence's uncached blocks fit in the free pool
+    pub fn can_allocate(&self, seq: &Sequence) -> bool {
+        seq.num_blocks().saturating_sub(seq.num_cached_blocks()) <= self.num_free_blocks()
+    }
+
+    /// Allocates blocks for a sequence and fills in its block table
+    ///
+    /// Completely filled blocks are looked up in the prefix cache first; hits
+    /// are shared with the sequences that already hold them and counted as
+    /// cached tokens on the sequence. At least the last token is always left
+    /// uncached, so a block-aligned sequence seen before recomputes its final
+    /// block rather than having nothing left to prefill.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the sequence's block size differs from the
+    /// manager's, if the sequence already has a block table, or if the free
+    /// pool runs out of blocks.
+    pub fn allocate(&mut self, seq: &mut Sequence) -> Result<()> {
+        if seq.block_size != self.block_size {
+            anyhow::bail!(
+                "Sequence {} uses block size {} but the KV cache uses {}",
+                seq.seq_id, seq.block_size, self.block_size
+            );
+        }
+        if !seq.block_table.is_empty() {
+            anyhow::bail!("Sequence {} already has an allocated block table", seq.seq_id);
+        }
+
+        let mut prefix = None;
+        let mut cache_miss = false;
+        for i in 0..seq.num_blocks() {
+            let token_ids = seq.block(i).to_vec();
+            let hash = if token_ids.len() == self.block_size {
+                Some(Self::compute_hash(&token_ids, prefix))
+            } else {
+                None
+            };
+            // The last token always runs through the model, so a block that
+            // would complete a fully cached sequence is recomputed instead.
+            let leaves_tokens_to_run = (i + 1) * self.block_size < seq.len();
+            let cached = hash
+                .filter(|_| leaves_tokens_to_run)
+                .and_then(|h| self.hash_to_block_id.get(&h).copied())
+                .filter(|&id| self.blocks[id].token_ids == token_ids);
+            if cached.is_none() {
+                cache_miss = true;
+            }
+
+            let block_id = match cached {
+                Some(block_id) if !cache_miss => {
+                    seq.num_cached_tokens += self.block_size;
+                    if self.used_block_ids.contains(&block_id) {
+                        self.blocks[block_id].ref_count += 1;
+                    } else {
+                        self.allocate_block(block_id);
+                    }
+                    block_id
+                }
+                _ => {
+                    let Some(block_id) = self.next_free_block() else {
+                        // Release the blocks taken so far so the sequence can be retried.
+                        // The fresh ones hold no computed KV, so their hashes go too.
+                        self.deallocate(seq);
+                        anyhow::bail!("Out of KV cache blocks while allocating sequence {}", seq.seq_id);
+                    };
+                    self.allocate_block(block_id);
+                    block_id
+                }
+            };
+
+            if let Some(h) = hash {
+                self.blocks[block_id].update(h, &token_ids);
+                self.hash_to_block_id.insert(h, block_id);
+            }
+            seq.block_table.push(block_id);
+            prefix = hash;
+        }
+        Ok(())
+    }
+
+    /// Allocates every block a sequence needs to reach `total_tokens` tokens
+    ///
+    /// Unallocated sequences are allocated first, using the prefix cache like
+    /// `allocate`. The block table is then extended up front, so appending
+    /// tokens up to `total_tokens` never allocates. Reserved blocks beyond
+    /// the sequence's tokens stay empty until it grows into them.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - The sequence to reserve blocks for
+    /// * `total_tokens` - Number of tokens, prompt included, the sequence
+    ///   will reach, e.g. its length plus `max_tokens`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving the sequence untouched, if allocation fails
+    /// or the free pool cannot hold the reservation.
+    pub fn reserve(&mut self, seq: &mut Sequence, total_tokens: usize) -> Result<()> {
+        let was_allocated = !seq.block_table.is_empty();
+        if !was_allocated {
+            self.allocate(seq)?;
+        }
+        let num_needed = total_tokens.div_ceil(self.block_size).saturating_sub(seq.block_table.len());
+        if num_needed > self.num_free_blocks() {
+            if !was_allocated {
+                // None of the fresh blocks hold computed KV, so releasing
+                // them also removes their hashes from the prefix cache.
+                self.deallocate(seq);
+            }
+            anyhow::bail!(
+                "Cannot reserve {} tokens for sequence {}: {} more blocks are needed but only {} are free",
+                total_tokens, seq.seq_id, num_needed, self.num_free_blocks()
+            );
+        }
+        for _ in 0..num_needed {
+            // Safe to unwrap since the pool size was checked above.
+            let block_id = self.next_free_block().unwrap();
+            self.allocate_block(block_id);
+            seq.block_table.push(block_id);
+        }
+        Ok(())
+    }
+
+    /// Checks whether a running sequence can grow by one token
+    ///
+    /// A new block is only needed when the latest token starts a block that
+    /// is neither allocated nor reserved.
+    pub fn can_append(&self, seq: &Sequence) -> bool {
+        let needs_block = seq.num_blocks() > seq.block_table.len();
+        self.num_free_blocks() >= needs_block as usize
+    }
+
+    /// Updates the block table of a running sequence after a token was appended
+    ///
+    /// Equivalent to `append_slot` for callers that do not need the slot.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a new block is needed but the free pool is empty.
+    pub fn may_append(&mut self, seq: &mut Sequence) -> Result<()> {
+        self.append_slot(seq).map(|_| ())
+    }
+
+    /// Reserves the KV cache slot for the latest token of a running sequence
+    ///
+    /// A new block is allocated and appended to the block table only when the
+    /// latest token starts a fresh block that was not reserved with
+    /// `reserve`. Once the latest token's block becomes full it is
+    /// registered in the prefix cache.
+    ///
+    /// # Returns
+    ///
+    /// The physical slot, `block_id * block_size + offset`, of the latest token
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a new block is needed but the free pool is empty,
+    /// so that the caller can preempt sequences and retry.
+    pub fn append_slot(&mut self, seq: &mut Sequence) -> Result<usize> {
+        if seq.block_table.is_empty() {
+            anyhow::bail!("Sequence {} has no allocated blocks", seq.seq_id);
+        }
+
+        let num_tokens_in_last_block = seq.last_block_num_tokens();
+        if seq.num_blocks() > seq.block_table.len() {
+            let Some(block_id) = self.next_free_block() else {
+                anyhow::bail!("Out of KV cache blocks while appending to sequence {}", seq.seq_id);
+            };
+            self.allocate_block(block_id);
+            seq.block_table.push(block_id);
+        }
+
+        // Reserved blocks may follow the block holding the latest token.
+        let num_blocks = seq.num_blocks();
+        let last_block_id = seq.block_table[num_blocks - 1];
+        if num_tokens_in_last_block == self.block_size {
+            let prefix = match num_blocks {
+                n if n > 1 => self.blocks[seq.block_table[n - 2]].hash,
+                _ => None,
+            };
+            let token_ids = seq.block(num_blocks - 1);
+            let hash = Self::compute_hash(token_ids, prefix);
+            self.blocks[last_block_id].update(hash, token_ids);
+            self.hash_to_block_id.insert(hash, last_block_id);
+        }
+        Ok(last_block_id * self.block_size + num_tokens_in_last_block - 1)
+    }
+
+    /// Releases all blocks held by a sequence
+    ///
+    /// Blocks whose reference count drops to zero return to the free pool but
+    /// keep their hash, so they can still be reused by the prefix cache until
+    /// they are evicted. Hashes are registered before the KV of a block is
+    /// computed, so blocks not covered by the sequence's cached tokens, e.g.
+    /// of a prompt aborted mid-prefill, leave the prefix cache instead.
+    pub fn deallocate(&mut self, seq: &mut Sequence) {
+        self.forget_uncomputed_blocks(seq);
+        self.release_blocks(&seq.block_table);
+        seq.num_cached_tokens = 0;
+        seq.block_table.clear();
+    }
+
+    /// Moves a sequence's KV cache blocks into the CPU swap pool
+    ///
+    /// Every block of the sequence is assigned its own CPU block, and the
+    /// sequence's references to its GPU blocks are released. Afterwards the
+    /// block table holds the CPU block IDs until `swap_in` is called.
+    ///
+    /// # Returns
+    ///
+    /// The `(gpu_block_id, cpu_block_id)` pairs whose contents must be copied
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving the sequence untouched, if the swap pool
+    /// does not have a free block for every block of the sequence.
+    pub fn swap_out(&mut self, seq: &mut Sequence) -> Result<Vec<(usize, usize)>> {
+        if seq.block_table.len() > self.num_free_cpu_blocks() {
+            anyhow::bail!(
+                "Swap space exhausted: sequence {} needs {} CPU blocks but only {} are free",
+                seq.seq_id, seq.block_table.len(), self.num_free_cpu_blocks()
+            );
+        }
+        self.release_blocks(&seq.block_table);
+        let mapping: Vec<(usize, usize)> = seq
+            .block_table
+            .iter()
+            // Safe to unwrap since the pool size was checked above.
+            .map(|&gpu_block_id| (gpu_block_id, self.free_cpu_block_ids.pop_front().unwrap()))
+            .collect();
+        seq.block_table = mapping.iter().map(|&(_, cpu_block_id)| cpu_block_id).collect();
+        Ok(mapping)
+    }
+
+    /// Releases the CPU swap blocks held by a swapped-out sequence
+    ///
+    /// Used when a swapped-out sequence is dropped instead of swapped in.
+    pub fn deallocate_swapped(&mut self, seq: &mut Sequence) {
+        self.free_cpu_block_ids.extend(seq.block_table.drain(..));
+        seq.num_cached_tokens = 0;
+    }
+
+    /// Checks whether enough free blocks remain to swap a sequence back in
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - The swapped-out sequence
+    /// * `num_reserved` - Number of free blocks that must remain afterwards,
+    ///   e.g. for the next token of every running sequence
+    pub fn can_swap_in(&self, seq: &Sequence, num_reserved: usize) -> bool {
+        seq.block_table.len() + num_reserved <= self.num_free_blocks()
+    }
+
+    /// Moves a swapped-out sequence's blocks back into the KV cache
+    ///
+    /// Fresh blocks are allocated for every CPU block in the block table and
+    /// the CPU blocks return to the swap pool.
+    ///
+    /// # Returns
+    ///
+    /// The `(cpu_block_id, gpu_block_id)` pairs whose contents must be copied
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving the sequence untouched, if the free pool
+    /// does not have enough blocks.
+    pub fn swap_in(&mut self, seq: &mut Sequence) -> Result<Vec<(usize, usize)>> {
+        if !self.can_swap_in(seq, 0) {
+            anyhow::bail!("Out of KV cache blocks while swapping in sequence {}", seq.seq_id);
+        }
+        let mut mapping = Vec::with_capacity(seq.block_table.len());
+        for &cpu_block_id in &seq.block_table {
+            // Safe to unwrap since the pool size was checked above.
+            let gpu_block_id = self.next_free_block().unwrap();
+            self.allocate_block(gpu_block_id);
+            self.free_cpu_block_ids.push_back(cpu_block_id);
+            mapping.push((cpu_block_id, gpu_block_id));
+        }
+        seq.block_table = mapping.iter().map(|&(_, gpu_block_id)| gpu_block_id).collect();
+        Ok(mapping)
+    }
+
+    /// Returns the length of the longest run of consecutive free block IDs
+    ///
+    /// Only empty blocks count; unreferenced blocks that still hold a cached
+    /// prefix break a run.
+    pub fn largest_free_run(&self) -> usize {
+        let mut ids: Vec<usize> = self.free_block_ids.iter().copied().collect();
+        ids.sort_unstable();
+        let mut longest = 0;
+        let mut run = 0;
+        for (i, &id) in ids.iter().enumerate() {
+            run = if i > 0 && ids[i - 1] + 1 == id { run + 1 } else { 1 };
+            longest = longest.max(run);
+        }
+        longest
+    }
+
+    /// Compacts the free pool into contiguous runs of block IDs
+    ///
+    /// The free list is sorted so that fresh allocations take consecutive
+    /// blocks, and unreferenced cached blocks are moved to the highest empty
+    /// IDs, so the empty blocks gather at the low end of the pool. A
+    /// relocated block keeps its prefix hash, token IDs, and place in the
+    /// eviction order. Since only unreferenced blocks move, no sequence's
+    /// block table has to change. Pinned blocks are never moved, nor used
+    /// as destinations. This is an opt-in maintenance call.
+    ///
+    /// # Returns
+    ///
+    /// The `(src_block_id, dst_block_id)` pairs whose KV must be copied
+    /// before the next forward pass, e.g. with `KvCache::copy_blocks`;
+    /// until then the relocated prefixes would serve uninitialized blocks
+    pub fn defragment(&mut self) -> Vec<(usize, usize)> {
+        self.free_block_ids.make_contiguous().sort_unstable();
+        let mut movable: Vec<usize> = self
+            .cached_block_ids
+            .iter()
+            .filter(|id| !self.pinned_block_ids.contains(id))
+            .copied()
+            .collect();
+        movable.sort_unstable();
+        let mut destinations: Vec<usize> = self
+            .free_block_ids
+            .iter()
+            .filter(|id| !self.pinned_block_ids.contains(id))
+            .copied()
+            .collect();
+
+        let mut mapping = Vec::new();
+        for src in movable {
+            match destinations.last() {
+                Some(&dst) if dst > src => {
+                    destinations.pop();
+                    self.free_block_ids.retain(|&id| id != dst);
+                    self.relocate_cached_block(src, dst);
+                    mapping.push((src, dst));
+                }
+                _ => break,
+            }
+        }
+        self.free_block_ids.make_contiguous().sort_unstable();
+        mapping
+    }
+
+    /// Moves an unreferenced cached block into an empty block
+    fn relocate_cached_block(&mut self, src: usize, dst: usize) {
+        let hash = self.blocks[src].hash.take();
+        self.blocks[dst].hash = hash;
+        self.blocks[dst].token_ids = std::mem::take(&mut self.blocks[src].token_ids);
+        if let Some(hash) = hash
+            && self.hash_to_block_id.get(&hash) == Some(&src)
+        {
+            self.hash_to_block_id.insert(hash, dst);
+        }
+        for id in self.cached_block_ids.iter_mut().filter(|id| **id == src) {
+            *id = dst;
+        }
+        self.free_block_ids.push_front(src);
+    }
+
+    /// Removes the blocks of a sequence that hold no computed KV from the
+    /// prefix cache
+    fn forget_uncomputed_blocks(&mut self, seq: &Sequence) {
+        for &block_id in seq.block_table.iter().skip(seq.num_cached_blocks()) {
+            let block = &mut self.blocks[block_id];
+            if let Some(hash) = block.hash.take()
+                && self.hash_to_block_id.get(&hash) == Some(&block_id)
+            {
+                self.hash_to_block_id.remove(&hash);
+            }
+            block.token_ids.clear();
+        }
+    }
+
+    /// Drops one reference to each block, freeing unreferenced blocks
+    fn release_blocks(&mut self, block_table: &[usize]) {
+        for &block_id in block_table.iter().rev() {
+            let block = &mut self.blocks[block_id];
+            block.ref_count -= 1;
+            if block.ref_count == 0 {
+                self.deallocate_block(block_id);
+            }
+        }
+    }
+
+    /// Returns the block that the next fresh allocation should use
+    ///
+    /// Empty blocks are preferred; once none is left, the least recently
+    /// used unreferenced cached block is evicted. Referenced and pinned
+    /// blocks are never returned.
+    fn next_free_block(&self) -> Option<usize> {
+        self.free_block_ids
+            .iter()
+            .chain(&self.cached_block_ids)
+            .find(|id| !self.pinned_block_ids.contains(id))
+            .copied()
+    }
+
+    /// Moves a free block into the used set with a single reference
+    ///
+    /// If the block still holds a cached prefix, it is dropped from the
+    /// prefix cache; cache hits re-register it afterwards.
+    fn allocate_block(&mut self, block_id: usize) {
+        let block = &mut self.blocks[block_id];
+        debug_assert_eq!(block.ref_count, 0, "Block {} is already in use", block_id);
+        if let Some(hash) = block.hash
+            && self.hash_to_block_id.get(&hash) == Some(&block_id)
+        {
+            self.hash_to_block_id.remove(&hash);
+        }
+        block.reset();
+        self.free_block_ids.retain(|&id| id != block_id);
+        self.cached_block_ids.retain(|&id| id != block_id);
+        self.used_block_ids.insert(block_id);
+    }
+
+    /// Moves an unreferenced block back into the free pool
+    ///
+    /// Blocks holding a cached prefix become the most recently used
+    /// eviction candidates.
+    fn deallocate_block(&mut self, block_id: usize) {
+        self.used_block_ids.remove(&block_id);
+        if self.blocks[block_id].hash.is_some() {
+            self.cached_block_ids.push_back(block_id);
+        } else {
+            self.free_block_ids.push_back(block_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::sampling::SamplingParams;
+
+    fn seq_with_len(len: usize) -> Sequence {
+        Sequence::new((0..len as u32).collect(), SamplingParams::default())
+    }
+
+    #[test]
+    fn can_allocate_flips_when_pool_is_exhausted() {
+        let block_size = Sequence::DEFAULT_BLOCK_SIZE;
+        let mut manager = BlockManager::new(4, block_size);
+        assert_eq!(manager.num_free_blocks(), 4);
+
+        // Three distinct single-block sequences leave one block free.
+        let mut held = Vec::new();
+        for i in 0..3u32 {
+            let mut seq = Sequence::new(vec![i + 1], SamplingParams::default());
+            assert!(manager.can_allocate(&seq));
+            manager.allocate(&mut seq).unwrap();
+            held.push(seq);
+        }
+        assert_eq!(manager.num_free_blocks(), 1);
+
+        assert!(manager.can_allocate(&seq_with_len(block_size)));
+        assert!(!manager.can_allocate(&seq_with_len(block_size + 1)));
+
+        manager.deallocate(&mut held[0]);
+        assert_eq!(manager.num_free_blocks(), 2);
+        assert!(manager.can_allocate(&seq_with_len(block_size + 1)));
+    }
+
+    #[test]
+    fn can_allocate_discounts_cached_blocks() {
+        let block_size = Sequence::DEFAULT_BLOCK_SIZE;
+        let manager = BlockManager::new(1, block_size);
+
+        let mut seq = seq_with_len(block_size + 1);
+        assert!(!manager.can_allocate(&seq));
+
+        seq.num_cached_tokens = block_size;
+        assert!(manager.can_allocate(&seq));
+    }
+
+    #[test]
+    fn prefix_blocks_are_shared() {
+        let block_size = Sequence::DEFAULT_BLOCK_SIZE;
+        let mut manager = BlockManager::new(4, block_size);
+
+        let mut first = seq_with_len(block_size + 1);
+        let mut second = seq_with_len(block_size + 2);
+        manager.allocate(&mut first).unwrap();
+        manager.allocate(&mut second).unwrap();
+
+        assert_eq!(first.block_table[0], second.block_table[0]);
+        assert_eq!(second.num_cached_tokens, block_size);
+        assert_eq!(manager.num_free_blocks(), 1);
+    }
+
+    #[test]
+    fn repeated_block_aligned_prompt_leaves_its_last_block_uncached() {
+        let block_size = 2;
+        let mut manager = BlockManager::new(4, block_size);
+        let seq = || Sequence::with_block_size(vec![1, 2, 4, 5], SamplingParams::default(), block_size);
+        let mut first = seq();
+        manager.allocate(&mut first).unwrap();
+        let first_blocks = first.block_table.clone();
+        first.num_cached_tokens = first.len();
+        manager.deallocate(&mut first);
+
+        let mut second = seq();
+        manager.allocate(&mut second).unwrap();
+        assert_eq!(second.num_cached_tokens, block_size);
+        assert_eq!(second.prefill_chunk(usize::MAX), block_size);
+        assert_eq!(second.block_table[0], first_blocks[0]);
+    }
+
+    #[test]
+    fn blocks_released_before_their_kv_is_computed_are_not_cached() {
+        let block_size = 2;
+        let mut manager = BlockManager::new(8, block_size);
+        let seq = || Sequence::with_block_size(vec![1, 2, 3, 4, 5, 6, 7], SamplingParams::default(), block_size);
+
+        // Only the first chunk of the prompt ran before it was aborted.
+        let mut aborted = seq();
+        manager.allocate(&mut aborted).unwrap();
+        aborted.num_cached_tokens = 2;
+        manager.deallocate(&mut aborted);
+        assert_eq!(manager.num_evictable_blocks(), 1);
+
+        let mut next = seq();
+        manager.allocate(&mut next).unwrap();
+        assert_eq!(next.num_cached_tokens, block_size);
+    }
+
+    #[test]
+    fn eviction_reuses_least_recently_used_unreferenced_block() {
+        let block_size = 2;
+        let mut manager = BlockManager::new(4, block_size);
+        let mut seq = |tokens: Vec<u32>| Sequence::with_block_size(tokens, SamplingParams::default(), block_size);
+        let mut held: Vec<Sequence> = [vec![1, 2], vec![3, 4], vec![5, 6]].into_iter().map(&mut seq).collect();
+        for s in &mut held {
+            manager.allocate(s).unwrap();
+            s.num_cached_tokens = s.len();
+        }
+        let [lru, recent, referenced] = [0, 1, 2].map(|i| held[i].block_table[0]);
+        manager.deallocate(&mut held[0]);
+        manager.deallocate(&mut held[1]);
+        assert_eq!((manager.num_free_blocks(), manager.num_evictable_blocks()), (3, 2));
+
+        // The empty block is used before any cached block is evicted.
+        let mut fresh = seq(vec![7, 8]);
+        manager.allocate(&mut fresh).unwrap();
+        assert!(![lru, recent, referenced].contains(&fresh.block_table[0]));
+
+        // Then the least recently released cached block is evicted.
+        let mut evicting = seq(vec![9, 10]);
+        manager.allocate(&mut evicting).unwrap();
+        assert_eq!(evicting.block_table[0], lru);
+        assert_eq!(manager.num_evictable_blocks(), 1);
+
+        // The other cached block still serves prefix cache hits.
+        manager.deallocate(&mut fresh);
+        let mut hit = seq(vec![3, 4, 11]);
+        manager.allocate(&mut hit).unwrap();
+        assert_eq!((hit.block_table[0], hit.num_cached_tokens), (recent, block_size));
+        assert_eq!(held[2].block_table[0], referenced);
+        assert_eq!(manager.num_free_blocks(), 0);
+    }
+
+    #[test]
+    fn pinned_blocks_survive_eviction() {
+        let block_size = 2;
+        let mut manager = BlockManager::new(3, block_size);
+        let seq = |tokens: Vec<u32>| Sequence::with_block_size(tokens, SamplingParams::default(), block_size);
+        let mut system_prompt = seq(vec![1, 2]);
+        manager.allocate(&mut system_prompt).unwrap();
+        let pinned = system_prompt.block_table.clone();
+        manager.pin_blocks(&pinned).unwrap();
+        system_prompt.num_cached_tokens = system_prompt.len();
+        manager.deallocate(&mut system_prompt);
+        assert_eq!((manager.num_free_blocks(), manager.num_evictable_blocks()), (2, 0));
+
+        // Filling the cache evicts nothing, even though the pinned block is unreferenced.
+        let mut held: Vec<Sequence> = [vec![3, 4], vec![5, 6]].into_iter().map(seq).collect();
+        for s in &mut held {
+            manager.allocate(s).unwrap();
+        }
+        assert!(!manager.can_allocate(&seq(vec![7, 8])));
+        assert!(manager.allocate(&mut seq(vec![7, 8])).is_err());
+
+        let mut request = seq(vec![1, 2, 9]);
+        manager.deallocate(&mut held[0]);
+        manager.allocate(&mut request).unwrap();
+        assert_eq!((request.block_table[0], request.num_cached_tokens), (pinned[0], block_size));
+
+        // Once unpinned and unreferenced, the block can be evicted again.
+        manager.deallocate(&mut request);
+        manager.unpin_blocks(&pinned);
+        assert_eq!(manager.num_evictable_blocks(), 1);
+        assert!(manager.pin_blocks(&[3]).is_err());
+    }
+
+    #[test]
+    fn defragment_gathers_free_blocks_into_one_run() {
+        let block_size = 2;
+        let mut manager = BlockManager::new(6, block_size);
+        // Full blocks stay cached after release, partial ones become empty.
+        let mut held: Vec<Sequence> = (0..6u32)
+            .map(|i| {
+                let tokens = if i % 2 == 0 { vec![10 * i, 10 * i + 1] } else { vec![10 * i] };
+                Sequence::with_block_size(tokens, SamplingParams::default(), block_size)
+            })
+            .collect();
+        for s in &mut held {
+            manager.allocate(s).unwrap();
+            s.num_cached_tokens = s.len();
+        }
+        for s in held.iter_mut().rev() {
+            manager.deallocate(s);
+        }
+        assert_eq!((manager.largest_free_run(), manager.num_evictable_blocks()), (1, 3));
+
+        let mapping = manager.defragment();
+        assert_eq!(mapping, vec![(0, 5), (2, 3)]);
+        assert_eq!(manager.largest_free_run(), 3);
+        assert_eq!((manager.num_free_blocks(), manager.num_evictable_blocks()), (6, 3));
+
+        // The relocated prefix is still served from its new block.
+        let mut hit = Sequence::with_block_size(vec![0, 1, 7], SamplingParams::default(), block_size);
+        manager.allocate(&mut hit).unwrap();
+        assert_eq!((hit.block_table[0], hit.num_cached_tokens), (5, block_size));
+        assert_eq!(hit.block_table[1], 0);
+    }
+
+    #[test]
+    fn defragment_never_moves_into_a_pinned_block() {
+        let block_size = 2;
+        let mut manager = BlockManager::new(3, block_size);
+        let mut seq = Sequence::with_block_size(vec![1, 2], SamplingParams::default(), block_size);
+        manager.allocate(&mut seq).unwrap();
+        seq.num_cached_tokens = seq.len();
+        manager.deallocate(&mut seq);
+        manager.pin_blocks(&[2]).unwrap();
+
+        assert_eq!(manager.defragment(), vec![(0, 1)]);
+        let mut hit = Sequence::with_block_size(vec![1, 2, 3], SamplingParams::default(), block_size);
+        manager.allocate(&mut hit).unwrap();
+        assert_eq!((hit.block_table[0], hit.num_cached_tokens), (1, block_size));
+        // The pinned block is never handed out either.
+        assert_eq!(hit.block_table[1], 0);
+    }
+
+    #[test]
+    fn allocate_fails_cleanly_when_out_of_blocks() {
+        let block_size = Sequence::DEFAULT_BLOCK_SIZE;
+        let mut manager = BlockManager::new(1, block_size);
+
+        let mut seq = seq_with_len(block_size + 1);
+        assert!(manager.allocate(&mut seq).is_err());
+        assert!(seq.block_table.is_empty());
+        assert_eq!(manager.num_free_blocks(), 1);
+        // The full block taken before the failure was never computed.
+        assert_eq!(manager.num_evictable_blocks(), 0);
+    }
+
+    #[test]
+    fn append_slot_allocates_exactly_at_block_boundary() {
+        let block_size = 4;
+        let mut manager = BlockManager::new(4, block_size);
+        let mut seq = Sequence::with_block_size(vec![1, 2, 3], SamplingParams::default(), block_size);
+        manager.allocate(&mut seq).unwrap();
+        let first_block = seq.block_table[0];
+        assert_eq!(manager.num_free_blocks(), 3);
+
+        // Filling the first block does not allocate.
+        seq.append_token(4);
+        assert_eq!(manager.append_slot(&mut seq).unwrap(), first_block * block_size + 3);
+        assert_eq!(seq.block_table.len(), 1);
+        assert_eq!(manager.num_free_blocks(), 3);
+
+        // The first token past the boundary starts a new block.
+        seq.append_token(5);
+        let slot = manager.append_slot(&mut seq).unwrap();
+        assert_eq!(seq.block_table.len(), 2);
+        assert_eq!(slot, seq.block_table[1] * block_size);
+        assert_eq!(manager.num_free_blocks(), 2);
+
+        seq.append_token(6);
+        assert_eq!(manager.append_slot(&mut seq).unwrap(), slot + 1);
+        assert_eq!(seq.block_table.len(), 2);
+    }
+
+    #[test]
+    fn reserved_sequence_decodes_without_allocating() {
+        let block_size = 16;
+        let mut manager = BlockManager::new(64, block_size);
+        let mut seq = Sequence::with_block_size((0..10).collect(), SamplingParams::default(), block_size);
+        manager.reserve(&mut seq, 1000).unwrap();
+        assert_eq!(seq.block_table.len(), 63);
+        assert_eq!(manager.num_free_blocks(), 1);
+
+        let block_table = seq.block_table.clone();
+        for token_id in 10..1000 {
+            seq.append_token(token_id);
+            assert!(manager.can_append(&seq));
+            let position = token_id as usize;
+            let expected = block_table[position / block_size] * block_size + position % block_size;
+            assert_eq!(manager.append_slot(&mut seq).unwrap(), expected);
+        }
+        assert_eq!(seq.block_table, block_table);
+        assert_eq!(manager.num_free_blocks(), 1);
+
+        // A reservation that does not fit leaves the sequence unallocated.
+        let mut other = Sequence::with_block_size(vec![1, 2], SamplingParams::default(), block_size);
+        assert!(manager.reserve(&mut other, 100).is_err());
+        assert!(other.block_table.is_empty());
+        assert_eq!(manager.num_free_blocks(), 1);
+    }
+
+    #[test]
+    fn failed_reservation_leaves_no_prefix_cache_entries() {
+        let block_size = 2;
+        let mut manager = BlockManager::new(4, block_size);
+        let seq = || Sequence::with_block_size(vec![1, 2, 3, 4, 5], SamplingParams::default(), block_size);
+        assert!(manager.reserve(&mut seq(), 100).is_err());
+        assert_eq!((manager.num_free_blocks(), manager.num_evictable_blocks()), (4, 0));
+
+        let mut next = seq();
+        manager.allocate(&mut next).unwrap();
+        assert_eq!(next.num_cached_tokens, 0);
+    }
+
+    #[test]
+    fn append_slot_fails_when_out_of_blocks() {
+        let mut manager = BlockManager::new(1, 2);
+        let mut seq = Sequence::with_block_size(vec![1, 2], SamplingParams::default(), 2);
+        manager.allocate(&mut seq).unwrap();
+
+        seq.append_token(3);
+        assert!(manager.append_slot(&mut seq).is_err());
+        assert_eq!(seq.block_table.len(), 1);
+    }
+
+    #[test]
+    fn swap_out_and_in_round_trips_blocks() {
+        let mut manager = BlockManager::new(2, 4).with_cpu_blocks(2);
+        let mut seq = Sequence::with_block_size((0..6).collect(), SamplingParams::default(), 4);
+        manager.allocate(&mut seq).unwrap();
+        let gpu_blocks = seq.block_table.clone();
+
+        let out = manager.swap_out(&mut seq).unwrap();
+        assert_eq!(out.iter().map(|&(gpu, _)| gpu).collect::<Vec<_>>(), gpu_blocks);
+        assert_eq!((manager.num_free_blocks(), manager.num_free_cpu_blocks()), (2, 0));
+
+        let back = manager.swap_in(&mut seq).unwrap();
+        assert_eq!(back.len(), 2);
+        assert_eq!((manager.num_free_blocks(), manager.num_free_cpu_blocks()), (0, 2));
+    }
+
+    #[test]
+    fn swap_out_fails_when_swap_space_is_too_small() {
+        let mut manager = BlockManager::new(2, 4).with_cpu_blocks(1);
+        let mut seq = Sequence::with_block_size((0..6).collect(), SamplingParams::default(), 4);
+        manager.allocate(&mut seq).unwrap();
+        let gpu_blocks = seq.block_table.clone();
+
+        assert!(manager.swap_out(&mut seq).is_err());
+        assert_eq!(seq.block_table, gpu_blocks);
+        assert_eq!((manager.num_free_blocks(), manager.num_free_cpu_blocks()), (0, 1));
+    }
+}
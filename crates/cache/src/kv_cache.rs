@@ -0,0 +1,198 @@
+//! Per-layer storage of the paged KV cache
+//!
+//! The cache is allocated as one `(key, value)` pair of tensors per decoder
+//! layer. `KvCache` wraps that list so each attention layer can fetch its own
+//! pair by index instead of juggling the flat vector. It also copies whole
+//! blocks between two caches, which is how sequences are swapped between the
+//! GPU cache and the CPU swap pool.
+
+use candle_core::{Device, Result, Tensor};
+
+/// Key and value cache tensors of every decoder layer
+#[derive(Debug, Clone)]
+pub struct KvCache {
+    /// `(key, value)` cache tensors, indexed by layer
+    layers: Vec<(Tensor, Tensor)>,
+}
+
+impl KvCache {
+    /// Wraps the per-layer cache tensors
+    ///
+    /// # Arguments
+    ///
+    /// * `layers` - One `(key, value)` pair per decoder layer, in layer order
+    pub fn new(layers: Vec<(Tensor, Tensor)>) -> Self {
+        Self { layers }
+    }
+
+    /// Returns the number of layers in the cache
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Returns the key and value cache of a layer
+    ///
+    /// # Arguments
+    ///
+    /// * `idx` - Index of the decoder layer
+    ///
+    /// # Panics
+    ///
+    /// Panics if the layer index is out of bounds (>= num_layers())
+    pub fn layer(&self, idx: usize) -> (&Tensor, &Tensor) {
+        assert!(idx < self.num_layers(), "Layer index {} out of bounds for {} layers", idx, self.num_layers());
+        let (key, value) = &self.layers[idx];
+        (key, value)
+    }
+
+    /// Returns the key and value cache of a layer for writing
+    ///
+    /// # Arguments
+    ///
+    /// * `idx` - Index of the decoder layer
+    ///
+    /// # Panics
+    ///
+    /// Panics if the layer index is out of bounds (>= num_layers())
+    pub fn layer_mut(&mut self, idx: usize) -> (&mut Tensor, &mut Tensor) {
+        assert!(idx < self.num_layers(), "Layer index {} out of bounds for {} layers", idx, self.num_layers());
+        let (key, value) = &mut self.layers[idx];
+        (key, value)
+    }
+
+    /// Allocates a zeroed cache with this cache's layout on another device
+    ///
+    /// Every layer gets key and value tensors of shape
+    /// `[num_slots, num_kv_heads, head_dim]`, in the dtype of this cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_slots` - Number of token slots, i.e. blocks times block size
+    /// * `device` - Device on which the new cache is allocated
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a layer is not 3-dimensional or allocation fails.
+    pub fn zeros_like(&self, num_slots: usize, device: &Device) -> Result<Self> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|(key, value)| {
+                let zeros = |t: &Tensor| -> Result<Tensor> {
+                    let (_, num_kv_heads, head_dim) = t.dims3()?;
+                    Tensor::zeros((num_slots, num_kv_heads, head_dim), t.dtype(), device)
+                };
+                Ok((zeros(key)?, zeros(value)?))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { layers })
+    }
+
+    /// Copies whole blocks of every layer into another cache
+    ///
+    /// The copies are written in place, so the destination's tensors, and
+    /// any clone of them, see the new contents.
+    ///
+    /// # Arguments
+    ///
+    /// * `dst` - Cache to write to; it must have as many layers as this one
+    /// * `blocks` - `(src_block_id, dst_block_id)` pairs
+    /// * `block_size` - Number of slots in each block
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the caches have different numbers of layers, or
+    /// a block is out of range of its cache.
+    pub fn copy_blocks_to(&self, dst: &KvCache, blocks: &[(usize, usize)], block_size: usize) -> Result<()> {
+        if dst.num_layers() != self.num_layers() {
+            candle_core::bail!("cannot copy blocks of {} layers into a cache of {}", self.num_layers(), dst.num_layers());
+        }
+        for ((src_key, src_value), (dst_key, dst_value)) in self.layers.iter().zip(&dst.layers) {
+            for &(src_block, dst_block) in blocks {
+                for (src, dst) in [(src_key, dst_key), (src_value, dst_value)] {
+                    let rows = src.narrow(0, src_block * block_size, block_size)?;
+                    dst.slice_set(&rows.to_device(dst.device())?, 0, dst_block * block_size)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies whole blocks of every layer to other blocks of this cache
+    ///
+    /// Used to apply the relocations of `BlockManager::defragment`.
+    ///
+    /// # Arguments
+    ///
+    /// * `blocks` - `(src_block_id, dst_block_id)` pairs
+    /// * `block_size` - Number of slots in each block
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a block is out of range of the cache.
+    pub fn copy_blocks(&self, blocks: &[(usize, usize)], block_size: usize) -> Result<()> {
+        for (key, value) in &self.layers {
+            for &(src_block, dst_block) in blocks {
+                for cache in [key, value] {
+                    // A view of the cache would share the storage being written.
+                    let rows = cache.narrow(0, src_block * block_size, block_size)?.copy()?;
+                    cache.slice_set(&rows, 0, dst_block * block_size)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+
+    #[test]
+    fn each_layer_is_addressed_by_index() {
+        let layers = (0..3)
+            .map(|i| {
+                let key = Tensor::zeros((i + 1, 4, 2, 8), DType::F32, &Device::Cpu).unwrap();
+                let value = Tensor::zeros((i + 1, 4, 2, 8), DType::F32, &Device::Cpu).unwrap();
+                (key, value)
+            })
+            .collect();
+        let mut cache = KvCache::new(layers);
+        assert_eq!(cache.num_layers(), 3);
+        for i in 0..3 {
+            let (key, value) = cache.layer(i);
+            assert_eq!(key.dims(), [i + 1, 4, 2, 8]);
+            assert_eq!(value.dims(), [i + 1, 4, 2, 8]);
+        }
+
+        let (key, _) = cache.layer_mut(1);
+        *key = Tensor::ones((2, 4, 2, 8), DType::F32, &Device::Cpu).unwrap();
+        assert_eq!(cache.layer(1).0.sum_all().unwrap().to_scalar::<f32>().unwrap(), 128.0);
+    }
+
+    #[test]
+    fn blocks_round_trip_through_a_swap_pool() {
+        let block_size = 2;
+        let values = Tensor::arange(0f32, 48., &Device::Cpu).unwrap().reshape((6, 2, 4)).unwrap();
+        let gpu = KvCache::new(vec![(values.clone(), (values * 2.0).unwrap())]);
+        let cpu = gpu.zeros_like(2 * block_size, &Device::Cpu).unwrap();
+        assert_eq!(cpu.layer(0).0.dims(), [4, 2, 4]);
+
+        gpu.copy_blocks_to(&cpu, &[(2, 1)], block_size).unwrap();
+        let rows = |t: &Tensor, block: usize| t.narrow(0, block * block_size, block_size).unwrap().flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(rows(cpu.layer(0).0, 1), rows(gpu.layer(0).0, 2));
+        assert_eq!(rows(cpu.layer(0).1, 1), rows(gpu.layer(0).1, 2));
+
+        cpu.copy_blocks_to(&gpu, &[(1, 0)], block_size).unwrap();
+        assert_eq!(rows(gpu.layer(0).0, 0), rows(gpu.layer(0).0, 2));
+        assert!(gpu.copy_blocks_to(&cpu, &[(0, 2)], block_size).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn out_of_range_layer_panics() {
+        let key = Tensor::zeros((1, 4), DType::F32, &Device::Cpu).unwrap();
+        KvCache::new(vec![(key.clone(), key)]).layer(1);
+    }
+}
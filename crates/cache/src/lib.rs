@@ -1,14 +1,19 @@
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
-}
+//! KV cache management for the candle-nano-vllm project
+//!
+//! This crate provides the bookkeeping for the paged key-value cache,
+//! including allocation of physical blocks to sequences and prefix caching.
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+mod block_manager;
+mod kv_cache;
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+/// Re-exports from the block_manager module
+///
+/// These exports provide access to the BlockManager and its Block type
+/// for allocating and reclaiming KV cache blocks.
+pub use block_manager::{Block, BlockManager};
+
+/// Re-exports from the kv_cache module
+///
+/// `KvCache` gives each attention layer access to its own key and value
+/// cache tensors.
+pub use kv_cache::KvCache;
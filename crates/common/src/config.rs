@@ -1,12 +1,13 @@
-/// Configuration for model loading and inference
-///
-/// This module provides types and functions for configuring the behavior
-/// of language models, including memory usage, batch sizes, and other
-/// performance-related parameters.
+//! Configuration for model loading and inference
+//!
+//! This module provides types and functions for configuring the behavior
+//! of language models, including memory usage, batch sizes, and other
+//! performance-related parameters.
 
 use anyhow::Result;
-use candle_transformers::models::qwen2::Config as HfConfig;
-use serde::Deserialize;
+use candle_core::Device;
+use crate::hf_config::{HfConfig, ModelArch};
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
 /// Configuration for model loading and inference
@@ -17,7 +18,7 @@ use std::path::PathBuf;
 ///
 /// The configuration can be loaded from a file or created programmatically.
 /// Many fields have sensible defaults that can be overridden as needed.
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     /// Directory containing the model files
     ///
@@ -42,6 +43,15 @@ pub struct Config {
     #[serde(default = "default_max_num_seqs")]
     pub max_num_seqs: usize,
     
+    /// Maximum number of prompt tokens prefilled for a sequence in one step
+    ///
+    /// Prompts longer than this are prefilled in chunks over several steps,
+    /// which bounds the latency of each step for long prompts. When unset,
+    /// chunks are bounded only by `max_num_batched_tokens`. Must be at
+    /// least 1 when set.
+    #[serde(default)]
+    pub max_num_partial_prefill_tokens: Option<usize>,
+    
     /// Maximum sequence length supported by the model
     ///
     /// This is the maximum number of tokens that can be in a single sequence,
@@ -49,6 +59,14 @@ pub struct Config {
     /// by the model's training configuration.
     #[serde(default = "default_max_model_len")]
     pub max_model_len: usize,
+
+    /// Number of decode steps `LLMEngine::multi_step` runs per scheduling
+    ///
+    /// Values above 1 reuse the same decode batch for several steps,
+    /// amortizing the scheduling overhead, and re-schedule early only when
+    /// a sequence finishes or needs a block that is not free.
+    #[serde(default = "default_num_scheduler_steps")]
+    pub num_scheduler_steps: usize,
     
     /// Fraction of GPU memory to use for the model
     ///
@@ -73,6 +91,14 @@ pub struct Config {
     /// CUDA graphs cause issues.
     #[serde(default)]
     pub enforce_eager: bool,
+
+    /// Decode batch sizes for which CUDA graphs are captured
+    ///
+    /// A decode batch is padded up to the smallest captured size that fits
+    /// it; batches larger than every captured size run eagerly. Capturing
+    /// only these sizes bounds the memory held by the graphs.
+    #[serde(default = "default_cuda_graph_batch_sizes")]
+    pub cuda_graph_batch_sizes: Vec<usize>,
     
     /// Size of each block in the KV cache, in tokens
     ///
@@ -82,11 +108,68 @@ pub struct Config {
     #[serde(default = "default_kvcache_block_size")]
     pub kvcache_block_size: usize,
     
+    /// Data type used for model weights and the KV cache
+    ///
+    /// Uses the Hugging Face naming (e.g. "bfloat16", "float16", "float32").
+    /// It determines the memory footprint of both weights and cache blocks.
+    #[serde(default = "default_dtype")]
+    pub dtype: String,
+
+    /// Device the model runs on
+    ///
+    /// One of "cpu", "cuda", "metal", optionally with an ordinal such as
+    /// "cuda:1", or "auto" to pick the first available of CUDA, Metal, and
+    /// the CPU. See `Config::resolve_device`.
+    #[serde(default = "default_device")]
+    pub device: String,
+
+    /// Size of the CPU-side KV cache pool used for swapping, in bytes
+    ///
+    /// Preempted sequences are swapped out to this pool when it has room,
+    /// which avoids recomputing their prompts. Set to 0 to always preempt
+    /// by recomputation.
+    #[serde(default = "default_swap_space_bytes")]
+    pub swap_space_bytes: usize,
+
+    /// Base of the rotary embedding frequencies, replacing the model's
+    /// `rope_theta`
+    ///
+    /// Fine-tuned long-context models sometimes change the base, and it is
+    /// not always recorded in `config.json`. See `Config::rope_theta`.
+    #[serde(default)]
+    pub rope_theta_override: Option<f64>,
+
+    /// Attention kernel used by the attention layers
+    ///
+    /// When unset, each forward pass uses `AttentionBackend::Paged` if its
+    /// context carries block tables and `AttentionBackend::Naive` otherwise.
+    #[serde(default)]
+    pub attention_backend: Option<AttentionBackend>,
+
+    /// Seed that makes a whole engine run reproducible
+    ///
+    /// Requests without their own `SamplingParams.seed` get one derived from
+    /// this seed and the order in which they were added, so replaying the
+    /// same requests in the same order reproduces every completion. When
+    /// None, such requests are sampled unseeded.
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    /// How over-length prompts are shortened instead of rejected
+    ///
+    /// When set, a prompt that leaves no room for a completion within
+    /// `max_model_len` keeps its `keep_prefix` leading tokens (typically the
+    /// system prompt) and its most recent tokens, dropping the middle. When
+    /// None, such prompts are rejected.
+    #[serde(default)]
+    pub prompt_truncation: Option<PromptTruncation>,
+    
     /// Hugging Face model configuration
     ///
     /// This contains the model-specific parameters loaded from the
-    /// config.json file in the model directory. It's loaded dynamically
-    /// and not deserialized directly from configuration files.
+    /// config.json file in the model directory, parsed according to the
+    /// detected architecture. It's loaded dynamically and not deserialized
+    /// directly from configuration files.
     #[serde(skip)] // This will be loaded dynamically
     pub hf_config: Option<HfConfig>,
     
@@ -104,6 +187,14 @@ pub struct Config {
     /// the key-value cache.
     #[serde(skip)]
     pub num_kvcache_blocks: Option<usize>,
+
+    /// Number of blocks in the CPU-side KV cache pool
+    ///
+    /// When unset, the engine calculates it from `swap_space_bytes` with
+    /// `compute_num_cpu_blocks` for models that expose their KV cache; for
+    /// other models no sequences are swapped.
+    #[serde(skip)]
+    pub num_cpu_blocks: Option<usize>,
 }
 
 /// Default value for maximum number of tokens in a batch
@@ -124,6 +215,11 @@ fn default_max_num_seqs() -> usize { 512 }
 /// many modern language models.
 fn default_max_model_len() -> usize { 4096 }
 
+/// Default number of decode steps per scheduling
+///
+/// Returns 1, which re-schedules before every step.
+fn default_num_scheduler_steps() -> usize { 1 }
+
 /// Default value for GPU memory utilization
 ///
 /// Returns 0.9 (90%), which reserves some GPU memory for system
@@ -136,12 +232,132 @@ fn default_gpu_memory_utilization() -> f64 { 0.9 }
 /// This is appropriate for single-GPU setups.
 fn default_tensor_parallel_size() -> usize { 1 }
 
+/// Default value for the captured CUDA graph batch sizes
+///
+/// Returns the powers of two from 1 to 512, which covers the default
+/// `max_num_seqs` while padding any batch by less than a factor of two.
+fn default_cuda_graph_batch_sizes() -> Vec<usize> { (0..10).map(|i| 1 << i).collect() }
+
 /// Default value for KV cache block size
 ///
 /// Returns 256 tokens per block, which provides a good balance
 /// between memory efficiency and performance for most use cases.
 fn default_kvcache_block_size() -> usize { 256 }
 
+/// Default value for the model data type
+///
+/// Returns "bfloat16", the dtype most modern checkpoints are published in.
+fn default_dtype() -> String { "bfloat16".to_string() }
+
+/// Default value for the model device
+///
+/// Returns "auto", which selects the fastest backend available at runtime.
+fn default_device() -> String { "auto".to_string() }
+
+/// Default value for the CPU swap space
+///
+/// Returns 4 GiB, enough to hold the KV cache of several preempted
+/// sequences without noticeably reducing host memory.
+fn default_swap_space_bytes() -> usize { 4 << 30 }
+
+/// Attention kernels the attention layers can dispatch to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttentionBackend {
+    /// Reference implementation that attends over the keys and values of
+    /// the current forward pass only
+    Naive,
+
+    /// Reads each sequence's keys and values from the KV cache through its
+    /// block table
+    Paged,
+
+    /// Fused flash-attention kernels, which this build does not include
+    Flash,
+}
+
+impl AttentionBackend {
+    /// Returns whether this build can run the backend
+    pub fn is_available(self) -> bool {
+        !matches!(self, Self::Flash)
+    }
+}
+
+/// Policy for shortening prompts that do not fit `max_model_len`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PromptTruncation {
+    /// Number of leading prompt tokens that are never dropped
+    #[serde(default)]
+    pub keep_prefix: usize,
+}
+
+/// Default implementation for Config
+///
+/// Creates a new Config using the same defaults as deserialization, with
+/// an empty model directory and all runtime-resolved fields unset.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            model_dir: PathBuf::new(),
+            max_num_batched_tokens: default_max_num_batched_tokens(),
+            max_num_seqs: default_max_num_seqs(),
+            max_num_partial_prefill_tokens: None,
+            max_model_len: default_max_model_len(),
+            num_scheduler_steps: default_num_scheduler_steps(),
+            gpu_memory_utilization: default_gpu_memory_utilization(),
+            tensor_parallel_size: default_tensor_parallel_size(),
+            enforce_eager: false,
+            cuda_graph_batch_sizes: default_cuda_graph_batch_sizes(),
+            kvcache_block_size: default_kvcache_block_size(),
+            dtype: default_dtype(),
+            device: default_device(),
+            swap_space_bytes: default_swap_space_bytes(),
+            rope_theta_override: None,
+            attention_backend: None,
+            seed: None,
+            prompt_truncation: None,
+            hf_config: None,
+            eos_token_id: None,
+            num_kvcache_blocks: None,
+            num_cpu_blocks: None,
+        }
+    }
+}
+
+/// Flattened view of the model-specific parameters in `HfConfig`
+///
+/// The candle config types only implement `Deserialize`, so the fields
+/// relevant for diagnosing a run are copied into this serializable view.
+#[derive(Debug, Serialize)]
+struct HfConfigView {
+    arch: ModelArch,
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    max_position_embeddings: usize,
+    tie_word_embeddings: bool,
+    rope_theta: f64,
+    rms_norm_eps: f64,
+    hidden_act: serde_json::Value,
+}
+
+/// Serializable view of a fully resolved configuration
+///
+/// Combines the user-facing configuration with the values that are resolved
+/// at runtime and therefore skipped during regular (de)serialization.
+#[derive(Debug, Serialize)]
+struct RuntimeConfigView<'a> {
+    #[serde(flatten)]
+    config: &'a Config,
+    hf_config: Option<HfConfigView>,
+    eos_token_id: Option<u32>,
+    num_kvcache_blocks: Option<usize>,
+    num_cpu_blocks: Option<usize>,
+}
+
 impl Config {
     /// Creates a new Config from a model directory
     ///
@@ -164,11 +380,12 @@ impl Config {
     /// - The config.json file does not exist in the model directory
     /// - The file cannot be read
     /// - The file contains invalid JSON
-    /// - The JSON does not match the expected HfConfig structure
+    /// - The model architecture is not supported
+    /// - The JSON does not match the architecture's config structure
     pub fn new(model_dir: PathBuf) -> Result<Self> {
         // TODO: Load from a file, but for now, we construct it.
         let hf_config_path = model_dir.join("config.json");
-        let hf_config: HfConfig = serde_json::from_str(&std::fs::read_to_string(hf_config_path)?)?;
+        let hf_config = HfConfig::from_json(&std::fs::read_to_string(hf_config_path)?)?;
 
         Ok(Self {
             model_dir,
@@ -176,4 +393,313 @@ impl Config {
             ..Default::default()
         })
     }
+
+    /// Returns the number of bytes one KV cache block occupies
+    ///
+    /// A block holds the keys and values of `kvcache_block_size` tokens for
+    /// every layer and key-value head.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `hf_config` is not loaded or `dtype` is unknown
+    pub fn kvcache_block_bytes(&self) -> Result<usize> {
+        Ok(self.kvcache_block_size * self.kv_bytes_per_token()?)
+    }
+
+    /// Returns the number of KV cache bytes one token occupies on each device
+    ///
+    /// A token holds a key and a value for every layer and key-value head.
+    /// With tensor parallelism each device holds its share of the heads, and
+    /// at least one head when there are fewer heads than devices.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `hf_config` is not loaded or `dtype` is unknown
+    pub fn kv_bytes_per_token(&self) -> Result<usize> {
+        let Some(hf) = &self.hf_config else {
+            anyhow::bail!("hf_config must be loaded to compute the KV cache size");
+        };
+        let num_kv_heads = (hf.num_key_value_heads() / self.tensor_parallel_size.max(1)).max(1);
+        Ok(2 * hf.num_hidden_layers() * num_kv_heads * hf.head_dim() * self.dtype_bytes()?)
+    }
+
+    /// Returns the dimension of each attention head
+    ///
+    /// The single source of the head dimension for every module; see
+    /// `HfConfig::head_dim`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hf_config` is not loaded
+    pub fn head_dim(&self) -> usize {
+        self.hf_config.as_ref().expect("hf_config must be loaded to know the head dimension").head_dim()
+    }
+
+    /// Returns the base of the rotary embedding frequencies
+    ///
+    /// `rope_theta_override` when set, the model's `rope_theta` otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if neither the override nor `hf_config` is set
+    pub fn rope_theta(&self) -> f64 {
+        self.rope_theta_override.unwrap_or_else(|| {
+            self.hf_config.as_ref().expect("hf_config must be loaded to know rope_theta").rope_theta()
+        })
+    }
+
+    /// Estimates the number of bytes the model weights occupy
+    ///
+    /// Counts the embedding, the attention projections (with their biases
+    /// when `HfConfig::attention_bias` is set), MLP and norms of every
+    /// layer, the final norm, and the LM head, which is not counted again
+    /// when it is tied to the embedding.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `hf_config` is not loaded or `dtype` is unknown
+    pub fn estimated_weight_bytes(&self) -> Result<usize> {
+        let Some(hf) = &self.hf_config else {
+            anyhow::bail!("hf_config must be loaded to estimate the weight size");
+        };
+        let hidden = hf.hidden_size();
+        let q_dim = hf.num_attention_heads() * hf.head_dim();
+        let kv_dim = hf.num_key_value_heads() * hf.head_dim();
+        let embedding = hf.vocab_size() * hidden;
+
+        let qkv_bias = if hf.attention_bias() { q_dim + 2 * kv_dim } else { 0 };
+        let attention = hidden * q_dim + 2 * hidden * kv_dim + qkv_bias + q_dim * hidden;
+        let mlp = 3 * hidden * hf.intermediate_size();
+        let layer = attention + mlp + 2 * hidden;
+
+        let lm_head = if hf.tie_word_embeddings() { 0 } else { embedding };
+        let num_params = embedding + hf.num_hidden_layers() * layer + hidden + lm_head;
+        Ok(num_params * self.dtype_bytes()?)
+    }
+
+    /// Returns the number of bytes of one element of `dtype`
+    fn dtype_bytes(&self) -> Result<usize> {
+        match self.dtype.as_str() {
+            "float32" => Ok(4),
+            "float16" | "bfloat16" => Ok(2),
+            other => anyhow::bail!("Unsupported dtype {}", other),
+        }
+    }
+
+    /// Creates the device named by `device`
+    ///
+    /// With "auto", CUDA and then Metal are probed and the first backend
+    /// that is compiled in and initializes is used, falling back to the CPU.
+    /// The chosen device is logged. Any other value forces that device.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device string is not recognized, or if an
+    /// explicitly requested device is not available.
+    pub fn resolve_device(&self) -> Result<Device> {
+        let (backend, ordinal) = match self.device.split_once(':') {
+            Some((backend, ordinal)) => (backend, ordinal.parse::<usize>()?),
+            None => (self.device.as_str(), 0),
+        };
+        match backend {
+            "auto" => {
+                let (name, device) = if candle_core::utils::cuda_is_available()
+                    && let Ok(device) = Device::new_cuda(ordinal)
+                {
+                    ("cuda", device)
+                } else if candle_core::utils::metal_is_available()
+                    && let Ok(device) = Device::new_metal(ordinal)
+                {
+                    ("metal", device)
+                } else {
+                    ("cpu", Device::Cpu)
+                };
+                log::info!("Resolved device \"auto\" to {}", name);
+                Ok(device)
+            }
+            "cpu" => Ok(Device::Cpu),
+            "cuda" => Ok(Device::new_cuda(ordinal)?),
+            "metal" => Ok(Device::new_metal(ordinal)?),
+            other => anyhow::bail!("Unsupported device {}", other),
+        }
+    }
+
+    /// Selects the captured CUDA graph batch size for a decode batch
+    ///
+    /// # Arguments
+    ///
+    /// * `batch_size` - Number of sequences in the decode batch
+    ///
+    /// # Returns
+    ///
+    /// The smallest entry of `cuda_graph_batch_sizes` that is at least
+    /// `batch_size`, to which the batch is padded, or None if the batch must
+    /// run eagerly because it is too large or `enforce_eager` is set
+    pub fn cuda_graph_batch_size(&self, batch_size: usize) -> Option<usize> {
+        if self.enforce_eager {
+            return None;
+        }
+        self.cuda_graph_batch_sizes.iter().copied().filter(|&size| size >= batch_size).min()
+    }
+
+    /// Computes how many KV cache blocks fit in the CPU swap space
+    ///
+    /// # Returns
+    ///
+    /// `swap_space_bytes` divided by the size of one block, rounded down
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the block size cannot be computed
+    pub fn compute_num_cpu_blocks(&self) -> Result<usize> {
+        Ok(self.swap_space_bytes / self.kvcache_block_bytes()?)
+    }
+
+    /// Serializes the configuration together with its resolved runtime values
+    ///
+    /// Regular serialization skips `hf_config`, `eos_token_id`, and
+    /// `num_kvcache_blocks` since they are not part of the input configuration.
+    /// This view includes them, which makes it suitable for logging exactly
+    /// which configuration a run used.
+    ///
+    /// # Returns
+    ///
+    /// A pretty-printed JSON string of the runtime configuration
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the configuration cannot be serialized
+    pub fn to_runtime_json(&self) -> Result<String> {
+        let hf_config = match &self.hf_config {
+            Some(HfConfig::Qwen2(hf)) => Some(HfConfigView {
+                arch: ModelArch::Qwen2,
+                vocab_size: hf.vocab_size,
+                hidden_size: hf.hidden_size,
+                intermediate_size: hf.intermediate_size,
+                num_hidden_layers: hf.num_hidden_layers,
+                num_attention_heads: hf.num_attention_heads,
+                num_key_value_heads: hf.num_key_value_heads,
+                max_position_embeddings: hf.max_position_embeddings,
+                tie_word_embeddings: hf.tie_word_embeddings,
+                rope_theta: hf.rope_theta,
+                rms_norm_eps: hf.rms_norm_eps,
+                hidden_act: serde_json::to_value(hf.hidden_act)?,
+            }),
+            None => None,
+        };
+        let view = RuntimeConfigView {
+            config: self,
+            hf_config,
+            eos_token_id: self.eos_token_id,
+            num_kvcache_blocks: self.num_kvcache_blocks,
+            num_cpu_blocks: self.num_cpu_blocks,
+        };
+        Ok(serde_json::to_string_pretty(&view)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal Qwen2-style config.json used across the config tests
+    const QWEN2_CONFIG: &str = r#"{
+        "architectures": ["Qwen2ForCausalLM"],
+        "model_type": "qwen2",
+        "vocab_size": 1000,
+        "hidden_size": 64,
+        "intermediate_size": 128,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 4,
+        "num_key_value_heads": 2,
+        "max_position_embeddings": 4096,
+        "sliding_window": 4096,
+        "max_window_layers": 2,
+        "tie_word_embeddings": true,
+        "rope_theta": 1000000.0,
+        "rms_norm_eps": 1e-6,
+        "use_sliding_window": false,
+        "hidden_act": "silu"
+    }"#;
+
+    #[test]
+    fn runtime_json_includes_resolved_fields() {
+        let config = Config {
+            hf_config: Some(HfConfig::from_json(QWEN2_CONFIG).unwrap()),
+            eos_token_id: Some(151643),
+            num_kvcache_blocks: Some(42),
+            ..Default::default()
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&config.to_runtime_json().unwrap()).unwrap();
+        assert_eq!(json["eos_token_id"], 151643);
+        assert_eq!(json["num_kvcache_blocks"], 42);
+        assert_eq!(json["dtype"], "bfloat16");
+        assert_eq!(json["kvcache_block_size"], 256);
+        assert_eq!(json["hf_config"]["arch"], "qwen2");
+        assert_eq!(json["hf_config"]["vocab_size"], 1000);
+        assert_eq!(json["hf_config"]["hidden_act"], "silu");
+    }
+
+    #[test]
+    fn decode_batches_pad_to_captured_graph_sizes() {
+        let mut config = Config::default();
+        assert_eq!(config.cuda_graph_batch_size(3), Some(4));
+        assert_eq!(config.cuda_graph_batch_size(16), Some(16));
+        assert_eq!(config.cuda_graph_batch_size(10_000), None);
+
+        config.enforce_eager = true;
+        assert_eq!(config.cuda_graph_batch_size(3), None);
+    }
+
+    #[test]
+    fn auto_device_falls_back_to_cpu() {
+        let mut config = Config::default();
+        assert_eq!(config.device, "auto");
+        if !candle_core::utils::cuda_is_available() && !candle_core::utils::metal_is_available() {
+            assert!(config.resolve_device().unwrap().is_cpu());
+            config.device = "cuda".to_string();
+            assert!(config.resolve_device().is_err());
+        }
+        config.device = "tpu".to_string();
+        assert_eq!(config.resolve_device().unwrap_err().to_string(), "Unsupported device tpu");
+    }
+
+    #[test]
+    fn weight_bytes_count_tied_embeddings_once() {
+        let mut hf = HfConfig::from_json(QWEN2_CONFIG).unwrap();
+        let mut config = Config { hf_config: Some(hf.clone()), ..Default::default() };
+        // Per layer: q 64*64+64, k and v 64*32+32 each, o 64*64, MLP 3*64*128, norms 2*64
+        let layer = 4160 + 2 * 2080 + 4096 + 24576 + 128;
+        let tied = 1000 * 64 + 2 * layer + 64;
+        assert_eq!(config.estimated_weight_bytes().unwrap(), tied * 2);
+
+        let HfConfig::Qwen2(qwen2) = &mut hf;
+        qwen2.tie_word_embeddings = false;
+        config.hf_config = Some(hf);
+        assert_eq!(config.estimated_weight_bytes().unwrap(), (tied + 1000 * 64) * 2);
+        assert!(Config::default().estimated_weight_bytes().is_err());
+    }
+
+    #[test]
+    fn cpu_blocks_are_bounded_by_swap_space() {
+        let mut config = Config {
+            hf_config: Some(HfConfig::from_json(QWEN2_CONFIG).unwrap()),
+            ..Default::default()
+        };
+        // 2 (K and V) * 2 layers * 256 tokens * 2 KV heads * 16 head dim * 2 bytes
+        assert_eq!(config.kvcache_block_bytes().unwrap(), 65536);
+        assert_eq!(config.compute_num_cpu_blocks().unwrap(), 65536);
+
+        config.swap_space_bytes = 100_000;
+        assert_eq!(config.compute_num_cpu_blocks().unwrap(), 1);
+        assert!(Config::default().compute_num_cpu_blocks().is_err());
+
+        // Each of two devices holds one of the two KV heads, and no device
+        // holds less than one head.
+        config.tensor_parallel_size = 2;
+        assert_eq!(config.kvcache_block_bytes().unwrap(), 32768);
+        config.tensor_parallel_size = 4;
+        assert_eq!(config.kvcache_block_bytes().unwrap(), 32768);
+    }
 }
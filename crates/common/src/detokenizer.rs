@@ -0,0 +1,187 @@
+//! Conversion of token IDs back to text
+//!
+//! Output formatting only needs to turn token IDs into text, so it depends
+//! on the small `Detokenizer` trait rather than on a tokenizer library. With
+//! the `tokenizers` feature, Hugging Face tokenizers implement it directly.
+
+use anyhow::Result;
+
+/// Decodes token IDs into text
+pub trait Detokenizer {
+    /// Decodes a sequence of token IDs into text
+    ///
+    /// # Arguments
+    ///
+    /// * `token_ids` - The token IDs to decode
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a token ID is not in the vocabulary or the
+    /// decoded bytes are not valid text.
+    fn decode(&self, token_ids: &[u32]) -> Result<String>;
+}
+
+/// Decodes with a Hugging Face tokenizer, skipping special tokens
+#[cfg(feature = "tokenizers")]
+impl Detokenizer for tokenizers::Tokenizer {
+    fn decode(&self, token_ids: &[u32]) -> Result<String> {
+        // Tokenizer derefs to TokenizerImpl, whose inherent decode takes the flag.
+        (**self).decode(token_ids, true).map_err(|e| anyhow::anyhow!(e))
+    }
+}
+
+/// Per-sequence state for decoding a completion one token at a time
+///
+/// Re-decoding a whole completion for every token is quadratic, while
+/// decoding tokens one by one loses the spacing and multi-byte characters
+/// that depend on neighbouring tokens. This keeps a short window instead:
+/// the tokens from `prefix_offset` to `read_offset` were already emitted
+/// and give the newest tokens their context, and a new piece is only
+/// emitted once it no longer ends in an incomplete character.
+#[derive(Debug, Clone, Default)]
+pub struct IncrementalDetokenizer {
+    /// Completion tokens seen so far
+    token_ids: Vec<u32>,
+
+    /// Start of the window decoded for context
+    prefix_offset: usize,
+
+    /// End of the tokens whose text was already emitted
+    read_offset: usize,
+
+    /// Whether `flush` drops a trailing incomplete character instead of
+    /// emitting it as U+FFFD
+    drop_incomplete_tail: bool,
+}
+
+impl IncrementalDetokenizer {
+    /// Creates the state for an empty completion
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `flush` drop the bytes of a trailing incomplete character
+    ///
+    /// By default they are emitted as U+FFFD replacement characters, so the
+    /// pieces always add up to the decode of the whole completion.
+    pub fn with_incomplete_tail_dropped(mut self) -> Self {
+        self.drop_incomplete_tail = true;
+        self
+    }
+
+    /// Returns the completion tokens seen so far
+    pub fn token_ids(&self) -> &[u32] {
+        &self.token_ids
+    }
+
+    /// Adds a token and returns the text it adds to the completion
+    ///
+    /// The piece is empty while the newest tokens only form part of a
+    /// character; their bytes are emitted with a later token or by `flush`.
+    ///
+    /// # Arguments
+    ///
+    /// * `detokenizer` - Decodes the token window
+    /// * `token_id` - The new completion token
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the window cannot be decoded.
+    pub fn push(&mut self, detokenizer: &dyn Detokenizer, token_id: u32) -> Result<String> {
+        self.token_ids.push(token_id);
+        let (prefix_text, new_text) = self.decode_window(detokenizer)?;
+        if new_text.ends_with('\u{FFFD}') {
+            return Ok(String::new());
+        }
+        let Some(piece) = new_text.get(prefix_text.len()..) else {
+            return Ok(String::new());
+        };
+        let piece = piece.to_string();
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.token_ids.len();
+        Ok(piece)
+    }
+
+    /// Returns any text still held back, even if it ends in an incomplete
+    /// character
+    ///
+    /// Call this once the completion has ended so that the concatenated
+    /// pieces equal the decode of the whole completion. With
+    /// `with_incomplete_tail_dropped`, the replacement characters of a
+    /// trailing incomplete character are left out instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the window cannot be decoded.
+    pub fn flush(&mut self, detokenizer: &dyn Detokenizer) -> Result<String> {
+        if self.read_offset == self.token_ids.len() {
+            return Ok(String::new());
+        }
+        let (prefix_text, new_text) = self.decode_window(detokenizer)?;
+        let mut piece = new_text.get(prefix_text.len()..).unwrap_or_default();
+        if self.drop_incomplete_tail {
+            piece = piece.trim_end_matches('\u{FFFD}');
+        }
+        let piece = piece.to_string();
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.token_ids.len();
+        Ok(piece)
+    }
+
+    /// Decodes the emitted context window and the window with the new tokens
+    fn decode_window(&self, detokenizer: &dyn Detokenizer) -> Result<(String, String)> {
+        let prefix_text = detokenizer.decode(&self.token_ids[self.prefix_offset..self.read_offset])?;
+        let new_text = detokenizer.decode(&self.token_ids[self.prefix_offset..])?;
+        Ok((prefix_text, new_text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Detokenizer treating each token ID as one UTF-8 byte
+    struct Bytes;
+
+    impl Detokenizer for Bytes {
+        fn decode(&self, token_ids: &[u32]) -> Result<String> {
+            let bytes: Vec<u8> = token_ids.iter().map(|&id| id as u8).collect();
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        }
+    }
+
+    #[test]
+    fn pieces_concatenate_to_the_full_decode() {
+        let text = "héllo 👋!";
+        let token_ids: Vec<u32> = text.bytes().map(u32::from).collect();
+
+        let mut state = IncrementalDetokenizer::new();
+        let pieces: Vec<String> = token_ids.iter().map(|&id| state.push(&Bytes, id).unwrap()).collect();
+        assert_eq!(pieces.concat() + &state.flush(&Bytes).unwrap(), text);
+        // The two bytes of 'é' emit nothing, then the whole character.
+        assert_eq!(pieces[1], "");
+        assert_eq!(pieces[2], "é");
+
+        // Bytes still buffered at the end are flushed as they decode.
+        let mut state = IncrementalDetokenizer::new();
+        assert_eq!(state.push(&Bytes, 0xC3).unwrap(), "");
+        assert_eq!(state.flush(&Bytes).unwrap(), Bytes.decode(&[0xC3]).unwrap());
+        assert_eq!(state.token_ids(), &[0xC3]);
+    }
+
+    #[test]
+    fn split_character_is_never_emitted_half_decoded() {
+        // 'é' is 0xC3 0xA9, split across two tokens.
+        let mut state = IncrementalDetokenizer::new();
+        let pieces: Vec<String> = [b'a', 0xC3, 0xA9].iter().map(|&b| state.push(&Bytes, b.into()).unwrap()).collect();
+        assert_eq!(pieces, vec!["a", "", "é"]);
+        assert!(pieces.iter().all(|piece| !piece.contains('\u{FFFD}')));
+
+        // A completion ending mid-character can drop the incomplete bytes.
+        let mut state = IncrementalDetokenizer::new().with_incomplete_tail_dropped();
+        assert_eq!(state.push(&Bytes, b'a'.into()).unwrap(), "a");
+        assert_eq!(state.push(&Bytes, 0xE2).unwrap(), "");
+        assert_eq!(state.push(&Bytes, 0x82).unwrap(), "");
+        assert_eq!(state.flush(&Bytes).unwrap(), "");
+    }
+}
@@ -0,0 +1,242 @@
+//! Hugging Face model configurations
+//!
+//! This module reads a model's `config.json`, detects its architecture from
+//! the `architectures` or `model_type` field, and parses it into the matching
+//! candle configuration type.
+
+use anyhow::Result;
+use candle_transformers::models::qwen2::Config as Qwen2Config;
+use serde::{Deserialize, Serialize};
+use std::ops::{Deref, DerefMut};
+
+/// Model architectures supported by the engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelArch {
+    /// Qwen2 decoder-only models (`Qwen2ForCausalLM`)
+    Qwen2,
+}
+
+impl ModelArch {
+    /// Detects the architecture of a parsed `config.json`
+    ///
+    /// The first entry of `architectures` is checked first, falling back to
+    /// `model_type` for configs that omit it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the architecture if it is not supported, or
+    /// if the config has neither field.
+    pub fn detect(config: &serde_json::Value) -> Result<Self> {
+        let architecture = config["architectures"].get(0).and_then(|a| a.as_str());
+        if let Some(name) = architecture {
+            match name {
+                "Qwen2ForCausalLM" => return Ok(Self::Qwen2),
+                other => anyhow::bail!("Unsupported architecture {}", other),
+            }
+        }
+        match config["model_type"].as_str() {
+            Some("qwen2") => Ok(Self::Qwen2),
+            Some(other) => anyhow::bail!("Unsupported model_type {}", other),
+            None => anyhow::bail!("config.json has neither an architectures nor a model_type field"),
+        }
+    }
+}
+
+/// Qwen2 configuration with the fields candle's config does not read
+///
+/// Dereferences to the candle `Qwen2Config`, so its fields are accessed
+/// directly.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Qwen2HfConfig {
+    /// The configuration as read by candle
+    #[serde(flatten)]
+    pub base: Qwen2Config,
+
+    /// Dimension of each attention head, when set independently of
+    /// `hidden_size / num_attention_heads`
+    #[serde(default)]
+    pub head_dim: Option<usize>,
+}
+
+impl Deref for Qwen2HfConfig {
+    type Target = Qwen2Config;
+
+    fn deref(&self) -> &Qwen2Config {
+        &self.base
+    }
+}
+
+impl DerefMut for Qwen2HfConfig {
+    fn deref_mut(&mut self) -> &mut Qwen2Config {
+        &mut self.base
+    }
+}
+
+/// Hugging Face model configuration for one of the supported architectures
+///
+/// Accessors expose the fields every architecture shares, so engine code
+/// does not need to match on the variant.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HfConfig {
+    /// Configuration of a Qwen2 model
+    Qwen2(Qwen2HfConfig),
+}
+
+impl HfConfig {
+    /// Parses the contents of a `config.json` file
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - The contents of `config.json`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the JSON is invalid, the architecture is not
+    /// supported, or the config does not match the architecture's schema.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        match ModelArch::detect(&value)? {
+            ModelArch::Qwen2 => {
+                let config = Self::Qwen2(serde_json::from_value(value)?);
+                let (head_dim, num_heads) = (config.head_dim(), config.num_attention_heads());
+                if head_dim * num_heads != config.hidden_size() {
+                    log::warn!(
+                        "head_dim {} times {} attention heads does not match hidden_size {}",
+                        head_dim, num_heads, config.hidden_size()
+                    );
+                }
+                Ok(config)
+            }
+        }
+    }
+
+    /// Returns the architecture of this configuration
+    pub fn arch(&self) -> ModelArch {
+        match self {
+            Self::Qwen2(_) => ModelArch::Qwen2,
+        }
+    }
+
+    /// Returns the number of tokens in the vocabulary
+    pub fn vocab_size(&self) -> usize {
+        match self {
+            Self::Qwen2(c) => c.vocab_size,
+        }
+    }
+
+    /// Returns the width of the hidden states
+    pub fn hidden_size(&self) -> usize {
+        match self {
+            Self::Qwen2(c) => c.hidden_size,
+        }
+    }
+
+    /// Returns the width of the MLP's hidden layer
+    pub fn intermediate_size(&self) -> usize {
+        match self {
+            Self::Qwen2(c) => c.intermediate_size,
+        }
+    }
+
+    /// Returns whether the LM head shares the input embedding's weight
+    pub fn tie_word_embeddings(&self) -> bool {
+        match self {
+            Self::Qwen2(c) => c.tie_word_embeddings,
+        }
+    }
+
+    /// Returns the number of decoder layers
+    pub fn num_hidden_layers(&self) -> usize {
+        match self {
+            Self::Qwen2(c) => c.num_hidden_layers,
+        }
+    }
+
+    /// Returns the number of query heads
+    pub fn num_attention_heads(&self) -> usize {
+        match self {
+            Self::Qwen2(c) => c.num_attention_heads,
+        }
+    }
+
+    /// Returns the number of key-value heads
+    pub fn num_key_value_heads(&self) -> usize {
+        match self {
+            Self::Qwen2(c) => c.num_key_value_heads,
+        }
+    }
+
+    /// Returns the dimension of each attention head
+    ///
+    /// Uses the config's explicit `head_dim` when present, which some models
+    /// set to a value that does not divide `hidden_size`, and falls back to
+    /// `hidden_size / num_attention_heads` otherwise.
+    pub fn head_dim(&self) -> usize {
+        match self {
+            Self::Qwen2(c) => c.head_dim.unwrap_or(c.hidden_size / c.num_attention_heads),
+        }
+    }
+
+    /// Returns the base of the rotary position embedding frequencies
+    pub fn rope_theta(&self) -> f64 {
+        match self {
+            Self::Qwen2(c) => c.rope_theta,
+        }
+    }
+
+    /// Returns the attention window, if the model uses sliding-window attention
+    ///
+    /// Qwen2 configs always carry a `sliding_window` size but only use it
+    /// when `use_sliding_window` is set.
+    pub fn sliding_window(&self) -> Option<usize> {
+        match self {
+            Self::Qwen2(c) => c.use_sliding_window.then_some(c.sliding_window),
+        }
+    }
+
+    /// Returns whether the q/k/v projections carry a bias term
+    ///
+    /// Qwen2 always has q/k/v biases and a bias-free output projection.
+    pub fn attention_bias(&self) -> bool {
+        match self {
+            Self::Qwen2(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qwen2_config_is_detected() {
+        let json = r#"{
+            "architectures": ["Qwen2ForCausalLM"],
+            "vocab_size": 1000, "hidden_size": 64, "intermediate_size": 128,
+            "num_hidden_layers": 2, "num_attention_heads": 4, "num_key_value_heads": 2,
+            "max_position_embeddings": 4096, "sliding_window": 4096, "max_window_layers": 2,
+            "tie_word_embeddings": true, "rope_theta": 1000000.0, "rms_norm_eps": 1e-6,
+            "use_sliding_window": false, "hidden_act": "silu"
+        }"#;
+        let config = HfConfig::from_json(json).unwrap();
+        assert_eq!(config.arch(), ModelArch::Qwen2);
+        assert_eq!(config.num_key_value_heads(), 2);
+        assert_eq!(config.sliding_window(), None);
+        assert!(config.attention_bias());
+        assert_eq!(config.head_dim(), 16);
+
+        let explicit = json.replacen("\"hidden_size\": 64,", "\"hidden_size\": 64, \"head_dim\": 24,", 1);
+        assert_eq!(HfConfig::from_json(&explicit).unwrap().head_dim(), 24);
+    }
+
+    #[test]
+    fn llama_config_is_unsupported() {
+        let json = r#"{"architectures": ["LlamaForCausalLM"], "model_type": "llama"}"#;
+        let err = HfConfig::from_json(json).unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported architecture LlamaForCausalLM");
+
+        let err = HfConfig::from_json(r#"{"model_type": "llama"}"#).unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported model_type llama");
+    }
+}
@@ -1,3 +1,6 @@
 pub mod config;
+pub mod detokenizer;
+pub mod hf_config;
 pub mod sampling;
-pub mod sequence;
\ No newline at end of file
+pub mod sequence;
+pub mod tokenizer;
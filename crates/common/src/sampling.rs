@@ -6,7 +6,7 @@ use serde::{Deserialize, Serialize};
 /// are sampled from the model's output distribution during text generation.
 /// It allows customization of the generation process through temperature,
 /// maximum token count, and end-of-sequence handling.
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct SamplingParams {
     /// Temperature for controlling randomness in sampling
     ///
@@ -15,6 +15,21 @@ pub struct SamplingParams {
     /// A value of 0.0 will result in greedy sampling (always selecting the most likely token).
     #[serde(default = "default_temperature")]
     pub temperature: f32,
+
+    /// Cumulative probability mass kept by nucleus (top-p) sampling
+    ///
+    /// A value of 1.0 keeps the whole distribution. Smaller values restrict
+    /// sampling to the most likely tokens whose probabilities sum to top_p.
+    #[serde(default = "default_top_p")]
+    pub top_p: f32,
+
+    /// Number of most likely tokens kept by top-k sampling
+    ///
+    /// A value of 0 keeps the whole vocabulary. Otherwise every token less
+    /// likely than the k-th most likely one is masked before nucleus
+    /// filtering; tokens tied with it are kept.
+    #[serde(default)]
+    pub top_k: usize,
     
     /// Maximum number of tokens to generate
     ///
@@ -30,6 +45,69 @@ pub struct SamplingParams {
     /// up to the max_tokens limit. When false, generation stops at EOS token.
     #[serde(default)]
     pub ignore_eos: bool,
+
+    /// Whether to compute the log-probability of each prompt token
+    ///
+    /// When true, the prefill step records how likely the model found each
+    /// prompt token given the tokens before it. This is useful for evaluation
+    /// but costs an extra log-softmax over the prompt logits.
+    #[serde(default)]
+    pub prompt_logprobs: bool,
+
+    /// Whether to keep the raw logits of the sequence's last step
+    ///
+    /// When true, the logits the model produced for the sequence's final
+    /// position are copied to the CPU and stored on the sequence as
+    /// `final_logits`. A row of the full vocabulary is copied every step,
+    /// so this is meant for research rather than serving.
+    #[serde(default)]
+    pub return_logits: bool,
+
+    /// Number of completions to generate for the prompt
+    ///
+    /// Each completion is generated by its own sequence sharing the prompt.
+    #[serde(default = "default_n")]
+    pub n: usize,
+
+    /// Base seed for reproducible sampling
+    ///
+    /// When set, each of the `n` sequences samples from its own random
+    /// stream derived from this seed, so re-running a request with the same
+    /// seed reproduces every completion. When None, a seed is derived from
+    /// the engine's `Config.seed` if one is set; otherwise sampling is not
+    /// seeded.
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    /// Token IDs that may never be sampled, typically special tokens
+    ///
+    /// Their logits are set to negative infinity at every step, so the model
+    /// cannot emit e.g. `<|im_start|>` in the middle of a completion. Unlike
+    /// skipping special tokens when decoding, this changes what is generated.
+    #[serde(default)]
+    pub suppress_special_tokens: Option<Vec<u32>>,
+
+    /// Whether to decode with beam search instead of sampling
+    ///
+    /// Beam search keeps the `best_of` most likely partial completions at
+    /// every step and returns the `n` best of them; `temperature`, `top_p`,
+    /// and `seed` are ignored. Run it with `LLMEngine::beam_search`.
+    #[serde(default)]
+    pub use_beam_search: bool,
+
+    /// Number of beams kept at every step of beam search
+    ///
+    /// Values below `n` are raised to `n`.
+    #[serde(default = "default_best_of")]
+    pub best_of: usize,
+
+    /// Exponent of the completion length that beam scores are divided by
+    ///
+    /// A finished beam is ranked by `cumulative_logprob / len^length_penalty`.
+    /// 0.0 ranks by the raw log-probability, which favors short completions;
+    /// larger values favor longer ones.
+    #[serde(default = "default_length_penalty")]
+    pub length_penalty: f32,
 }
 
 /// Default temperature value for token sampling
@@ -38,24 +116,108 @@ pub struct SamplingParams {
 /// This is used as the default value for the temperature field in SamplingParams.
 fn default_temperature() -> f32 { 1.0 }
 
+/// Default nucleus sampling mass
+///
+/// Returns 1.0, which keeps the whole distribution.
+/// This is used as the default value for the top_p field in SamplingParams.
+fn default_top_p() -> f32 { 1.0 }
+
 /// Default maximum number of tokens to generate
 ///
 /// Returns 1024, which is a reasonable limit for most generation tasks.
 /// This is used as the default value for the max_tokens field in SamplingParams.
 fn default_max_tokens() -> usize { 1024 }
 
+/// Default number of beams for beam search
+///
+/// Returns 1, which is raised to `n` when the request asks for more.
+fn default_best_of() -> usize { 1 }
+
+/// Default length normalization exponent for beam search
+///
+/// Returns 1.0, which ranks beams by their mean token log-probability.
+fn default_length_penalty() -> f32 { 1.0 }
+
+/// Default number of completions per prompt
+///
+/// Returns 1, generating a single completion for each prompt.
+/// This is used as the default value for the n field in SamplingParams.
+fn default_n() -> usize { 1 }
+
 /// Default implementation for SamplingParams
 ///
 /// Creates a new SamplingParams instance with default values:
 /// - temperature: 1.0 (balanced randomness)
+/// - top_p: 1.0 (no nucleus filtering)
 /// - max_tokens: 1024 (reasonable generation limit)
 /// - ignore_eos: false (generation stops at end-of-sequence token)
+/// - prompt_logprobs: false (prompt tokens are not scored)
+/// - return_logits: false (logits are not kept)
+/// - n: 1 (a single completion per prompt)
+/// - seed: None (unseeded sampling)
+/// - suppress_special_tokens: None (every token may be sampled)
+/// - use_beam_search: false (tokens are sampled)
+/// - best_of: 1 (one beam per completion)
+/// - length_penalty: 1.0 (beams ranked by mean log-probability)
 impl Default for SamplingParams {
     fn default() -> Self {
         Self {
             temperature: default_temperature(),
+            top_p: default_top_p(),
+            top_k: 0,
             max_tokens: default_max_tokens(),
             ignore_eos: false,
+            prompt_logprobs: false,
+            return_logits: false,
+            n: default_n(),
+            seed: None,
+            suppress_special_tokens: None,
+            use_beam_search: false,
+            best_of: default_best_of(),
+            length_penalty: default_length_penalty(),
         }
     }
 }
+
+impl SamplingParams {
+    /// Creates parameters for deterministic greedy decoding
+    ///
+    /// # Arguments
+    ///
+    /// * `max_tokens` - Maximum number of tokens to generate
+    pub fn greedy(max_tokens: usize) -> Self {
+        Self { temperature: 0.0, max_tokens, ..Default::default() }
+    }
+
+    /// Creates parameters for random sampling
+    ///
+    /// # Arguments
+    ///
+    /// * `temperature` - Temperature to sample with
+    /// * `top_p` - Cumulative probability mass to sample from
+    /// * `max_tokens` - Maximum number of tokens to generate
+    pub fn sampling(temperature: f32, top_p: f32, max_tokens: usize) -> Self {
+        Self { temperature, top_p, max_tokens, ..Default::default() }
+    }
+
+    /// Returns whether these parameters select greedy decoding
+    pub fn is_greedy(&self) -> bool {
+        self.temperature == 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constructors_select_greedy_or_sampling() {
+        let greedy = SamplingParams::greedy(10);
+        assert!(greedy.is_greedy());
+        assert_eq!(greedy.max_tokens, 10);
+
+        let sampling = SamplingParams::sampling(1.0, 0.9, 10);
+        assert!(!sampling.is_greedy());
+        assert_eq!(sampling.top_p, 0.9);
+    }
+}
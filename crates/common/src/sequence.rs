@@ -1,12 +1,17 @@
-/// Sequence management for text generation
-///
-/// This module provides types and functions for managing sequences of tokens
-/// during text generation, including tracking their state, handling KV cache
-/// blocks, and managing token generation.
+//! Sequence management for text generation
+//!
+//! This module provides types and functions for managing sequences of tokens
+//! during text generation, including tracking their state, handling KV cache
+//! blocks, and managing token generation.
 
+use candle_core::Tensor;
 use serde::{Deserialize, Serialize};
 use std::ops::Index;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::config::Config;
+use crate::detokenizer::Detokenizer;
 use crate::sampling::SamplingParams;
 
 /// Status of a sequence in the generation pipeline
@@ -26,6 +31,12 @@ pub enum SequenceStatus {
     /// The sequence is actively being processed by the model and
     /// may be generating new tokens.
     Running,
+
+    /// Sequence was preempted and its KV cache swapped out to the CPU
+    ///
+    /// The sequence keeps its progress and resumes decoding once its
+    /// blocks are swapped back in.
+    Swapped,
     
     /// Sequence has completed processing
     ///
@@ -45,6 +56,37 @@ impl Default for SequenceStatus {
     }
 }
 
+/// Reason why a sequence finished generating
+///
+/// Recorded on the sequence when its status becomes Finished so that
+/// callers can tell natural stops apart from truncated generations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model generated the end-of-sequence token
+    Stop,
+
+    /// The sequence reached its maximum number of completion tokens
+    Length,
+
+    /// The request was cancelled before it finished
+    Aborted,
+
+    /// The request waited for admission past its deadline
+    Timeout,
+
+    /// A stop condition registered with the request returned true
+    Custom,
+
+    /// The model failed while running the sequence
+    Error,
+}
+
+/// Caller-supplied stop condition evaluated after each appended token
+///
+/// Returning true finishes the sequence with `FinishReason::Custom`.
+pub type StopFn = Arc<dyn Fn(&Sequence) -> bool + Send + Sync>;
+
 /// Global counter for generating unique sequence IDs
 ///
 /// This atomic counter ensures that each sequence created during the
@@ -89,6 +131,13 @@ pub struct Sequence {
     #[serde(default)]
     pub status: SequenceStatus,
 
+    /// Why the sequence finished, if it has
+    ///
+    /// Set together with the Finished status and None while the sequence
+    /// is still waiting or running.
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+
     // --- Token Data ---
     /// All token IDs in this sequence, including both prompt and completion
     ///
@@ -114,6 +163,14 @@ pub struct Sequence {
     /// and the generated completion tokens.
     pub num_prompt_tokens: usize,
 
+    /// Token index at which the returned completion starts
+    ///
+    /// Defaults to `num_prompt_tokens`. Callers resuming from previously
+    /// generated text can pass the whole text as the prompt, so it is
+    /// prefilled and cached, and set this to where the generation started
+    /// so that `completion_token_ids` still includes the earlier output.
+    pub generation_offset: usize,
+
     // --- KV Cache Management ---
     /// Number of tokens that have been cached in the KV cache
     ///
@@ -129,6 +186,22 @@ pub struct Sequence {
     #[serde(default)]
     pub block_table: Vec<usize>,
 
+    /// Token index at which each conversation turn starts
+    ///
+    /// The first entry is always 0, the start of the initial prompt. Each
+    /// turn added with `push_turn` records where it begins, so that old
+    /// turns can be located, e.g. to truncate them.
+    #[serde(default = "default_turn_boundaries")]
+    pub turn_boundaries: Vec<usize>,
+
+    /// Number of tokens stored in each KV cache block
+    ///
+    /// Must match the block size of the block manager that allocates this
+    /// sequence's blocks, which is sourced from `Config.kvcache_block_size`.
+    /// Never zero; deserializing a zero block size is an error.
+    #[serde(default = "default_block_size", deserialize_with = "deserialize_block_size")]
+    pub block_size: usize,
+
     // --- Sampling Parameters ---
     /// Temperature for controlling randomness in token generation
     ///
@@ -147,15 +220,107 @@ pub struct Sequence {
     /// When true, the generation will continue even after an EOS token is produced,
     /// up to the max_tokens limit. When false, generation stops at EOS token.
     pub ignore_eos: bool,
+
+    /// Seed of this sequence's sampling stream, if sampling is seeded
+    ///
+    /// Initialized from `SamplingParams.seed`. Engines generating several
+    /// completions per prompt replace it with a distinct seed per sequence.
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    /// Token IDs that may never be sampled for this sequence
+    ///
+    /// Initialized from `SamplingParams.suppress_special_tokens`.
+    #[serde(default)]
+    pub suppress_special_tokens: Option<Vec<u32>>,
+
+    /// The sampling parameters the sequence was created with
+    ///
+    /// Stored in full so that a deserialized sequence resumes with exactly
+    /// the original sampling behavior. The fields above hold the effective
+    /// values, which the engine may adjust, e.g. by capping `max_tokens`.
+    #[serde(default)]
+    pub sampling_params: SamplingParams,
+
+    /// Latest time at which the sequence may still be admitted for prefill
+    ///
+    /// A sequence still waiting to start once this passes is finished with
+    /// `FinishReason::Timeout`. Instants are process-local, so the deadline
+    /// is not serialized.
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
+
+    // --- Outputs ---
+    /// Log-probability of each prompt token, if requested
+    ///
+    /// None when prompt logprobs were not requested. Otherwise the vector is
+    /// empty until the prefill step fills it with one entry per prompt token;
+    /// the first token has no predecessor and tokens served from the prefix
+    /// cache are not recomputed, so their entries are None.
+    #[serde(default)]
+    pub prompt_logprobs: Option<Vec<Option<f32>>>,
+
+    /// Sum of the log-probabilities of the completion tokens
+    ///
+    /// The engine adds the log-probability of every sampled token under the
+    /// processed logits, and `append_token_with_logprob` adds the one it is
+    /// given. Beam search ranks beams by it.
+    #[serde(default)]
+    pub cumulative_logprob: f32,
+
+    /// Raw logits of the most recent step, if requested
+    ///
+    /// Set only when `SamplingParams.return_logits` is enabled: an F32 CPU
+    /// tensor of shape `[vocab_size]`, taken before any logits processor.
+    /// Each step replaces it, so a finished sequence holds the logits its
+    /// last token was sampled from. Not serialized.
+    #[serde(skip)]
+    pub final_logits: Option<Tensor>,
+}
+
+/// Default block size for sequences
+///
+/// Returns `Sequence::DEFAULT_BLOCK_SIZE`, which matches the default
+/// `Config.kvcache_block_size`. Used when deserializing sequences saved
+/// before the block size was stored on the sequence.
+fn default_block_size() -> usize {
+    Sequence::DEFAULT_BLOCK_SIZE
+}
+
+/// Deserializes a block size, rejecting zero
+///
+/// The block math divides by the block size, so a zero in a saved sequence
+/// fails here instead of panicking in `num_blocks` later.
+fn deserialize_block_size<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    let block_size = usize::deserialize(deserializer)?;
+    if block_size == 0 {
+        return Err(serde::de::Error::custom("block_size must be greater than zero"));
+    }
+    Ok(block_size)
+}
+
+/// Default turn boundaries for sequences
+///
+/// Returns a single boundary at 0, treating the whole sequence as one turn.
+/// Used when deserializing sequences saved before turns were tracked.
+fn default_turn_boundaries() -> Vec<usize> {
+    vec![0]
 }
 
 impl Sequence {
+    /// The default size of a block in the KV cache, in tokens
+    ///
+    /// This constant is the block size used by `Sequence::new`. Engines with
+    /// a different `Config.kvcache_block_size` should create sequences with
+    /// `Sequence::with_block_size` instead.
+    pub const DEFAULT_BLOCK_SIZE: usize = 256;
+
     /// The size of a block in the KV cache, in tokens
     ///
-    /// This constant defines how many tokens are stored in each block
-    /// of the key-value cache. It affects memory allocation and efficiency
-    /// of the paged attention mechanism.
-    pub const BLOCK_SIZE: usize = 256;
+    /// Kept for backward compatibility. The block size is now a runtime
+    /// value stored on each sequence.
+    #[deprecated(note = "use the `block_size` field or `Sequence::DEFAULT_BLOCK_SIZE` instead")]
+    pub const BLOCK_SIZE: usize = Self::DEFAULT_BLOCK_SIZE;
 
     /// Creates a new sequence from a prompt and sampling parameters
     ///
@@ -176,23 +341,97 @@ impl Sequence {
     ///
     /// Panics if `token_ids` is empty, as a sequence must have at least one token
     pub fn new(token_ids: Vec<u32>, params: SamplingParams) -> Self {
+        Self::with_block_size(token_ids, params, Self::DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Creates a new sequence, rejecting an empty prompt instead of panicking
+    ///
+    /// Non-panicking counterpart of `Sequence::new` for prompts that come
+    /// from outside the engine, e.g. a client request.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_ids` - Vector of token IDs representing the prompt
+    /// * `params` - Sampling parameters to control the generation process
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `token_ids` is empty.
+    pub fn try_new(token_ids: Vec<u32>, params: SamplingParams) -> anyhow::Result<Self> {
+        if token_ids.is_empty() {
+            anyhow::bail!("Cannot create a sequence with empty token_ids");
+        }
+        Ok(Self::new(token_ids, params))
+    }
+
+    /// Creates a new sequence by tokenizing a text prompt
+    ///
+    /// The text is encoded with the special tokens the tokenizer's
+    /// post-processor adds for the model, e.g. a BOS token.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The prompt text
+    /// * `tokenizer` - Tokenizer of the model
+    /// * `params` - Sampling parameters to control the generation process
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if encoding fails or produces no tokens.
+    #[cfg(feature = "tokenizers")]
+    pub fn from_text(text: &str, tokenizer: &tokenizers::Tokenizer, params: SamplingParams) -> anyhow::Result<Self> {
+        let encoding = tokenizer.encode(text, true).map_err(|e| anyhow::anyhow!(e))?;
+        if encoding.get_ids().is_empty() {
+            anyhow::bail!("Prompt {:?} encodes to no tokens", text);
+        }
+        crate::tokenizer::warn_on_double_bos(tokenizer, encoding.get_ids());
+        Self::try_new(encoding.get_ids().to_vec(), params)
+    }
+
+    /// Creates a new sequence that uses the given KV cache block size
+    ///
+    /// Behaves like `Sequence::new`, but computes its block layout with
+    /// `block_size` instead of `DEFAULT_BLOCK_SIZE`.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_ids` - Vector of token IDs representing the prompt
+    /// * `params` - Sampling parameters to control the generation process
+    /// * `block_size` - Number of tokens stored in each KV cache block
+    ///
+    /// # Panics
+    ///
+    /// Panics if `token_ids` is empty or `block_size` is zero
+    pub fn with_block_size(token_ids: Vec<u32>, params: SamplingParams, block_size: usize) -> Self {
         assert!(!token_ids.is_empty(), "Cannot create a sequence with empty token_ids");
+        assert!(block_size > 0, "Block size must be greater than zero");
 
         let num_tokens = token_ids.len();
 
         Self {
             seq_id: next_seq_id(),
             status: SequenceStatus::Waiting,
+            finish_reason: None,
             // Safe to unwrap due to the assert above.
             last_token_id: *token_ids.last().unwrap(),
             num_prompt_tokens: num_tokens,
+            generation_offset: num_tokens,
             num_tokens,
             token_ids,
             num_cached_tokens: 0,
             block_table: Vec::new(),
+            turn_boundaries: default_turn_boundaries(),
+            block_size,
             temperature: params.temperature,
             max_tokens: params.max_tokens,
             ignore_eos: params.ignore_eos,
+            seed: params.seed,
+            suppress_special_tokens: params.suppress_special_tokens.clone(),
+            prompt_logprobs: params.prompt_logprobs.then(Vec::new),
+            sampling_params: params,
+            deadline: None,
+            cumulative_logprob: 0.0,
+            final_logits: None,
         }
     }
 
@@ -233,6 +472,14 @@ impl Sequence {
         self.status == SequenceStatus::Finished
     }
 
+    /// Finishes the sequence with the given reason
+    ///
+    /// The caller remains responsible for releasing the sequence's blocks.
+    pub fn finish(&mut self, reason: FinishReason) {
+        self.status = SequenceStatus::Finished;
+        self.finish_reason = Some(reason);
+    }
+
     /// The number of tokens generated by the model, excluding the prompt
     ///
     /// This is calculated as the difference between the total number of tokens
@@ -260,13 +507,14 @@ impl Sequence {
     /// The token IDs of the generated completion
     ///
     /// Returns a slice containing only the token IDs that were generated
-    /// by the model, excluding the original prompt tokens.
+    /// by the model, excluding the original prompt tokens. When
+    /// `generation_offset` is moved, the completion starts there instead.
     ///
     /// # Returns
     ///
     /// A slice of the token IDs from the generated completion
     pub fn completion_token_ids(&self) -> &[u32] {
-        &self.token_ids[self.num_prompt_tokens..]
+        &self.token_ids[self.generation_offset.min(self.num_tokens)..]
     }
 
     /// The number of blocks in the KV cache that are already computed and stored
@@ -277,7 +525,20 @@ impl Sequence {
     ///
     /// The number of complete blocks in the KV cache
     pub fn num_cached_blocks(&self) -> usize {
-        self.num_cached_tokens / Self::BLOCK_SIZE
+        self.num_cached_tokens / self.block_size
+    }
+
+    /// The number of uncached tokens to prefill in this step
+    ///
+    /// # Arguments
+    ///
+    /// * `budget` - Maximum number of tokens the step can process for this sequence
+    ///
+    /// # Returns
+    ///
+    /// `min(len - num_cached_tokens, budget)`, which is 0 once every token is cached
+    pub fn prefill_chunk(&self, budget: usize) -> usize {
+        self.num_tokens.saturating_sub(self.num_cached_tokens).min(budget)
     }
 
     /// The total number of blocks required to store the entire sequence
@@ -289,7 +550,7 @@ impl Sequence {
     ///
     /// The total number of blocks needed for the entire sequence
     pub fn num_blocks(&self) -> usize {
-        (self.num_tokens + Self::BLOCK_SIZE - 1) / Self::BLOCK_SIZE
+        self.num_tokens.div_ceil(self.block_size)
     }
 
     /// The number of tokens in the last, possibly partially filled, block
@@ -305,14 +566,29 @@ impl Sequence {
         if num_blocks == 0 {
             0
         } else {
-            self.num_tokens - (num_blocks - 1) * Self::BLOCK_SIZE
+            self.num_tokens - (num_blocks - 1) * self.block_size
         }
     }
 
+    /// The positions of the tokens that need processing in the next step
+    ///
+    /// These are the positions whose key-value pairs are not cached yet,
+    /// `num_cached_tokens..num_tokens`. Before prefill this covers the
+    /// uncached part of the prompt; once the scheduler has recorded a step's
+    /// tokens as cached, it is just the most recent token, `num_tokens - 1`.
+    /// The positions match the slots written by the slot mapping.
+    ///
+    /// # Returns
+    ///
+    /// The position ids of the tokens to process, in order
+    pub fn position_ids(&self) -> Vec<u32> {
+        (self.num_cached_tokens as u32..self.num_tokens as u32).collect()
+    }
+
     /// Returns a slice of token IDs for the i-th block
     ///
     /// Retrieves the token IDs that belong to the specified block index.
-    /// Each block contains up to BLOCK_SIZE tokens, except possibly the last block.
+    /// Each block contains up to `block_size` tokens, except possibly the last block.
     ///
     /// # Arguments
     ///
@@ -327,11 +603,122 @@ impl Sequence {
     /// Panics if the block index is out of bounds (>= num_blocks())
     pub fn block(&self, i: usize) -> &[u32] {
         assert!(i < self.num_blocks(), "Block index out of bounds");
-        let start = i * Self::BLOCK_SIZE;
-        let end = ((i + 1) * Self::BLOCK_SIZE).min(self.token_ids.len());
+        let start = i * self.block_size;
+        let end = (start + self.block_size).min(self.num_tokens);
+        &self.token_ids[start..end]
+    }
+
+    /// Lists the physical block and fill level of every logical block
+    ///
+    /// Purely diagnostic: the result can be printed and checked against the
+    /// KV cache when a generation looks corrupted. Blocks reserved beyond
+    /// the tokens stored so far are listed with zero tokens.
+    ///
+    /// # Returns
+    ///
+    /// A `(logical_block, physical_block, num_tokens_in_block)` tuple for
+    /// every entry of the block table
+    pub fn debug_block_map(&self) -> Vec<(usize, usize, usize)> {
+        let block_size = self.block_size;
+        self.block_table
+            .iter()
+            .enumerate()
+            .map(|(i, &block_id)| (i, block_id, self.num_tokens.saturating_sub(i * block_size).min(block_size)))
+            .collect()
+    }
+
+    /// Returns the number of bytes the sequence's KV cache blocks occupy
+    ///
+    /// Every block is counted in full, including the unused slots of the
+    /// last one, since whole blocks are allocated.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Configuration with the model's `hf_config` and `dtype`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `config.hf_config` is not loaded or `config.dtype`
+    /// is unknown.
+    pub fn kv_bytes(&self, config: &Config) -> anyhow::Result<usize> {
+        Ok(self.num_blocks() * self.block_size * config.kv_bytes_per_token()?)
+    }
+
+    /// Returns a slice of token IDs for the i-th block, if it exists
+    ///
+    /// Non-panicking counterpart of `Sequence::block`.
+    ///
+    /// # Arguments
+    ///
+    /// * `i` - The block index to retrieve
+    ///
+    /// # Returns
+    ///
+    /// The block's token IDs, or `None` if `i >= num_blocks()`
+    pub fn try_block(&self, i: usize) -> Option<&[u32]> {
+        (i < self.num_blocks()).then(|| self.block(i))
+    }
+
+    /// Returns an iterator over the token IDs of every block, in order
+    pub fn iter_blocks(&self) -> impl Iterator<Item = &[u32]> {
+        self.token_ids[..self.num_tokens].chunks(self.block_size)
+    }
+
+    /// Returns the number of conversation turns in the sequence
+    pub fn num_turns(&self) -> usize {
+        self.turn_boundaries.len()
+    }
+
+    /// Returns the token IDs of the `i`-th conversation turn
+    ///
+    /// A turn spans from its boundary to the next turn's boundary, so the
+    /// last turn includes the tokens generated after it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `i` is not less than `num_turns()`
+    pub fn turn(&self, i: usize) -> &[u32] {
+        assert!(i < self.num_turns(), "Turn index out of bounds");
+        let start = self.turn_boundaries[i];
+        let end = self.turn_boundaries.get(i + 1).copied().unwrap_or(self.num_tokens);
         &self.token_ids[start..end]
     }
 
+    /// Starts a new conversation turn with the given tokens
+    ///
+    /// The turn's start is recorded in `turn_boundaries` and its tokens are
+    /// appended. The whole conversation so far becomes the prompt for the
+    /// next generation: the sequence returns to Waiting and its finish
+    /// reason is cleared, while already computed KV entries stay usable
+    /// through the prefix cache.
+    ///
+    /// The caller must release the sequence's blocks before adding a turn.
+    /// The engine's `add_turn` continues finished sequences this way.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_ids` - Token IDs of the new turn, e.g. a templated user message
+    ///
+    /// # Panics
+    ///
+    /// Panics if `token_ids` is empty
+    pub fn push_turn(&mut self, token_ids: &[u32]) {
+        assert!(!token_ids.is_empty(), "Cannot add an empty turn");
+
+        self.turn_boundaries.push(self.num_tokens);
+        self.token_ids.extend_from_slice(token_ids);
+        self.num_tokens = self.token_ids.len();
+        self.num_prompt_tokens = self.num_tokens;
+        self.generation_offset = self.num_tokens;
+        // Safe to unwrap due to the assert above.
+        self.last_token_id = *token_ids.last().unwrap();
+        self.status = SequenceStatus::Waiting;
+        self.finish_reason = None;
+        if self.prompt_logprobs.is_some() {
+            self.prompt_logprobs = Some(Vec::new());
+        }
+    }
+
     /// Appends a new token to the sequence, updating its state
     ///
     /// Adds a new token to the end of the sequence and updates the related
@@ -345,6 +732,214 @@ impl Sequence {
         self.last_token_id = token_id;
         self.num_tokens += 1;
     }
+
+    /// Appends a new token and finishes the sequence if a stop condition is met
+    ///
+    /// The sequence finishes with `FinishReason::Stop` if the token is the
+    /// EOS token and `ignore_eos` is not set, or with `FinishReason::Length`
+    /// once `max_tokens` completion tokens have been generated. In both cases
+    /// the status becomes Finished and the finish reason is recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_id` - The ID of the token to append
+    /// * `eos` - The end-of-sequence token ID, if known
+    ///
+    /// # Returns
+    ///
+    /// `true` if the sequence is finished after appending the token
+    pub fn try_append(&mut self, token_id: u32, eos: Option<u32>) -> bool {
+        self.append_token(token_id);
+        let finish_reason = if !self.ignore_eos && Some(token_id) == eos {
+            Some(FinishReason::Stop)
+        } else if self.num_completion_tokens() >= self.max_tokens {
+            Some(FinishReason::Length)
+        } else {
+            None
+        };
+        if finish_reason.is_some() {
+            self.status = SequenceStatus::Finished;
+            self.finish_reason = finish_reason;
+        }
+        self.is_finished()
+    }
+
+    /// Appends a token with its log-probability, finishing on a stop condition
+    ///
+    /// Behaves like `try_append` and also adds `logprob` to
+    /// `cumulative_logprob`.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_id` - The ID of the token to append
+    /// * `logprob` - Log-probability of the token given the sequence so far
+    /// * `eos` - The end-of-sequence token ID, if known
+    ///
+    /// # Returns
+    ///
+    /// `true` if the sequence is finished after appending the token
+    pub fn append_token_with_logprob(&mut self, token_id: u32, logprob: f32, eos: Option<u32>) -> bool {
+        self.cumulative_logprob += logprob;
+        self.try_append(token_id, eos)
+    }
+
+    /// Returns the length-normalized score beam search ranks the sequence by
+    ///
+    /// The score is `cumulative_logprob / len^length_penalty`, where `len`
+    /// is the number of completion tokens (at least 1).
+    ///
+    /// # Arguments
+    ///
+    /// * `length_penalty` - Exponent of the completion length
+    pub fn beam_score(&self, length_penalty: f32) -> f32 {
+        self.cumulative_logprob / (self.num_completion_tokens().max(1) as f32).powf(length_penalty)
+    }
+
+    /// Appends the accepted prefix of a speculative proposal
+    ///
+    /// In speculative decoding a draft model proposes several tokens which the
+    /// target model then verifies. Only the first `accepted` proposed tokens are
+    /// kept; the bonus token sampled by the target model at the first mismatch
+    /// is appended separately with `append_token`.
+    ///
+    /// # Arguments
+    ///
+    /// * `proposed` - Token IDs proposed by the draft model
+    /// * `accepted` - Number of leading proposed tokens accepted by the target model
+    ///
+    /// # Panics
+    ///
+    /// Panics if `accepted` is greater than `proposed.len()`
+    pub fn accept_speculative(&mut self, proposed: &[u32], accepted: usize) {
+        assert!(
+            accepted <= proposed.len(),
+            "Cannot accept {} of {} proposed tokens", accepted, proposed.len()
+        );
+        for &token_id in &proposed[..accepted] {
+            self.append_token(token_id);
+        }
+    }
+
+    /// Truncates the sequence to its first `num_tokens` tokens
+    ///
+    /// Used to roll back tokens that were appended speculatively but later
+    /// rejected. The number of cached tokens is clamped to the new length;
+    /// the block table is left untouched so the blocks can be reused.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_tokens` - The new total number of tokens in the sequence
+    ///
+    /// # Panics
+    ///
+    /// Panics if `num_tokens` would drop prompt tokens or exceeds the
+    /// current length
+    pub fn truncate(&mut self, num_tokens: usize) {
+        assert!(
+            num_tokens >= self.num_prompt_tokens && num_tokens <= self.num_tokens,
+            "Cannot truncate a sequence of {} tokens ({} prompt) to {}",
+            self.num_tokens, self.num_prompt_tokens, num_tokens
+        );
+        self.token_ids.truncate(num_tokens);
+        self.num_tokens = num_tokens;
+        // Safe to unwrap since the prompt is never empty.
+        self.last_token_id = *self.token_ids.last().unwrap();
+        self.num_cached_tokens = self.num_cached_tokens.min(num_tokens);
+    }
+
+    /// Creates a copy of this sequence with a new `seq_id`
+    ///
+    /// Used to generate several completions for the same prompt; the fork
+    /// shares the tokens and sampling parameters but not the identity.
+    pub fn fork(&self) -> Self {
+        Self {
+            seq_id: next_seq_id(),
+            ..self.clone()
+        }
+    }
+
+    /// Serializes the sequence's output as an OpenAI-style completion object
+    ///
+    /// The object has the shape `{ id, object, choices: [{ index, text,
+    /// token_ids, finish_reason, logprobs }], usage: { prompt_tokens,
+    /// completion_tokens, total_tokens } }`. `text` holds the decoded
+    /// completion when a tokenizer is given and decoding succeeds, and is
+    /// null otherwise; `finish_reason` is null while the sequence is running.
+    /// Per-token logprobs are not tracked yet, so `logprobs` is always null.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokenizer` - Tokenizer used to decode the completion, if any
+    ///
+    /// # Returns
+    ///
+    /// The completion object as JSON
+    pub fn to_completion_json(&self, tokenizer: Option<&dyn Detokenizer>) -> serde_json::Value {
+        let completion = self.completion_token_ids();
+        let text = tokenizer.and_then(|tokenizer| tokenizer.decode(completion).ok());
+        serde_json::json!({
+            "id": format!("cmpl-{}", self.seq_id),
+            "object": "text_completion",
+            "choices": [{
+                "index": 0,
+                "text": text,
+                "token_ids": completion,
+                "finish_reason": self.finish_reason,
+                "logprobs": null,
+            }],
+            "usage": {
+                "prompt_tokens": self.num_tokens - completion.len(),
+                "completion_tokens": completion.len(),
+                "total_tokens": self.num_tokens,
+            },
+        })
+    }
+
+    /// Reuses this sequence for a new prompt
+    ///
+    /// The existing `token_ids` buffer is cleared and refilled, so its
+    /// capacity is retained and pooled sequences avoid reallocating. Status,
+    /// counters, block table, and sampling parameters are reset as if the
+    /// sequence had been created with `Sequence::with_block_size`, and a new
+    /// `seq_id` is assigned. The block size is kept.
+    ///
+    /// The caller must release the sequence's blocks before resetting it.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_ids` - Token IDs of the new prompt
+    /// * `params` - Sampling parameters for the new request
+    ///
+    /// # Panics
+    ///
+    /// Panics if `token_ids` is empty
+    pub fn reset(&mut self, token_ids: Vec<u32>, params: SamplingParams) {
+        assert!(!token_ids.is_empty(), "Cannot reset a sequence with empty token_ids");
+
+        self.token_ids.clear();
+        self.token_ids.extend_from_slice(&token_ids);
+        self.seq_id = next_seq_id();
+        self.status = SequenceStatus::Waiting;
+        self.finish_reason = None;
+        // Safe to unwrap due to the assert above.
+        self.last_token_id = *token_ids.last().unwrap();
+        self.num_prompt_tokens = token_ids.len();
+        self.generation_offset = token_ids.len();
+        self.num_tokens = token_ids.len();
+        self.num_cached_tokens = 0;
+        self.block_table.clear();
+        self.turn_boundaries = default_turn_boundaries();
+        self.temperature = params.temperature;
+        self.max_tokens = params.max_tokens;
+        self.ignore_eos = params.ignore_eos;
+        self.seed = params.seed;
+        self.suppress_special_tokens = params.suppress_special_tokens.clone();
+        self.prompt_logprobs = params.prompt_logprobs.then(Vec::new);
+        self.sampling_params = params;
+        self.deadline = None;
+        self.cumulative_logprob = 0.0;
+        self.final_logits = None;
+    }
 }
 
 /// Allows for indexing the sequence's token IDs directly, e.g., `sequence[i]`
@@ -382,4 +977,275 @@ impl Index<usize> for Sequence {
     fn index(&self, index: usize) -> &Self::Output {
         &self.token_ids[index]
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_math_uses_sequence_block_size() {
+        let seq = Sequence::with_block_size((0..8).collect(), SamplingParams::default(), 4);
+        assert_eq!(seq.num_blocks(), 2);
+        assert_eq!(seq.last_block_num_tokens(), 4);
+        assert_eq!(seq.block(1), &[4, 5, 6, 7]);
+
+        let seq = Sequence::with_block_size((0..9).collect(), SamplingParams::default(), 4);
+        assert_eq!(seq.num_blocks(), 3);
+        assert_eq!(seq.last_block_num_tokens(), 1);
+        assert_eq!(seq.block(2), &[8]);
+    }
+
+    #[test]
+    fn empty_prompt_is_an_error_with_try_new() {
+        assert!(Sequence::try_new(vec![], SamplingParams::default()).is_err());
+        let seq = Sequence::try_new(vec![1, 2, 3], SamplingParams::default()).unwrap();
+        assert_eq!(seq.prompt_token_ids(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn blocks_can_be_iterated_without_panicking() {
+        let seq = Sequence::with_block_size((0..600).collect(), SamplingParams::default(), 256);
+        let sizes: Vec<usize> = seq.iter_blocks().map(<[u32]>::len).collect();
+        assert_eq!(sizes, [256, 256, 88]);
+        assert_eq!(seq.try_block(2), Some(seq.block(2)));
+        assert_eq!(seq.try_block(3), None);
+    }
+
+    #[cfg(feature = "tokenizers")]
+    #[test]
+    fn from_text_encodes_the_prompt() {
+        use tokenizers::models::wordlevel::WordLevel;
+        use tokenizers::pre_tokenizers::whitespace::Whitespace;
+
+        let vocab = [("[UNK]", 0), ("hello", 1), ("world", 2)].map(|(word, id)| (word.to_string(), id));
+        let model = WordLevel::builder().vocab(vocab.into_iter().collect()).unk_token("[UNK]".to_string()).build().unwrap();
+        let mut tokenizer = tokenizers::Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+
+        let seq = Sequence::from_text("hello big world", &tokenizer, SamplingParams::default()).unwrap();
+        let expected = tokenizer.encode("hello big world", true).unwrap();
+        assert_eq!(seq.prompt_token_ids(), expected.get_ids());
+        assert_eq!(seq.prompt_token_ids(), &[1, 0, 2]);
+        assert!(Sequence::from_text("", &tokenizer, SamplingParams::default()).is_err());
+    }
+
+    #[test]
+    fn sampling_params_survive_serialization() {
+        let params = SamplingParams {
+            seed: Some(7),
+            suppress_special_tokens: Some(vec![3]),
+            ..SamplingParams::sampling(0.7, 0.9, 32)
+        };
+        let seq = Sequence::new(vec![1, 2, 3], params.clone());
+        let restored: Sequence = serde_json::from_str(&serde_json::to_string(&seq).unwrap()).unwrap();
+        assert_eq!(restored.sampling_params, params);
+        assert_eq!(restored.sampling_params.top_p, 0.9);
+    }
+
+    #[test]
+    fn kv_bytes_count_whole_blocks() {
+        let json = r#"{
+            "architectures": ["Qwen2ForCausalLM"],
+            "vocab_size": 1000, "hidden_size": 64, "intermediate_size": 128,
+            "num_hidden_layers": 2, "num_attention_heads": 4, "num_key_value_heads": 2,
+            "max_position_embeddings": 4096, "sliding_window": 4096, "max_window_layers": 2,
+            "tie_word_embeddings": true, "rope_theta": 1000000.0, "rms_norm_eps": 1e-6,
+            "use_sliding_window": false, "hidden_act": "silu"
+        }"#;
+        let config = Config {
+            hf_config: Some(crate::hf_config::HfConfig::from_json(json).unwrap()),
+            ..Default::default()
+        };
+        let seq = Sequence::new((0..300).collect(), SamplingParams::default());
+        // 2 blocks * 256 tokens * 2 (K and V) * 2 layers * 2 KV heads * 16 head dim * 2 bytes
+        assert_eq!(seq.kv_bytes(&config).unwrap(), 2 * 256 * 2 * 2 * 2 * 16 * 2);
+        assert!(seq.kv_bytes(&Config::default()).is_err());
+    }
+
+    #[test]
+    fn block_size_is_per_sequence_and_serialized() {
+        let small = Sequence::with_block_size((0..300).collect(), SamplingParams::default(), 16);
+        let large = Sequence::with_block_size((0..300).collect(), SamplingParams::default(), 256);
+        assert_eq!(small.num_blocks(), 19);
+        assert_eq!(large.num_blocks(), 2);
+
+        let restored: Sequence = serde_json::from_str(&serde_json::to_string(&small).unwrap()).unwrap();
+        assert_eq!(restored.block_size, 16);
+        assert_eq!(restored.num_blocks(), 19);
+
+        let mut json = serde_json::to_value(&small).unwrap();
+        json["block_size"] = serde_json::json!(0);
+        let err = serde_json::from_value::<Sequence>(json).unwrap_err();
+        assert!(err.to_string().contains("block_size"), "{}", err);
+    }
+
+    #[test]
+    fn accept_speculative_appends_accepted_prefix() {
+        let mut seq = Sequence::new(vec![1, 2, 3], SamplingParams::default());
+        seq.accept_speculative(&[10, 11, 12, 13, 14], 3);
+
+        assert_eq!(seq.num_completion_tokens(), 3);
+        assert_eq!(seq.completion_token_ids(), &[10, 11, 12]);
+        assert_eq!(seq.last_token_id, 12);
+        assert_eq!(seq.len(), 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot accept")]
+    fn accept_speculative_rejects_too_many() {
+        let mut seq = Sequence::new(vec![1], SamplingParams::default());
+        seq.accept_speculative(&[10, 11], 3);
+    }
+
+    #[test]
+    fn truncate_rolls_back_completion() {
+        let mut seq = Sequence::new(vec![1, 2], SamplingParams::default());
+        seq.accept_speculative(&[10, 11, 12], 3);
+        seq.num_cached_tokens = 5;
+
+        seq.truncate(3);
+        assert_eq!(seq.completion_token_ids(), &[10]);
+        assert_eq!(seq.last_token_id, 10);
+        assert_eq!(seq.num_cached_tokens, 3);
+    }
+
+    #[test]
+    fn position_ids_start_at_first_uncached_token() {
+        let mut seq = Sequence::with_block_size((0..10).collect(), SamplingParams::default(), 4);
+        seq.num_cached_tokens = 8;
+        assert_eq!(seq.position_ids(), vec![8, 9]);
+
+        seq.num_cached_tokens = 10;
+        seq.append_token(42);
+        assert_eq!(seq.position_ids(), vec![10]);
+    }
+
+    #[test]
+    fn turns_slice_the_conversation() {
+        let mut seq = Sequence::new(vec![1, 2, 3], SamplingParams::default());
+        seq.append_token(4);
+        seq.status = SequenceStatus::Finished;
+        seq.push_turn(&[5, 6]);
+        seq.append_token(7);
+
+        assert_eq!(seq.num_turns(), 2);
+        assert_eq!(seq.turn(0), &[1, 2, 3, 4]);
+        assert_eq!(seq.turn(1), &[5, 6, 7]);
+        assert_eq!(seq.status, SequenceStatus::Waiting);
+        assert_eq!((seq.num_prompt_tokens, seq.completion_token_ids()), (6, &[7][..]));
+    }
+
+    #[test]
+    fn completion_json_reports_usage() {
+        struct Letters;
+        impl Detokenizer for Letters {
+            fn decode(&self, token_ids: &[u32]) -> anyhow::Result<String> {
+                Ok(token_ids.iter().map(|&id| (b'a' + id as u8) as char).collect())
+            }
+        }
+
+        let params = SamplingParams { max_tokens: 2, ..Default::default() };
+        let mut seq = Sequence::new(vec![1, 2, 3], params);
+        assert!(!seq.try_append(7, None));
+        assert!(seq.try_append(4, None));
+
+        let json = seq.to_completion_json(Some(&Letters));
+        assert_eq!(json["usage"]["prompt_tokens"], seq.num_prompt_tokens);
+        assert_eq!(json["usage"]["completion_tokens"], seq.num_completion_tokens());
+        assert_eq!(json["usage"]["total_tokens"], 5);
+        let choice = &json["choices"][0];
+        assert_eq!(choice["text"], "he");
+        assert_eq!(choice["token_ids"], serde_json::json!([7, 4]));
+        assert_eq!(choice["finish_reason"], "length");
+        assert!(seq.to_completion_json(None)["choices"][0]["text"].is_null());
+    }
+
+    #[test]
+    fn generation_offset_controls_returned_completion() {
+        // Resume from "prompt + earlier output" passed as a single prompt.
+        let mut seq = Sequence::new(vec![1, 2, 10, 11], SamplingParams::default());
+        assert_eq!(seq.generation_offset, 4);
+        seq.generation_offset = 2;
+        seq.append_token(12);
+
+        assert_eq!(seq.completion_token_ids(), &[10, 11, 12]);
+        assert_eq!(seq.prompt_token_ids(), &[1, 2, 10, 11]);
+        assert_eq!(seq.num_completion_tokens(), 1);
+        // Usage counts the returned completion, not just the sampled tokens.
+        let usage = &seq.to_completion_json(None)["usage"];
+        assert_eq!(usage["prompt_tokens"], 2);
+        assert_eq!(usage["completion_tokens"], 3);
+        assert_eq!(usage["total_tokens"], 5);
+    }
+
+    #[test]
+    fn reset_reuses_buffer_for_new_prompt() {
+        let mut seq = Sequence::new(vec![1, 2, 3, 4], SamplingParams::default());
+        seq.append_token(5);
+        seq.num_cached_tokens = 4;
+        seq.block_table = vec![0];
+        seq.status = SequenceStatus::Finished;
+        seq.finish_reason = Some(FinishReason::Length);
+        let old_id = seq.seq_id;
+        let capacity = seq.token_ids.capacity();
+
+        let params = SamplingParams { temperature: 0.5, max_tokens: 3, ..Default::default() };
+        seq.reset(vec![7, 8], params);
+
+        assert_ne!(seq.seq_id, old_id);
+        assert_eq!(seq.status, SequenceStatus::Waiting);
+        assert_eq!(seq.finish_reason, None);
+        assert_eq!(seq.token_ids, vec![7, 8]);
+        assert_eq!(seq.token_ids.capacity(), capacity);
+        assert_eq!((seq.len(), seq.num_prompt_tokens, seq.num_completion_tokens()), (2, 2, 0));
+        assert_eq!((seq.last_token_id, seq.num_cached_tokens), (8, 0));
+        assert!(seq.block_table.is_empty());
+        assert_eq!((seq.temperature, seq.max_tokens), (0.5, 3));
+    }
+
+    #[test]
+    fn try_append_finishes_at_max_tokens() {
+        let params = SamplingParams { max_tokens: 3, ..Default::default() };
+        let mut seq = Sequence::new(vec![1, 2], params);
+
+        assert!(!seq.try_append(5, Some(0)));
+        assert!(!seq.try_append(6, Some(0)));
+        assert!(seq.try_append(7, Some(0)));
+        assert_eq!(seq.status, SequenceStatus::Finished);
+        assert_eq!(seq.finish_reason, Some(FinishReason::Length));
+        assert_eq!(seq.completion_token_ids(), &[5, 6, 7]);
+    }
+
+    #[test]
+    fn try_append_stops_at_eos_unless_ignored() {
+        let mut seq = Sequence::new(vec![1], SamplingParams::default());
+        assert!(seq.try_append(0, Some(0)));
+        assert_eq!(seq.finish_reason, Some(FinishReason::Stop));
+
+        let params = SamplingParams { ignore_eos: true, ..Default::default() };
+        let mut seq = Sequence::new(vec![1], params);
+        assert!(!seq.try_append(0, Some(0)));
+        assert_eq!(seq.finish_reason, None);
+    }
+
+    #[test]
+    fn prefill_chunk_respects_remaining_tokens_and_budget() {
+        let mut seq = Sequence::new((0..10).collect(), SamplingParams::default());
+        seq.num_cached_tokens = 6;
+        assert_eq!(seq.prefill_chunk(3), 3);
+        assert_eq!(seq.prefill_chunk(8), 4);
+        seq.num_cached_tokens = 10;
+        assert_eq!(seq.prefill_chunk(8), 0);
+    }
+
+    #[test]
+    fn debug_block_map_lists_physical_blocks_and_fill() {
+        let mut seq = Sequence::with_block_size(vec![1; 40], SamplingParams::default(), 16);
+        seq.block_table = vec![7, 2, 9];
+        assert_eq!(seq.debug_block_map(), vec![(0, 7, 16), (1, 2, 16), (2, 9, 8)]);
+
+        seq.block_table.push(4);
+        assert_eq!(seq.debug_block_map()[3], (3, 4, 0));
+    }
+}
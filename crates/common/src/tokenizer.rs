@@ -0,0 +1,159 @@
+//! Conversion of text to token IDs
+//!
+//! Like `Detokenizer`, the `Tokenizer` trait keeps the engine independent of
+//! a tokenizer library. With the `tokenizers` feature, Hugging Face
+//! tokenizers implement it directly.
+
+use crate::detokenizer::Detokenizer;
+use anyhow::Result;
+
+/// Encodes text into token IDs and decodes them back
+pub trait Tokenizer: Detokenizer {
+    /// Encodes text into token IDs
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to encode
+    /// * `add_special` - Whether to add the model's special tokens, e.g. BOS
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the text cannot be encoded.
+    fn encode(&self, text: &str, add_special: bool) -> Result<Vec<u32>>;
+
+    /// Encodes several texts, returning the token IDs of each in order
+    ///
+    /// The default implementation encodes the texts one at a time;
+    /// tokenizers with a parallel batch path override it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any text cannot be encoded.
+    fn encode_batch(&self, texts: &[&str], add_special: bool) -> Result<Vec<Vec<u32>>> {
+        texts.iter().map(|text| self.encode(text, add_special)).collect()
+    }
+
+    /// Returns the BOS token ID the tokenizer adds with its special tokens
+    ///
+    /// The default implementation returns None, for tokenizers that add no
+    /// BOS token.
+    fn bos_token_id(&self) -> Option<u32> {
+        None
+    }
+
+    /// Encodes text with the model's special tokens except a leading BOS
+    ///
+    /// Meant for text that already starts with a start token, e.g. the
+    /// output of a chat template, which would otherwise get a second one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the text cannot be encoded.
+    fn encode_without_bos(&self, text: &str) -> Result<Vec<u32>> {
+        let mut token_ids = self.encode(text, true)?;
+        if let Some(bos) = self.bos_token_id()
+            && token_ids.first() == Some(&bos)
+        {
+            token_ids.remove(0);
+        }
+        Ok(token_ids)
+    }
+
+    /// Returns true if the token IDs start with the BOS token twice
+    ///
+    /// This happens when text that already includes a BOS token is encoded
+    /// with the special tokens; models are not trained to expect it.
+    fn has_double_bos(&self, token_ids: &[u32]) -> bool {
+        self.bos_token_id().is_some_and(|bos| token_ids.starts_with(&[bos, bos]))
+    }
+}
+
+/// Logs a warning if an encoded prompt starts with two BOS tokens
+///
+/// # Arguments
+///
+/// * `tokenizer` - Tokenizer that encoded the prompt
+/// * `token_ids` - The encoded prompt
+pub fn warn_on_double_bos(tokenizer: &(impl Tokenizer + ?Sized), token_ids: &[u32]) {
+    if tokenizer.has_double_bos(token_ids) {
+        log::warn!(
+            "Prompt starts with two BOS tokens; encode text that already has one with encode_without_bos"
+        );
+    }
+}
+
+/// Encodes with a Hugging Face tokenizer
+#[cfg(feature = "tokenizers")]
+impl Tokenizer for tokenizers::Tokenizer {
+    fn encode(&self, text: &str, add_special: bool) -> Result<Vec<u32>> {
+        // Tokenizer derefs to TokenizerImpl, whose inherent encode takes the flag.
+        let encoding = (**self).encode(text, add_special).map_err(|e| anyhow::anyhow!(e))?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    fn encode_batch(&self, texts: &[&str], add_special: bool) -> Result<Vec<Vec<u32>>> {
+        let encodings = (**self).encode_batch(texts.to_vec(), add_special).map_err(|e| anyhow::anyhow!(e))?;
+        Ok(encodings.iter().map(|encoding| encoding.get_ids().to_vec()).collect())
+    }
+
+    fn bos_token_id(&self) -> Option<u32> {
+        // The post-processor marks the tokens it adds as special, so a BOS
+        // shows up as a leading special token the plain encoding lacks.
+        let with_special = (**self).encode("a", true).ok()?;
+        let without_special = (**self).encode("a", false).ok()?;
+        let (ids, plain) = (with_special.get_ids(), without_special.get_ids());
+        let is_added = with_special.get_special_tokens_mask().first() == Some(&1);
+        (is_added && ids.len() > plain.len() && ids[1..].starts_with(plain)).then(|| ids[0])
+    }
+}
+
+#[cfg(all(test, feature = "tokenizers"))]
+mod tests {
+    use super::*;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+    use tokenizers::processors::template::TemplateProcessing;
+
+    #[test]
+    fn batch_encoding_matches_individual_encoding() {
+        let vocab = [("[UNK]", 0), ("the", 1), ("cat", 2), ("sat", 3)].map(|(word, id)| (word.to_string(), id));
+        let model = WordLevel::builder().vocab(vocab.into_iter().collect()).unk_token("[UNK]".to_string()).build().unwrap();
+        let mut tokenizer = tokenizers::Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+
+        let texts = ["the cat", "cat sat on the mat", "sat"];
+        let batch = Tokenizer::encode_batch(&tokenizer, &texts, true).unwrap();
+        assert_eq!(batch.len(), texts.len());
+        for (text, ids) in texts.iter().zip(&batch) {
+            assert_eq!(ids, &Tokenizer::encode(&tokenizer, text, true).unwrap());
+        }
+        assert_eq!(batch[1], vec![2, 3, 0, 1, 0]);
+    }
+
+    #[test]
+    fn encode_without_bos_drops_only_the_leading_bos() {
+        let vocab = [("[UNK]", 0), ("[BOS]", 1), ("the", 2), ("cat", 3)].map(|(word, id)| (word.to_string(), id));
+        let model = WordLevel::builder().vocab(vocab.into_iter().collect()).unk_token("[UNK]".to_string()).build().unwrap();
+        let mut tokenizer = tokenizers::Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        let template = TemplateProcessing::builder()
+            .try_single("[BOS] $A")
+            .unwrap()
+            .special_tokens(vec![("[BOS]", 1)])
+            .build()
+            .unwrap();
+        tokenizer.with_post_processor(Some(template));
+
+        assert_eq!(Tokenizer::bos_token_id(&tokenizer), Some(1));
+        let with_bos = Tokenizer::encode(&tokenizer, "the cat", true).unwrap();
+        let without_bos = tokenizer.encode_without_bos("the cat").unwrap();
+        assert_eq!(with_bos, vec![1, 2, 3]);
+        assert_eq!(with_bos[1..], without_bos[..]);
+        assert!(!tokenizer.has_double_bos(&with_bos));
+
+        tokenizer.add_special_tokens(&[tokenizers::AddedToken::from("[BOS]", true)]);
+        let templated = Tokenizer::encode(&tokenizer, "[BOS] the cat", true).unwrap();
+        assert!(tokenizer.has_double_bos(&templated));
+        assert!(!tokenizer.has_double_bos(&tokenizer.encode_without_bos("[BOS] the cat").unwrap()));
+    }
+}
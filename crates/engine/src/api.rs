@@ -0,0 +1,138 @@
+//! Framework-independent request and response types
+//!
+//! A server binds to `GenerationRequest` and `GenerationResponse` rather
+//! than to the engine's internals: it deserializes a request from its wire
+//! format, passes it to `LLMEngine::handle_request`, and serializes the
+//! responses back.
+
+use common::sampling::SamplingParams;
+use common::sequence::{FinishReason, Sequence};
+use common::tokenizer::Tokenizer;
+use serde::{Deserialize, Serialize};
+
+/// Prompt of a generation request
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptInput {
+    /// An already tokenized prompt
+    Tokens(Vec<u32>),
+
+    /// A text prompt, tokenized by the engine with special tokens
+    Text(String),
+}
+
+/// A generation request
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GenerationRequest {
+    /// The prompt to complete
+    pub prompt: PromptInput,
+
+    /// How to sample the completion
+    #[serde(default)]
+    pub params: SamplingParams,
+}
+
+/// Token counts of a finished sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Usage {
+    /// Number of tokens before the returned completion, i.e. the prompt
+    /// unless `Sequence::generation_offset` was moved
+    pub prompt_tokens: usize,
+
+    /// Number of completion tokens returned, see `Sequence::completion_token_ids`
+    pub completion_tokens: usize,
+
+    /// Number of tokens in the sequence, the sum of the prompt and completion tokens
+    pub total_tokens: usize,
+}
+
+impl Usage {
+    /// Returns the token counts of a sequence
+    ///
+    /// The completion is counted as returned by `completion_token_ids`, so
+    /// it includes earlier output when `generation_offset` is moved before
+    /// the end of the prompt. Everything before it counts as prompt.
+    pub fn from_sequence(seq: &Sequence) -> Self {
+        let completion_tokens = seq.completion_token_ids().len();
+        Self {
+            prompt_tokens: seq.num_tokens - completion_tokens,
+            completion_tokens,
+            total_tokens: seq.num_tokens,
+        }
+    }
+}
+
+/// One completion of a generation request
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GenerationResponse {
+    /// ID of the sequence that generated the completion
+    pub seq_id: usize,
+
+    /// Completion token IDs
+    pub tokens: Vec<u32>,
+
+    /// Decoded completion, when a tokenizer is available
+    pub text: Option<String>,
+
+    /// Why the sequence finished
+    pub finish_reason: Option<FinishReason>,
+
+    /// Token counts of the sequence
+    pub usage: Usage,
+}
+
+impl GenerationResponse {
+    /// Builds the response for a finished sequence
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - The finished sequence
+    /// * `tokenizer` - Decodes the completion text, if given
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the completion cannot be decoded.
+    pub fn from_sequence(seq: &Sequence, tokenizer: Option<&dyn Tokenizer>) -> anyhow::Result<Self> {
+        let tokens = seq.completion_token_ids().to_vec();
+        let text = tokenizer.map(|tokenizer| tokenizer.decode(&tokens)).transpose()?;
+        Ok(Self {
+            seq_id: seq.seq_id,
+            usage: Usage::from_sequence(seq),
+            tokens,
+            text,
+            finish_reason: seq.finish_reason,
+        })
+    }
+}
+
+/// One completion of `LLMEngine::generate_detailed`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GenerationOutput {
+    /// ID of the sequence that generated the completion
+    pub seq_id: usize,
+
+    /// Completion token IDs
+    pub token_ids: Vec<u32>,
+
+    /// Why the sequence finished
+    pub finish_reason: Option<FinishReason>,
+
+    /// Sum of the log-probabilities of the completion tokens
+    pub cumulative_logprob: f32,
+
+    /// Token counts of the sequence
+    pub usage: Usage,
+}
+
+impl GenerationOutput {
+    /// Builds the output for a finished sequence
+    pub fn from_sequence(seq: &Sequence) -> Self {
+        Self {
+            seq_id: seq.seq_id,
+            token_ids: seq.completion_token_ids().to_vec(),
+            finish_reason: seq.finish_reason,
+            cumulative_logprob: seq.cumulative_logprob,
+            usage: Usage::from_sequence(seq),
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! Construction of attention contexts for scheduled batches
+//!
+//! The attention layers read their batch metadata (sequence boundaries, KV
+//! cache slots, block tables) from the global `Context`. This module builds
+//! that context from the sequences of a prefill or decode batch so that the
+//! runner can hand it to `set_context` before the forward pass.
+
+use candle_core::{Device, Result, Tensor};
+use common::sequence::Sequence;
+use utils::Context;
+
+/// Slot used for tokens whose KV entries must not be written to the cache
+///
+/// Sequences without an allocated block table (e.g. warmup batches) map all
+/// of their tokens to this slot.
+const PAD_SLOT: i64 = -1;
+
+/// Builds the context for a prefill batch
+///
+/// The uncached prompt tokens of every sequence are packed back to back.
+/// `cu_seqlens_q` holds the cumulative number of new tokens per sequence and
+/// `cu_seqlens_k` the cumulative number of total tokens, which differ only
+/// when part of a prompt is served from the prefix cache. In that case the
+/// block tables are included so attention can read the cached keys.
+///
+/// # Arguments
+///
+/// * `seqs` - The sequences being prefilled, in batch order
+/// * `device` - Device on which the context tensors are created
+///
+/// # Returns
+///
+/// A prefill `Context` whose `slot_mapping` has one entry per packed token
+///
+/// # Errors
+///
+/// Returns an error if a tensor cannot be created on the device.
+pub fn build_prefill_context(seqs: &[&Sequence], device: &Device) -> Result<Context> {
+    let num_new_tokens: Vec<usize> = seqs.iter().map(|seq| seq.len() - seq.num_cached_tokens).collect();
+    build_chunked_prefill_context(seqs, &num_new_tokens, device)
+}
+
+/// Builds the context for a prefill batch that may prefill partial prompts
+///
+/// Like `build_prefill_context`, but only the next `num_new_tokens[i]`
+/// uncached tokens of sequence `i` are packed. Keys span every token up to
+/// the end of the chunk, so later chunks attend to the earlier ones through
+/// the block tables.
+///
+/// # Arguments
+///
+/// * `seqs` - The sequences being prefilled, in batch order
+/// * `num_new_tokens` - Number of tokens to prefill for each sequence
+/// * `device` - Device on which the context tensors are created
+///
+/// # Returns
+///
+/// A prefill `Context` whose `slot_mapping` has one entry per packed token
+///
+/// # Errors
+///
+/// Returns an error if a tensor cannot be created on the device.
+pub fn build_chunked_prefill_context(
+    seqs: &[&Sequence],
+    num_new_tokens: &[usize],
+    device: &Device,
+) -> Result<Context> {
+    let mut cu_seqlens_q = vec![0u32];
+    let mut cu_seqlens_k = vec![0u32];
+    let mut max_seqlen_q = 0;
+    let mut max_seqlen_k = 0;
+    let mut slot_mapping = Vec::new();
+    for (seq, &seqlen_q) in seqs.iter().zip(num_new_tokens) {
+        let seqlen_k = seq.num_cached_tokens + seqlen_q;
+        cu_seqlens_q.push(cu_seqlens_q[cu_seqlens_q.len() - 1] + seqlen_q as u32);
+        cu_seqlens_k.push(cu_seqlens_k[cu_seqlens_k.len() - 1] + seqlen_k as u32);
+        max_seqlen_q = max_seqlen_q.max(seqlen_q);
+        max_seqlen_k = max_seqlen_k.max(seqlen_k);
+
+        if seq.block_table.is_empty() {
+            slot_mapping.extend(std::iter::repeat_n(PAD_SLOT, seqlen_q));
+            continue;
+        }
+        slot_mapping.extend((seq.num_cached_tokens..seqlen_k).map(|position| slot(seq, position)));
+    }
+
+    let has_prefix_cache = cu_seqlens_k.last() > cu_seqlens_q.last();
+    let num_seqs = seqs.len() + 1;
+    let num_tokens = slot_mapping.len();
+    Ok(Context {
+        is_prefill: true,
+        cu_seqlens_q: Some(Tensor::from_vec(cu_seqlens_q, num_seqs, device)?),
+        cu_seqlens_k: Some(Tensor::from_vec(cu_seqlens_k, num_seqs, device)?),
+        max_seqlen_q,
+        max_seqlen_k,
+        slot_mapping: Some(Tensor::from_vec(slot_mapping, num_tokens, device)?),
+        context_lens: None,
+        block_tables: if has_prefix_cache { Some(build_block_tables(seqs, device)?) } else { None },
+    })
+}
+
+/// Builds the context for a decode batch
+///
+/// Every sequence contributes only its most recent token, whose KV entry is
+/// written to the slot that `BlockManager::append_slot` reserved for it.
+/// Attention reads the full context of each sequence through its block
+/// table, padded with `build_block_tables`.
+///
+/// # Arguments
+///
+/// * `seqs` - The running sequences being decoded, in batch order
+/// * `device` - Device on which the context tensors are created
+///
+/// # Returns
+///
+/// A decode `Context` with one slot, context length, and block table row
+/// per sequence
+///
+/// # Errors
+///
+/// Returns an error if a tensor cannot be created on the device.
+pub fn build_decode_context(seqs: &[&Sequence], device: &Device) -> Result<Context> {
+    let slot_mapping: Vec<i64> = seqs
+        .iter()
+        .map(|seq| if seq.block_table.is_empty() { PAD_SLOT } else { slot(seq, seq.len() - 1) })
+        .collect();
+    let context_lens: Vec<u32> = seqs.iter().map(|seq| seq.len() as u32).collect();
+    Ok(Context {
+        is_prefill: false,
+        slot_mapping: Some(Tensor::from_vec(slot_mapping, seqs.len(), device)?),
+        context_lens: Some(Tensor::from_vec(context_lens, seqs.len(), device)?),
+        block_tables: Some(build_block_tables(seqs, device)?),
+        ..Context::default()
+    })
+}
+
+/// Builds the flattened input IDs of a batch
+///
+/// For prefill, the next `num_new_tokens` uncached prompt tokens of every
+/// sequence are packed back to back, in the same order as
+/// `build_chunked_prefill_context` packs their slots. For decode, each
+/// sequence contributes its `last_token_id`.
+///
+/// # Arguments
+///
+/// * `seqs` - The sequences in the batch, in batch order
+/// * `num_new_tokens` - Number of tokens each sequence runs in this step;
+///   1 for every sequence of a decode batch
+/// * `is_prefill` - Whether this is a prefill or a decode step
+/// * `device` - Device on which the tensor is created
+///
+/// # Returns
+///
+/// A U32 tensor of shape `[total_tokens]`
+///
+/// # Errors
+///
+/// Returns an error if `num_new_tokens` does not have one entry per
+/// sequence, a decode sequence runs other than one token, or the tensor
+/// cannot be created on the device.
+pub fn build_input_ids(
+    seqs: &[&Sequence],
+    num_new_tokens: &[usize],
+    is_prefill: bool,
+    device: &Device,
+) -> Result<Tensor> {
+    if num_new_tokens.len() != seqs.len() {
+        candle_core::bail!("expected {} token counts, got {}", seqs.len(), num_new_tokens.len());
+    }
+    let input_ids: Vec<u32> = if is_prefill {
+        seqs.iter()
+            .zip(num_new_tokens)
+            .flat_map(|(seq, &num_new)| seq.token_ids[seq.num_cached_tokens..seq.num_cached_tokens + num_new].iter().copied())
+            .collect()
+    } else {
+        if num_new_tokens.iter().any(|&num_new| num_new != 1) {
+            candle_core::bail!("decode steps run exactly one token per sequence");
+        }
+        seqs.iter().map(|seq| seq.last_token_id).collect()
+    };
+    let num_tokens = input_ids.len();
+    Tensor::from_vec(input_ids, num_tokens, device)
+}
+
+/// Builds the block table of every sequence, padded to a common length
+///
+/// Each sequence gets one `I64` row as long as the longest block table in
+/// the batch; unused entries are filled with -1 so that attention kernels
+/// can index the rows uniformly.
+///
+/// # Arguments
+///
+/// * `seqs` - The sequences in the batch
+/// * `device` - Device on which the rows are created
+///
+/// # Errors
+///
+/// Returns an error if a tensor cannot be created on the device.
+pub fn build_block_tables(seqs: &[&Sequence], device: &Device) -> Result<Vec<Tensor>> {
+    let max_len = seqs.iter().map(|seq| seq.block_table.len()).max().unwrap_or(0);
+    seqs.iter()
+        .map(|seq| {
+            let mut row: Vec<i64> = seq.block_table.iter().map(|&id| id as i64).collect();
+            row.resize(max_len, -1);
+            Tensor::from_vec(row, max_len, device)
+        })
+        .collect()
+}
+
+/// Returns the physical KV cache slot of a token position of a sequence
+fn slot(seq: &Sequence, position: usize) -> i64 {
+    let block_id = seq.block_table[position / seq.block_size];
+    (block_id * seq.block_size + position % seq.block_size) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::sampling::SamplingParams;
+
+    fn seq(len: u32, block_table: Vec<usize>) -> Sequence {
+        let mut seq = Sequence::with_block_size((0..len).collect(), SamplingParams::default(), 4);
+        seq.block_table = block_table;
+        seq
+    }
+
+    #[test]
+    fn prefill_context_packs_prompts() {
+        let first = seq(3, vec![2]);
+        let second = seq(5, vec![0, 5]);
+        let context = build_prefill_context(&[&first, &second], &Device::Cpu).unwrap();
+
+        assert!(context.is_prefill);
+        assert_eq!(context.cu_seqlens_q.unwrap().to_vec1::<u32>().unwrap(), vec![0, 3, 8]);
+        assert_eq!(context.cu_seqlens_k.unwrap().to_vec1::<u32>().unwrap(), vec![0, 3, 8]);
+        assert_eq!((context.max_seqlen_q, context.max_seqlen_k), (5, 5));
+        assert_eq!(
+            context.slot_mapping.unwrap().to_vec1::<i64>().unwrap(),
+            vec![8, 9, 10, 0, 1, 2, 3, 20]
+        );
+        assert!(context.block_tables.is_none());
+    }
+
+    #[test]
+    fn input_ids_pack_uncached_prompts_or_last_tokens() {
+        let first = seq(3, vec![2]);
+        let mut second = seq(6, vec![1, 3]);
+        second.num_cached_tokens = 4;
+        let prefill = build_input_ids(&[&first, &second], &[3, 2], true, &Device::Cpu).unwrap();
+        assert_eq!(prefill.to_vec1::<u32>().unwrap(), vec![0, 1, 2, 4, 5]);
+        // A chunked prefill runs only the next chunk of each prompt.
+        let chunk = build_input_ids(&[&first, &second], &[2, 1], true, &Device::Cpu).unwrap();
+        assert_eq!(chunk.to_vec1::<u32>().unwrap(), vec![0, 1, 4]);
+
+        second.append_token(9);
+        let decode = build_input_ids(&[&first, &second], &[1, 1], false, &Device::Cpu).unwrap();
+        assert_eq!(decode.to_vec1::<u32>().unwrap(), vec![2, 9]);
+        assert!(build_input_ids(&[&first, &second], &[1], false, &Device::Cpu).is_err());
+    }
+
+    #[test]
+    fn prefill_context_skips_cached_prefix() {
+        let mut cached = seq(6, vec![1, 3]);
+        cached.num_cached_tokens = 4;
+        let context = build_prefill_context(&[&cached], &Device::Cpu).unwrap();
+
+        assert_eq!(context.cu_seqlens_q.unwrap().to_vec1::<u32>().unwrap(), vec![0, 2]);
+        assert_eq!(context.cu_seqlens_k.unwrap().to_vec1::<u32>().unwrap(), vec![0, 6]);
+        assert_eq!(context.slot_mapping.unwrap().to_vec1::<i64>().unwrap(), vec![12, 13]);
+        assert_eq!(context.block_tables.unwrap()[0].to_vec1::<i64>().unwrap(), vec![1, 3]);
+    }
+
+    #[test]
+    fn chunked_prefill_context_stops_at_chunk_end() {
+        let mut partial = seq(10, vec![4, 1, 6]);
+        partial.num_cached_tokens = 4;
+        let context = build_chunked_prefill_context(&[&partial], &[3], &Device::Cpu).unwrap();
+
+        assert_eq!(context.cu_seqlens_q.unwrap().to_vec1::<u32>().unwrap(), vec![0, 3]);
+        assert_eq!(context.cu_seqlens_k.unwrap().to_vec1::<u32>().unwrap(), vec![0, 7]);
+        assert_eq!(context.slot_mapping.unwrap().to_vec1::<i64>().unwrap(), vec![4, 5, 6]);
+        assert!(context.block_tables.is_some());
+    }
+
+    #[test]
+    fn decode_context_pads_block_tables() {
+        let mut short = seq(3, vec![2]);
+        short.append_token(7);
+        let mut long = seq(5, vec![0, 5]);
+        long.append_token(7);
+        let context = build_decode_context(&[&short, &long], &Device::Cpu).unwrap();
+
+        assert!(!context.is_prefill);
+        assert!(context.cu_seqlens_q.is_none());
+        assert_eq!(context.slot_mapping.unwrap().to_vec1::<i64>().unwrap(), vec![11, 21]);
+        assert_eq!(context.context_lens.unwrap().to_vec1::<u32>().unwrap(), vec![4, 6]);
+        let block_tables = context.block_tables.unwrap();
+        assert_eq!(block_tables[0].to_vec1::<i64>().unwrap(), vec![2, -1]);
+        assert_eq!(block_tables[1].to_vec1::<i64>().unwrap(), vec![0, 5]);
+    }
+}
@@ -0,0 +1,57 @@
+//! Error type for the engine
+//!
+//! This module provides `EngineError`, which unifies the candle errors raised
+//! by the layers and the anyhow errors raised by the loader and configuration
+//! so that callers can react to specific failure modes.
+
+use thiserror::Error;
+
+/// Errors returned by the public engine API
+#[derive(Debug, Error)]
+pub enum EngineError {
+    /// The configuration is invalid or incomplete
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    /// The model weights or configuration files could not be loaded
+    ///
+    /// Wraps the anyhow error of the loader or `Config::new`; callers map
+    /// into this variant at the load call site.
+    #[error("failed to load model: {0:#}")]
+    Load(anyhow::Error),
+
+    /// The KV cache does not have enough free blocks
+    ///
+    /// Callers can retry with fewer concurrent sequences or shorter prompts.
+    #[error("out of KV cache memory: {0}")]
+    OutOfMemory(String),
+
+    /// A request has invalid sampling parameters
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
+    /// A sequence does not fit within the model's maximum length
+    #[error("sequence of {len} tokens exceeds the maximum of {max} tokens")]
+    SequenceTooLong {
+        /// Length of the offending sequence
+        len: usize,
+
+        /// Maximum length allowed
+        max: usize,
+    },
+
+    /// A tensor operation failed
+    #[error(transparent)]
+    Candle(#[from] candle_core::Error),
+
+    /// Tokenization or detokenization failed
+    #[error("tokenizer error: {0}")]
+    Tokenizer(String),
+
+    /// The engine reached a state it should never be in
+    ///
+    /// This indicates a bug rather than a problem with the request or the
+    /// available memory, so retrying does not help.
+    #[error("internal error: {0}")]
+    Internal(String),
+}
@@ -0,0 +1,43 @@
+//! Inference engine for the candle-nano-vllm project
+//!
+//! This crate ties the scheduler, the KV cache block manager, the model,
+//! and the sampler together into an engine that serves generation requests.
+
+mod api;
+mod context_builder;
+mod error;
+mod llm_engine;
+mod model_runner;
+mod stream;
+#[cfg(test)]
+mod testing;
+
+/// Re-exports from the api module
+///
+/// These exports provide the framework-independent request and response
+/// types a server binds to.
+pub use api::{GenerationOutput, GenerationRequest, GenerationResponse, PromptInput, Usage};
+
+/// Re-exports from the context_builder module
+///
+/// These exports build the attention context consumed by `set_context`.
+pub use context_builder::{
+    build_block_tables, build_chunked_prefill_context, build_decode_context, build_input_ids, build_prefill_context,
+};
+
+/// Re-exports from the error module
+///
+/// These exports provide the error type returned by the engine's public API.
+pub use error::EngineError;
+
+/// Re-exports from the llm_engine module
+///
+/// These exports provide the LLMEngine, the main entry point for generation,
+/// the queue statistics it reports, and prompt truncation.
+pub use llm_engine::{LLMEngine, QueueStats, truncate_prompt_left};
+
+/// Re-exports from the stream module
+///
+/// These exports provide the events yielded while streaming a generation,
+/// one token at a time or in chunks.
+pub use stream::{ChunkedEvent, ChunkedStream, GenerationEvent, GenerationStream};
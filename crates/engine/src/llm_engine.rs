@@ -0,0 +1,1818 @@
+//! The main generation engine
+//!
+//! This module provides `LLMEngine`, which accepts generation requests,
+//! schedules them into batches, and drives the model until they finish.
+
+use crate::api::{GenerationOutput, GenerationRequest, GenerationResponse, PromptInput};
+use crate::error::EngineError;
+use crate::model_runner::ModelRunner;
+use crate::stream::GenerationStream;
+use cache::KvCache;
+use candle_core::Device;
+use common::config::Config;
+use common::detokenizer::Detokenizer;
+use common::sampling::SamplingParams;
+use common::sequence::{FinishReason, Sequence, StopFn};
+use common::tokenizer::{Tokenizer, warn_on_double_bos};
+use layers::logits_processor::LogitsProcessorChain;
+use layers::sampler::sequence_seed;
+use model::CausalLM;
+use scheduler::{NothingScheduled, ScheduledBatch, Scheduler};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// Outcome of a single engine step
+#[derive(Debug, Default)]
+pub(crate) struct StepOutput {
+    /// ID and token of every sequence that sampled a completion token
+    pub sampled: Vec<(usize, u32)>,
+
+    /// Sequences that finished during the step
+    pub finished: Vec<Sequence>,
+}
+
+/// Number of sequences in each scheduling queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Sequences waiting for (the rest of) their prefill
+    pub waiting: usize,
+
+    /// Sequences that are decoding
+    pub running: usize,
+
+    /// Preempted sequences swapped out to the CPU
+    pub swapped: usize,
+}
+
+/// Engine that serves generation requests with continuous batching
+///
+/// Requests are added as sequences to the scheduler. Each call to `step`
+/// runs one prefill or decode batch and returns the sequences that finished
+/// during that step.
+pub struct LLMEngine<M: CausalLM> {
+    /// Resolved engine configuration
+    config: Config,
+
+    /// Runner executing scheduled batches on the model
+    model_runner: ModelRunner<M>,
+
+    /// Scheduler owning the waiting and running queues
+    scheduler: Scheduler,
+
+    /// CPU copy of the KV cache layout that swapped-out blocks are moved to,
+    /// if swapping is enabled
+    swap_space: Option<KvCache>,
+
+    /// Cancellation flag of each unfinished cancellable sequence
+    cancellation_flags: HashMap<usize, Arc<AtomicBool>>,
+
+    /// Sequences that finished in `step` since the last `drain_finished`
+    finished: Vec<Sequence>,
+
+    /// Number of requests added so far, used to derive seeds from `Config.seed`
+    num_requests: usize,
+
+    /// Number of completion tokens generated across all sequences
+    num_generated_tokens: usize,
+
+    /// Cap on `num_generated_tokens`, if any
+    token_budget: Option<usize>,
+}
+
+impl<M: CausalLM> LLMEngine<M> {
+    /// Creates a new engine for a model on the given device
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Engine configuration with `num_kvcache_blocks` resolved
+    /// * `model` - The language model to serve
+    /// * `device` - Device on which model inputs are created
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::Config` if `config.num_kvcache_blocks` has not
+    /// been resolved, `config.kvcache_block_size` or
+    /// `config.max_num_partial_prefill_tokens` is zero, or
+    /// `config.num_cpu_blocks` is positive for a model that does not expose
+    /// its KV cache, and `EngineError::Candle` if the swap space cannot be
+    /// allocated.
+    ///
+    /// # Notes
+    ///
+    /// When `config.num_cpu_blocks` is unset and the model exposes its KV
+    /// cache (see `CausalLM::kv_cache`), it is derived from
+    /// `swap_space_bytes` with `Config::compute_num_cpu_blocks`, and a CPU
+    /// swap space of that many blocks is allocated.
+    pub fn new(mut config: Config, model: M, device: Device) -> Result<Self, EngineError> {
+        // Every sequence the engine builds takes its block size from the config,
+        // so any positive size addresses the KV cache consistently; zero would
+        // divide by zero in the slot math.
+        if config.kvcache_block_size == 0 {
+            return Err(EngineError::Config(
+                "kvcache_block_size must be at least 1; every KV cache slot is computed as \
+                 block_id * kvcache_block_size + offset"
+                    .to_string(),
+            ));
+        }
+        // A zero chunk would schedule prefills that never make progress.
+        if config.max_num_partial_prefill_tokens == Some(0) {
+            return Err(EngineError::Config(
+                "max_num_partial_prefill_tokens must be at least 1 when set".to_string(),
+            ));
+        }
+        let num_kvcache_blocks = config.num_kvcache_blocks.ok_or_else(|| {
+            EngineError::Config("num_kvcache_blocks must be resolved before creating the engine".to_string())
+        })?;
+        if config.num_cpu_blocks.is_none() && model.kv_cache().is_some() {
+            match config.compute_num_cpu_blocks() {
+                Ok(num_cpu_blocks) => config.num_cpu_blocks = Some(num_cpu_blocks),
+                Err(e) => log::warn!("Swapping is disabled because the swap space cannot be sized: {}", e),
+            }
+        }
+        let num_cpu_blocks = config.num_cpu_blocks.unwrap_or(0);
+        let swap_space = match model.kv_cache() {
+            Some(kv_cache) if num_cpu_blocks > 0 => {
+                Some(kv_cache.zeros_like(num_cpu_blocks * config.kvcache_block_size, &Device::Cpu)?)
+            }
+            Some(_) => None,
+            // Swapping copies blocks out of the model's cache, so it must be reachable.
+            None if num_cpu_blocks > 0 => {
+                return Err(EngineError::Config(
+                    "num_cpu_blocks requires a model that exposes its KV cache through CausalLM::kv_cache; \
+                     swapped sequences would otherwise resume on overwritten blocks"
+                        .to_string(),
+                ));
+            }
+            None => None,
+        };
+        let scheduler = Scheduler::new(&config, num_kvcache_blocks);
+        Ok(Self {
+            config,
+            model_runner: ModelRunner::new(model, device),
+            scheduler,
+            swap_space,
+            cancellation_flags: HashMap::new(),
+            finished: Vec::new(),
+            num_requests: 0,
+            num_generated_tokens: 0,
+            token_budget: None,
+        })
+    }
+
+    /// Returns the engine configuration
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns the scheduler, e.g. to inspect KV cache usage
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// Returns the number of sequences in each scheduling queue
+    pub fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            waiting: self.scheduler.num_waiting(),
+            running: self.scheduler.num_running(),
+            swapped: self.scheduler.num_swapped(),
+        }
+    }
+
+    /// Returns the IDs of the sequences that are decoding
+    pub fn running_seq_ids(&self) -> Vec<usize> {
+        self.scheduler.running_seq_ids()
+    }
+
+    /// Returns the number of completion tokens generated across all sequences
+    pub fn num_generated_tokens(&self) -> usize {
+        self.num_generated_tokens
+    }
+
+    /// Caps the total number of completion tokens the engine generates
+    ///
+    /// Once `num_generated_tokens` reaches the budget, no further steps are
+    /// run: every unfinished sequence finishes with `FinishReason::Length`
+    /// at the start of the next step. The step that reaches the budget runs
+    /// its whole batch, so the count can exceed it by less than the batch
+    /// size. None removes the cap.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_budget` - Maximum number of generated tokens, or None
+    pub fn set_token_budget(&mut self, token_budget: Option<usize>) {
+        self.token_budget = token_budget;
+    }
+
+    /// Returns true if generating `pending` more tokens reaches the token budget
+    fn reaches_token_budget(&self, pending: usize) -> bool {
+        self.token_budget.is_some_and(|budget| self.num_generated_tokens + pending >= budget)
+    }
+
+    /// Replaces the logits processors applied to every sequence before sampling
+    pub fn set_logits_processors(&mut self, logits_processors: LogitsProcessorChain) {
+        self.model_runner.set_logits_processors(logits_processors);
+    }
+
+    /// Adds a generation request for a tokenized prompt
+    ///
+    /// One sequence is created for each of the `params.n` requested
+    /// completions. When `params.seed` is set, every sequence gets its own
+    /// seed derived from it with `sequence_seed`, so the completions differ
+    /// from each other yet are reproduced exactly by re-running the request.
+    ///
+    /// The request's `max_tokens` is capped so that the prompt and completion
+    /// together fit within `max_model_len`; a warning is logged when the cap
+    /// applies. The effective value is recorded on the sequences, which then
+    /// finish with `FinishReason::Length` once it is reached.
+    ///
+    /// # Returns
+    ///
+    /// The IDs of the sequences created for the request, one per completion
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::InvalidRequest` if `params.n` is zero or a
+    /// prompt token ID is outside the vocabulary,
+    /// `EngineError::SequenceTooLong` if the prompt leaves no room for any
+    /// completion token and `Config.prompt_truncation` is not set, or
+    /// `EngineError::OutOfMemory` if the prompt needs
+    /// more blocks than the whole KV cache holds.
+    pub fn add_request(&mut self, prompt: Vec<u32>, params: SamplingParams) -> Result<Vec<usize>, EngineError> {
+        self.add_sequences(prompt, params, None)
+    }
+
+    /// Adds a generation request that times out if not started by `deadline`
+    ///
+    /// Behaves like `add_request`, but sequences still waiting for admission
+    /// once `deadline` has passed finish with `FinishReason::Timeout` at the
+    /// start of the next step. Sequences that have started running are
+    /// never timed out.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `add_request`.
+    pub fn add_request_with_deadline(
+        &mut self,
+        prompt: Vec<u32>,
+        params: SamplingParams,
+        deadline: Instant,
+    ) -> Result<Vec<usize>, EngineError> {
+        self.add_sequences(prompt, params, Some(deadline))
+    }
+
+    /// Validates a request and queues one sequence per completion
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors documented on `add_request`.
+    fn add_sequences(
+        &mut self,
+        prompt: Vec<u32>,
+        params: SamplingParams,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<usize>, EngineError> {
+        if params.use_beam_search {
+            return Err(EngineError::InvalidRequest(
+                "beam search requests must be run with beam_search or generate".to_string(),
+            ));
+        }
+        let max_model_len = self.config.max_model_len;
+        let prompt = match self.config.prompt_truncation {
+            Some(truncation) if prompt.len() >= max_model_len => {
+                log::warn!(
+                    "Truncating prompt of {} tokens from the left to {} to fit max_model_len {}",
+                    prompt.len(), max_model_len - 1, max_model_len
+                );
+                truncate_prompt_left(prompt, max_model_len - 1, truncation.keep_prefix)
+            }
+            _ => prompt,
+        };
+        self.check_request(&prompt, &params)?;
+
+        let mut seq = Sequence::with_block_size(prompt, params, self.config.kvcache_block_size);
+        seq.deadline = deadline;
+        let total_blocks = self.scheduler.block_manager().num_blocks();
+        if seq.num_blocks() > total_blocks {
+            return Err(EngineError::OutOfMemory(format!(
+                "prompt of {} tokens needs {} blocks but the KV cache only has {}",
+                seq.len(), seq.num_blocks(), total_blocks
+            )));
+        }
+        let remaining = max_model_len - seq.len();
+        if seq.max_tokens > remaining {
+            log::warn!(
+                "Capping max_tokens of sequence {} from {} to {} to fit max_model_len {}",
+                seq.seq_id, seq.max_tokens, remaining, max_model_len
+            );
+            seq.max_tokens = remaining;
+        }
+
+        // Requests without a seed of their own derive one from the engine
+        // seed, counting only the requests that were accepted.
+        let engine_seed = self.config.seed.map(|seed| sequence_seed(seed, self.num_requests));
+        self.num_requests += 1;
+        let (n, seed) = (seq.sampling_params.n, seq.sampling_params.seed.or(engine_seed));
+        let forks: Vec<Sequence> = (1..n).map(|_| seq.fork()).collect();
+        let mut seq_ids = Vec::with_capacity(n);
+        for (index, mut sample) in std::iter::once(seq).chain(forks).enumerate() {
+            sample.seed = seed.map(|seed| sequence_seed(seed, index));
+            seq_ids.push(sample.seq_id);
+            self.scheduler.add(sample);
+        }
+        Ok(seq_ids)
+    }
+
+    /// Checks the completion count, prompt length, and vocabulary of a request
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::InvalidRequest` if `params.n` is zero or a
+    /// prompt token ID is outside the vocabulary, or
+    /// `EngineError::SequenceTooLong` if the prompt leaves no room for any
+    /// completion token.
+    fn check_request(&self, prompt: &[u32], params: &SamplingParams) -> Result<(), EngineError> {
+        if params.n == 0 {
+            return Err(EngineError::InvalidRequest("n must be at least 1".to_string()));
+        }
+        let max_model_len = self.config.max_model_len;
+        if prompt.len() >= max_model_len {
+            return Err(EngineError::SequenceTooLong { len: prompt.len(), max: max_model_len - 1 });
+        }
+        self.check_vocabulary(prompt)
+    }
+
+    /// Checks that every token ID is inside the model's vocabulary
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::InvalidRequest` naming the first token ID
+    /// outside the vocabulary.
+    fn check_vocabulary(&self, token_ids: &[u32]) -> Result<(), EngineError> {
+        // Prefer the checkpoint's config; engines built without one ask the model.
+        let vocab_size = match &self.config.hf_config {
+            Some(hf_config) => hf_config.vocab_size(),
+            None => self.model_runner.vocab_size(),
+        };
+        if let Some((position, token_id)) = token_ids.iter().enumerate().find(|&(_, &id)| id as usize >= vocab_size) {
+            return Err(EngineError::InvalidRequest(format!(
+                "token id {} at position {} is outside the vocabulary of {} tokens",
+                token_id, position, vocab_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Adds a generation request that can be cancelled through a flag
+    ///
+    /// Behaves like `add_request`, but the engine checks `cancelled` at the
+    /// start of every step. Once it is set, the request's sequences finish
+    /// with `FinishReason::Aborted` and their blocks are freed. The flag can
+    /// be set from any thread without access to the engine.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `add_request`.
+    pub fn add_request_cancellable(
+        &mut self,
+        prompt: Vec<u32>,
+        params: SamplingParams,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<Vec<usize>, EngineError> {
+        let seq_ids = self.add_request(prompt, params)?;
+        for &seq_id in &seq_ids {
+            self.cancellation_flags.insert(seq_id, cancelled.clone());
+        }
+        Ok(seq_ids)
+    }
+
+    /// Adds a generation request with an extra stop condition
+    ///
+    /// Behaves like `add_request`, but `stop_fn` is evaluated on each of the
+    /// request's sequences after every appended token that did not already
+    /// finish it. Once it returns true, the sequence finishes with
+    /// `FinishReason::Custom` and its blocks are freed.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `add_request`.
+    pub fn add_request_with_stop_fn(
+        &mut self,
+        prompt: Vec<u32>,
+        params: SamplingParams,
+        stop_fn: StopFn,
+    ) -> Result<Vec<usize>, EngineError> {
+        let seq_ids = self.add_request(prompt, params)?;
+        for &seq_id in &seq_ids {
+            self.scheduler.set_stop_fn(seq_id, stop_fn.clone());
+        }
+        Ok(seq_ids)
+    }
+
+    /// Continues a finished conversation with a new user turn
+    ///
+    /// The turn is appended to `seq` with `Sequence::push_turn`, which
+    /// records its start in `turn_boundaries`, and the whole conversation
+    /// is queued again as the prompt of the next completion. Blocks of the
+    /// earlier turns are reused through the prefix cache. The sequence keeps
+    /// its ID and sampling settings; `max_tokens` is capped to fit within
+    /// `max_model_len` as in `add_request`.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - A finished sequence, e.g. taken from `drain_finished`
+    /// * `turn` - Token IDs of the new turn, e.g. a templated user message
+    ///
+    /// # Returns
+    ///
+    /// The ID of the continued sequence
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::InvalidRequest` if `seq` has not finished, the
+    /// turn is empty, or a token ID is outside the vocabulary,
+    /// `EngineError::SequenceTooLong` if the conversation leaves no room for
+    /// any completion token, or `EngineError::OutOfMemory` if it needs more
+    /// blocks than the whole KV cache holds.
+    pub fn add_turn(&mut self, mut seq: Sequence, turn: Vec<u32>) -> Result<usize, EngineError> {
+        if !seq.is_finished() {
+            return Err(EngineError::InvalidRequest(format!(
+                "sequence {} must finish before a turn is added",
+                seq.seq_id
+            )));
+        }
+        if turn.is_empty() {
+            return Err(EngineError::InvalidRequest("turn must not be empty".to_string()));
+        }
+        self.check_vocabulary(&turn)?;
+        let max_model_len = self.config.max_model_len;
+        let len = seq.len() + turn.len();
+        if len >= max_model_len {
+            return Err(EngineError::SequenceTooLong { len, max: max_model_len - 1 });
+        }
+
+        // Finished sequences have already released their blocks.
+        seq.push_turn(&turn);
+        seq.deadline = None;
+        let total_blocks = self.scheduler.block_manager().num_blocks();
+        if seq.num_blocks() > total_blocks {
+            return Err(EngineError::OutOfMemory(format!(
+                "conversation of {} tokens needs {} blocks but the KV cache only has {}",
+                seq.len(), seq.num_blocks(), total_blocks
+            )));
+        }
+        seq.max_tokens = seq.max_tokens.min(max_model_len - seq.len());
+        let seq_id = seq.seq_id;
+        self.scheduler.add(seq);
+        Ok(seq_id)
+    }
+
+    /// Returns true if every request has finished
+    pub fn is_finished(&self) -> bool {
+        self.scheduler.is_finished()
+    }
+
+    /// Runs a single scheduling step
+    ///
+    /// When no sequences are waiting or running this is a no-op, so callers
+    /// never build an empty batch.
+    ///
+    /// # Returns
+    ///
+    /// The ID and completion token IDs of every sequence that finished
+    /// during this step, including cancelled and timed-out ones. The sequences
+    /// themselves are kept until collected with `drain_finished`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::OutOfMemory` if no sequence can be scheduled,
+    /// `EngineError::Candle` if the model fails, or `EngineError::Internal`
+    /// if the scheduler reaches an invalid state. The sequences of a failed
+    /// batch finish with `FinishReason::Error` and can be collected with
+    /// `drain_finished`.
+    pub fn step(&mut self) -> Result<Vec<(usize, Vec<u32>)>, EngineError> {
+        let finished = self.step_sampled()?.finished;
+        let outputs = finished
+            .iter()
+            .map(|seq| (seq.seq_id, seq.completion_token_ids().to_vec()))
+            .collect();
+        self.finished.extend(finished);
+        Ok(outputs)
+    }
+
+    /// Removes and returns the sequences that finished in `step` since the
+    /// last call
+    ///
+    /// Their KV cache blocks were already freed when they finished, so this
+    /// only hands over the sequences; a server driving the engine with
+    /// `step` should drain regularly to bound memory. `generate` and
+    /// `handle_request` return their own sequences directly, but sequences
+    /// of other requests that finish while they drive the engine are kept
+    /// here. `stream` reports every sequence and does not add to this
+    /// buffer.
+    ///
+    /// # Returns
+    ///
+    /// The finished sequences, in the order they finished
+    pub fn drain_finished(&mut self) -> Vec<Sequence> {
+        std::mem::take(&mut self.finished)
+    }
+
+    /// Runs up to `num_scheduler_steps` decode steps on one scheduled batch
+    ///
+    /// Like `step`, but a decode batch is run again right away instead of
+    /// being handed back to the scheduler, for `Config.num_scheduler_steps`
+    /// steps in total. The batch is re-scheduled early as soon as one of its
+    /// sequences finishes or the next tokens need blocks that are not free.
+    /// Prefill batches always run a single step. Cancellation flags and
+    /// deadlines are checked once per call.
+    ///
+    /// # Returns
+    ///
+    /// The ID and completion token IDs of every sequence that finished
+    /// during these steps, including cancelled and timed-out ones. The
+    /// sequences themselves are kept until collected with `drain_finished`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `step`.
+    pub fn multi_step(&mut self) -> Result<Vec<(usize, Vec<u32>)>, EngineError> {
+        let finished = self.multi_step_sampled()?.finished;
+        let outputs = finished
+            .iter()
+            .map(|seq| (seq.seq_id, seq.completion_token_ids().to_vec()))
+            .collect();
+        self.finished.extend(finished);
+        Ok(outputs)
+    }
+
+    /// Runs a single scheduling step, reporting every sampled token
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `step`.
+    pub(crate) fn step_sampled(&mut self) -> Result<StepOutput, EngineError> {
+        self.run_steps(1)
+    }
+
+    /// Runs `multi_step`, reporting every sampled token
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `step`.
+    pub(crate) fn multi_step_sampled(&mut self) -> Result<StepOutput, EngineError> {
+        self.run_steps(self.config.num_scheduler_steps.max(1))
+    }
+
+    /// Schedules a batch and runs it for up to `num_steps` decode steps
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `step`.
+    fn run_steps(&mut self, num_steps: usize) -> Result<StepOutput, EngineError> {
+        let cancelled: Vec<usize> = self
+            .cancellation_flags
+            .iter()
+            .filter(|(_, flag)| flag.load(Ordering::Relaxed))
+            .map(|(&seq_id, _)| seq_id)
+            .collect();
+        let mut removed = Vec::new();
+        for seq_id in cancelled {
+            self.cancellation_flags.remove(&seq_id);
+            if let Some(seq) = self.scheduler.abort(seq_id) {
+                self.model_runner.release(seq_id);
+                removed.push(seq);
+            }
+        }
+        // Timed-out sequences never ran, so they hold no sampler state.
+        for seq in self.scheduler.expire_waiting(Instant::now()) {
+            self.cancellation_flags.remove(&seq.seq_id);
+            removed.push(seq);
+        }
+        if self.reaches_token_budget(0) {
+            for seq in self.scheduler.finish_all(FinishReason::Length) {
+                self.model_runner.release(seq.seq_id);
+                self.cancellation_flags.remove(&seq.seq_id);
+                removed.push(seq);
+            }
+        }
+        if self.scheduler.is_finished() {
+            return Ok(StepOutput { sampled: Vec::new(), finished: removed });
+        }
+
+        let mut batch = self.scheduler.schedule().map_err(|e| {
+            if e.is::<NothingScheduled>() {
+                EngineError::OutOfMemory(e.to_string())
+            } else {
+                EngineError::Internal(format!("{:#}", e))
+            }
+        })?;
+        let num_steps = if batch.is_prefill { 1 } else { num_steps };
+        let mut sampled = Vec::new();
+        let mut finished = Vec::new();
+        for step in 1..=num_steps {
+            let ran = self.swap_blocks(&batch).and_then(|()| {
+                Ok(self.model_runner.run_chunked(&mut batch.seqs, &batch.num_new_tokens, batch.is_prefill)?)
+            });
+            let token_ids = match ran {
+                Ok(token_ids) => token_ids,
+                Err(e) => {
+                    self.num_generated_tokens += sampled.len();
+                    removed.extend(finished);
+                    self.fail_batch(batch, removed);
+                    return Err(e);
+                }
+            };
+            // A sequence still mid-prefill after this chunk discards its sample.
+            sampled.extend(
+                batch
+                    .seqs
+                    .iter()
+                    .zip(&batch.num_new_tokens)
+                    .zip(&token_ids)
+                    .filter(|&((seq, &num_new), _)| !batch.is_prefill || seq.num_cached_tokens + num_new >= seq.len())
+                    .map(|((seq, _), &token_id)| (seq.seq_id, token_id)),
+            );
+            if step == num_steps || self.reaches_token_budget(sampled.len()) {
+                finished.extend(self.scheduler.postprocess(batch, &token_ids));
+                break;
+            }
+            let (next, done) = self
+                .scheduler
+                .continue_decode(batch, &token_ids)
+                .map_err(|e| EngineError::Internal(format!("{:#}", e)))?;
+            finished.extend(done);
+            match next {
+                Some(next) => batch = next,
+                None => break,
+            }
+        }
+        for seq in &finished {
+            self.model_runner.release(seq.seq_id);
+            self.cancellation_flags.remove(&seq.seq_id);
+        }
+        self.num_generated_tokens += sampled.len();
+        finished.splice(0..0, removed);
+        Ok(StepOutput { sampled, finished })
+    }
+
+    /// Finishes the sequences of a batch the model failed on
+    ///
+    /// The batch's sequences finish with `FinishReason::Error` and release
+    /// their blocks and sampler state. Since the error replaces the step's
+    /// output, they are kept for `drain_finished` together with `finished`,
+    /// the sequences that finished earlier in the same step.
+    fn fail_batch(&mut self, batch: ScheduledBatch, finished: Vec<Sequence>) {
+        let failed = self.scheduler.fail_batch(batch);
+        for seq in finished.iter().chain(&failed) {
+            self.model_runner.release(seq.seq_id);
+            self.cancellation_flags.remove(&seq.seq_id);
+        }
+        self.finished.extend(finished.into_iter().chain(failed));
+    }
+
+    /// Copies the blocks a batch swaps out and in before it runs
+    ///
+    /// Swap-outs are copied first, since a GPU block freed by a swap-out may
+    /// receive a swap-in of the same batch.
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::Candle` if a copy fails, or
+    /// `EngineError::Internal` if the batch swaps without a swap space.
+    fn swap_blocks(&self, batch: &ScheduledBatch) -> Result<(), EngineError> {
+        if batch.blocks_to_swap_out.is_empty() && batch.blocks_to_swap_in.is_empty() {
+            return Ok(());
+        }
+        let (Some(kv_cache), Some(swap_space)) = (self.model_runner.kv_cache(), &self.swap_space) else {
+            return Err(EngineError::Internal("cannot swap KV cache blocks without a swap space".to_string()));
+        };
+        let block_size = self.config.kvcache_block_size;
+        kv_cache.copy_blocks_to(swap_space, &batch.blocks_to_swap_out, block_size)?;
+        swap_space.copy_blocks_to(kv_cache, &batch.blocks_to_swap_in, block_size)?;
+        Ok(())
+    }
+
+    /// Compacts the free KV cache blocks into contiguous runs
+    ///
+    /// Unreferenced prefix cache blocks are moved to the highest empty block
+    /// IDs with `BlockManager::defragment`, and their KV is copied along so
+    /// they keep serving prefix cache hits. This is an opt-in maintenance
+    /// call, e.g. between steps of a long-running server.
+    ///
+    /// # Returns
+    ///
+    /// The number of relocated blocks
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::Config` if the model does not expose its KV
+    /// cache, or `EngineError::Candle` if a copy fails.
+    pub fn defragment(&mut self) -> Result<usize, EngineError> {
+        if self.model_runner.kv_cache().is_none() {
+            return Err(EngineError::Config(
+                "defragmenting moves KV cache blocks, which requires a model that exposes its KV cache \
+                 through CausalLM::kv_cache"
+                    .to_string(),
+            ));
+        }
+        let mapping = self.scheduler.block_manager_mut().defragment();
+        if let Some(kv_cache) = self.model_runner.kv_cache() {
+            kv_cache.copy_blocks(&mapping, self.config.kvcache_block_size)?;
+        }
+        Ok(mapping.len())
+    }
+
+    /// Returns a stream of events for the requests added so far
+    ///
+    /// Iterating the stream drives the engine until every request has
+    /// finished, yielding a `GenerationEvent::Token` per sampled token and a
+    /// `GenerationEvent::Finished` per finished sequence.
+    ///
+    /// # Arguments
+    ///
+    /// * `detokenizer` - Decodes the text of each token, if given
+    pub fn stream<'a>(&'a mut self, detokenizer: Option<&'a dyn Detokenizer>) -> GenerationStream<'a, M> {
+        GenerationStream::new(self, detokenizer)
+    }
+
+    /// Generates completions for a batch of tokenized prompts
+    ///
+    /// # Returns
+    ///
+    /// The completion token IDs for each prompt, in the order given. With
+    /// `params.n > 1` the `n` completions of each prompt are consecutive.
+    pub fn generate(
+        &mut self,
+        prompts: Vec<Vec<u32>>,
+        params: SamplingParams,
+    ) -> Result<Vec<Vec<u32>>, EngineError> {
+        let outputs = self.generate_detailed(prompts, params)?;
+        Ok(outputs.into_iter().map(|output| output.token_ids).collect())
+    }
+
+    /// Generates completions for a batch of tokenized prompts, with details
+    ///
+    /// Behaves like `generate`, but each completion also carries its finish
+    /// reason, cumulative log-probability, and token counts.
+    ///
+    /// # Returns
+    ///
+    /// One output per completion, in the order of `generate`
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `add_request` for the first invalid prompt,
+    /// after aborting the prompts added before it, or an error from a step.
+    pub fn generate_detailed(
+        &mut self,
+        prompts: Vec<Vec<u32>>,
+        params: SamplingParams,
+    ) -> Result<Vec<GenerationOutput>, EngineError> {
+        if params.use_beam_search {
+            let mut outputs = Vec::new();
+            for prompt in prompts {
+                let beams = self.beam_search(prompt, params.clone())?;
+                outputs.extend(beams.iter().map(GenerationOutput::from_sequence));
+            }
+            return Ok(outputs);
+        }
+        let mut seq_ids = Vec::new();
+        for prompt in prompts {
+            match self.add_request(prompt, params.clone()) {
+                Ok(ids) => seq_ids.extend(ids),
+                Err(e) => {
+                    // Prompts added before the failing one must not run on.
+                    for seq_id in seq_ids {
+                        self.scheduler.abort(seq_id);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut outputs = HashMap::new();
+        while !self.is_finished() {
+            for seq in self.step_sampled()?.finished {
+                if seq_ids.contains(&seq.seq_id) {
+                    outputs.insert(seq.seq_id, GenerationOutput::from_sequence(&seq));
+                } else {
+                    self.finished.push(seq);
+                }
+            }
+        }
+        // Every added sequence finishes before the scheduler is empty.
+        Ok(seq_ids.iter().filter_map(|seq_id| outputs.remove(seq_id)).collect())
+    }
+
+    /// Serves a generation request until all of its completions finish
+    ///
+    /// Text prompts are tokenized with the model's special tokens. Other
+    /// requests in the engine advance in the same steps, but only this
+    /// request's completions are returned; the others that finish are kept
+    /// for `drain_finished`.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request to serve
+    /// * `tokenizer` - Tokenizes text prompts and decodes the completions
+    ///
+    /// # Returns
+    ///
+    /// One response per completion, in the order of the created sequences
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::InvalidRequest` if the prompt is empty or is
+    /// text without a tokenizer, `EngineError::Tokenizer` if encoding or
+    /// decoding fails, and otherwise the errors of `add_request` and `step`.
+    pub fn handle_request(
+        &mut self,
+        request: GenerationRequest,
+        tokenizer: Option<&dyn Tokenizer>,
+    ) -> Result<Vec<GenerationResponse>, EngineError> {
+        let prompt = match request.prompt {
+            PromptInput::Tokens(token_ids) => token_ids,
+            PromptInput::Text(text) => {
+                let Some(tokenizer) = tokenizer else {
+                    return Err(EngineError::InvalidRequest("text prompts require a tokenizer".to_string()));
+                };
+                let token_ids = tokenizer.encode(&text, true).map_err(|e| EngineError::Tokenizer(e.to_string()))?;
+                warn_on_double_bos(tokenizer, &token_ids);
+                token_ids
+            }
+        };
+        if prompt.is_empty() {
+            return Err(EngineError::InvalidRequest("prompt must not be empty".to_string()));
+        }
+
+        let seq_ids = self.add_request(prompt, request.params)?;
+        let mut finished = HashMap::new();
+        while !self.is_finished() && seq_ids.iter().any(|seq_id| !finished.contains_key(seq_id)) {
+            for seq in self.step_sampled()?.finished {
+                if seq_ids.contains(&seq.seq_id) {
+                    finished.insert(seq.seq_id, seq);
+                } else {
+                    self.finished.push(seq);
+                }
+            }
+        }
+        seq_ids
+            .iter()
+            .filter_map(|seq_id| finished.get(seq_id))
+            .map(|seq| {
+                GenerationResponse::from_sequence(seq, tokenizer).map_err(|e| EngineError::Tokenizer(e.to_string()))
+            })
+            .collect()
+    }
+
+    /// Scores a completion of a prompt with teacher forcing
+    ///
+    /// The prompt and completion are run as a single prefill, as done for
+    /// `SamplingParams.prompt_logprobs`, and the log-probabilities of the
+    /// completion tokens given everything before them are summed. Nothing
+    /// is sampled and the KV cache block pool is untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `prompt_ids` - The prompt tokens
+    /// * `completion_ids` - The completion tokens to score
+    ///
+    /// # Returns
+    ///
+    /// The total log-probability of the completion under the model
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::InvalidRequest` if the prompt or completion is
+    /// empty or a token ID is outside the vocabulary,
+    /// `EngineError::SequenceTooLong` if both exceed `max_model_len`
+    /// together, or `EngineError::Candle` if the model fails.
+    pub fn score(&mut self, prompt_ids: &[u32], completion_ids: &[u32]) -> Result<f32, EngineError> {
+        if prompt_ids.is_empty() || completion_ids.is_empty() {
+            return Err(EngineError::InvalidRequest(
+                "scoring needs a non-empty prompt and completion".to_string(),
+            ));
+        }
+        let token_ids = [prompt_ids, completion_ids].concat();
+        let max_model_len = self.config.max_model_len;
+        if token_ids.len() > max_model_len {
+            return Err(EngineError::SequenceTooLong { len: token_ids.len(), max: max_model_len });
+        }
+        self.check_vocabulary(&token_ids)?;
+
+        let params = SamplingParams { prompt_logprobs: true, ..SamplingParams::greedy(1) };
+        let mut seqs = [Sequence::with_block_size(token_ids, params, self.config.kvcache_block_size)];
+        self.model_runner.run(&mut seqs, true)?;
+        self.model_runner.release(seqs[0].seq_id);
+        // Safe to unwrap since prompt_logprobs was requested and the prefill filled them.
+        let logprobs = seqs[0].prompt_logprobs.as_ref().unwrap();
+        Ok(logprobs[prompt_ids.len()..].iter().flatten().sum())
+    }
+
+    /// Decodes a prompt with beam search
+    ///
+    /// `max(best_of, n)` beams are kept. At every step each beam is expanded
+    /// by its most likely next tokens, the candidates are ranked by
+    /// `cumulative_logprob`, and the best ones become the new beams;
+    /// candidates ending in EOS or reaching `max_tokens` are set aside as
+    /// finished. Once as many beams have finished as are kept, the `n` with
+    /// the best `Sequence::beam_score` under `params.length_penalty` are
+    /// returned. Like `score`, the search bypasses the scheduler and
+    /// recomputes every beam in full at each step, so the KV cache block
+    /// pool is left untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `prompt` - The prompt tokens
+    /// * `params` - Beam search parameters; sampling fields are ignored
+    ///
+    /// # Returns
+    ///
+    /// The `n` best finished beams, best first
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::InvalidRequest` if the prompt is empty, `n` is
+    /// zero, or a token ID is outside the vocabulary,
+    /// `EngineError::SequenceTooLong` if the prompt leaves no room for any
+    /// completion token, or `EngineError::Candle` if the model fails.
+    pub fn beam_search(&mut self, prompt: Vec<u32>, params: SamplingParams) -> Result<Vec<Sequence>, EngineError> {
+        if prompt.is_empty() {
+            return Err(EngineError::InvalidRequest("prompt must not be empty".to_string()));
+        }
+        self.check_request(&prompt, &params)?;
+
+        let (n, width, length_penalty) = (params.n, params.best_of.max(params.n), params.length_penalty);
+        let eos = self.config.eos_token_id;
+        let mut root = Sequence::with_block_size(prompt, params, self.config.kvcache_block_size);
+        root.max_tokens = root.max_tokens.min(self.config.max_model_len - root.len());
+
+        let mut beams = vec![root];
+        let mut finished = Vec::new();
+        while !beams.is_empty() && finished.len() < width {
+            let logprobs = self.model_runner.next_token_logprobs(&mut beams)?.to_vec2::<f32>()?;
+            let mut candidates = Vec::with_capacity(beams.len() * width);
+            for (beam, row) in logprobs.iter().enumerate() {
+                let by_logprob = |a: &u32, b: &u32| row[*b as usize].total_cmp(&row[*a as usize]).then(a.cmp(b));
+                let mut token_ids: Vec<u32> = (0..row.len() as u32).collect();
+                if width < token_ids.len() {
+                    token_ids.select_nth_unstable_by(width - 1, by_logprob);
+                    token_ids.truncate(width);
+                }
+                token_ids.sort_by(by_logprob);
+                candidates.extend(token_ids.into_iter().map(|token_id| (beam, token_id, row[token_id as usize])));
+            }
+            // The sort is stable, so ties keep beam and token order.
+            let total = |&(beam, _, logprob): &(usize, u32, f32)| beams[beam].cumulative_logprob + logprob;
+            candidates.sort_by(|a, b| total(b).total_cmp(&total(a)));
+
+            let mut next = Vec::with_capacity(width);
+            for (beam, token_id, logprob) in candidates {
+                if next.len() == width {
+                    break;
+                }
+                let mut candidate = beams[beam].fork();
+                if candidate.append_token_with_logprob(token_id, logprob, eos) {
+                    finished.push(candidate);
+                } else {
+                    next.push(candidate);
+                }
+            }
+            beams = next;
+        }
+        finished.sort_by(|a, b| b.beam_score(length_penalty).total_cmp(&a.beam_score(length_penalty)));
+        finished.truncate(n);
+        Ok(finished)
+    }
+
+    /// Runs a dummy prefill and decode at the maximum batch shape
+    ///
+    /// The first real step is otherwise slowed down by lazily allocated
+    /// buffers. As many sequences of `max_model_len` tokens as fit the batch
+    /// limits and the free KV cache blocks are allocated through the block
+    /// manager, prefilled, and decoded for one token, so both passes write
+    /// the KV cache. Their blocks are freed afterwards and kept out of the
+    /// prefix cache, though unreferenced cached blocks may be evicted to
+    /// make room. A cache too small for two tokens is not warmed up.
+    ///
+    /// # Errors
+    ///
+    /// Returns `EngineError::Candle` if the model fails on the dummy batch,
+    /// or `EngineError::Internal` if its blocks cannot be allocated.
+    pub fn warmup(&mut self) -> Result<(), EngineError> {
+        let block_size = self.config.kvcache_block_size;
+        let num_free_blocks = self.scheduler.block_manager().num_free_blocks();
+        // The decode token sits at position seq_len - 1, the last one the
+        // rotary tables cover.
+        let seq_len = self.config.max_model_len.min(num_free_blocks * block_size);
+        if seq_len < 2 {
+            return Ok(());
+        }
+        let num_seqs = (self.config.max_num_batched_tokens / seq_len)
+            .clamp(1, self.config.max_num_seqs.max(1))
+            .min(num_free_blocks / seq_len.div_ceil(block_size));
+
+        let vocab_size = self.model_runner.vocab_size();
+        let mut seqs = Vec::with_capacity(num_seqs);
+        for i in 0..num_seqs {
+            // Distinct prompts, so no dummy sequence is a prefix cache hit of another.
+            let prompt = vec![(i % vocab_size) as u32; seq_len - 1];
+            let mut seq = Sequence::with_block_size(prompt, SamplingParams::default(), block_size);
+            if let Err(e) = self.scheduler.block_manager_mut().allocate(&mut seq) {
+                self.release_warmup(&mut seqs);
+                return Err(EngineError::Internal(format!("{:#}", e)));
+            }
+            seqs.push(seq);
+        }
+        let result = self.run_warmup(&mut seqs);
+        self.release_warmup(&mut seqs);
+        result
+    }
+
+    /// Prefills and decodes the allocated warmup sequences
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `warmup`.
+    fn run_warmup(&mut self, seqs: &mut [Sequence]) -> Result<(), EngineError> {
+        let token_ids = self.model_runner.run(seqs, true)?;
+        for (seq, token_id) in seqs.iter_mut().zip(token_ids) {
+            seq.num_cached_tokens = seq.len();
+            seq.append_token(token_id);
+            self.scheduler
+                .block_manager_mut()
+                .append_slot(seq)
+                .map_err(|e| EngineError::Internal(format!("{:#}", e)))?;
+        }
+        self.model_runner.run(seqs, false)?;
+        Ok(())
+    }
+
+    /// Frees the blocks and sampler state of the warmup sequences
+    fn release_warmup(&mut self, seqs: &mut [Sequence]) {
+        for seq in seqs {
+            // Marking nothing as computed keeps the dummy blocks out of the prefix cache.
+            seq.num_cached_tokens = 0;
+            self.scheduler.block_manager_mut().deallocate(seq);
+            self.model_runner.release(seq.seq_id);
+        }
+    }
+}
+
+/// Shortens a prompt to `max_len` tokens by dropping tokens from the left
+///
+/// The first `keep_prefix` tokens, typically a system prompt, are always
+/// kept, followed by as many of the most recent tokens as fit; the tokens
+/// in between are dropped. Prompts that already fit are returned unchanged.
+///
+/// # Arguments
+///
+/// * `ids` - The prompt tokens
+/// * `max_len` - Maximum number of tokens to keep
+/// * `keep_prefix` - Number of leading tokens that are never dropped; a
+///   prefix longer than `max_len` is itself cut to `max_len`
+///
+/// # Returns
+///
+/// The prompt with at most `max_len` tokens
+pub fn truncate_prompt_left(mut ids: Vec<u32>, max_len: usize, keep_prefix: usize) -> Vec<u32> {
+    if ids.len() <= max_len {
+        return ids;
+    }
+    let keep_prefix = keep_prefix.min(max_len);
+    let num_dropped = ids.len() - max_len;
+    ids.drain(keep_prefix..keep_prefix + num_dropped);
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Usage;
+    use crate::testing::{CachedStubModel, StubModel};
+    use std::sync::Mutex;
+    use common::config::PromptTruncation;
+
+    fn engine() -> LLMEngine<StubModel> {
+        let config = Config {
+            max_model_len: 512,
+            num_kvcache_blocks: Some(16),
+            ..Default::default()
+        };
+        let model = StubModel { vocab_size: 8, next_token: 3 };
+        LLMEngine::new(config, model, Device::Cpu).unwrap()
+    }
+
+    #[test]
+    fn generate_runs_until_max_tokens() {
+        let mut engine = engine();
+        let params = SamplingParams { temperature: 0.0, max_tokens: 4, ..Default::default() };
+        let outputs = engine.generate(vec![vec![1, 2], vec![5, 6, 7]], params).unwrap();
+        assert_eq!(outputs, vec![vec![3; 4], vec![3; 4]]);
+        assert!(engine.is_finished());
+    }
+
+    #[test]
+    fn generation_stops_when_every_sequence_hits_eos() {
+        let config = Config {
+            eos_token_id: Some(3),
+            num_kvcache_blocks: Some(16),
+            ..Default::default()
+        };
+        let model = StubModel { vocab_size: 8, next_token: 3 };
+        let mut engine = LLMEngine::new(config, model, Device::Cpu).unwrap();
+
+        let params = SamplingParams { temperature: 0.0, ..Default::default() };
+        let outputs = engine.generate(vec![vec![1, 2], vec![4]], params).unwrap();
+        assert_eq!(outputs, vec![vec![3], vec![3]]);
+        assert!(engine.is_finished());
+        assert!(engine.step().unwrap().is_empty());
+    }
+
+    #[test]
+    fn generate_detailed_reports_finish_reasons_and_logprobs() {
+        /// Model that predicts the successor of each input token
+        struct Successor;
+
+        impl CausalLM for Successor {
+            fn forward(&mut self, input_ids: &candle_core::Tensor, _positions: &candle_core::Tensor) -> candle_core::Result<candle_core::Tensor> {
+                let rows: Vec<Vec<f32>> = input_ids
+                    .to_vec1::<u32>()?
+                    .into_iter()
+                    .map(|token_id| {
+                        let mut row = vec![0f32; 8];
+                        row[(token_id as usize + 1) % 8] = 10.0;
+                        row
+                    })
+                    .collect();
+                candle_core::Tensor::new(rows, input_ids.device())
+            }
+
+            fn vocab_size(&self) -> usize {
+                8
+            }
+        }
+
+        let config = Config { eos_token_id: Some(3), num_kvcache_blocks: Some(16), ..Default::default() };
+        let mut engine = LLMEngine::new(config, Successor, Device::Cpu).unwrap();
+        let outputs = engine.generate_detailed(vec![vec![1], vec![5]], SamplingParams::greedy(4)).unwrap();
+        assert_eq!(outputs[0].token_ids, vec![2, 3]);
+        assert_eq!(outputs[0].finish_reason, Some(FinishReason::Stop));
+        assert_eq!(outputs[1].token_ids, vec![6, 7, 0, 1]);
+        assert_eq!(outputs[1].finish_reason, Some(FinishReason::Length));
+        assert_eq!(outputs[1].usage, Usage { prompt_tokens: 1, completion_tokens: 4, total_tokens: 5 });
+        let logprob = 10.0 - (10f32.exp() + 7.0).ln();
+        assert!((outputs[0].cumulative_logprob - 2.0 * logprob).abs() < 1e-5);
+        assert!((outputs[1].cumulative_logprob - 4.0 * logprob).abs() < 1e-5);
+    }
+
+    #[test]
+    fn invalid_prompt_leaves_no_earlier_prompt_queued() {
+        let mut engine = engine();
+        let err = engine.generate(vec![vec![1, 2], vec![99]], SamplingParams::greedy(4)).unwrap_err();
+        assert!(matches!(err, EngineError::InvalidRequest(_)));
+        assert!(engine.is_finished());
+        assert_eq!(engine.scheduler().block_manager().num_free_blocks(), 16);
+    }
+
+    #[test]
+    fn repeated_block_aligned_prompt_is_served_from_the_prefix_cache() {
+        let config = Config { kvcache_block_size: 2, num_kvcache_blocks: Some(16), ..Default::default() };
+        let model = StubModel { vocab_size: 8, next_token: 3 };
+        let mut engine = LLMEngine::new(config, model, Device::Cpu).unwrap();
+        let params = SamplingParams { n: 2, ..SamplingParams::greedy(2) };
+        for _ in 0..2 {
+            let outputs = engine.generate(vec![vec![1, 2, 4, 5]], params.clone()).unwrap();
+            assert_eq!(outputs, vec![vec![3, 3], vec![3, 3]]);
+        }
+    }
+
+    #[test]
+    fn max_tokens_is_capped_by_remaining_context() {
+        let mut engine = engine();
+        let params = SamplingParams { temperature: 0.0, max_tokens: 1000, ..Default::default() };
+        let outputs = engine.generate(vec![vec![1; 500]], params).unwrap();
+        assert_eq!(outputs[0].len(), 12);
+    }
+
+    #[test]
+    fn prompt_filling_context_is_rejected() {
+        let mut engine = engine();
+        let err = engine.add_request(vec![1; 512], SamplingParams::default()).unwrap_err();
+        assert!(matches!(err, EngineError::SequenceTooLong { len: 512, max: 511 }));
+    }
+
+    #[test]
+    fn out_of_vocabulary_prompt_is_rejected() {
+        let mut engine = engine();
+        let err = engine.add_request(vec![1, 2, 8], SamplingParams::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid request: token id 8 at position 2 is outside the vocabulary of 8 tokens"
+        );
+    }
+
+    #[test]
+    fn queue_stats_track_sequences_moving_to_running() {
+        let mut engine = engine();
+        let ids: Vec<usize> = (0..3)
+            .map(|i| engine.add_request(vec![1, 2 + i], SamplingParams::greedy(4)).unwrap()[0])
+            .collect();
+        assert_eq!(engine.queue_stats(), QueueStats { waiting: 3, running: 0, swapped: 0 });
+        assert!(engine.running_seq_ids().is_empty());
+
+        engine.step().unwrap();
+        assert_eq!(engine.queue_stats(), QueueStats { waiting: 0, running: 3, swapped: 0 });
+        assert_eq!(engine.running_seq_ids(), ids);
+    }
+
+    #[test]
+    fn drain_finished_collects_completed_sequences_once() {
+        let mut engine = engine();
+        let ids = engine.add_request(vec![1, 2], SamplingParams { n: 2, ..SamplingParams::greedy(3) }).unwrap();
+        while !engine.is_finished() {
+            engine.step().unwrap();
+        }
+
+        let drained = engine.drain_finished();
+        assert_eq!(drained.iter().map(|seq| seq.seq_id).collect::<Vec<_>>(), ids);
+        assert!(drained.iter().all(|seq| seq.is_finished() && seq.completion_token_ids() == [3, 3, 3]));
+        assert_eq!(engine.queue_stats(), QueueStats::default());
+        assert_eq!(engine.scheduler().block_manager().num_free_blocks(), 16);
+        assert!(engine.drain_finished().is_empty());
+
+        engine.generate(vec![vec![4]], SamplingParams::greedy(2)).unwrap();
+        assert!(engine.drain_finished().is_empty());
+    }
+
+    #[test]
+    fn multi_step_decodes_several_tokens_per_call() {
+        let config = Config {
+            max_model_len: 512,
+            num_kvcache_blocks: Some(16),
+            num_scheduler_steps: 4,
+            ..Default::default()
+        };
+        let model = StubModel { vocab_size: 8, next_token: 3 };
+        let mut engine = LLMEngine::new(config, model, Device::Cpu).unwrap();
+        let ids = [
+            engine.add_request(vec![1, 2], SamplingParams::greedy(100)).unwrap()[0],
+            engine.add_request(vec![4], SamplingParams::greedy(6)).unwrap()[0],
+        ];
+
+        // The prefill runs a single step.
+        assert_eq!(engine.multi_step_sampled().unwrap().sampled.len(), 2);
+
+        let output = engine.multi_step_sampled().unwrap();
+        for seq_id in ids {
+            assert_eq!(output.sampled.iter().filter(|&&(id, _)| id == seq_id).count(), 4);
+        }
+        assert!(output.finished.is_empty());
+
+        // The second sequence reaches its 6 tokens after one more step.
+        let output = engine.multi_step_sampled().unwrap();
+        assert_eq!(output.sampled.len(), 2);
+        assert_eq!(output.finished.len(), 1);
+        assert_eq!(output.finished[0].completion_token_ids(), &[3; 6]);
+        assert_eq!(engine.queue_stats(), QueueStats { waiting: 0, running: 1, swapped: 0 });
+    }
+
+    #[test]
+    fn score_sums_completion_logprobs() {
+        let mut engine = engine();
+        // The stub puts a logit of 10 on token 3 and 0 on the other 7 tokens.
+        let log_norm = (10f32.exp() + 7.0).ln();
+        let score = engine.score(&[1, 2], &[3, 5, 3]).unwrap();
+        let expected = 2.0 * (10.0 - log_norm) - log_norm;
+        assert!((score - expected).abs() < 1e-4, "{} != {}", score, expected);
+
+        assert!(engine.score(&[], &[3]).is_err());
+        assert!(engine.score(&[1], &[9]).is_err());
+        assert_eq!(engine.scheduler().block_manager().num_free_blocks(), 16);
+    }
+
+    #[test]
+    fn cancelled_request_finishes_as_aborted() {
+        let mut engine = engine();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let seq_id = engine
+            .add_request_cancellable(vec![1, 2], SamplingParams::greedy(100), cancelled.clone())
+            .unwrap()[0];
+        let other = engine.add_request(vec![4], SamplingParams::greedy(3)).unwrap()[0];
+        assert!(engine.step_sampled().unwrap().finished.is_empty());
+
+        cancelled.store(true, Ordering::Relaxed);
+        let output = engine.step_sampled().unwrap();
+        assert_eq!(output.finished.len(), 1);
+        assert_eq!(output.finished[0].seq_id, seq_id);
+        assert_eq!(output.finished[0].finish_reason, Some(FinishReason::Aborted));
+        assert!(output.sampled.iter().all(|&(id, _)| id == other));
+        assert_eq!(output.finished[0].completion_token_ids(), &[3]);
+
+        while !engine.is_finished() {
+            engine.step().unwrap();
+        }
+        let block_manager = engine.scheduler().block_manager();
+        assert_eq!(block_manager.num_free_blocks(), block_manager.num_blocks());
+    }
+
+    #[test]
+    fn stop_fn_finishes_the_sequence_as_custom() {
+        let mut engine = engine();
+        let stop_fn: StopFn = Arc::new(|seq: &Sequence| seq.num_completion_tokens() == 3);
+        let seq_id = engine
+            .add_request_with_stop_fn(vec![1, 2], SamplingParams::greedy(100), stop_fn)
+            .unwrap()[0];
+        while !engine.is_finished() {
+            engine.step().unwrap();
+        }
+        let finished = engine.drain_finished();
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].seq_id, seq_id);
+        assert_eq!(finished[0].finish_reason, Some(FinishReason::Custom));
+        assert_eq!(finished[0].num_completion_tokens(), 3);
+        let block_manager = engine.scheduler().block_manager();
+        assert_eq!(block_manager.num_free_blocks(), block_manager.num_blocks());
+    }
+
+    #[test]
+    fn token_budget_halts_generation_across_the_batch() {
+        let mut engine = engine();
+        engine.set_token_budget(Some(6));
+        engine.add_request(vec![1, 2], SamplingParams::greedy(100)).unwrap();
+        engine.add_request(vec![4], SamplingParams::greedy(100)).unwrap();
+        while !engine.is_finished() {
+            engine.step().unwrap();
+        }
+        assert_eq!(engine.num_generated_tokens(), 6);
+        let finished = engine.drain_finished();
+        assert_eq!(finished.len(), 2);
+        for seq in &finished {
+            assert_eq!(seq.finish_reason, Some(FinishReason::Length));
+            assert_eq!(seq.completion_token_ids(), &[3, 3, 3]);
+        }
+        let block_manager = engine.scheduler().block_manager();
+        assert_eq!(block_manager.num_free_blocks(), block_manager.num_blocks());
+    }
+
+    #[test]
+    fn text_request_round_trips_to_a_response() {
+        /// Tokenizer mapping each lowercase letter to its index in the alphabet
+        struct Letters;
+
+        impl common::detokenizer::Detokenizer for Letters {
+            fn decode(&self, token_ids: &[u32]) -> anyhow::Result<String> {
+                Ok(token_ids.iter().map(|&id| (b'a' + id as u8) as char).collect())
+            }
+        }
+
+        impl Tokenizer for Letters {
+            fn encode(&self, text: &str, _add_special: bool) -> anyhow::Result<Vec<u32>> {
+                Ok(text.bytes().map(|b| (b - b'a') as u32).collect())
+            }
+        }
+
+        let mut engine = engine();
+        let request = GenerationRequest {
+            prompt: PromptInput::Text("bc".to_string()),
+            params: SamplingParams::greedy(2),
+        };
+        let responses = engine.handle_request(request.clone(), Some(&Letters)).unwrap();
+        assert_eq!(responses.len(), 1);
+        let response = &responses[0];
+        assert_eq!(response.tokens, vec![3, 3]);
+        assert_eq!(response.text.as_deref(), Some("dd"));
+        assert_eq!(response.finish_reason, Some(FinishReason::Length));
+        assert_eq!(response.usage, Usage { prompt_tokens: 2, completion_tokens: 2, total_tokens: 4 });
+
+        let err = engine.handle_request(request, None).unwrap_err();
+        assert!(matches!(err, EngineError::InvalidRequest(_)));
+    }
+
+    #[test]
+    fn background_requests_finished_by_other_calls_are_kept() {
+        let mut engine = engine();
+        let background = engine.add_request(vec![1], SamplingParams::greedy(1)).unwrap();
+        let request = GenerationRequest {
+            prompt: PromptInput::Tokens(vec![2, 3]),
+            params: SamplingParams::greedy(3),
+        };
+        let responses = engine.handle_request(request, None).unwrap();
+        assert_eq!(responses.len(), 1);
+        let finished = engine.drain_finished();
+        assert_eq!(finished.iter().map(|seq| seq.seq_id).collect::<Vec<_>>(), background);
+
+        let background = engine.add_request(vec![4], SamplingParams::greedy(1)).unwrap();
+        let outputs = engine.generate_detailed(vec![vec![5]], SamplingParams::greedy(2)).unwrap();
+        assert_eq!(outputs.len(), 1);
+        let finished = engine.drain_finished();
+        assert_eq!(finished.iter().map(|seq| seq.seq_id).collect::<Vec<_>>(), background);
+        assert_eq!(finished[0].completion_token_ids(), &[3]);
+    }
+
+    #[test]
+    fn added_turn_continues_a_finished_conversation() {
+        let mut engine = engine();
+        engine.add_request(vec![1, 2], SamplingParams::greedy(2)).unwrap();
+        while !engine.is_finished() {
+            engine.step().unwrap();
+        }
+        let seq = engine.drain_finished().pop().unwrap();
+        assert!(matches!(engine.add_turn(seq.clone(), Vec::new()), Err(EngineError::InvalidRequest(_))));
+
+        let seq_id = engine.add_turn(seq, vec![5, 6]).unwrap();
+        while !engine.is_finished() {
+            engine.step().unwrap();
+        }
+        let seq = engine.drain_finished().pop().unwrap();
+        assert_eq!(seq.seq_id, seq_id);
+        assert_eq!(seq.num_turns(), 2);
+        assert_eq!(seq.turn(0), &[1, 2, 3, 3]);
+        assert_eq!(seq.turn(1), &[5, 6, 3, 3]);
+        assert_eq!(seq.completion_token_ids(), &[3, 3]);
+        assert_eq!(engine.scheduler().block_manager().num_free_blocks(), 16);
+    }
+
+    #[test]
+    fn kvcache_block_size_is_validated_at_construction() {
+        let config = |kvcache_block_size| Config { num_kvcache_blocks: Some(4), kvcache_block_size, ..Default::default() };
+        let model = StubModel { vocab_size: 8, next_token: 3 };
+        let err = LLMEngine::new(config(0), model, Device::Cpu).err().unwrap();
+        assert!(matches!(&err, EngineError::Config(msg) if msg.contains("kvcache_block_size")), "{}", err);
+
+        // Sequences follow the configured size, so non-default sizes are fine.
+        let model = StubModel { vocab_size: 8, next_token: 3 };
+        let mut engine = LLMEngine::new(config(128), model, Device::Cpu).unwrap();
+        let params = SamplingParams { temperature: 0.0, max_tokens: 2, ..Default::default() };
+        engine.add_request(vec![1; 130], params).unwrap();
+        while !engine.is_finished() {
+            engine.step().unwrap();
+        }
+        let finished = engine.drain_finished();
+        assert_eq!((finished[0].block_size, finished[0].completion_token_ids()), (128, &[3, 3][..]));
+    }
+
+    #[test]
+    fn swapped_sequence_resumes_with_its_kv_blocks() {
+        let block_size = 4;
+        let stub = || StubModel { vocab_size: 8, next_token: 3 };
+        let config = Config { kvcache_block_size: block_size, num_kvcache_blocks: Some(3), ..Default::default() };
+        let err = LLMEngine::new(Config { num_cpu_blocks: Some(4), ..config.clone() }, stub(), Device::Cpu).err().unwrap();
+        assert!(matches!(&err, EngineError::Config(msg) if msg.contains("CausalLM::kv_cache")), "{}", err);
+
+        // Every slot holds the number of its block, plus one.
+        let values: Vec<f32> = (0..3 * block_size).map(|slot| (slot / block_size + 1) as f32).collect();
+        let gpu = candle_core::Tensor::from_vec(values, (3 * block_size, 1, 1), &Device::Cpu).unwrap();
+        let model = CachedStubModel { stub: stub(), kv_cache: KvCache::new(vec![(gpu.clone(), gpu.clone())]) };
+        let json = r#"{
+            "architectures": ["Qwen2ForCausalLM"],
+            "vocab_size": 8, "hidden_size": 64, "intermediate_size": 128,
+            "num_hidden_layers": 2, "num_attention_heads": 4, "num_key_value_heads": 2,
+            "max_position_embeddings": 4096, "sliding_window": 4096, "max_window_layers": 2,
+            "tie_word_embeddings": true, "rope_theta": 1000000.0, "rms_norm_eps": 1e-6,
+            "use_sliding_window": false, "hidden_act": "silu"
+        }"#;
+        let mut config = Config { hf_config: Some(common::hf_config::HfConfig::from_json(json).unwrap()), ..config };
+        config.swap_space_bytes = 4 * config.kvcache_block_bytes().unwrap();
+        let mut engine = LLMEngine::new(config, model, Device::Cpu).unwrap();
+        assert_eq!(engine.config().num_cpu_blocks, Some(4));
+
+        engine.add_request(vec![1, 2, 3, 4], SamplingParams::greedy(6)).unwrap();
+        engine.add_request(vec![5, 6, 7, 5], SamplingParams::greedy(6)).unwrap();
+        engine.step().unwrap();
+        engine.step().unwrap();
+        assert_eq!(engine.queue_stats().swapped, 1);
+
+        let block_values = |cache: &candle_core::Tensor| -> Vec<f32> {
+            let values = cache.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+            values.chunks(block_size).map(|block| block[0]).collect()
+        };
+        // The preempted sequence's block was copied out before the batch ran.
+        let swap_space = engine.swap_space.as_ref().unwrap();
+        let swapped: Vec<f32> = block_values(swap_space.layer(0).0).into_iter().filter(|&v| v != 0.0).collect();
+        assert_eq!(swapped.len(), 1);
+
+        // Other sequences may overwrite the freed GPU blocks before the swap-in.
+        gpu.slice_set(&gpu.zeros_like().unwrap(), 0, 0).unwrap();
+        while !engine.is_finished() {
+            engine.step().unwrap();
+        }
+        assert_eq!(engine.queue_stats().swapped, 0);
+        assert!(block_values(&gpu).contains(&swapped[0]));
+    }
+
+    #[test]
+    fn defragment_moves_cached_blocks_with_their_kv() {
+        let block_size = 2;
+        let config = Config { kvcache_block_size: block_size, num_kvcache_blocks: Some(6), ..Default::default() };
+        let stub = || StubModel { vocab_size: 8, next_token: 3 };
+        assert!(matches!(LLMEngine::new(config.clone(), stub(), Device::Cpu).unwrap().defragment(), Err(EngineError::Config(_))));
+
+        // Every slot holds the number of its block, plus one.
+        let values: Vec<f32> = (0..6 * block_size).map(|slot| (slot / block_size + 1) as f32).collect();
+        let gpu = candle_core::Tensor::from_vec(values, (6 * block_size, 1, 1), &Device::Cpu).unwrap();
+        let model = CachedStubModel { stub: stub(), kv_cache: KvCache::new(vec![(gpu.clone(), gpu.clone())]) };
+        let config = Config { num_cpu_blocks: Some(0), ..config };
+        let mut engine = LLMEngine::new(config, model, Device::Cpu).unwrap();
+        // The computed first block stays cached at block 0, the second is freed.
+        engine.generate(vec![vec![1, 2, 4]], SamplingParams::greedy(1)).unwrap();
+        assert_eq!(engine.scheduler().block_manager().num_evictable_blocks(), 1);
+
+        // Block 0 moves to the last empty block, taking its KV along.
+        assert_eq!(engine.defragment().unwrap(), 1);
+        let slot_values = gpu.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(slot_values[5 * block_size..], [1.0, 1.0]);
+
+        // The relocated prefix is still served from the prefix cache, so
+        // releasing the hit leaves a single cached block.
+        engine.add_request(vec![1, 2, 5], SamplingParams::greedy(1)).unwrap();
+        engine.step().unwrap();
+        let finished = engine.drain_finished();
+        assert_eq!(finished[0].completion_token_ids(), &[3]);
+        assert_eq!(engine.scheduler().block_manager().num_evictable_blocks(), 1);
+    }
+
+    #[test]
+    fn exhausted_kv_cache_reports_out_of_memory() {
+        let config = Config {
+            num_kvcache_blocks: Some(1),
+            kvcache_block_size: 4,
+            ..Default::default()
+        };
+        let model = StubModel { vocab_size: 8, next_token: 3 };
+        let mut engine = LLMEngine::new(config, model, Device::Cpu).unwrap();
+
+        let err = engine.add_request(vec![1; 5], SamplingParams::default()).unwrap_err();
+        assert!(matches!(err, EngineError::OutOfMemory(_)));
+
+        // A sequence that fits at admission runs out of blocks while decoding.
+        let params = SamplingParams { temperature: 0.0, max_tokens: 8, ..Default::default() };
+        engine.add_request(vec![1; 4], params).unwrap();
+        let err = loop {
+            if let Err(err) = engine.step() {
+                break err;
+            }
+        };
+        assert!(matches!(err, EngineError::OutOfMemory(_)));
+    }
+
+    #[test]
+    fn usage_counts_the_returned_completion() {
+        let mut seq = Sequence::new(vec![1, 2, 10, 11], SamplingParams::default());
+        seq.generation_offset = 2;
+        seq.append_token(12);
+        assert_eq!(Usage::from_sequence(&seq), Usage { prompt_tokens: 2, completion_tokens: 3, total_tokens: 5 });
+    }
+
+    #[test]
+    fn logits_processors_steer_sampling() {
+        use layers::logits_processor::LogitBias;
+
+        let mut engine = engine();
+        engine.set_logits_processors(
+            LogitsProcessorChain::new().with(LogitBias::new([(5, 20.0)].into_iter().collect())),
+        );
+        let params = SamplingParams { temperature: 0.0, max_tokens: 2, ..Default::default() };
+        assert_eq!(engine.generate(vec![vec![1]], params).unwrap(), vec![vec![5, 5]]);
+    }
+
+    #[test]
+    fn suppressed_special_tokens_are_never_sampled() {
+        // The stub strongly prefers token 3; suppressing it leaves the rest.
+        let params = SamplingParams {
+            max_tokens: 200,
+            seed: Some(7),
+            suppress_special_tokens: Some(vec![3]),
+            ..Default::default()
+        };
+        let outputs = engine().generate(vec![vec![1, 2]], params).unwrap();
+        assert_eq!(outputs[0].len(), 200);
+        assert!(!outputs[0].contains(&3));
+    }
+
+    #[test]
+    fn seeded_samples_are_reproducible_and_distinct() {
+        let params = SamplingParams {
+            temperature: 10.0,
+            max_tokens: 8,
+            n: 4,
+            seed: Some(1234),
+            ..Default::default()
+        };
+        let first = engine().generate(vec![vec![1, 2]], params.clone()).unwrap();
+        let second = engine().generate(vec![vec![1, 2]], params).unwrap();
+
+        assert_eq!(first.len(), 4);
+        assert_eq!(first, second);
+        for i in 0..4 {
+            for j in i + 1..4 {
+                assert_ne!(first[i], first[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn engine_seed_reproduces_a_run() {
+        let run = |seed| {
+            let config = Config { num_kvcache_blocks: Some(16), seed, ..Default::default() };
+            let mut engine = LLMEngine::new(config, StubModel { vocab_size: 64, next_token: 3 }, Device::Cpu).unwrap();
+            // A high temperature flattens the stub's distribution so samples vary.
+            let params = SamplingParams { temperature: 10.0, max_tokens: 8, n: 2, ..Default::default() };
+            let mut outputs = engine.generate(vec![vec![1, 2], vec![4]], params.clone()).unwrap();
+            outputs.extend(engine.generate(vec![vec![1, 2]], params).unwrap());
+            outputs
+        };
+        let first = run(Some(7));
+        assert_eq!(first, run(Some(7)));
+        // Each request and each of its samples gets its own stream.
+        assert_ne!(first[0], first[1]);
+        assert_ne!(first[0], first[4]);
+        assert_ne!(first, run(Some(8)));
+    }
+
+    #[test]
+    fn rejected_request_does_not_shift_engine_seeds() {
+        let run = |reject_first| {
+            let config = Config { num_kvcache_blocks: Some(1), seed: Some(7), ..Default::default() };
+            let mut engine = LLMEngine::new(config, StubModel { vocab_size: 64, next_token: 3 }, Device::Cpu).unwrap();
+            let params = SamplingParams { temperature: 10.0, max_tokens: 8, ..Default::default() };
+            if reject_first {
+                let too_long = vec![1; engine.config.kvcache_block_size + 1];
+                let err = engine.add_request(too_long, params.clone()).unwrap_err();
+                assert!(matches!(err, EngineError::OutOfMemory(_)));
+            }
+            engine.generate(vec![vec![1, 2]], params).unwrap()
+        };
+        assert_eq!(run(true), run(false));
+    }
+
+    #[test]
+    fn left_truncation_keeps_the_prefix_and_recent_tokens() {
+        let prompt: Vec<u32> = (0..5000).collect();
+        let truncated = truncate_prompt_left(prompt.clone(), 4096, 50);
+        assert_eq!(truncated.len(), 4096);
+        assert_eq!(&truncated[..50], &prompt[..50]);
+        assert_eq!(&truncated[50..], &prompt[5000 - 4046..]);
+        assert!(!truncated.contains(&50) && !truncated.contains(&953));
+        assert_eq!(truncate_prompt_left(vec![1, 2, 3], 4, 2), vec![1, 2, 3]);
+
+        let config = Config {
+            max_model_len: 16,
+            num_kvcache_blocks: Some(16),
+            prompt_truncation: Some(PromptTruncation { keep_prefix: 2 }),
+            ..Default::default()
+        };
+        let mut engine = LLMEngine::new(config, StubModel { vocab_size: 64, next_token: 3 }, Device::Cpu).unwrap();
+        engine.add_request((0..40).collect(), SamplingParams::greedy(1)).unwrap();
+        while !engine.is_finished() {
+            engine.step().unwrap();
+        }
+        let seq = engine.drain_finished().remove(0);
+        assert_eq!(seq.prompt_token_ids(), &[0, 1, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39]);
+    }
+
+    #[test]
+    fn zero_samples_are_rejected() {
+        let params = SamplingParams { n: 0, ..Default::default() };
+        let err = engine().add_request(vec![1], params).unwrap_err();
+        assert!(matches!(err, EngineError::InvalidRequest(_)));
+    }
+
+    /// Model whose next-token logits depend only on the current token
+    ///
+    /// After token 1, token 2 is slightly more likely than token 4, but every
+    /// continuation of 2 is equally unlikely while 4 is almost always
+    /// followed by 5, so the best two-token path is 4, 5.
+    struct PathModel;
+
+    impl CausalLM for PathModel {
+        fn forward(&mut self, input_ids: &candle_core::Tensor, _positions: &candle_core::Tensor) -> candle_core::Result<candle_core::Tensor> {
+            let rows: Vec<Vec<f32>> = input_ids
+                .to_vec1::<u32>()?
+                .into_iter()
+                .map(|token_id| {
+                    let mut row = vec![0f32; 8];
+                    match token_id {
+                        1 => {
+                            row = vec![-10.; 8];
+                            row[2] = 2.0;
+                            row[4] = 1.6;
+                        }
+                        4 => row[5] = 10.0,
+                        _ => {}
+                    }
+                    row
+                })
+                .collect();
+            candle_core::Tensor::new(rows, input_ids.device())
+        }
+
+        fn vocab_size(&self) -> usize {
+            8
+        }
+    }
+
+    #[test]
+    fn beam_search_finds_the_most_likely_path() {
+        let config = Config { max_model_len: 64, num_kvcache_blocks: Some(4), ..Default::default() };
+        let mut engine = LLMEngine::new(config, PathModel, Device::Cpu).unwrap();
+        let greedy = engine.generate(vec![vec![1]], SamplingParams::greedy(2)).unwrap();
+        assert_eq!(greedy[0][0], 2);
+
+        let params = SamplingParams { use_beam_search: true, n: 2, max_tokens: 2, ..Default::default() };
+        let beams = engine.beam_search(vec![1], params.clone()).unwrap();
+        assert_eq!(beams.len(), 2);
+        assert_eq!(beams[0].completion_token_ids(), &[4, 5]);
+        assert_eq!(beams[1].completion_token_ids()[0], 2);
+        assert!(beams[0].cumulative_logprob > beams[1].cumulative_logprob);
+        assert_eq!(engine.generate(vec![vec![1]], params.clone()).unwrap()[0], vec![4, 5]);
+
+        assert!(matches!(engine.add_request(vec![1], params), Err(EngineError::InvalidRequest(_))));
+        assert_eq!(engine.scheduler().block_manager().num_free_blocks(), 4);
+    }
+
+    #[test]
+    fn zero_partial_prefill_chunk_is_a_config_error() {
+        let config = Config {
+            max_num_partial_prefill_tokens: Some(0),
+            num_kvcache_blocks: Some(16),
+            ..Default::default()
+        };
+        let model = StubModel { vocab_size: 8, next_token: 3 };
+        let result = LLMEngine::new(config, model, Device::Cpu);
+        assert!(matches!(result, Err(EngineError::Config(_))));
+    }
+
+    #[test]
+    fn model_failure_finishes_the_batch_and_frees_its_blocks() {
+        struct FailsOnDecode {
+            num_forwards: usize,
+        }
+
+        impl CausalLM for FailsOnDecode {
+            fn forward(&mut self, input_ids: &candle_core::Tensor, positions: &candle_core::Tensor) -> candle_core::Result<candle_core::Tensor> {
+                self.num_forwards += 1;
+                if self.num_forwards > 1 {
+                    candle_core::bail!("device lost");
+                }
+                StubModel { vocab_size: 8, next_token: 3 }.forward(input_ids, positions)
+            }
+
+            fn vocab_size(&self) -> usize {
+                8
+            }
+        }
+
+        let config = Config { num_kvcache_blocks: Some(16), ..Default::default() };
+        let mut engine = LLMEngine::new(config, FailsOnDecode { num_forwards: 0 }, Device::Cpu).unwrap();
+        let seq_ids = engine.add_request(vec![1, 2], SamplingParams::greedy(4)).unwrap();
+        engine.step().unwrap();
+        assert!(engine.scheduler().block_manager().num_free_blocks() < 16);
+
+        assert!(matches!(engine.step(), Err(EngineError::Candle(_))));
+        assert_eq!(engine.scheduler().block_manager().num_free_blocks(), 16);
+        assert!(engine.is_finished());
+        let failed = engine.drain_finished();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(vec![failed[0].seq_id], seq_ids);
+        assert_eq!(failed[0].finish_reason, Some(FinishReason::Error));
+        assert_eq!(failed[0].completion_token_ids(), &[3]);
+    }
+
+    #[test]
+    fn warmup_runs_the_maximum_batch_shape() {
+        struct Recording {
+            num_tokens: Arc<Mutex<Vec<usize>>>,
+        }
+
+        impl CausalLM for Recording {
+            fn forward(&mut self, input_ids: &candle_core::Tensor, positions: &candle_core::Tensor) -> candle_core::Result<candle_core::Tensor> {
+                self.num_tokens.lock().unwrap().push(input_ids.dim(0)?);
+                StubModel { vocab_size: 8, next_token: 3 }.forward(input_ids, positions)
+            }
+
+            fn vocab_size(&self) -> usize {
+                8
+            }
+        }
+
+        let config = Config {
+            max_model_len: 512,
+            max_num_batched_tokens: 2048,
+            max_num_seqs: 16,
+            num_kvcache_blocks: Some(16),
+            ..Default::default()
+        };
+        let num_tokens = Arc::new(Mutex::new(Vec::new()));
+        let model = Recording { num_tokens: num_tokens.clone() };
+        let mut engine = LLMEngine::new(config, model, Device::Cpu).unwrap();
+        engine.warmup().unwrap();
+        // Four sequences reaching max_model_len tokens fill the batch token budget.
+        assert_eq!(*num_tokens.lock().unwrap(), vec![4 * 511, 4]);
+        let block_manager = engine.scheduler().block_manager();
+        assert_eq!((block_manager.num_free_blocks(), block_manager.num_evictable_blocks()), (16, 0));
+        assert!(engine.is_finished());
+    }
+}
@@ -0,0 +1,322 @@
+//! Execution of scheduled batches on the model
+//!
+//! This module prepares the model inputs for a scheduled batch, runs the
+//! forward pass, and samples the next token for every sequence.
+
+use crate::context_builder::{build_chunked_prefill_context, build_decode_context, build_input_ids};
+use cache::KvCache;
+use candle_core::{DType, Device, Result, Tensor};
+use common::sequence::Sequence;
+use layers::logits_processor::{LogitsProcessor, LogitsProcessorChain, SuppressSpecialTokens};
+use layers::sampler::{Sampler, log_softmax};
+use model::CausalLM;
+use utils::{Context, set_context};
+
+/// Runs a model over scheduled batches of sequences
+pub struct ModelRunner<M: CausalLM> {
+    /// The language model being served
+    model: M,
+
+    /// Processors applied to each sequence's logits before sampling
+    logits_processors: LogitsProcessorChain,
+
+    /// Sampler used to pick the next token from the logits
+    sampler: Sampler,
+
+    /// Device on which inputs are created
+    device: Device,
+}
+
+impl<M: CausalLM> ModelRunner<M> {
+    /// Creates a new ModelRunner for a model on the given device
+    ///
+    /// On the CPU, the BLAS backend candle was built with is logged, so a
+    /// missing `accelerate` feature is visible at startup.
+    pub fn new(model: M, device: Device) -> Self {
+        if device.is_cpu() {
+            log::info!("Running on the CPU with the {} BLAS backend", layers::cpu_backend());
+        }
+        Self {
+            model,
+            logits_processors: LogitsProcessorChain::new(),
+            sampler: Sampler::new(),
+            device,
+        }
+    }
+
+    /// Replaces the logits processors applied before sampling
+    pub fn set_logits_processors(&mut self, logits_processors: LogitsProcessorChain) {
+        self.logits_processors = logits_processors;
+    }
+
+    /// Returns the size of the model's vocabulary
+    pub fn vocab_size(&self) -> usize {
+        self.model.vocab_size()
+    }
+
+    /// Returns the model's paged KV cache, if it exposes one
+    pub fn kv_cache(&self) -> Option<&KvCache> {
+        self.model.kv_cache()
+    }
+
+    /// Runs one step for a batch and samples the next token of each sequence
+    ///
+    /// For prefill every uncached token of each sequence is processed; for
+    /// decode only the most recent token of each sequence is processed. In
+    /// both cases the logits of the last processed token of each sequence
+    /// are sampled, after suppressing each sequence's `suppress_special_tokens`
+    /// and applying the logits processors. During prefill, sequences that
+    /// requested prompt logprobs also have them recorded. The attention
+    /// context for the batch is installed with `set_context` before the
+    /// forward pass.
+    ///
+    /// # Arguments
+    ///
+    /// * `seqs` - The sequences in the batch
+    /// * `is_prefill` - Whether this is a prefill or a decode step
+    ///
+    /// # Returns
+    ///
+    /// The sampled token for each sequence, in batch order
+    pub fn run(&mut self, seqs: &mut [Sequence], is_prefill: bool) -> Result<Vec<u32>> {
+        let num_new_tokens: Vec<usize> = seqs
+            .iter()
+            .map(|seq| if is_prefill { seq.len() - seq.num_cached_tokens } else { 1 })
+            .collect();
+        self.run_chunked(seqs, &num_new_tokens, is_prefill)
+    }
+
+    /// Runs one step for a batch that may prefill partial prompts
+    ///
+    /// Like `run`, but during prefill only the next `num_new_tokens[i]`
+    /// uncached tokens of sequence `i` are processed. The token sampled for
+    /// a sequence whose prompt is not finished yet should be discarded.
+    /// Every other sampled token's log-probability under the processed
+    /// logits is added to its sequence's `cumulative_logprob`.
+    ///
+    /// # Arguments
+    ///
+    /// * `seqs` - The sequences in the batch
+    /// * `num_new_tokens` - Number of tokens processed for each sequence
+    /// * `is_prefill` - Whether this is a prefill or a decode step
+    ///
+    /// # Returns
+    ///
+    /// The sampled token for each sequence, in batch order
+    pub fn run_chunked(
+        &mut self,
+        seqs: &mut [Sequence],
+        num_new_tokens: &[usize],
+        is_prefill: bool,
+    ) -> Result<Vec<u32>> {
+        let logits = self.last_token_logits(seqs, num_new_tokens, is_prefill)?;
+        let token_ids = self.sampler.forward_sequences(&logits, &seqs.iter().collect::<Vec<_>>())?;
+        let sampled = Tensor::from_vec(token_ids.clone(), (token_ids.len(), 1), logits.device())?;
+        let logprobs = log_softmax(&logits)?.gather(&sampled, 1)?.squeeze(1)?.to_vec1::<f32>()?;
+        for ((seq, &num_new), logprob) in seqs.iter_mut().zip(num_new_tokens).zip(logprobs) {
+            // A partially prefilled prompt discards its sampled token.
+            if !is_prefill || seq.num_cached_tokens + num_new >= seq.len() {
+                seq.cumulative_logprob += logprob;
+            }
+        }
+        Ok(token_ids)
+    }
+
+    /// Prefills a batch and returns the log-probabilities of each next token
+    ///
+    /// Every uncached token of each sequence is processed, as in `run`, but
+    /// nothing is sampled: the processed logits of each sequence's last
+    /// token are normalized instead, so the caller can choose among the
+    /// candidates itself, e.g. for beam search.
+    ///
+    /// # Arguments
+    ///
+    /// * `seqs` - The sequences in the batch
+    ///
+    /// # Returns
+    ///
+    /// F32 log-probabilities of shape `[batch, vocab_size]`
+    pub fn next_token_logprobs(&mut self, seqs: &mut [Sequence]) -> Result<Tensor> {
+        let num_new_tokens: Vec<usize> = seqs.iter().map(|seq| seq.len() - seq.num_cached_tokens).collect();
+        log_softmax(&self.last_token_logits(seqs, &num_new_tokens, true)?)
+    }
+
+    /// Runs the forward pass and returns each sequence's processed logits
+    ///
+    /// The logits of the last processed token of each sequence are returned
+    /// after suppressing special tokens and applying the logits processors.
+    /// Prompt logprobs and raw logits are recorded on the sequences that
+    /// requested them.
+    fn last_token_logits(
+        &mut self,
+        seqs: &mut [Sequence],
+        num_new_tokens: &[usize],
+        is_prefill: bool,
+    ) -> Result<Tensor> {
+        let mut positions = Vec::new();
+        let mut first_indices = Vec::with_capacity(seqs.len());
+        let mut last_indices = Vec::with_capacity(seqs.len());
+        for (seq, &num_new) in seqs.iter().zip(num_new_tokens) {
+            let start = if is_prefill { seq.num_cached_tokens } else { seq.len() - 1 };
+            first_indices.push(positions.len());
+            positions.extend(start as u32..(start + num_new) as u32);
+            last_indices.push(positions.len() as u32 - 1);
+        }
+
+        let refs: Vec<&Sequence> = seqs.iter().collect();
+        let input_ids = build_input_ids(&refs, num_new_tokens, is_prefill, &self.device)?;
+        let context = if is_prefill {
+            build_chunked_prefill_context(&refs, num_new_tokens, &self.device)?
+        } else {
+            build_decode_context(&refs, &self.device)?
+        };
+        install_context(context);
+
+        let num_tokens = positions.len();
+        let positions = Tensor::from_vec(positions, num_tokens, &self.device)?;
+        let logits = self.model.forward(&input_ids, &positions)?;
+
+        if is_prefill {
+            let rows = first_indices.iter().zip(num_new_tokens);
+            for (seq, (&first_index, &num_new)) in seqs.iter_mut().zip(rows) {
+                record_prompt_logprobs(seq, &logits, first_index, num_new)?;
+            }
+        }
+
+        let last_indices = Tensor::from_vec(last_indices, seqs.len(), &self.device)?;
+        let mut logits = logits.index_select(&last_indices, 0)?;
+        for (i, seq) in seqs.iter_mut().enumerate() {
+            if seq.sampling_params.return_logits {
+                seq.final_logits = Some(logits.get(i)?.to_dtype(DType::F32)?.to_device(&Device::Cpu)?);
+            }
+        }
+        let has_suppressed_tokens = seqs.iter().any(|seq| seq.suppress_special_tokens.is_some());
+        if has_suppressed_tokens || !self.logits_processors.is_empty() {
+            let rows = seqs
+                .iter()
+                .enumerate()
+                .map(|(i, seq)| {
+                    let mut row = logits.get(i)?;
+                    SuppressSpecialTokens.process(&mut row, seq)?;
+                    self.logits_processors.process(&mut row, seq)?;
+                    Ok(row)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            logits = Tensor::stack(&rows, 0)?;
+        }
+        Ok(logits)
+    }
+
+    /// Releases the per-sequence sampling state of a finished sequence
+    pub fn release(&self, seq_id: usize) {
+        self.sampler.release(seq_id);
+    }
+}
+
+/// Makes a built context the global context read by the attention layers
+fn install_context(context: Context) {
+    set_context(
+        context.is_prefill,
+        context.cu_seqlens_q,
+        context.cu_seqlens_k,
+        context.max_seqlen_q,
+        context.max_seqlen_k,
+        context.slot_mapping,
+        context.context_lens,
+        context.block_tables,
+    );
+}
+
+/// Records the log-probability of each prompt token for a prefilled sequence
+///
+/// The logits at position `p` predict the token at position `p + 1`, so the
+/// prompt token at position `p + 1` is scored with the row of position `p`.
+/// Only positions processed in this step are scored, so a prompt prefilled
+/// in chunks is filled in over several steps; the first token and tokens
+/// served from the prefix cache are recorded as None. Sequences that did not
+/// request prompt logprobs are left untouched.
+///
+/// # Arguments
+///
+/// * `seq` - The sequence being prefilled
+/// * `logits` - Logits for the whole flattened batch
+/// * `first_index` - Row of `logits` holding the sequence's first processed token
+/// * `num_new_tokens` - Number of tokens of the sequence processed in this step
+fn record_prompt_logprobs(
+    seq: &mut Sequence,
+    logits: &Tensor,
+    first_index: usize,
+    num_new_tokens: usize,
+) -> Result<()> {
+    let num_prompt_tokens = seq.num_prompt_tokens;
+    let start = seq.num_cached_tokens;
+    // Positions start..end predict prompt tokens start + 1..end + 1.
+    let end = (start + num_new_tokens).min(num_prompt_tokens.saturating_sub(1));
+    let Some(logprobs) = seq.prompt_logprobs.as_mut() else {
+        return Ok(());
+    };
+    if logprobs.is_empty() {
+        *logprobs = vec![None; num_prompt_tokens];
+    }
+
+    let num_scored = end.saturating_sub(start);
+    if num_scored > 0 {
+        let targets: Vec<u32> = seq.token_ids[start + 1..end + 1].to_vec();
+        let targets = Tensor::from_vec(targets, (num_scored, 1), logits.device())?;
+        let scored = log_softmax(&logits.narrow(0, first_index, num_scored)?)?
+            .gather(&targets, 1)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        for (offset, logprob) in scored.into_iter().enumerate() {
+            logprobs[start + 1 + offset] = Some(logprob);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::StubModel;
+    use common::sampling::SamplingParams;
+
+    #[test]
+    fn prefill_records_prompt_logprobs() {
+        let model = StubModel { vocab_size: 8, next_token: 3 };
+        let mut runner = ModelRunner::new(model, Device::Cpu);
+        let params = SamplingParams { temperature: 0.0, prompt_logprobs: true, ..Default::default() };
+        let mut seqs = vec![
+            Sequence::new(vec![1, 3, 5], params),
+            Sequence::new(vec![2, 3], SamplingParams::default()),
+        ];
+
+        assert_eq!(runner.run(&mut seqs, true).unwrap()[0], 3);
+
+        // The stub puts a logit of 10 on token 3 and 0 on the other 7 tokens.
+        let log_norm = (10f32.exp() + 7.0).ln();
+        let logprobs = seqs[0].prompt_logprobs.as_ref().unwrap();
+        assert_eq!(logprobs.len(), 3);
+        assert_eq!(logprobs[0], None);
+        assert!((logprobs[1].unwrap() - (10.0 - log_norm)).abs() < 1e-5);
+        assert!((logprobs[2].unwrap() + log_norm).abs() < 1e-5);
+        assert!(seqs[1].prompt_logprobs.is_none());
+    }
+
+    #[test]
+    fn final_logits_are_kept_only_when_requested() {
+        let model = StubModel { vocab_size: 8, next_token: 3 };
+        let mut runner = ModelRunner::new(model, Device::Cpu);
+        let params = SamplingParams { return_logits: true, ..SamplingParams::greedy(4) };
+        let mut seqs = vec![
+            Sequence::new(vec![1, 3, 5], params),
+            Sequence::new(vec![2, 3], SamplingParams::default()),
+        ];
+
+        runner.run(&mut seqs, true).unwrap();
+        let logits = seqs[0].final_logits.as_ref().unwrap();
+        assert_eq!(logits.dims(), &[8]);
+        assert!(logits.device().is_cpu());
+        assert_eq!(logits.to_vec1::<f32>().unwrap()[3], 10.0);
+        assert!(seqs[1].final_logits.is_none());
+    }
+}
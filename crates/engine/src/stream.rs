@@ -0,0 +1,311 @@
+//! Streaming generation events
+//!
+//! This module provides `GenerationStream`, an iterator that drives an
+//! `LLMEngine` step by step and yields a `GenerationEvent` for every sampled
+//! token and every finished sequence. It is the natural interface for a
+//! server-sent-events endpoint.
+
+use crate::error::EngineError;
+use crate::llm_engine::LLMEngine;
+use common::detokenizer::{Detokenizer, IncrementalDetokenizer};
+use common::sequence::FinishReason;
+use model::CausalLM;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Event produced while generating
+#[derive(Debug, Clone, PartialEq)]
+pub enum GenerationEvent {
+    /// A sequence sampled a new completion token
+    Token {
+        /// ID of the sequence
+        seq_id: usize,
+
+        /// The sampled token
+        token_id: u32,
+
+        /// Text added to the completion by this token, when a detokenizer
+        /// is attached; empty while the token only completes part of a
+        /// character. The texts of a sequence's tokens concatenate to the
+        /// decode of its whole completion.
+        text: Option<String>,
+    },
+
+    /// A sequence finished; no further events are produced for it
+    Finished {
+        /// ID of the sequence
+        seq_id: usize,
+
+        /// Why the sequence finished
+        reason: FinishReason,
+    },
+}
+
+/// Iterator over the events of an engine's requests
+///
+/// Each call to `next` runs engine steps until an event is available, and
+/// the iterator ends once every request has finished. Created by
+/// `LLMEngine::stream`.
+pub struct GenerationStream<'a, M: CausalLM> {
+    /// Engine being driven
+    engine: &'a mut LLMEngine<M>,
+
+    /// Decodes completion tokens into text, if attached
+    detokenizer: Option<&'a dyn Detokenizer>,
+
+    /// Events produced by the last step and not yet yielded
+    pending: VecDeque<GenerationEvent>,
+
+    /// Detokenizer state of each unfinished sequence
+    completions: HashMap<usize, IncrementalDetokenizer>,
+}
+
+impl<'a, M: CausalLM> GenerationStream<'a, M> {
+    /// Creates a stream over the requests already added to `engine`
+    pub(crate) fn new(engine: &'a mut LLMEngine<M>, detokenizer: Option<&'a dyn Detokenizer>) -> Self {
+        Self {
+            engine,
+            detokenizer,
+            pending: VecDeque::new(),
+            completions: HashMap::new(),
+        }
+    }
+
+    /// Groups the stream's tokens into chunks of up to `size` per sequence
+    ///
+    /// Each sequence's tokens are buffered until `size` of them are
+    /// available and then yielded together, which reduces how often
+    /// consumers are called back. A partial chunk is yielded just before
+    /// the sequence's `Finished` event.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero
+    pub fn chunked(self, size: usize) -> ChunkedStream<'a, M> {
+        assert!(size > 0, "Chunk size must be greater than zero");
+        ChunkedStream {
+            inner: self,
+            size,
+            buffers: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns the text a new token adds to a sequence's completion
+    ///
+    /// Bytes of an incomplete character are held back for a later token,
+    /// unless this is the sequence's last token, in which case they are
+    /// flushed so that no text is lost.
+    fn decode_delta(&mut self, seq_id: usize, token_id: u32, is_last: bool) -> Result<Option<String>, EngineError> {
+        let Some(detokenizer) = self.detokenizer else {
+            return Ok(None);
+        };
+        let state = self.completions.entry(seq_id).or_default();
+        let mut piece = state
+            .push(detokenizer, token_id)
+            .map_err(|e| EngineError::Tokenizer(e.to_string()))?;
+        if is_last {
+            piece += &state.flush(detokenizer).map_err(|e| EngineError::Tokenizer(e.to_string()))?;
+        }
+        Ok(Some(piece))
+    }
+
+    /// Runs one engine step and queues its events
+    fn step(&mut self) -> Result<(), EngineError> {
+        let output = self.engine.step_sampled()?;
+        let finished: HashSet<usize> = output.finished.iter().map(|seq| seq.seq_id).collect();
+        for (seq_id, token_id) in output.sampled {
+            let text = self.decode_delta(seq_id, token_id, finished.contains(&seq_id))?;
+            self.pending.push_back(GenerationEvent::Token { seq_id, token_id, text });
+        }
+        for seq in output.finished {
+            self.completions.remove(&seq.seq_id);
+            let reason = seq.finish_reason.expect("finished sequences have a finish reason");
+            self.pending.push_back(GenerationEvent::Finished { seq_id: seq.seq_id, reason });
+        }
+        Ok(())
+    }
+}
+
+impl<M: CausalLM> Iterator for GenerationStream<'_, M> {
+    type Item = Result<GenerationEvent, EngineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // A step may only advance a partial prefill and produce no events.
+        while self.pending.is_empty() && !self.engine.is_finished() {
+            if let Err(err) = self.step() {
+                return Some(Err(err));
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+/// Event produced by a `ChunkedStream`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkedEvent {
+    /// Consecutive completion tokens of one sequence
+    Tokens {
+        /// ID of the sequence
+        seq_id: usize,
+
+        /// The sampled tokens, in order
+        token_ids: Vec<u32>,
+
+        /// Text added to the completion by these tokens, when a detokenizer
+        /// is attached
+        text: Option<String>,
+    },
+
+    /// A sequence finished; no further events are produced for it
+    Finished {
+        /// ID of the sequence
+        seq_id: usize,
+
+        /// Why the sequence finished
+        reason: FinishReason,
+    },
+}
+
+/// Iterator yielding a generation's tokens in fixed-size chunks
+///
+/// Created by `GenerationStream::chunked`.
+pub struct ChunkedStream<'a, M: CausalLM> {
+    /// Stream of single-token events being grouped
+    inner: GenerationStream<'a, M>,
+
+    /// Number of tokens in every chunk but a sequence's last
+    size: usize,
+
+    /// Tokens and text buffered for each sequence since its last chunk
+    buffers: HashMap<usize, (Vec<u32>, Option<String>)>,
+
+    /// Events ready to be yielded
+    pending: VecDeque<ChunkedEvent>,
+}
+
+impl<M: CausalLM> ChunkedStream<'_, M> {
+    /// Queues the tokens buffered for a sequence as one chunk, if any
+    fn flush(&mut self, seq_id: usize) {
+        if let Some((token_ids, text)) = self.buffers.remove(&seq_id) {
+            self.pending.push_back(ChunkedEvent::Tokens { seq_id, token_ids, text });
+        }
+    }
+}
+
+impl<M: CausalLM> Iterator for ChunkedStream<'_, M> {
+    type Item = Result<ChunkedEvent, EngineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            match self.inner.next()? {
+                Err(err) => return Some(Err(err)),
+                Ok(GenerationEvent::Token { seq_id, token_id, text }) => {
+                    let (token_ids, buffered_text) = self.buffers.entry(seq_id).or_default();
+                    token_ids.push(token_id);
+                    if let Some(text) = text {
+                        buffered_text.get_or_insert_with(String::new).push_str(&text);
+                    }
+                    if token_ids.len() == self.size {
+                        self.flush(seq_id);
+                    }
+                }
+                Ok(GenerationEvent::Finished { seq_id, reason }) => {
+                    self.flush(seq_id);
+                    self.pending.push_back(ChunkedEvent::Finished { seq_id, reason });
+                }
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::StubModel;
+    use candle_core::Device;
+    use common::config::Config;
+    use common::sampling::SamplingParams;
+
+    /// Detokenizer spelling token `i` as the `i`-th lowercase letter
+    struct Letters;
+
+    impl Detokenizer for Letters {
+        fn decode(&self, token_ids: &[u32]) -> anyhow::Result<String> {
+            Ok(token_ids.iter().map(|&id| (b'a' + id as u8) as char).collect())
+        }
+    }
+
+    #[test]
+    fn stream_ends_with_finished_event() {
+        let config = Config {
+            eos_token_id: Some(3),
+            num_kvcache_blocks: Some(16),
+            ..Default::default()
+        };
+        let model = StubModel { vocab_size: 8, next_token: 2 };
+        let mut engine = LLMEngine::new(config, model, Device::Cpu).unwrap();
+        let seq_id = engine.add_request(vec![1, 2], SamplingParams::greedy(2)).unwrap()[0];
+
+        let events: Vec<_> = engine.stream(Some(&Letters)).collect::<Result<_, _>>().unwrap();
+        let token = |text: &str| GenerationEvent::Token { seq_id, token_id: 2, text: Some(text.to_string()) };
+        assert_eq!(events, vec![
+            token("c"),
+            token("c"),
+            GenerationEvent::Finished { seq_id, reason: FinishReason::Length },
+        ]);
+    }
+
+    /// Detokenizer treating each token ID as one UTF-8 byte
+    struct Bytes;
+
+    impl Detokenizer for Bytes {
+        fn decode(&self, token_ids: &[u32]) -> anyhow::Result<String> {
+            let bytes: Vec<u8> = token_ids.iter().map(|&id| id as u8).collect();
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        }
+    }
+
+    #[test]
+    fn streamed_texts_concatenate_to_the_full_decode() {
+        let config = Config {
+            num_kvcache_blocks: Some(16),
+            ..Default::default()
+        };
+        // 0xC3 starts a two-byte character that is never completed.
+        let model = StubModel { vocab_size: 256, next_token: 0xC3 };
+        let mut engine = LLMEngine::new(config, model, Device::Cpu).unwrap();
+        engine.add_request(vec![1, 2], SamplingParams::greedy(3)).unwrap();
+
+        let mut tokens = Vec::new();
+        let mut texts = Vec::new();
+        for event in engine.stream(Some(&Bytes)) {
+            if let GenerationEvent::Token { token_id, text, .. } = event.unwrap() {
+                tokens.push(token_id);
+                texts.push(text.unwrap());
+            }
+        }
+        assert_eq!(texts[..2], ["", ""]);
+        assert_eq!(texts.concat(), Bytes.decode(&tokens).unwrap());
+    }
+
+    #[test]
+    fn chunked_stream_buffers_tokens_per_sequence() {
+        let config = Config {
+            num_kvcache_blocks: Some(16),
+            ..Default::default()
+        };
+        let model = StubModel { vocab_size: 8, next_token: 2 };
+        let mut engine = LLMEngine::new(config, model, Device::Cpu).unwrap();
+        let seq_id = engine.add_request(vec![1, 2], SamplingParams::greedy(10)).unwrap()[0];
+
+        let events: Vec<_> = engine.stream(Some(&Letters)).chunked(4).collect::<Result<_, _>>().unwrap();
+        let chunk = |len: usize| ChunkedEvent::Tokens { seq_id, token_ids: vec![2; len], text: Some("c".repeat(len)) };
+        assert_eq!(events, vec![
+            chunk(4),
+            chunk(4),
+            chunk(2),
+            ChunkedEvent::Finished { seq_id, reason: FinishReason::Length },
+        ]);
+    }
+}
@@ -0,0 +1,50 @@
+//! Test doubles shared by the engine's unit tests
+
+use cache::KvCache;
+use candle_core::{Result, Tensor};
+use model::CausalLM;
+
+/// Model that always predicts the same token
+///
+/// Every input position gets a logit of 10.0 for `next_token` and 0.0 for
+/// every other token.
+pub struct StubModel {
+    pub vocab_size: usize,
+    pub next_token: u32,
+}
+
+impl CausalLM for StubModel {
+    fn forward(&mut self, input_ids: &Tensor, _positions: &Tensor) -> Result<Tensor> {
+        let num_tokens = input_ids.dim(0)?;
+        let mut logits = vec![0f32; num_tokens * self.vocab_size];
+        for row in 0..num_tokens {
+            logits[row * self.vocab_size + self.next_token as usize] = 10.0;
+        }
+        Tensor::from_vec(logits, (num_tokens, self.vocab_size), input_ids.device())
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+}
+
+/// `StubModel` that exposes a KV cache only the engine writes to, by
+/// swapping or relocating blocks
+pub struct CachedStubModel {
+    pub stub: StubModel,
+    pub kv_cache: KvCache,
+}
+
+impl CausalLM for CachedStubModel {
+    fn forward(&mut self, input_ids: &Tensor, positions: &Tensor) -> Result<Tensor> {
+        self.stub.forward(input_ids, positions)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.stub.vocab_size()
+    }
+
+    fn kv_cache(&self) -> Option<&KvCache> {
+        Some(&self.kv_cache)
+    }
+}
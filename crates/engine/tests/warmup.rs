@@ -0,0 +1,88 @@
+//! Warmup against real attention and rotary embedding layers
+//!
+//! The model reads the global attention context, so this test lives in its
+//! own binary where no other test replaces that context mid-forward.
+
+use cache::KvCache;
+use candle_core::{DType, Device, Result, Tensor};
+use common::config::Config;
+use common::hf_config::HfConfig;
+use engine::LLMEngine;
+use layers::attention::Attention;
+use layers::rotary_embedding::RotaryEmbedding;
+use model::CausalLM;
+use utils::get_context_checked;
+
+const VOCAB_SIZE: usize = 8;
+
+/// Single attention layer whose keys and queries are constant and whose
+/// values are the token IDs plus one
+struct AttentionOnly {
+    attention: Attention,
+    rotary_embedding: RotaryEmbedding,
+    kv_cache: KvCache,
+}
+
+impl CausalLM for AttentionOnly {
+    fn forward(&mut self, input_ids: &Tensor, positions: &Tensor) -> Result<Tensor> {
+        let num_tokens = input_ids.dim(0)?;
+        let device = input_ids.device();
+        let q = Tensor::ones((num_tokens, 4, 16), DType::F32, device)?;
+        let k = Tensor::ones((num_tokens, 2, 16), DType::F32, device)?;
+        let v = (input_ids.to_dtype(DType::F32)? + 1.)?.reshape((num_tokens, 1, 1))?.broadcast_as((num_tokens, 2, 16))?;
+        let (q, k) = self.rotary_embedding.forward(positions, &q, &k)?;
+        let (k_cache, v_cache) = self.kv_cache.layer(0);
+        let output = self.attention.forward(&q, &k, &v.contiguous()?, Some((k_cache, v_cache)), &get_context_checked()?)?;
+        let hidden = output.flatten_from(1)?.sum_keepdim(1)?;
+        hidden.broadcast_as((num_tokens, VOCAB_SIZE))?.contiguous()
+    }
+
+    fn vocab_size(&self) -> usize {
+        VOCAB_SIZE
+    }
+
+    fn kv_cache(&self) -> Option<&KvCache> {
+        Some(&self.kv_cache)
+    }
+}
+
+#[test]
+fn warmup_prefills_and_decodes_through_the_kv_cache() {
+    let json = r#"{
+        "architectures": ["Qwen2ForCausalLM"],
+        "vocab_size": 8, "hidden_size": 64, "intermediate_size": 128,
+        "num_hidden_layers": 1, "num_attention_heads": 4, "num_key_value_heads": 2,
+        "max_position_embeddings": 4096, "sliding_window": 4096, "max_window_layers": 1,
+        "tie_word_embeddings": true, "rope_theta": 1000000.0, "rms_norm_eps": 1e-6,
+        "use_sliding_window": false, "hidden_act": "silu"
+    }"#;
+    let (block_size, num_blocks) = (16, 8);
+    let config = Config {
+        max_model_len: 64,
+        max_num_batched_tokens: 128,
+        max_num_seqs: 16,
+        kvcache_block_size: block_size,
+        num_kvcache_blocks: Some(num_blocks),
+        num_cpu_blocks: Some(0),
+        hf_config: Some(HfConfig::from_json(json).unwrap()),
+        ..Default::default()
+    };
+    let device = Device::Cpu;
+    let cache = || Tensor::zeros((num_blocks * block_size, 2, 16), DType::F32, &device).unwrap();
+    let (k_cache, v_cache) = (cache(), cache());
+    let model = AttentionOnly {
+        attention: Attention::from_config(&config).unwrap(),
+        rotary_embedding: RotaryEmbedding::from_config(&config, &device).unwrap(),
+        kv_cache: KvCache::new(vec![(k_cache, v_cache.clone())]),
+    };
+    let mut engine = LLMEngine::new(config, model, device).unwrap();
+    engine.warmup().unwrap();
+
+    // Two sequences of 64 tokens fill the token budget and every block.
+    let written = v_cache.flatten_from(1).unwrap().max_keepdim(1).unwrap().flatten_all().unwrap();
+    let num_written = written.to_vec1::<f32>().unwrap().iter().filter(|&&v| v > 0.).count();
+    assert_eq!(num_written, 2 * 64);
+    let block_manager = engine.scheduler().block_manager();
+    assert_eq!((block_manager.num_free_blocks(), block_manager.num_evictable_blocks()), (num_blocks, 0));
+    assert!(engine.is_finished());
+}
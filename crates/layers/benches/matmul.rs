@@ -0,0 +1,31 @@
+//! Benchmarks a CPU matmul the size of a Qwen2-0.5B MLP projection
+//!
+//! The benchmark ID names the BLAS backend candle was built with, so runs
+//! with and without the `accelerate` feature are reported side by side:
+//! `cargo bench -p layers --bench matmul` and
+//! `cargo bench -p layers --bench matmul --features accelerate`.
+
+use candle_core::{Device, Tensor};
+use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
+use layers::cpu_backend;
+
+/// Hidden size of Qwen2-0.5B
+const HIDDEN_SIZE: usize = 896;
+
+/// Intermediate size of Qwen2-0.5B
+const INTERMEDIATE_SIZE: usize = 4864;
+
+/// Number of tokens in the batch
+const NUM_TOKENS: usize = 64;
+
+fn bench_matmul(c: &mut Criterion) {
+    let x = Tensor::randn(0f32, 1., (NUM_TOKENS, HIDDEN_SIZE), &Device::Cpu).unwrap();
+    let w = Tensor::randn(0f32, 1., (HIDDEN_SIZE, INTERMEDIATE_SIZE), &Device::Cpu).unwrap();
+
+    let mut group = c.benchmark_group("matmul");
+    group.bench_function(BenchmarkId::new("up_proj", cpu_backend()), |b| b.iter(|| black_box(&x).matmul(&w).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, bench_matmul);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+//! Benchmarks top-k masking over a Qwen2-sized vocabulary
+//!
+//! Compares `apply_top_k`, which finds each row's threshold with a
+//! linear-time selection, against sorting the whole row. Run with
+//! `cargo bench -p layers --bench top_k`.
+
+use candle_core::{Device, Tensor};
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+use layers::sampler::apply_top_k;
+
+/// Vocabulary size of the Qwen2 models
+const VOCAB_SIZE: usize = 151_936;
+
+/// Number of sequences in the batch
+const BATCH: usize = 8;
+
+/// Number of candidates kept for each row
+const TOP_K: usize = 50;
+
+/// Masks every logit below the `k`-th largest by sorting each row
+fn full_sort_top_k(logits: &Tensor, k: usize) -> Tensor {
+    let mut rows = logits.to_vec2::<f32>().unwrap();
+    for row in &mut rows {
+        let mut sorted = row.clone();
+        sorted.sort_unstable_by(|a, b| b.total_cmp(a));
+        let threshold = sorted[k - 1];
+        for logit in row.iter_mut().filter(|logit| **logit < threshold) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+    Tensor::new(rows, logits.device()).unwrap()
+}
+
+fn bench_top_k(c: &mut Criterion) {
+    let logits = Tensor::randn(0f32, 1., (BATCH, VOCAB_SIZE), &Device::Cpu).unwrap();
+    let top_k = [TOP_K; BATCH];
+    assert_eq!(
+        apply_top_k(&logits, &top_k).unwrap().to_vec2::<f32>().unwrap(),
+        full_sort_top_k(&logits, TOP_K).to_vec2::<f32>().unwrap()
+    );
+
+    let mut group = c.benchmark_group("top_k");
+    group.bench_function("select_nth", |b| b.iter(|| apply_top_k(black_box(&logits), &top_k).unwrap()));
+    group.bench_function("full_sort", |b| b.iter(|| full_sort_top_k(black_box(&logits), TOP_K)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_top_k);
+criterion_main!(benches);
@@ -1,11 +1,8 @@
-/// Activation functions for neural network layers
-///
-/// This module provides implementations of various activation functions
-/// used in transformer-based language models, particularly those that
-/// involve specialized operations beyond standard activations.
-
-#[cfg(feature = "accelerate")]
-extern crate accelerate_src;
+//! Activation functions for neural network layers
+//!
+//! This module provides implementations of various activation functions
+//! used in transformer-based language models, particularly those that
+//! involve specialized operations beyond standard activations.
 
 use candle_core::{Result, Tensor};
 #[cfg(feature = "candle-nn")]
@@ -95,4 +92,130 @@ impl SiluAndMul {
         let y = &chunks[1];
         x.silu()?.mul(y)
     }
-}
\ No newline at end of file
+}
+
+/// Kind of gated activation used in a feed-forward network
+///
+/// All kinds split the input in half along the last dimension, apply the
+/// activation to the first half, and multiply by the second half.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivationKind {
+    /// SiLU gate (SwiGLU), used by LLaMA, Mistral, and Qwen2
+    SiluAndMul,
+
+    /// Exact (erf-based) GELU gate (GeGLU)
+    GeluAndMul,
+
+    /// Tanh-approximated GELU gate, used by Gemma
+    GeluTanhAndMul,
+}
+
+impl ActivationKind {
+    /// Selects the gated activation for a model's `hidden_act` config value
+    ///
+    /// # Arguments
+    ///
+    /// * `hidden_act` - The `hidden_act` string from the model's config.json
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the activation name is not supported
+    pub fn from_hidden_act(hidden_act: &str) -> Result<Self> {
+        match hidden_act {
+            "silu" | "swish" => Ok(Self::SiluAndMul),
+            "gelu" => Ok(Self::GeluAndMul),
+            "gelu_pytorch_tanh" | "gelu_new" | "gelu_fast" => Ok(Self::GeluTanhAndMul),
+            _ => candle_core::bail!("unsupported hidden_act {}", hidden_act),
+        }
+    }
+}
+
+/// Gated activation that dispatches on an `ActivationKind`
+///
+/// This lets a single MLP module serve architectures that differ only in
+/// their gating function, without boxing a trait object.
+#[derive(Debug, Clone, Copy)]
+pub struct GatedActivation {
+    kind: ActivationKind,
+}
+
+/// Creates the gated activation for the given kind
+///
+/// # Arguments
+///
+/// * `kind` - The gated activation to create
+///
+/// # Returns
+///
+/// A GatedActivation applying the requested gating function
+pub fn make_gated_activation(kind: ActivationKind) -> GatedActivation {
+    GatedActivation { kind }
+}
+
+impl GatedActivation {
+    /// Returns the kind of gated activation applied
+    pub fn kind(&self) -> ActivationKind {
+        self.kind
+    }
+
+    /// Applies the gated activation to the input tensor
+    ///
+    /// # Arguments
+    ///
+    /// * `x` - Input tensor that will be split into two parts along the last dimension.
+    ///   The tensor must have an even size in its last dimension.
+    ///
+    /// # Returns
+    ///
+    /// A tensor with half the size of the input in the last dimension, where each element
+    /// is act(x₁) * x₂ for the selected activation.
+    pub fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let last_dim = x.rank() - 1;
+        let chunks = x.chunk(2, last_dim)?;
+        if chunks.len() != 2 {
+            candle_core::bail!("expected 2 chunks, got {}", chunks.len());
+        }
+        let gate = match self.kind {
+            ActivationKind::SiluAndMul => chunks[0].silu()?,
+            ActivationKind::GeluAndMul => chunks[0].gelu_erf()?,
+            ActivationKind::GeluTanhAndMul => chunks[0].gelu()?,
+        };
+        gate.mul(&chunks[1])
+    }
+}
+
+#[cfg(feature = "candle-nn")]
+impl Module for GatedActivation {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        GatedActivation::forward(self, x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn gated_activation_dispatches_on_hidden_act() {
+        let x = Tensor::new(&[[0.5f32, -1.0, 2.0, 3.0]], &Device::Cpu).unwrap();
+        let a = Tensor::new(&[[0.5f32, -1.0]], &Device::Cpu).unwrap();
+        let b = Tensor::new(&[[2.0f32, 3.0]], &Device::Cpu).unwrap();
+
+        for (hidden_act, expected) in [
+            ("silu", a.silu().unwrap()),
+            ("gelu", a.gelu_erf().unwrap()),
+            ("gelu_pytorch_tanh", a.gelu().unwrap()),
+        ] {
+            let kind = ActivationKind::from_hidden_act(hidden_act).unwrap();
+            let out = make_gated_activation(kind).forward(&x).unwrap();
+            let expected = expected.mul(&b).unwrap();
+            assert_eq!(out.to_vec2::<f32>().unwrap(), expected.to_vec2::<f32>().unwrap());
+        }
+    }
+
+    #[test]
+    fn unknown_hidden_act_is_rejected() {
+        assert!(ActivationKind::from_hidden_act("relu2").is_err());
+    }
+}
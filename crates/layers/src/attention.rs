@@ -0,0 +1,507 @@
+//! Attention helpers
+//!
+//! This module collects the pieces of the attention computation that are
+//! shared across models and easy to get subtly wrong.
+
+use crate::sampler::stable_softmax;
+use candle_core::{DType, Device, Result, Tensor};
+use common::config::{AttentionBackend, Config};
+use utils::Context;
+
+/// Returns the factor applied to attention scores before the softmax
+///
+/// Most models scale `q @ k^T` by `1 / sqrt(head_dim)`; some override it
+/// with a custom value from their configuration.
+///
+/// # Arguments
+///
+/// * `head_dim` - Dimension of each attention head
+/// * `scale_override` - Model-specific scale that replaces the default, if any
+pub fn attention_scale(head_dim: usize, scale_override: Option<f64>) -> f64 {
+    scale_override.unwrap_or_else(|| 1.0 / (head_dim as f64).sqrt())
+}
+
+/// Builds the additive attention mask for one sequence
+///
+/// The queries are the last `seqlen_q` of the sequence's `seqlen_k` tokens,
+/// so the query in row `i` sits at position `seqlen_k - seqlen_q + i`. It may
+/// attend to every key at or before its own position and, with a sliding
+/// window of `W`, only to the `W` most recent of them (positions
+/// `pos - W + 1..=pos`).
+///
+/// # Arguments
+///
+/// * `seqlen_q` - Number of query tokens
+/// * `seqlen_k` - Number of key tokens, including the queries themselves
+/// * `sliding_window` - Attention window size, or None for full causal attention
+/// * `device` - Device on which the mask is created
+///
+/// # Returns
+///
+/// An F32 mask of shape `[seqlen_q, seqlen_k]` holding 0 for allowed and
+/// negative infinity for masked positions, to be added to the scores
+///
+/// # Errors
+///
+/// Returns an error if `seqlen_q > seqlen_k`, if the window is zero, or if
+/// the tensor cannot be created.
+pub fn causal_mask(
+    seqlen_q: usize,
+    seqlen_k: usize,
+    sliding_window: Option<usize>,
+    device: &Device,
+) -> Result<Tensor> {
+    if seqlen_q > seqlen_k {
+        candle_core::bail!("{} queries cannot attend over only {} keys", seqlen_q, seqlen_k);
+    }
+    if sliding_window == Some(0) {
+        candle_core::bail!("sliding window must be at least 1");
+    }
+    let offset = seqlen_k - seqlen_q;
+    let window = sliding_window.unwrap_or(usize::MAX);
+    let mask: Vec<f32> = (0..seqlen_q)
+        .flat_map(|i| {
+            let position = offset + i;
+            (0..seqlen_k).map(move |j| {
+                if j <= position && position - j < window { 0.0 } else { f32::NEG_INFINITY }
+            })
+        })
+        .collect();
+    Tensor::from_vec(mask, (seqlen_q, seqlen_k), device)
+}
+
+/// Writes new keys or values into their KV cache slots
+///
+/// Tokens whose slots are consecutive within one block, as in a prefill or
+/// a decode step filling the same block, are written with one contiguous
+/// copy per run. The remaining tokens, whose slots are isolated or cross a
+/// block boundary, are written with a single scatter. Tokens with a negative
+/// (padding) slot are skipped.
+///
+/// # Arguments
+///
+/// * `cache` - Contiguous cache of shape `[num_slots, num_kv_heads, head_dim]`,
+///   written in place
+/// * `new` - Keys or values of shape `[num_tokens, num_kv_heads, head_dim]`
+/// * `slot_mapping` - Cache slot of each token, `block_id * block_size + offset`
+/// * `block_size` - Number of slots in each block
+///
+/// # Errors
+///
+/// Returns an error if `slot_mapping` does not have one entry per token, or
+/// if a tensor operation fails, e.g. because a slot is out of range.
+pub fn write_kv_cache(cache: &Tensor, new: &Tensor, slot_mapping: &[i64], block_size: usize) -> Result<()> {
+    if slot_mapping.len() != new.dim(0)? {
+        candle_core::bail!("expected {} slots, got {}", new.dim(0)?, slot_mapping.len());
+    }
+    let mut scattered = Vec::new();
+    let mut start = 0;
+    while start < slot_mapping.len() {
+        let mut end = start + 1;
+        while end < slot_mapping.len()
+            && slot_mapping[start] >= 0
+            && slot_mapping[end] == slot_mapping[end - 1] + 1
+            && !(slot_mapping[end] as usize).is_multiple_of(block_size)
+        {
+            end += 1;
+        }
+        if end - start > 1 {
+            cache.slice_set(&new.narrow(0, start, end - start)?, 0, slot_mapping[start] as usize)?;
+        } else if slot_mapping[start] >= 0 {
+            scattered.push(start);
+        }
+        start = end;
+    }
+    if scattered.is_empty() {
+        return Ok(());
+    }
+    let slots: Vec<i64> = scattered.iter().map(|&i| slot_mapping[i]).collect();
+    let rows = Tensor::from_vec(scattered.iter().map(|&i| i as u32).collect(), scattered.len(), new.device())?;
+    write_kv_cache_scatter(cache, &new.index_select(&rows, 0)?, &slots)
+}
+
+/// Writes new keys or values into their KV cache slots one slot at a time
+///
+/// Reference implementation of `write_kv_cache` using only a scatter.
+///
+/// # Errors
+///
+/// Returns an error if `slot_mapping` does not have one entry per token, or
+/// if the scatter fails.
+pub fn write_kv_cache_scatter(cache: &Tensor, new: &Tensor, slot_mapping: &[i64]) -> Result<()> {
+    let (num_tokens, num_kv_heads, head_dim) = new.dims3()?;
+    if slot_mapping.len() != num_tokens {
+        candle_core::bail!("expected {} slots, got {}", num_tokens, slot_mapping.len());
+    }
+    let (rows, slots): (Vec<u32>, Vec<u32>) = slot_mapping
+        .iter()
+        .enumerate()
+        .filter(|&(_, &slot)| slot >= 0)
+        .map(|(row, &slot)| (row as u32, slot as u32))
+        .unzip();
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let num_rows = rows.len();
+    let source = new.index_select(&Tensor::from_vec(rows, num_rows, new.device())?, 0)?;
+    let indexes = Tensor::from_vec(slots, (num_rows, 1, 1), new.device())?
+        .broadcast_as((num_rows, num_kv_heads, head_dim))?
+        .contiguous()?;
+    cache.scatter_set(&indexes, &source, 0)
+}
+
+/// Scaled dot-product attention over a packed batch of sequences
+///
+/// Queries, keys, and values are packed as `[num_tokens, heads, head_dim]`
+/// and split into sequences with the batch `Context`. The new keys and
+/// values are always written to the KV cache when one is given; the backend
+/// only decides where attention reads them from.
+#[derive(Debug, Clone)]
+pub struct Attention {
+    /// Number of query heads
+    num_heads: usize,
+
+    /// Number of key-value heads, shared by groups of query heads
+    num_kv_heads: usize,
+
+    /// Factor applied to the scores before the softmax
+    scale: f64,
+
+    /// Attention window size, or None for full causal attention
+    sliding_window: Option<usize>,
+
+    /// Number of slots in each KV cache block
+    block_size: usize,
+
+    /// Backend used for every forward pass, or None to pick one per context
+    backend: Option<AttentionBackend>,
+}
+
+impl Attention {
+    /// Creates an attention module
+    ///
+    /// # Arguments
+    ///
+    /// * `num_heads` - Number of query heads
+    /// * `num_kv_heads` - Number of key-value heads; must divide `num_heads`
+    /// * `scale` - Factor applied to the scores, see `attention_scale`
+    /// * `sliding_window` - Attention window size, or None for full causal attention
+    /// * `block_size` - Number of slots in each KV cache block
+    /// * `backend` - Backend to use, or None to pick one per context
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend is not available in this build or if
+    /// the key-value heads do not divide the query heads.
+    pub fn new(
+        num_heads: usize,
+        num_kv_heads: usize,
+        scale: f64,
+        sliding_window: Option<usize>,
+        block_size: usize,
+        backend: Option<AttentionBackend>,
+    ) -> Result<Self> {
+        if let Some(backend) = backend.filter(|backend| !backend.is_available()) {
+            candle_core::bail!("attention backend {:?} is not available in this build", backend);
+        }
+        if num_kv_heads == 0 || !num_heads.is_multiple_of(num_kv_heads) {
+            candle_core::bail!("{} query heads cannot share {} key-value heads", num_heads, num_kv_heads);
+        }
+        Ok(Self { num_heads, num_kv_heads, scale, sliding_window, block_size, backend })
+    }
+
+    /// Creates the attention module described by a configuration
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `Config::attention_backend` is not available in
+    /// this build.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hf_config` is not loaded
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let hf = config.hf_config.as_ref().expect("hf_config must be loaded to build attention");
+        Self::new(
+            hf.num_attention_heads(),
+            hf.num_key_value_heads(),
+            attention_scale(hf.head_dim(), None),
+            hf.sliding_window(),
+            config.kvcache_block_size,
+            config.attention_backend,
+        )
+    }
+
+    /// Returns the backend used for a forward pass over the given context
+    ///
+    /// The configured backend if there is one, otherwise `Paged` when the
+    /// context carries block tables and `Naive` when it does not.
+    pub fn backend(&self, context: &Context) -> AttentionBackend {
+        self.backend.unwrap_or(if context.block_tables.is_some() {
+            AttentionBackend::Paged
+        } else {
+            AttentionBackend::Naive
+        })
+    }
+
+    /// Attends every query to the keys of its own sequence
+    ///
+    /// # Arguments
+    ///
+    /// * `q` - Queries of shape `[num_tokens, num_heads, head_dim]`
+    /// * `k` - New keys of shape `[num_tokens, num_kv_heads, head_dim]`
+    /// * `v` - New values of shape `[num_tokens, num_kv_heads, head_dim]`
+    /// * `kv_cache` - Key and value caches of shape
+    ///   `[num_slots, num_kv_heads, head_dim]`, if the model has one
+    /// * `context` - Batch metadata of the forward pass
+    ///
+    /// # Returns
+    ///
+    /// The attention output of shape `[num_tokens, num_heads, head_dim]`, in
+    /// the dtype of `q`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the context lacks the metadata the backend needs,
+    /// e.g. the naive backend on a batch whose keys are not all in the
+    /// forward pass or a paged block table that does not cover a sequence's
+    /// cached keys, or if a tensor operation fails.
+    pub fn forward(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        kv_cache: Option<(&Tensor, &Tensor)>,
+        context: &Context,
+    ) -> Result<Tensor> {
+        if let (Some((k_cache, v_cache)), Some(slot_mapping)) = (kv_cache, &context.slot_mapping) {
+            let slot_mapping = slot_mapping.to_vec1::<i64>()?;
+            write_kv_cache(k_cache, k, &slot_mapping, self.block_size)?;
+            write_kv_cache(v_cache, v, &slot_mapping, self.block_size)?;
+        }
+
+        let spans = sequence_spans(context)?;
+        let backend = self.backend(context);
+        let mut outputs = Vec::with_capacity(spans.len());
+        for (i, &(q_start, seqlen_q, k_start, seqlen_k)) in spans.iter().enumerate() {
+            let q_i = q.narrow(0, q_start, seqlen_q)?;
+            let (k_i, v_i) = match (backend, kv_cache, &context.block_tables) {
+                (AttentionBackend::Paged, Some((k_cache, v_cache)), Some(block_tables)) => {
+                    match self.key_slots(block_tables.get(i), seqlen_k, k.device())? {
+                        Some(slots) => (k_cache.index_select(&slots, 0)?, v_cache.index_select(&slots, 0)?),
+                        // Sequences run outside the scheduler, e.g. by warmup and score, hold no
+                        // blocks; a full prefill still has every key in the forward pass.
+                        None if context.is_prefill && seqlen_q == seqlen_k => {
+                            (k.narrow(0, k_start, seqlen_k)?, v.narrow(0, k_start, seqlen_k)?)
+                        }
+                        None => candle_core::bail!(
+                            "the block table of sequence {} does not cover its {} keys",
+                            i,
+                            seqlen_k
+                        ),
+                    }
+                }
+                (AttentionBackend::Paged, None, Some(_)) => {
+                    candle_core::bail!("paged attention needs a KV cache to read the block tables from")
+                }
+                _ if context.is_prefill && seqlen_q == seqlen_k => {
+                    (k.narrow(0, k_start, seqlen_k)?, v.narrow(0, k_start, seqlen_k)?)
+                }
+                _ => candle_core::bail!(
+                    "{:?} attention needs every key of sequence {} in the forward pass, but only {} of {} are",
+                    backend,
+                    i,
+                    seqlen_q,
+                    seqlen_k
+                ),
+            };
+            outputs.push(self.attend(&q_i, &k_i, &v_i, seqlen_q, seqlen_k)?);
+        }
+        Tensor::cat(&outputs, 0)?.to_dtype(q.dtype())
+    }
+
+    /// Returns the KV cache slot of every key position of one sequence
+    ///
+    /// Returns None if the block table is missing, too short, or padded
+    /// with `-1` before `seqlen_k`, i.e. when some key has no block.
+    fn key_slots(&self, block_table: Option<&Tensor>, seqlen_k: usize, device: &Device) -> Result<Option<Tensor>> {
+        let Some(block_table) = block_table else {
+            return Ok(None);
+        };
+        let block_table = block_table.to_vec1::<i64>()?;
+        let slots: Option<Vec<u32>> = (0..seqlen_k)
+            .map(|position| {
+                let block_id = usize::try_from(*block_table.get(position / self.block_size)?).ok()?;
+                Some((block_id * self.block_size + position % self.block_size) as u32)
+            })
+            .collect();
+        slots.map(|slots| Tensor::from_vec(slots, seqlen_k, device)).transpose()
+    }
+
+    /// Computes masked softmax attention for one sequence in F32
+    fn attend(&self, q: &Tensor, k: &Tensor, v: &Tensor, seqlen_q: usize, seqlen_k: usize) -> Result<Tensor> {
+        let head_dim = q.dim(2)?;
+        let group = self.num_heads / self.num_kv_heads;
+        // [tokens, kv_heads, head_dim] -> [num_heads, tokens, head_dim], repeating each kv head.
+        let expand = |x: &Tensor| -> Result<Tensor> {
+            x.to_dtype(DType::F32)?
+                .transpose(0, 1)?
+                .unsqueeze(1)?
+                .broadcast_as((self.num_kv_heads, group, seqlen_k, head_dim))?
+                .reshape((self.num_heads, seqlen_k, head_dim))
+        };
+        let q = q.to_dtype(DType::F32)?.transpose(0, 1)?.contiguous()?;
+        let (k, v) = (expand(k)?, expand(v)?);
+        let scores = (q.matmul(&k.t()?)? * self.scale)?
+            .broadcast_add(&causal_mask(seqlen_q, seqlen_k, self.sliding_window, q.device())?)?;
+        stable_softmax(&scores)?.matmul(&v)?.transpose(0, 1)
+    }
+}
+
+/// Splits a batch into `(q_start, seqlen_q, k_start, seqlen_k)` per sequence
+///
+/// Prefill batches are split with `cu_seqlens_q` and `cu_seqlens_k`; decode
+/// batches have one query per sequence attending over `context_lens` keys.
+fn sequence_spans(context: &Context) -> Result<Vec<(usize, usize, usize, usize)>> {
+    if context.is_prefill {
+        let (Some(cu_q), Some(cu_k)) = (&context.cu_seqlens_q, &context.cu_seqlens_k) else {
+            candle_core::bail!("a prefill context needs cu_seqlens_q and cu_seqlens_k");
+        };
+        let (cu_q, cu_k) = (cu_q.to_vec1::<u32>()?, cu_k.to_vec1::<u32>()?);
+        Ok((1..cu_q.len())
+            .map(|i| {
+                let (q_start, k_start) = (cu_q[i - 1] as usize, cu_k[i - 1] as usize);
+                (q_start, cu_q[i] as usize - q_start, k_start, cu_k[i] as usize - k_start)
+            })
+            .collect())
+    } else {
+        let Some(context_lens) = &context.context_lens else {
+            candle_core::bail!("a decode context needs context_lens");
+        };
+        Ok(context_lens
+            .to_vec1::<u32>()?
+            .into_iter()
+            .enumerate()
+            .map(|(i, len)| (i, 1, i, len as usize))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_defaults_to_inverse_sqrt_head_dim() {
+        assert_eq!(attention_scale(128, None), 1.0 / 128f64.sqrt());
+        assert_eq!(attention_scale(128, Some(0.125)), 0.125);
+    }
+
+    #[test]
+    fn sliding_window_masks_old_keys() {
+        // A single decode query at position 10 over 11 keys.
+        let mask = causal_mask(1, 11, Some(4), &Device::Cpu).unwrap().to_vec2::<f32>().unwrap();
+        let allowed: Vec<usize> = (0..11).filter(|&j| mask[0][j] == 0.0).collect();
+        assert_eq!(allowed, vec![7, 8, 9, 10]);
+        assert_eq!(mask[0][5], f32::NEG_INFINITY);
+
+        let full = causal_mask(3, 3, None, &Device::Cpu).unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(full[1], vec![0.0, 0.0, f32::NEG_INFINITY]);
+    }
+
+    #[test]
+    fn naive_and_paged_backends_agree_on_a_short_sequence() {
+        let device = Device::Cpu;
+        let (num_tokens, block_size) = (5, 4);
+        let values = |start: f32, heads: usize| {
+            Tensor::arange(start, start + (num_tokens * heads * 8) as f32, &device)
+                .unwrap()
+                .affine(0.01, 0.)
+                .unwrap()
+                .sin()
+                .unwrap()
+                .reshape((num_tokens, heads, 8))
+                .unwrap()
+        };
+        let (q, k, v) = (values(0., 4), values(500., 2), values(900., 2));
+        let context = Context {
+            is_prefill: true,
+            cu_seqlens_q: Some(Tensor::new(&[0u32, 5], &device).unwrap()),
+            cu_seqlens_k: Some(Tensor::new(&[0u32, 5], &device).unwrap()),
+            max_seqlen_q: num_tokens,
+            max_seqlen_k: num_tokens,
+            // Blocks 2 and 0, so the paged keys come back through the block table.
+            slot_mapping: Some(Tensor::new(&[8i64, 9, 10, 11, 0], &device).unwrap()),
+            block_tables: Some(vec![Tensor::new(&[2i64, 0], &device).unwrap()]),
+            ..Context::default()
+        };
+        let scale = attention_scale(8, None);
+
+        let mut outputs = Vec::new();
+        for backend in [AttentionBackend::Naive, AttentionBackend::Paged] {
+            let attention = Attention::new(4, 2, scale, None, block_size, Some(backend)).unwrap();
+            let k_cache = Tensor::zeros((12, 2, 8), DType::F32, &device).unwrap();
+            let v_cache = Tensor::zeros((12, 2, 8), DType::F32, &device).unwrap();
+            let output = attention.forward(&q, &k, &v, Some((&k_cache, &v_cache)), &context).unwrap();
+            assert_eq!(output.dims(), &[5, 4, 8]);
+            outputs.push(output.flatten_all().unwrap().to_vec1::<f32>().unwrap());
+        }
+        for (naive, paged) in outputs[0].iter().zip(&outputs[1]) {
+            assert!((naive - paged).abs() < 1e-5, "{} != {}", naive, paged);
+        }
+
+        let auto = Attention::new(4, 2, scale, None, block_size, None).unwrap();
+        assert_eq!(auto.backend(&context), AttentionBackend::Paged);
+        assert_eq!(auto.backend(&Context::default()), AttentionBackend::Naive);
+        assert!(Attention::new(4, 2, scale, None, block_size, Some(AttentionBackend::Flash)).is_err());
+    }
+
+    #[test]
+    fn paged_attention_without_blocks_errors_instead_of_panicking() {
+        let device = Device::Cpu;
+        let attention = Attention::new(2, 2, attention_scale(4, None), None, 4, Some(AttentionBackend::Paged)).unwrap();
+        let cache = Tensor::zeros((8, 2, 4), DType::F32, &device).unwrap();
+        let q = Tensor::ones((3, 2, 4), DType::F32, &device).unwrap();
+        let prefill = Context {
+            is_prefill: true,
+            cu_seqlens_q: Some(Tensor::new(&[0u32, 3], &device).unwrap()),
+            cu_seqlens_k: Some(Tensor::new(&[0u32, 3], &device).unwrap()),
+            max_seqlen_q: 3,
+            max_seqlen_k: 3,
+            block_tables: Some(vec![Tensor::new(&[-1i64], &device).unwrap()]),
+            ..Context::default()
+        };
+        // A full prefill falls back to the keys in the forward pass.
+        let output = attention.forward(&q, &q, &q, Some((&cache, &cache)), &prefill).unwrap();
+        assert_eq!(output.dims(), &[3, 2, 4]);
+
+        let decode = Context {
+            is_prefill: false,
+            context_lens: Some(Tensor::new(&[6u32], &device).unwrap()),
+            // One block of 4 slots for 6 keys.
+            block_tables: Some(vec![Tensor::new(&[1i64], &device).unwrap()]),
+            ..Context::default()
+        };
+        let q = q.narrow(0, 0, 1).unwrap();
+        assert!(attention.forward(&q, &q, &q, Some((&cache, &cache)), &decode).is_err());
+    }
+
+    #[test]
+    fn contiguous_kv_write_matches_scatter() {
+        let device = Device::Cpu;
+        let block_size = 4;
+        let new = Tensor::arange(1f32, 43., &device).unwrap().reshape((7, 2, 3)).unwrap();
+        // A run split at the block 0/1 boundary, an isolated slot, and padding.
+        let slot_mapping = [1, 2, 3, 4, 5, 10, -1];
+
+        let fast = Tensor::zeros((12, 2, 3), candle_core::DType::F32, &device).unwrap();
+        write_kv_cache(&fast, &new, &slot_mapping, block_size).unwrap();
+        let reference = Tensor::zeros((12, 2, 3), candle_core::DType::F32, &device).unwrap();
+        write_kv_cache_scatter(&reference, &new, &slot_mapping).unwrap();
+
+        let values = |t: &Tensor| t.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(values(&fast), values(&reference));
+        assert_eq!(values(&fast.get(10).unwrap()), values(&new.get(5).unwrap()));
+        assert_eq!(values(&fast.get(0).unwrap()), vec![0.; 6]);
+    }
+}
@@ -0,0 +1,76 @@
+//! Collective communication for tensor parallelism
+//!
+//! Parallel layers (row-parallel linear, vocab-parallel embedding) combine
+//! their partial results across ranks through the `Communicator` trait. Only
+//! a single-rank implementation exists for now; multi-process backends can
+//! be added later without changing the layers.
+
+use candle_core::{Result, Tensor};
+
+/// Collective operations across the ranks of a tensor-parallel group
+pub trait Communicator: Send + Sync {
+    /// Returns the index of this rank within the group
+    fn rank(&self) -> usize;
+
+    /// Returns the number of ranks in the group
+    fn world_size(&self) -> usize;
+
+    /// Sums a tensor element-wise across all ranks
+    ///
+    /// # Returns
+    ///
+    /// The sum, which every rank receives
+    fn all_reduce(&self, t: &Tensor) -> Result<Tensor>;
+
+    /// Concatenates a tensor from every rank along a dimension
+    ///
+    /// # Arguments
+    ///
+    /// * `t` - This rank's part of the result
+    /// * `dim` - Dimension along which the parts are concatenated, in rank order
+    fn all_gather(&self, t: &Tensor, dim: usize) -> Result<Tensor>;
+}
+
+/// Communicator for a group with a single rank
+///
+/// Every collective is the identity, which lets parallel layers run
+/// unchanged in a single process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SingleRank;
+
+impl Communicator for SingleRank {
+    fn rank(&self) -> usize {
+        0
+    }
+
+    fn world_size(&self) -> usize {
+        1
+    }
+
+    fn all_reduce(&self, t: &Tensor) -> Result<Tensor> {
+        Ok(t.clone())
+    }
+
+    fn all_gather(&self, t: &Tensor, dim: usize) -> Result<Tensor> {
+        // Validate the dimension as a real gather would.
+        t.dim(dim)?;
+        Ok(t.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn single_rank_collectives_are_identity() {
+        let comm = SingleRank;
+        let t = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu).unwrap();
+
+        assert_eq!((comm.rank(), comm.world_size()), (0, 1));
+        assert_eq!(comm.all_reduce(&t).unwrap().to_vec2::<f32>().unwrap(), t.to_vec2::<f32>().unwrap());
+        assert_eq!(comm.all_gather(&t, 1).unwrap().to_vec2::<f32>().unwrap(), t.to_vec2::<f32>().unwrap());
+        assert!(comm.all_gather(&t, 2).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! Normalization layers
+//!
+//! This module provides RMSNorm, the normalization used by LLaMA-style
+//! transformer blocks, with control over the dtype in which the variance
+//! is accumulated.
+
+use candle_core::{D, DType, Result, Tensor};
+#[cfg(feature = "candle-nn")]
+use candle_nn::Module;
+
+/// Root mean square layer normalization
+///
+/// Computes `x / sqrt(mean(x^2) + eps) * weight` over the last dimension,
+/// or `x / sqrt(mean(x^2) + eps)` for a norm created `without_weight`.
+/// The mean of squares is accumulated in F32 by default, which keeps large
+/// activations from losing precision or overflowing in half precision.
+#[derive(Debug, Clone)]
+pub struct RmsNorm {
+    /// Learned per-channel scale of shape `[hidden_size]`, or None for a
+    /// weightless norm
+    weight: Option<Tensor>,
+
+    /// Size of the normalized last dimension
+    hidden_size: usize,
+
+    /// Small constant added to the variance for numerical stability
+    eps: f64,
+
+    /// Dtype in which the variance is accumulated
+    accumulation_dtype: DType,
+}
+
+impl RmsNorm {
+    /// Creates a new RMSNorm that accumulates in F32
+    ///
+    /// # Arguments
+    ///
+    /// * `weight` - Per-channel scale of shape `[hidden_size]`
+    /// * `eps` - Constant added to the variance, e.g. the model's `rms_norm_eps`
+    pub fn new(weight: Tensor, eps: f64) -> Self {
+        Self {
+            hidden_size: weight.elem_count(),
+            weight: Some(weight),
+            eps,
+            accumulation_dtype: DType::F32,
+        }
+    }
+
+    /// Creates a new RMSNorm without a learned weight, accumulating in F32
+    ///
+    /// Equivalent to a weight of ones, without allocating or loading one.
+    ///
+    /// # Arguments
+    ///
+    /// * `hidden_size` - Size of the normalized last dimension
+    /// * `eps` - Constant added to the variance, e.g. the model's `rms_norm_eps`
+    pub fn without_weight(hidden_size: usize, eps: f64) -> Self {
+        Self {
+            weight: None,
+            hidden_size,
+            eps,
+            accumulation_dtype: DType::F32,
+        }
+    }
+
+    /// Returns the norm with the variance accumulated in `dtype`
+    ///
+    /// Passing the input dtype (e.g. BF16) skips the upcast, trading accuracy
+    /// on large activations for speed on hardware where F32 is costly.
+    pub fn with_accumulation(mut self, dtype: DType) -> Self {
+        self.accumulation_dtype = dtype;
+        self
+    }
+
+    /// Returns the dtype in which the variance is accumulated
+    pub fn accumulation_dtype(&self) -> DType {
+        self.accumulation_dtype
+    }
+
+    /// Normalizes the input over its last dimension
+    ///
+    /// # Arguments
+    ///
+    /// * `x` - Input of shape `[..., hidden_size]`
+    ///
+    /// # Returns
+    ///
+    /// The normalized tensor, with the same shape and dtype as `x`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the last dimension of `x` is not `hidden_size`.
+    pub fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let dtype = x.dtype();
+        let hidden_size = x.dim(D::Minus1)?;
+        if hidden_size != self.hidden_size {
+            candle_core::bail!("RmsNorm expects a last dimension of {}, got {}", self.hidden_size, hidden_size);
+        }
+        let x = x.to_dtype(self.accumulation_dtype)?;
+        let variance = x.sqr()?.mean_keepdim(D::Minus1)?;
+        let x = x.broadcast_div(&(variance + self.eps)?.sqrt()?)?.to_dtype(dtype)?;
+        match &self.weight {
+            Some(weight) => x.broadcast_mul(&weight.to_dtype(dtype)?),
+            None => Ok(x),
+        }
+    }
+}
+
+#[cfg(feature = "candle-nn")]
+impl Module for RmsNorm {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        RmsNorm::forward(self, x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn f32_accumulation_is_closer_to_reference() {
+        let device = Device::Cpu;
+        // Large activations with small variations: summing their squares in
+        // BF16 loses most of the mantissa.
+        let values: Vec<f32> = (0..4096).map(|i| 3000.0 + (i % 7) as f32).collect();
+        let mean_sq = values.iter().map(|&v| (v as f64).powi(2)).sum::<f64>() / values.len() as f64;
+        let reference: Vec<f64> = values.iter().map(|&v| v as f64 / (mean_sq + 1e-6).sqrt()).collect();
+
+        let x = Tensor::from_vec(values, (1, 4096), &device).unwrap().to_dtype(DType::BF16).unwrap();
+        let weight = Tensor::ones(4096, DType::BF16, &device).unwrap();
+        let error = |norm: RmsNorm| -> f64 {
+            let out = norm.forward(&x).unwrap();
+            assert_eq!(out.dtype(), DType::BF16);
+            let out = out.to_dtype(DType::F64).unwrap().flatten_all().unwrap().to_vec1::<f64>().unwrap();
+            out.iter().zip(&reference).map(|(o, r)| (o - r).abs()).sum()
+        };
+
+        let norm = RmsNorm::new(weight, 1e-6);
+        assert_eq!(norm.accumulation_dtype(), DType::F32);
+        let f32_error = error(norm.clone());
+        let bf16_error = error(norm.with_accumulation(DType::BF16));
+        assert!(f32_error < bf16_error, "f32 error {f32_error} >= bf16 error {bf16_error}");
+    }
+
+    #[test]
+    fn without_weight_divides_by_the_rms() {
+        let rows = [[1f32, -2., 3., 4.], [0.5, 0.5, -0.5, 0.5]];
+        let x = Tensor::new(&rows, &Device::Cpu).unwrap();
+        let norm = RmsNorm::without_weight(4, 1e-6);
+        let out = norm.forward(&x).unwrap().to_vec2::<f32>().unwrap();
+        for (row, expected) in out.iter().zip(&rows) {
+            let rms = (expected.iter().map(|v| v * v).sum::<f32>() / 4.0 + 1e-6).sqrt();
+            for (o, v) in row.iter().zip(expected) {
+                assert!((o - v / rms).abs() < 1e-6);
+            }
+        }
+        assert!(norm.forward(&Tensor::zeros((1, 3), DType::F32, &Device::Cpu).unwrap()).is_err());
+    }
+}
@@ -1 +1,44 @@
-pub mod activation;
\ No newline at end of file
+// Linking accelerate-src makes candle's CPU BLAS calls use Accelerate.
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+pub mod activation;
+pub mod attention;
+pub mod communicator;
+pub mod layernorm;
+pub mod linear;
+pub mod logits_processor;
+pub mod quantized_linear;
+pub mod rotary_embedding;
+pub mod sampler;
+
+/// Returns the BLAS backend candle uses for CPU tensors
+///
+/// "accelerate" when the `accelerate` feature routes matmuls through Apple's
+/// Accelerate framework, "mkl" when candle was built with MKL, and "native"
+/// for candle's own kernels. The value is read from candle itself, so it
+/// confirms that the feature reached candle-core.
+pub fn cpu_backend() -> &'static str {
+    if candle_core::utils::has_accelerate() {
+        "accelerate"
+    } else if candle_core::utils::has_mkl() {
+        "mkl"
+    } else {
+        "native"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{Device, Tensor};
+
+    #[test]
+    fn cpu_backend_follows_the_accelerate_feature() {
+        assert_eq!(cpu_backend() == "accelerate", cfg!(feature = "accelerate"));
+
+        let a = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu).unwrap();
+        let product = a.matmul(&a).unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(product, vec![vec![7., 10.], vec![15., 22.]]);
+    }
+}
@@ -0,0 +1,137 @@
+//! Linear layers with an optional bias
+//!
+//! This module provides `Linear`, used for the attention projections. Some
+//! checkpoints, such as Qwen2's, give the q/k/v projections a bias while the
+//! output projection has none, so whether a layer carries a bias is decided
+//! when it is created (see `HfConfig::attention_bias`). Checkpoint tensors
+//! are routed to it by their `.weight` and `.bias` suffixes.
+
+use candle_core::{Result, Tensor};
+
+/// Linear layer computing `x @ weight^T + bias`
+#[derive(Debug, Clone)]
+pub struct Linear {
+    /// Number of input features
+    in_features: usize,
+
+    /// Number of output features
+    out_features: usize,
+
+    /// Whether the layer has a bias term
+    has_bias: bool,
+
+    /// Weight of shape `[out_features, in_features]`, once loaded
+    weight: Option<Tensor>,
+
+    /// Bias of shape `[out_features]`, once loaded
+    bias: Option<Tensor>,
+}
+
+impl Linear {
+    /// Creates an unloaded linear layer
+    ///
+    /// # Arguments
+    ///
+    /// * `in_features` - Number of input features
+    /// * `out_features` - Number of output features
+    /// * `has_bias` - Whether the checkpoint provides a `.bias` tensor
+    pub fn new(in_features: usize, out_features: usize, has_bias: bool) -> Self {
+        Self {
+            in_features,
+            out_features,
+            has_bias,
+            weight: None,
+            bias: None,
+        }
+    }
+
+    /// Returns whether the layer has a bias term
+    pub fn has_bias(&self) -> bool {
+        self.has_bias
+    }
+
+    /// Loads one of the layer's checkpoint tensors
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The tensor's checkpoint name, ending in `.weight` or `.bias`
+    /// * `tensor` - The tensor read from the checkpoint
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tensor has the wrong shape, if `name` has
+    /// neither suffix, or if it is a bias and the layer has none.
+    pub fn load(&mut self, name: &str, tensor: Tensor) -> Result<()> {
+        if name.ends_with(".weight") {
+            if tensor.dims() != [self.out_features, self.in_features] {
+                candle_core::bail!(
+                    "expected {} of shape [{}, {}], got {:?}",
+                    name, self.out_features, self.in_features, tensor.dims()
+                );
+            }
+            self.weight = Some(tensor);
+        } else if name.ends_with(".bias") {
+            if !self.has_bias {
+                candle_core::bail!("{} given to a linear layer without a bias", name);
+            }
+            if tensor.dims() != [self.out_features] {
+                candle_core::bail!("expected {} of shape [{}], got {:?}", name, self.out_features, tensor.dims());
+            }
+            self.bias = Some(tensor);
+        } else {
+            candle_core::bail!("{} is not a linear layer tensor", name);
+        }
+        Ok(())
+    }
+
+    /// Applies the layer
+    ///
+    /// # Arguments
+    ///
+    /// * `x` - Input of shape `[..., in_features]`
+    ///
+    /// # Returns
+    ///
+    /// Output of shape `[..., out_features]`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the weight, or the bias of a layer that has one,
+    /// has not been loaded.
+    pub fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let Some(weight) = &self.weight else {
+            candle_core::bail!("linear layer used before its weight was loaded");
+        };
+        let y = x.broadcast_matmul(&weight.t()?)?;
+        match (&self.bias, self.has_bias) {
+            (Some(bias), _) => y.broadcast_add(bias),
+            (None, true) => candle_core::bail!("linear layer used before its bias was loaded"),
+            (None, false) => Ok(y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn bias_is_added_only_when_present() {
+        let device = Device::Cpu;
+        let weight = Tensor::new(&[[1f32, 0.], [0., 2.], [1., 1.]], &device).unwrap();
+        let bias = Tensor::new(&[0.5f32, -1., 2.], &device).unwrap();
+        let x = Tensor::new(&[[1f32, 2.]], &device).unwrap();
+
+        let mut q_proj = Linear::new(2, 3, true);
+        q_proj.load("self_attn.q_proj.weight", weight.clone()).unwrap();
+        assert!(q_proj.forward(&x).is_err());
+        q_proj.load("self_attn.q_proj.bias", bias.clone()).unwrap();
+        assert_eq!(q_proj.forward(&x).unwrap().to_vec2::<f32>().unwrap(), [[1.5, 3., 5.]]);
+
+        let mut o_proj = Linear::new(2, 3, false);
+        o_proj.load("self_attn.o_proj.weight", weight).unwrap();
+        assert!(o_proj.load("self_attn.o_proj.bias", bias).is_err());
+        assert_eq!(o_proj.forward(&x).unwrap().to_vec2::<f32>().unwrap(), [[1., 4., 3.]]);
+    }
+}
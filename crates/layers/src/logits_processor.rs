@@ -0,0 +1,454 @@
+//! Composable processing of logits before sampling
+//!
+//! This module provides the `LogitsProcessor` trait, which adjusts the logits
+//! of a single sequence in place, and `LogitsProcessorChain`, which applies a
+//! configured list of processors in order. Custom constraints are added by
+//! implementing the trait and pushing the processor onto a chain.
+
+use candle_core::{DType, Result, Tensor};
+use common::sequence::Sequence;
+use std::collections::{HashMap, HashSet};
+
+/// Adjusts the logits of one sequence before sampling
+///
+/// Implementations receive the logits for the next token of `seq`, a tensor
+/// of shape `[vocab_size]`, and may replace it with an adjusted tensor of
+/// the same shape.
+pub trait LogitsProcessor: Send + Sync {
+    /// Processes the next-token logits of a sequence in place
+    ///
+    /// # Arguments
+    ///
+    /// * `logits` - Logits of shape `[vocab_size]` for the next token of `seq`
+    /// * `seq` - The sequence the logits belong to
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any tensor operation fails.
+    fn process(&self, logits: &mut Tensor, seq: &Sequence) -> Result<()>;
+}
+
+/// An ordered list of logits processors
+///
+/// Processors run in the order they were added, each seeing the logits
+/// produced by the previous one.
+#[derive(Default)]
+pub struct LogitsProcessorChain {
+    /// Processors applied by the chain, in order
+    processors: Vec<Box<dyn LogitsProcessor>>,
+}
+
+impl LogitsProcessorChain {
+    /// Creates an empty chain that leaves logits unchanged
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a processor to the end of the chain
+    pub fn push(&mut self, processor: impl LogitsProcessor + 'static) {
+        self.processors.push(Box::new(processor));
+    }
+
+    /// Returns the chain with a processor appended to its end
+    pub fn with(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.push(processor);
+        self
+    }
+
+    /// Returns true if the chain has no processors
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+}
+
+impl LogitsProcessor for LogitsProcessorChain {
+    fn process(&self, logits: &mut Tensor, seq: &Sequence) -> Result<()> {
+        for processor in &self.processors {
+            processor.process(logits, seq)?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies an edit to a copy of the logits and writes it back
+///
+/// The edit operates on F32 values; the result is converted back to the
+/// original dtype and device.
+fn edit_logits(logits: &mut Tensor, edit: impl FnOnce(&mut [f32])) -> Result<()> {
+    let mut values = logits.to_dtype(candle_core::DType::F32)?.to_vec1::<f32>()?;
+    edit(&mut values);
+    *logits = Tensor::from_vec(values, logits.shape(), logits.device())?.to_dtype(logits.dtype())?;
+    Ok(())
+}
+
+/// Penalizes tokens that already occur in the sequence
+///
+/// Positive logits are divided by the penalty and negative logits are
+/// multiplied by it, so a penalty above 1.0 always makes repeats less likely.
+#[derive(Debug, Clone)]
+pub struct RepetitionPenalty {
+    /// Penalty factor; 1.0 disables the penalty
+    pub penalty: f32,
+}
+
+impl LogitsProcessor for RepetitionPenalty {
+    fn process(&self, logits: &mut Tensor, seq: &Sequence) -> Result<()> {
+        if self.penalty == 1.0 {
+            return Ok(());
+        }
+        let seen: HashSet<u32> = seq.token_ids.iter().copied().collect();
+        edit_logits(logits, |values| {
+            for token_id in seen {
+                if let Some(logit) = values.get_mut(token_id as usize) {
+                    *logit = if *logit > 0.0 { *logit / self.penalty } else { *logit * self.penalty };
+                }
+            }
+        })
+    }
+}
+
+/// Applies a per-row repetition penalty to a whole batch of logits at once
+///
+/// Each row is penalized like `RepetitionPenalty` does for a single
+/// sequence, but the presence mask of every row is built with one scatter
+/// over the sequences' token IDs and the penalty is applied with tensor
+/// operations across the batch, instead of editing each row on the host.
+///
+/// # Arguments
+///
+/// * `logits` - Logits of shape `[batch, vocab_size]`
+/// * `seqs` - The sequence of each row, whose tokens are penalized
+/// * `penalty` - Penalty factor of each row; 1.0 leaves the row unchanged
+///
+/// # Returns
+///
+/// The penalized logits, with the dtype of `logits`
+///
+/// # Errors
+///
+/// Returns an error if `seqs` or `penalty` do not have one entry per row,
+/// or if a tensor operation fails.
+pub fn apply_repetition_penalty_batched(logits: &Tensor, seqs: &[&Sequence], penalty: &[f32]) -> Result<Tensor> {
+    let (batch, vocab_size) = logits.dims2()?;
+    if seqs.len() != batch || penalty.len() != batch {
+        candle_core::bail!(
+            "expected {} sequences and penalties, got {} and {}",
+            batch, seqs.len(), penalty.len()
+        );
+    }
+    let device = logits.device();
+
+    // Pad every row's token IDs to the same length; padding scatters 0 onto token 0.
+    let width = seqs.iter().map(|seq| seq.token_ids.len()).max().unwrap_or(0).max(1);
+    let mut indices = vec![0u32; batch * width];
+    let mut present = vec![0f32; batch * width];
+    for (row, seq) in seqs.iter().enumerate() {
+        for (col, &token_id) in seq.token_ids.iter().enumerate() {
+            if (token_id as usize) < vocab_size {
+                indices[row * width + col] = token_id;
+                present[row * width + col] = 1.0;
+            }
+        }
+    }
+    let indices = Tensor::from_vec(indices, (batch, width), device)?;
+    let present = Tensor::from_vec(present, (batch, width), device)?;
+    let mask = Tensor::zeros((batch, vocab_size), DType::F32, device)?
+        .scatter_add(&indices, &present, 1)?
+        .gt(0f64)?;
+
+    let x = logits.to_dtype(DType::F32)?;
+    let penalty = Tensor::from_vec(penalty.to_vec(), (batch, 1), device)?;
+    let penalized = x.gt(0f64)?.where_cond(&x.broadcast_div(&penalty)?, &x.broadcast_mul(&penalty)?)?;
+    mask.where_cond(&penalized, &x)?.to_dtype(logits.dtype())
+}
+
+/// Default bound on the magnitude of an applied logit bias
+///
+/// A bias of ±100 already forces or forbids a token in practice, while
+/// keeping the logits finite in every dtype.
+pub const DEFAULT_MAX_LOGIT_BIAS: f32 = 100.0;
+
+/// Adds a fixed bias to the logits of selected tokens
+///
+/// Biases are clamped to `±max_magnitude` before they are applied, so huge
+/// values cannot overflow the softmax. A bias of exactly `f32::NEG_INFINITY`
+/// is kept as is and forbids the token outright.
+#[derive(Debug, Clone)]
+pub struct LogitBias {
+    /// Bias added to each token's logit, keyed by token ID
+    pub bias: HashMap<u32, f32>,
+
+    /// Largest magnitude of an applied bias; defaults to `DEFAULT_MAX_LOGIT_BIAS`
+    pub max_magnitude: f32,
+}
+
+impl LogitBias {
+    /// Creates a logit bias clamped to `DEFAULT_MAX_LOGIT_BIAS`
+    pub fn new(bias: HashMap<u32, f32>) -> Self {
+        Self {
+            bias,
+            max_magnitude: DEFAULT_MAX_LOGIT_BIAS,
+        }
+    }
+
+    /// Returns the logit bias with applied biases clamped to `±max_magnitude`
+    pub fn with_max_magnitude(mut self, max_magnitude: f32) -> Self {
+        self.max_magnitude = max_magnitude;
+        self
+    }
+}
+
+impl Default for LogitBias {
+    fn default() -> Self {
+        Self::new(HashMap::new())
+    }
+}
+
+impl LogitsProcessor for LogitBias {
+    fn process(&self, logits: &mut Tensor, _seq: &Sequence) -> Result<()> {
+        if self.bias.is_empty() {
+            return Ok(());
+        }
+        edit_logits(logits, |values| {
+            for (&token_id, &bias) in &self.bias {
+                if let Some(logit) = values.get_mut(token_id as usize) {
+                    *logit += if bias == f32::NEG_INFINITY {
+                        bias
+                    } else {
+                        bias.clamp(-self.max_magnitude, self.max_magnitude)
+                    };
+                }
+            }
+        })
+    }
+}
+
+/// Prevents the tokens in a sequence's `suppress_special_tokens` from being sampled
+///
+/// The suppressed token IDs come from each sequence's sampling parameters,
+/// so one instance serves every sequence in a batch.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SuppressSpecialTokens;
+
+impl LogitsProcessor for SuppressSpecialTokens {
+    fn process(&self, logits: &mut Tensor, seq: &Sequence) -> Result<()> {
+        let Some(token_ids) = seq.suppress_special_tokens.as_deref().filter(|ids| !ids.is_empty()) else {
+            return Ok(());
+        };
+        edit_logits(logits, |values| {
+            for &token_id in token_ids {
+                if let Some(logit) = values.get_mut(token_id as usize) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        })
+    }
+}
+
+/// Suppresses the EOS token until a minimum number of tokens was generated
+#[derive(Debug, Clone)]
+pub struct MinTokens {
+    /// Number of completion tokens that must be generated before EOS
+    pub min_tokens: usize,
+
+    /// The end-of-sequence token ID
+    pub eos_token_id: u32,
+}
+
+impl LogitsProcessor for MinTokens {
+    fn process(&self, logits: &mut Tensor, seq: &Sequence) -> Result<()> {
+        if seq.num_completion_tokens() >= self.min_tokens {
+            return Ok(());
+        }
+        edit_logits(logits, |values| {
+            if let Some(logit) = values.get_mut(self.eos_token_id as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        })
+    }
+}
+
+/// Forbids tokens that would repeat an n-gram already in the sequence
+///
+/// The last `ngram_size - 1` tokens are matched against every earlier window;
+/// the token that followed each match is banned.
+#[derive(Debug, Clone)]
+pub struct NoRepeatNgram {
+    /// Length of the n-grams that may not repeat; 0 disables the check
+    pub ngram_size: usize,
+}
+
+impl LogitsProcessor for NoRepeatNgram {
+    fn process(&self, logits: &mut Tensor, seq: &Sequence) -> Result<()> {
+        let n = self.ngram_size;
+        let tokens = &seq.token_ids;
+        if n == 0 || tokens.len() < n {
+            return Ok(());
+        }
+        let suffix = &tokens[tokens.len() + 1 - n..];
+        let banned: Vec<u32> = tokens
+            .windows(n)
+            .filter(|window| &window[..n - 1] == suffix)
+            .map(|window| window[n - 1])
+            .collect();
+        if banned.is_empty() {
+            return Ok(());
+        }
+        edit_logits(logits, |values| {
+            for token_id in banned {
+                if let Some(logit) = values.get_mut(token_id as usize) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        })
+    }
+}
+
+/// Restricts which tokens may be sampled at each step
+///
+/// Implementations typically track a grammar's parser state by replaying the
+/// sequence's tokens, which makes stateful constraints such as JSON output
+/// possible without extra bookkeeping in the engine.
+pub trait ConstraintMask: Send + Sync {
+    /// Returns the tokens permitted as the next token of a sequence
+    ///
+    /// # Returns
+    ///
+    /// The allowed token IDs, or None if the sequence is unconstrained at
+    /// this step
+    fn allowed_tokens(&self, seq: &Sequence) -> Option<Vec<u32>>;
+}
+
+/// Logits processor that enforces a `ConstraintMask`
+///
+/// Every token outside the allowed set has its logit forced to `-inf`, so
+/// neither greedy nor random sampling can select it.
+#[derive(Debug, Clone)]
+pub struct ConstraintProcessor<C: ConstraintMask> {
+    /// The constraint being enforced
+    pub constraint: C,
+}
+
+impl<C: ConstraintMask> ConstraintProcessor<C> {
+    /// Creates a processor that enforces the given constraint
+    pub fn new(constraint: C) -> Self {
+        Self { constraint }
+    }
+}
+
+impl<C: ConstraintMask> LogitsProcessor for ConstraintProcessor<C> {
+    fn process(&self, logits: &mut Tensor, seq: &Sequence) -> Result<()> {
+        let Some(allowed) = self.constraint.allowed_tokens(seq) else {
+            return Ok(());
+        };
+        edit_logits(logits, |values| {
+            let mut mask = vec![false; values.len()];
+            for token_id in allowed {
+                if let Some(allowed) = mask.get_mut(token_id as usize) {
+                    *allowed = true;
+                }
+            }
+            for (logit, allowed) in values.iter_mut().zip(mask) {
+                if !allowed {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+    use common::sampling::SamplingParams;
+
+    #[test]
+    fn chain_applies_processors_in_order() {
+        let seq = Sequence::new(vec![0, 2], SamplingParams::default());
+        let chain = LogitsProcessorChain::new()
+            .with(RepetitionPenalty { penalty: 2.0 })
+            .with(LogitBias::new(HashMap::from([(0, 1.0), (3, -1.0)])));
+
+        let mut logits = Tensor::new(&[4f32, 4., -4., 4.], &Device::Cpu).unwrap();
+        chain.process(&mut logits, &seq).unwrap();
+        assert_eq!(logits.to_vec1::<f32>().unwrap(), vec![3., 4., -8., 3.]);
+    }
+
+    #[test]
+    fn logit_bias_is_clamped_except_for_negative_infinity() {
+        let seq = Sequence::new(vec![0], SamplingParams::default());
+        let bias = LogitBias::new(HashMap::from([(0, 1e9), (1, f32::NEG_INFINITY), (2, -1e9)]));
+        let mut logits = Tensor::new(&[1f32, 1., 1., 1.], &Device::Cpu).unwrap();
+        bias.process(&mut logits, &seq).unwrap();
+        assert_eq!(logits.to_vec1::<f32>().unwrap(), vec![101., f32::NEG_INFINITY, -99., 1.]);
+
+        let probs = crate::sampler::stable_softmax(&logits).unwrap().to_vec1::<f32>().unwrap();
+        assert!(probs.iter().all(|p| p.is_finite()));
+        assert!((probs[0] - 1.0).abs() < 1e-6);
+
+        let mut logits = Tensor::new(&[1f32, 1., 1., 1.], &Device::Cpu).unwrap();
+        bias.with_max_magnitude(5.0).process(&mut logits, &seq).unwrap();
+        assert_eq!(logits.to_vec1::<f32>().unwrap(), vec![6., f32::NEG_INFINITY, -4., 1.]);
+    }
+
+    #[test]
+    fn batched_repetition_penalty_matches_per_row() {
+        let vocab_size = 6;
+        let first = Sequence::new(vec![0, 2, 2], SamplingParams::default());
+        let mut second = Sequence::new(vec![5], SamplingParams::default());
+        second.append_token(1);
+        let seqs = [&first, &second];
+        let penalties = [2.0, 1.5];
+        let rows = [[1f32, -2., 3., 0.5, -1., 4.], [-3., 2., 1., 0., 6., -0.5]];
+        let logits = Tensor::new(&rows, &Device::Cpu).unwrap();
+
+        let batched = apply_repetition_penalty_batched(&logits, &seqs, &penalties).unwrap();
+        for (i, (seq, penalty)) in seqs.iter().zip(penalties).enumerate() {
+            let mut row = Tensor::new(&rows[i], &Device::Cpu).unwrap();
+            RepetitionPenalty { penalty }.process(&mut row, seq).unwrap();
+            assert_eq!(batched.get(i).unwrap().to_vec1::<f32>().unwrap(), row.to_vec1::<f32>().unwrap());
+        }
+        assert_eq!(batched.dims(), [2, vocab_size]);
+    }
+
+    #[test]
+    fn no_repeat_ngram_bans_completing_token() {
+        let mut seq = Sequence::new(vec![1, 2, 3, 1], SamplingParams::default());
+        seq.append_token(2);
+        let mut logits = Tensor::zeros(5, candle_core::DType::F32, &Device::Cpu).unwrap();
+        NoRepeatNgram { ngram_size: 3 }.process(&mut logits, &seq).unwrap();
+
+        let values = logits.to_vec1::<f32>().unwrap();
+        assert_eq!(values[3], f32::NEG_INFINITY);
+        assert!(values.iter().enumerate().all(|(i, v)| i == 3 || *v == 0.0));
+    }
+
+    /// Toy constraint over a vocabulary where tokens 0-9 are digits and
+    /// token 10 opens a number: only digits may follow it.
+    struct DigitsAfterOpen;
+
+    impl ConstraintMask for DigitsAfterOpen {
+        fn allowed_tokens(&self, seq: &Sequence) -> Option<Vec<u32>> {
+            (seq.last_token_id == 10).then(|| (0..10).collect())
+        }
+    }
+
+    #[test]
+    fn constraint_masks_disallowed_tokens() {
+        let processor = ConstraintProcessor::new(DigitsAfterOpen);
+        let logits = Tensor::ones(12, candle_core::DType::F32, &Device::Cpu).unwrap();
+
+        let mut unconstrained = logits.clone();
+        let seq = Sequence::new(vec![11], SamplingParams::default());
+        processor.process(&mut unconstrained, &seq).unwrap();
+        assert_eq!(unconstrained.to_vec1::<f32>().unwrap(), vec![1.; 12]);
+
+        let mut constrained = logits;
+        let seq = Sequence::new(vec![11, 10], SamplingParams::default());
+        processor.process(&mut constrained, &seq).unwrap();
+        let values = constrained.to_vec1::<f32>().unwrap();
+        assert!(values[..10].iter().all(|&v| v == 1.0));
+        assert!(values[10..].iter().all(|&v| v == f32::NEG_INFINITY));
+    }
+}
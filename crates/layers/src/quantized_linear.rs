@@ -0,0 +1,236 @@
+//! Linear layers with quantized weights
+//!
+//! This module provides `QuantizedLinear`, which keeps its weight as 8-bit
+//! values with a per-output-channel scale and dequantizes it on the fly in
+//! `forward`. The loader routes checkpoint tensors to it by their
+//! `.qweight`, `.scales`, and `.qzeros` suffixes through
+//! `utils::SafeTensorLoadable::load_quantized`.
+
+use candle_core::{DType, Result, Tensor};
+use utils::QuantizedTensorKind;
+
+/// Linear layer with 8-bit weights
+///
+/// The weight is stored as `qweight` of shape `[out_features, in_features]`
+/// with one byte per element and `scales` of shape `[out_features]`. Without
+/// zero points the scheme is symmetric int8, `weight = qweight * scales[:, None]`,
+/// where the loader reads int8 data as `U8` and the bytes are reinterpreted
+/// as two's complement here. With `qzeros` of shape `[out_features]` the
+/// scheme is asymmetric uint8, `weight = (qweight - qzeros[:, None]) *
+/// scales[:, None]`. Int4 packing is not supported yet.
+#[derive(Debug, Clone)]
+pub struct QuantizedLinear {
+    /// Number of input features
+    in_features: usize,
+
+    /// Number of output features
+    out_features: usize,
+
+    /// Quantized weight, once loaded
+    qweight: Option<Tensor>,
+
+    /// Per-output-channel scale, once loaded
+    scales: Option<Tensor>,
+
+    /// Per-output-channel zero point of the asymmetric scheme, if loaded
+    qzeros: Option<Tensor>,
+}
+
+impl QuantizedLinear {
+    /// Creates an unloaded quantized linear layer
+    ///
+    /// # Arguments
+    ///
+    /// * `in_features` - Number of input features
+    /// * `out_features` - Number of output features
+    pub fn new(in_features: usize, out_features: usize) -> Self {
+        Self {
+            in_features,
+            out_features,
+            qweight: None,
+            scales: None,
+            qzeros: None,
+        }
+    }
+
+    /// Loads one of the layer's checkpoint tensors
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - Which quantized tensor `tensor` is
+    /// * `tensor` - The tensor read from the checkpoint
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tensor has the wrong shape or dtype.
+    pub fn load(&mut self, kind: QuantizedTensorKind, tensor: Tensor) -> Result<()> {
+        match kind {
+            QuantizedTensorKind::QWeight => {
+                if tensor.dims() != [self.out_features, self.in_features] {
+                    candle_core::bail!(
+                        "expected qweight of shape [{}, {}], got {:?}",
+                        self.out_features, self.in_features, tensor.dims()
+                    );
+                }
+                if tensor.dtype() != DType::U8 {
+                    candle_core::bail!("expected int8 qweight stored as u8, got {:?}", tensor.dtype());
+                }
+                self.qweight = Some(tensor);
+            }
+            QuantizedTensorKind::Scales => {
+                if tensor.elem_count() != self.out_features {
+                    candle_core::bail!(
+                        "expected {} scales, got shape {:?}",
+                        self.out_features, tensor.dims()
+                    );
+                }
+                self.scales = Some(tensor.reshape((self.out_features, 1))?);
+            }
+            QuantizedTensorKind::QZeros => {
+                if tensor.elem_count() != self.out_features {
+                    candle_core::bail!(
+                        "expected {} zero points, got shape {:?}",
+                        self.out_features, tensor.dims()
+                    );
+                }
+                if tensor.dtype() != DType::U8 {
+                    candle_core::bail!("expected uint8 qzeros, got {:?}", tensor.dtype());
+                }
+                self.qzeros = Some(tensor.reshape((self.out_features, 1))?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the dequantized weight of shape `[out_features, in_features]`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `qweight` or `scales` has not been loaded.
+    pub fn dequantize(&self, dtype: DType) -> Result<Tensor> {
+        let (Some(qweight), Some(scales)) = (&self.qweight, &self.scales) else {
+            candle_core::bail!("quantized linear layer used before qweight and scales were loaded");
+        };
+        let unsigned = qweight.to_dtype(DType::F32)?;
+        let centred = match &self.qzeros {
+            Some(qzeros) => unsigned.broadcast_sub(&qzeros.to_dtype(DType::F32)?)?,
+            // Reinterpret the u8 bytes as two's complement int8.
+            None => {
+                let negative = unsigned.ge(128f64)?.to_dtype(DType::F32)?;
+                (unsigned - (negative * 256.0)?)?
+            }
+        };
+        centred.broadcast_mul(&scales.to_dtype(DType::F32)?)?.to_dtype(dtype)
+    }
+
+    /// Applies the layer, computing `x @ weight^T`
+    ///
+    /// # Arguments
+    ///
+    /// * `x` - Input of shape `[..., in_features]`
+    ///
+    /// # Returns
+    ///
+    /// Output of shape `[..., out_features]` with the dtype of `x`
+    pub fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let weight = self.dequantize(x.dtype())?;
+        x.broadcast_matmul(&weight.t()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+    use std::collections::HashMap;
+    use utils::{SafeTensorLoadable, load_model_with_warnings, split_quantized_name};
+
+    #[test]
+    fn dequantized_matmul_matches_f32_reference() {
+        let device = Device::Cpu;
+        let qweight: [i8; 6] = [-128, -1, 0, 1, 64, 127];
+        let scales = [0.01f32, 0.5];
+        let reference: Vec<f32> = qweight
+            .iter()
+            .enumerate()
+            .map(|(i, &q)| q as f32 * scales[i / 3])
+            .collect();
+        let reference = Tensor::from_vec(reference, (2, 3), &device).unwrap();
+
+        let mut layer = QuantizedLinear::new(3, 2);
+        let bytes: Vec<u8> = qweight.iter().map(|&q| q as u8).collect();
+        let checkpoint = [
+            ("mlp.down_proj.qweight", Tensor::from_vec(bytes, (2, 3), &device).unwrap()),
+            ("mlp.down_proj.scales", Tensor::from_vec(scales.to_vec(), 2, &device).unwrap()),
+        ];
+        for (name, tensor) in checkpoint {
+            let (prefix, kind) = split_quantized_name(name).unwrap();
+            assert_eq!(prefix, "mlp.down_proj");
+            layer.load(kind, tensor).unwrap();
+        }
+
+        let x = Tensor::new(&[[1f32, 2., 3.], [-1., 0.5, 4.]], &device).unwrap();
+        let expected = x.matmul(&reference.t().unwrap()).unwrap().to_vec2::<f32>().unwrap();
+        let actual = layer.forward(&x).unwrap().to_vec2::<f32>().unwrap();
+        for (a, e) in actual.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - e).abs() < 1e-4, "{a} != {e}");
+        }
+    }
+
+    #[test]
+    fn zero_points_make_the_scheme_asymmetric() {
+        let device = Device::Cpu;
+        let mut layer = QuantizedLinear::new(2, 2);
+        layer.load(QuantizedTensorKind::QWeight, Tensor::new(&[[0u8, 128], [255, 10]], &device).unwrap()).unwrap();
+        layer.load(QuantizedTensorKind::Scales, Tensor::new(&[0.5f32, 2.], &device).unwrap()).unwrap();
+        layer.load(QuantizedTensorKind::QZeros, Tensor::new(&[128u8, 10], &device).unwrap()).unwrap();
+        let weight = layer.dequantize(DType::F32).unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(weight, vec![vec![-64., 0.], vec![490., 0.]]);
+    }
+
+    #[test]
+    fn checkpoint_tensors_are_routed_by_suffix() {
+        /// Model with a single quantized projection
+        struct Projection {
+            down_proj: QuantizedLinear,
+        }
+
+        impl SafeTensorLoadable for Projection {
+            fn load_weight(&mut self, _name: &str, _weight: Tensor, _shard_id: Option<usize>) -> anyhow::Result<bool> {
+                Ok(false)
+            }
+
+            fn load_quantized(
+                &mut self,
+                layer: &str,
+                kind: QuantizedTensorKind,
+                tensor: Tensor,
+                _shard_id: Option<usize>,
+            ) -> anyhow::Result<bool> {
+                if layer != "mlp.down_proj" {
+                    return Ok(false);
+                }
+                self.down_proj.load(kind, tensor)?;
+                Ok(true)
+            }
+        }
+
+        let device = Device::Cpu;
+        let dir = std::env::temp_dir().join(format!("quantized_checkpoint_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tensors = HashMap::from([
+            ("mlp.down_proj.qweight", Tensor::new(&[[1u8, 255], [2, 3]], &device).unwrap()),
+            ("mlp.down_proj.scales", Tensor::new(&[0.5f32, 0.25], &device).unwrap()),
+            ("mlp.down_proj.qzeros", Tensor::new(&[0u8, 1], &device).unwrap()),
+        ]);
+        candle_core::safetensors::save(&tensors, dir.join("model.safetensors")).unwrap();
+
+        let mut model = Projection { down_proj: QuantizedLinear::new(2, 2) };
+        let mut warnings = Vec::new();
+        load_model_with_warnings(&mut model, &dir, &mut |warning| warnings.push(warning.to_string())).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let weight = model.down_proj.dequantize(DType::F32).unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(weight, vec![vec![0.5, 127.5], vec![0.25, 0.5]]);
+    }
+}
@@ -0,0 +1,160 @@
+//! Rotary position embedding
+//!
+//! This module applies rotary position embeddings (RoPE) to queries and
+//! keys in the "rotate half" layout used by Qwen2 and most Hugging Face
+//! models, with cos/sin tables precomputed for every position.
+
+use candle_core::{DType, Device, Result, Tensor, D};
+use common::config::Config;
+
+/// Rotary position embedding with precomputed cos/sin tables
+#[derive(Debug, Clone)]
+pub struct RotaryEmbedding {
+    /// Inverse frequency of each rotated pair, of shape `[head_dim / 2]`
+    inv_freq: Tensor,
+
+    /// Cosine of every position and frequency, of shape `[max_position, head_dim / 2]`
+    cos: Tensor,
+
+    /// Sine of every position and frequency, of shape `[max_position, head_dim / 2]`
+    sin: Tensor,
+}
+
+impl RotaryEmbedding {
+    /// Creates a rotary embedding for the given head dimension and base
+    ///
+    /// The inverse frequencies are `theta^(-2i / head_dim)` for
+    /// `i in 0..head_dim / 2`.
+    ///
+    /// # Arguments
+    ///
+    /// * `head_dim` - Dimension of each attention head; must be even
+    /// * `max_position` - Number of positions the tables cover
+    /// * `theta` - Base of the frequencies
+    /// * `device` - Device on which the tables are created
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `head_dim` is odd or a tensor cannot be created.
+    pub fn new(head_dim: usize, max_position: usize, theta: f64, device: &Device) -> Result<Self> {
+        if !head_dim.is_multiple_of(2) {
+            candle_core::bail!("rotary embedding needs an even head_dim, got {}", head_dim);
+        }
+        let inv_freq: Vec<f32> = (0..head_dim / 2)
+            .map(|i| 1.0 / theta.powf(2.0 * i as f64 / head_dim as f64) as f32)
+            .collect();
+        let inv_freq = Tensor::from_vec(inv_freq, head_dim / 2, device)?;
+        let positions = Tensor::arange(0u32, max_position as u32, device)?.to_dtype(DType::F32)?;
+        let freqs = positions.unsqueeze(1)?.broadcast_mul(&inv_freq.unsqueeze(0)?)?;
+        Ok(Self {
+            inv_freq,
+            cos: freqs.cos()?,
+            sin: freqs.sin()?,
+        })
+    }
+
+    /// Creates the rotary embedding described by a configuration
+    ///
+    /// Uses `Config::head_dim`, `max_model_len` positions, and
+    /// `Config::rope_theta`, so `rope_theta_override` takes precedence over
+    /// the model's value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the head dimension is odd or a tensor cannot be
+    /// created.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hf_config` is not loaded
+    pub fn from_config(config: &Config, device: &Device) -> Result<Self> {
+        Self::new(config.head_dim(), config.max_model_len, config.rope_theta(), device)
+    }
+
+    /// Returns the inverse frequency of each rotated pair
+    pub fn inv_freq(&self) -> &Tensor {
+        &self.inv_freq
+    }
+
+    /// Rotates queries and keys by the angles of their positions
+    ///
+    /// # Arguments
+    ///
+    /// * `positions` - U32 position of each token, of shape `[num_tokens]`
+    /// * `q` - Queries of shape `[num_tokens, num_heads, head_dim]`
+    /// * `k` - Keys of shape `[num_tokens, num_kv_heads, head_dim]`
+    ///
+    /// # Returns
+    ///
+    /// The rotated queries and keys, in the dtype of the inputs
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a position is outside the tables or the shapes
+    /// do not match.
+    pub fn forward(&self, positions: &Tensor, q: &Tensor, k: &Tensor) -> Result<(Tensor, Tensor)> {
+        let cos = self.cos.index_select(positions, 0)?.unsqueeze(1)?;
+        let sin = self.sin.index_select(positions, 0)?.unsqueeze(1)?;
+        Ok((rotate(q, &cos, &sin)?, rotate(k, &cos, &sin)?))
+    }
+}
+
+/// Rotates the two halves of the last dimension of `x` as pairs
+fn rotate(x: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+    let half = x.dim(D::Minus1)? / 2;
+    let x_f32 = x.to_dtype(DType::F32)?;
+    let x1 = x_f32.narrow(D::Minus1, 0, half)?;
+    let x2 = x_f32.narrow(D::Minus1, half, half)?;
+    let rotated = Tensor::cat(
+        &[
+            (x1.broadcast_mul(cos)? - x2.broadcast_mul(sin)?)?,
+            (x2.broadcast_mul(cos)? + x1.broadcast_mul(sin)?)?,
+        ],
+        D::Minus1,
+    )?;
+    rotated.to_dtype(x.dtype())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::hf_config::HfConfig;
+
+    #[test]
+    fn rope_theta_override_sets_the_frequencies() {
+        let json = r#"{
+            "architectures": ["Qwen2ForCausalLM"],
+            "vocab_size": 1000, "hidden_size": 64, "intermediate_size": 128,
+            "num_hidden_layers": 2, "num_attention_heads": 4, "num_key_value_heads": 2,
+            "max_position_embeddings": 4096, "sliding_window": 4096, "max_window_layers": 2,
+            "tie_word_embeddings": true, "rope_theta": 1000000.0, "rms_norm_eps": 1e-6,
+            "use_sliding_window": false, "hidden_act": "silu"
+        }"#;
+        let mut config = Config {
+            max_model_len: 32,
+            hf_config: Some(HfConfig::from_json(json).unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(config.rope_theta(), 1_000_000.0);
+
+        config.rope_theta_override = Some(10_000.0);
+        let rope = RotaryEmbedding::from_config(&config, &Device::Cpu).unwrap();
+        let inv_freq = rope.inv_freq().to_vec1::<f32>().unwrap();
+        assert_eq!(inv_freq.len(), 8);
+        // inv_freq[i] = theta^(-2i / head_dim) with head_dim 16
+        assert!((inv_freq[1] - 10_000f32.powf(-2.0 / 16.0)).abs() < 1e-7);
+    }
+
+    #[test]
+    fn rotation_is_identity_at_position_zero_and_keeps_norms() {
+        let rope = RotaryEmbedding::new(4, 8, 10_000.0, &Device::Cpu).unwrap();
+        let q = Tensor::new(&[[[1f32, 2., 3., 4.]], [[1., 2., 3., 4.]]], &Device::Cpu).unwrap();
+        let positions = Tensor::new(&[0u32, 5], &Device::Cpu).unwrap();
+        let (q_rot, _) = rope.forward(&positions, &q, &q).unwrap();
+        let rows = q_rot.squeeze(1).unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(rows[0], vec![1., 2., 3., 4.]);
+        let norm = |row: &[f32]| row.iter().map(|x| x * x).sum::<f32>();
+        assert!((norm(&rows[1]) - 30.0).abs() < 1e-4);
+        assert_ne!(rows[1], rows[0]);
+    }
+}
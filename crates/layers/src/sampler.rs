@@ -0,0 +1,694 @@
+//! Token sampling from model logits
+//!
+//! This module turns the logits produced by the language model head into
+//! token IDs, applying per-sequence temperatures and falling back to greedy
+//! decoding for sequences with a temperature of zero. Sequences with a seed
+//! sample from their own deterministic random stream.
+
+use candle_core::{DType, Result, Tensor, D};
+use common::sequence::Sequence;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Default lower bound for sampling temperatures
+///
+/// Returns 0.0, which still allows greedy decoding.
+fn default_min_temperature() -> f32 { 0.0 }
+
+/// Default upper bound for sampling temperatures
+///
+/// Returns 10.0, which is permissive enough that only clearly unreasonable
+/// temperatures are clamped.
+fn default_max_temperature() -> f32 { 10.0 }
+
+/// Sampler that selects the next token for each sequence in a batch
+///
+/// Temperatures outside the configured bounds are clamped rather than
+/// rejected, with a warning emitted once per distinct out-of-range value.
+#[derive(Debug)]
+pub struct Sampler {
+    /// Smallest temperature that will be used for sampling
+    min_temperature: f32,
+
+    /// Largest temperature that will be used for sampling
+    max_temperature: f32,
+
+    /// Out-of-range temperatures that have already been warned about,
+    /// stored by their bit pattern
+    warned_temperatures: Mutex<HashSet<u32>>,
+
+    /// State of the random stream of each seeded sequence, keyed by seq_id
+    seeded_streams: Mutex<HashMap<usize, u64>>,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self::with_temperature_bounds(default_min_temperature(), default_max_temperature())
+    }
+}
+
+impl Sampler {
+    /// Creates a new Sampler with the default temperature bounds of `[0.0, 10.0]`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new Sampler that clamps temperatures into `[min, max]`
+    ///
+    /// # Arguments
+    ///
+    /// * `min` - Smallest temperature that will be used for sampling
+    /// * `max` - Largest temperature that will be used for sampling
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is negative or greater than `max`
+    pub fn with_temperature_bounds(min: f32, max: f32) -> Self {
+        assert!(min >= 0.0 && min <= max, "Invalid temperature bounds [{}, {}]", min, max);
+        Self {
+            min_temperature: min,
+            max_temperature: max,
+            warned_temperatures: Mutex::new(HashSet::new()),
+            seeded_streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Clamps a temperature into the configured bounds
+    ///
+    /// NaN is treated as out of range and mapped to the lower bound. A
+    /// warning is logged the first time each distinct out-of-range
+    /// temperature is seen.
+    pub fn clamp_temperature(&self, temperature: f32) -> f32 {
+        let clamped = if temperature.is_nan() {
+            self.min_temperature
+        } else {
+            temperature.clamp(self.min_temperature, self.max_temperature)
+        };
+        // NaN never compares equal, so it is always reported.
+        if clamped != temperature
+            && self.warned_temperatures.lock().unwrap().insert(temperature.to_bits())
+        {
+            log::warn!(
+                "Temperature {} is outside [{}, {}], clamping to {}",
+                temperature, self.min_temperature, self.max_temperature, clamped
+            );
+        }
+        clamped
+    }
+
+    /// Samples one token per row of the logits
+    ///
+    /// Rows with a (clamped) temperature of zero are decoded greedily. All
+    /// other rows are scaled by their temperature and sampled from the
+    /// resulting softmax distribution using the exponential-race trick,
+    /// `argmax(probs / Exp(1))`, which is equivalent to multinomial sampling.
+    ///
+    /// # Arguments
+    ///
+    /// * `logits` - Logits of shape `[batch, vocab_size]`
+    /// * `temperatures` - Temperature for each row of the batch
+    ///
+    /// # Returns
+    ///
+    /// The sampled token ID for each row of the batch
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the number of temperatures does not match the
+    /// batch size or if any tensor operation fails.
+    pub fn forward(&self, logits: &Tensor, temperatures: &[f32]) -> Result<Vec<u32>> {
+        self.forward_seeded(logits, temperatures, &vec![None; temperatures.len()])
+    }
+
+    /// Samples one token per row, drawing seeded rows from per-sequence streams
+    ///
+    /// Behaves like `forward`, except that rows with a `(seq_id, seed)` entry
+    /// are sampled with `gumbel_max`, drawing their noise from a random
+    /// stream keyed by `seq_id`, so no softmax is computed for them.
+    /// The stream is created from `seed` the first time the sequence is seen
+    /// and advances by one draw per vocabulary entry on every step, so a
+    /// sequence's samples depend only on its seed and its own history, not
+    /// on how it was batched. Call `release` once the sequence has finished.
+    ///
+    /// # Arguments
+    ///
+    /// * `logits` - Logits of shape `[batch, vocab_size]`
+    /// * `temperatures` - Temperature for each row of the batch
+    /// * `seeds` - Sequence ID and seed for each seeded row, None otherwise
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the number of temperatures or seeds does not
+    /// match the batch size, if every token of a row is masked, or if any
+    /// tensor operation fails.
+    pub fn forward_seeded(
+        &self,
+        logits: &Tensor,
+        temperatures: &[f32],
+        seeds: &[Option<(usize, u64)>],
+    ) -> Result<Vec<u32>> {
+        let (batch, _vocab_size) = logits.dims2()?;
+        if temperatures.len() != batch {
+            candle_core::bail!("expected {} temperatures, got {}", batch, temperatures.len());
+        }
+        if seeds.len() != batch {
+            candle_core::bail!("expected {} seeds, got {}", batch, seeds.len());
+        }
+        let temperatures: Vec<f32> = temperatures.iter().map(|&t| self.clamp_temperature(t)).collect();
+        check_unmasked_rows(logits)?;
+
+        let mut tokens = greedy(logits)?;
+        if temperatures.iter().all(|&t| t == 0.0) {
+            return Ok(tokens);
+        }
+        let logits = logits.to_dtype(DType::F32)?;
+
+        let is_random = |row: usize, seeded: bool| temperatures[row] != 0.0 && seeds[row].is_some() == seeded;
+        if (0..batch).any(|row| is_random(row, true)) {
+            let rows = logits.to_vec2::<f32>()?;
+            let mut streams = self.seeded_streams.lock().unwrap();
+            for row in (0..batch).filter(|&row| is_random(row, true)) {
+                // Safe to unwrap since is_random only accepts seeded rows here.
+                let (seq_id, seed) = seeds[row].unwrap();
+                let state = streams.entry(seq_id).or_insert(seed);
+                tokens[row] = gumbel_max(&rows[row], temperatures[row], state);
+            }
+        }
+        if (0..batch).any(|row| is_random(row, false)) {
+            // Greedy and seeded rows are divided by one; their sampled values are discarded below.
+            let divisors: Vec<f32> = (0..batch).map(|row| if is_random(row, false) { temperatures[row] } else { 1.0 }).collect();
+            let divisors = Tensor::from_vec(divisors, (batch, 1), logits.device())?;
+            let probs = stable_softmax(&logits.broadcast_div(&divisors)?)?;
+            let noise = Tensor::rand(0f32, 1f32, probs.shape(), probs.device())?
+                .clamp(1e-10f32, 1f32)?
+                .log()?
+                .neg()?;
+            let sampled = (&probs / noise)?.argmax(D::Minus1)?.to_vec1::<u32>()?;
+            for row in (0..batch).filter(|&row| is_random(row, false)) {
+                tokens[row] = sampled[row];
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Samples one token per row using the parameters stored on each sequence
+    ///
+    /// Every row is sampled with its own sequence's temperature, top-k,
+    /// top-p, and seed, so a batch may mix greedy, nucleus, and
+    /// high-temperature rows. Top-k and then nucleus filtering are applied
+    /// at each row's temperature before the rows are handed to
+    /// `forward_seeded`.
+    ///
+    /// # Arguments
+    ///
+    /// * `logits` - Logits of shape `[batch, vocab_size]`
+    /// * `seqs` - Sequence of each row of the batch
+    ///
+    /// # Returns
+    ///
+    /// The sampled token ID for each row of the batch
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the number of sequences does not match the batch
+    /// size, if every token of a row is masked, or if any tensor operation
+    /// fails.
+    pub fn forward_sequences(&self, logits: &Tensor, seqs: &[&Sequence]) -> Result<Vec<u32>> {
+        let temperatures: Vec<f32> = seqs.iter().map(|seq| self.clamp_temperature(seq.temperature)).collect();
+        let top_k: Vec<usize> = seqs.iter().map(|seq| seq.sampling_params.top_k).collect();
+        let top_p: Vec<f32> = seqs.iter().map(|seq| seq.sampling_params.top_p).collect();
+        let seeds: Vec<_> = seqs.iter().map(|seq| seq.seed.map(|seed| (seq.seq_id, seed))).collect();
+        let logits = apply_top_k(logits, &top_k)?;
+        let logits = apply_top_p(&logits, &top_p, &temperatures)?;
+        self.forward_seeded(&logits, &temperatures, &seeds)
+    }
+
+    /// Drops the random stream of a finished sequence
+    pub fn release(&self, seq_id: usize) {
+        self.seeded_streams.lock().unwrap().remove(&seq_id);
+    }
+}
+
+/// Masks every logit below the `k`-th largest of its row
+///
+/// The threshold is found with a linear-time selection on a host copy of
+/// each row instead of sorting the whole vocabulary. Logits equal to the
+/// threshold are all kept, so ties can leave more than `k` candidates but
+/// never depend on the order of the vocabulary.
+///
+/// # Arguments
+///
+/// * `logits` - Logits of shape `[batch, vocab_size]`
+/// * `top_k` - Number of candidates kept for each row; 0 keeps every token
+///
+/// # Returns
+///
+/// The logits with the masked entries set to negative infinity, in the
+/// dtype and on the device of `logits`
+pub fn apply_top_k(logits: &Tensor, top_k: &[usize]) -> Result<Tensor> {
+    let (batch, vocab_size) = logits.dims2()?;
+    if top_k.len() != batch {
+        candle_core::bail!("expected {} top_k values, got {}", batch, top_k.len());
+    }
+    if top_k.iter().all(|&k| k == 0 || k >= vocab_size) {
+        return Ok(logits.clone());
+    }
+
+    let mut rows = logits.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+    for (row, &k) in rows.iter_mut().zip(top_k) {
+        if k == 0 || k >= vocab_size {
+            continue;
+        }
+        let mut scratch = row.clone();
+        let (_, &mut threshold, _) = scratch.select_nth_unstable_by(k - 1, |a, b| b.total_cmp(a));
+        for logit in row.iter_mut().filter(|logit| **logit < threshold) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+    Tensor::new(rows, logits.device())?.to_dtype(logits.dtype())
+}
+
+/// Masks every token outside the nucleus of its row
+///
+/// The nucleus is the smallest set of most likely tokens whose probability
+/// at the row's temperature reaches `top_p`. Tokens as likely as the least
+/// likely member are kept too, so ties never depend on the order of the
+/// vocabulary. Rows with a temperature of zero are left untouched, since
+/// greedy decoding only ever picks the most likely token.
+///
+/// # Arguments
+///
+/// * `logits` - Logits of shape `[batch, vocab_size]`
+/// * `top_p` - Probability mass kept for each row; 1.0 keeps every token
+/// * `temperatures` - Temperature at which each row will be sampled
+///
+/// # Returns
+///
+/// The logits with the masked entries set to negative infinity, in the
+/// dtype and on the device of `logits`
+pub fn apply_top_p(logits: &Tensor, top_p: &[f32], temperatures: &[f32]) -> Result<Tensor> {
+    let (batch, _vocab_size) = logits.dims2()?;
+    if top_p.len() != batch || temperatures.len() != batch {
+        candle_core::bail!("expected {} top_p values and temperatures, got {} and {}", batch, top_p.len(), temperatures.len());
+    }
+    let is_filtered = |row: usize| top_p[row] < 1.0 && temperatures[row] != 0.0;
+    if !(0..batch).any(is_filtered) {
+        return Ok(logits.clone());
+    }
+
+    let mut rows = logits.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+    for (i, row) in rows.iter_mut().enumerate().filter(|(i, _)| is_filtered(*i)) {
+        let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let mut probs: Vec<f32> = row.iter().map(|&logit| ((logit - max) / temperatures[i]).exp()).collect();
+        let total: f32 = probs.iter().sum();
+        probs.sort_by(|a, b| b.total_cmp(a));
+
+        let mut mass = 0.0;
+        let mut threshold = probs[0];
+        for &p in &probs {
+            threshold = p;
+            mass += p / total;
+            if mass >= top_p[i] {
+                break;
+            }
+        }
+        for logit in row.iter_mut() {
+            if ((*logit - max) / temperatures[i]).exp() < threshold {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+    Tensor::new(rows, logits.device())?.to_dtype(logits.dtype())
+}
+
+/// Rejects rows in which every token has been masked
+///
+/// Stacked constraints such as top-k, a minimum length, and a grammar can
+/// leave a row entirely at negative infinity. Its softmax is then `nan` and
+/// any token it produced would be arbitrary, so the row is reported as an
+/// over-constrained distribution instead.
+///
+/// # Arguments
+///
+/// * `logits` - Logits of shape `[batch, vocab_size]`
+///
+/// # Errors
+///
+/// Returns an error naming the first row whose maximum logit is negative
+/// infinity or `nan`.
+pub fn check_unmasked_rows(logits: &Tensor) -> Result<()> {
+    let maxima = logits.max(D::Minus1)?.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    if let Some(row) = maxima.iter().position(|&max| max == f32::NEG_INFINITY || max.is_nan()) {
+        candle_core::bail!(
+            "every token of row {} is masked; the sampling constraints leave no token to sample",
+            row
+        );
+    }
+    Ok(())
+}
+
+/// Picks the highest-scoring token of every row
+///
+/// The argmax is taken over the raw logits of the whole batch in a single
+/// call, without any dtype conversion, temperature scaling, or softmax,
+/// since none of them change which token scores highest.
+///
+/// Ties are broken towards the lowest token ID. Backends do not agree on
+/// which index `argmax` returns for equal values, so the row maximum is
+/// found first and the smallest index reaching it is selected, which keeps
+/// greedy outputs identical across devices.
+///
+/// # Arguments
+///
+/// * `logits` - Logits of shape `[batch, vocab_size]`
+///
+/// # Returns
+///
+/// The index of the largest logit in each row
+pub fn greedy(logits: &Tensor) -> Result<Vec<u32>> {
+    let (batch, vocab_size) = logits.dims2()?;
+    let is_max = logits.broadcast_eq(&logits.max_keepdim(D::Minus1)?)?;
+    let indices = Tensor::arange(0u32, vocab_size as u32, logits.device())?.broadcast_as((batch, vocab_size))?;
+    let past_end = Tensor::full(vocab_size as u32, (batch, vocab_size), logits.device())?;
+    is_max.where_cond(&indices, &past_end)?.min(D::Minus1)?.to_vec1::<u32>()
+}
+
+/// Derives the seed of the `index`-th sequence sampled from a base seed
+///
+/// The index is mixed into the base seed with a bijective hash, so distinct
+/// indices always yield distinct seeds and therefore distinct streams.
+/// Deriving from the index rather than the globally assigned `seq_id` keeps
+/// the seeds identical when a request is re-run.
+pub fn sequence_seed(base_seed: u64, index: usize) -> u64 {
+    mix64(base_seed ^ index as u64)
+}
+
+/// SplitMix64 finalizer, a bijection on 64-bit integers
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Maps random bits to a value in the open interval `(0, 1)`
+///
+/// The top 23 bits select one of 2^23 equal cells and the value is the
+/// cell's centre, so neither 0 nor 1 is ever returned. With 24 bits the top
+/// cell's centre would round up to 1.0 in F32.
+fn unit_interval(bits: u64) -> f32 {
+    ((bits >> 41) as f32 + 0.5) / (1u32 << 23) as f32
+}
+
+/// Advances a SplitMix64 stream and returns a uniform value in `(0, 1)`
+fn next_uniform(state: &mut u64) -> f32 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    unit_interval(mix64(*state))
+}
+
+/// Samples a token with the Gumbel-max trick, without a softmax
+///
+/// Adds i.i.d. Gumbel noise `-ln(-ln(u))` to the temperature-scaled logits
+/// and takes the argmax, which picks each token with its softmax
+/// probability but never normalizes the row. Masked logits stay negative
+/// infinity whatever noise they get, so they are never picked. The noise
+/// for token `i` is the `i`-th draw from `state`, so the result depends
+/// only on the stream and the logits; it equals the exponential race
+/// `argmax(probs / Exp(1))` over the same draws.
+///
+/// # Arguments
+///
+/// * `logits` - Logits of one row of the vocabulary
+/// * `temperature` - Positive temperature the logits are divided by
+/// * `state` - Random stream, advanced by one draw per logit
+///
+/// # Returns
+///
+/// The index of the sampled token
+pub fn gumbel_max(logits: &[f32], temperature: f32, state: &mut u64) -> u32 {
+    let mut best = (0, f32::NEG_INFINITY);
+    for (token_id, &logit) in logits.iter().enumerate() {
+        // Draws lie strictly inside (0, 1), so the noise is always finite.
+        let exponential = -next_uniform(state).ln();
+        let score = logit / temperature - exponential.ln();
+        if score > best.1 {
+            best = (token_id as u32, score);
+        }
+    }
+    best.0
+}
+
+/// Numerically stable softmax over the last dimension
+///
+/// Computed in F32 as `exp(x - max) / sum(exp(x - max))`, so large logits
+/// never overflow and half-precision inputs keep full precision in the
+/// normalization. Sampling filters such as top-p and min-p build on it.
+///
+/// # Arguments
+///
+/// * `logits` - Logits of any dtype; the last dimension is normalized
+///
+/// # Returns
+///
+/// F32 probabilities with the same shape as `logits`
+pub fn stable_softmax(logits: &Tensor) -> Result<Tensor> {
+    let logits = logits.to_dtype(DType::F32)?;
+    let max = logits.max_keepdim(D::Minus1)?;
+    let exp = logits.broadcast_sub(&max)?.exp()?;
+    exp.broadcast_div(&exp.sum_keepdim(D::Minus1)?)
+}
+
+/// Numerically stable log-softmax over the last dimension
+///
+/// Computed in F32 as `x - max - log(sum(exp(x - max)))`, so large logits
+/// never overflow.
+///
+/// # Arguments
+///
+/// * `logits` - Logits of any dtype; the last dimension is normalized
+///
+/// # Returns
+///
+/// F32 log-probabilities with the same shape as `logits`
+pub fn log_softmax(logits: &Tensor) -> Result<Tensor> {
+    let logits = logits.to_dtype(DType::F32)?;
+    let shifted = logits.broadcast_sub(&logits.max_keepdim(D::Minus1)?)?;
+    let log_sum_exp = shifted.exp()?.sum_keepdim(D::Minus1)?.log()?;
+    shifted.broadcast_sub(&log_sum_exp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+    use common::sampling::SamplingParams;
+
+    #[test]
+    fn out_of_range_temperature_is_clamped_to_bound() {
+        let sampler = Sampler::with_temperature_bounds(0.0, 5.0);
+        assert_eq!(sampler.clamp_temperature(50.0), 5.0);
+        assert_eq!(sampler.clamp_temperature(2.0), 2.0);
+        assert!(sampler.warned_temperatures.lock().unwrap().contains(&50f32.to_bits()));
+
+        assert_eq!(sampler.clamp_temperature(f32::NAN), 0.0);
+
+        // Every temperature is clamped to zero, so sampling is greedy; at the
+        // requested 50 the 64 rows would almost surely differ.
+        let greedy_only = Sampler::with_temperature_bounds(0.0, 0.0);
+        let logits = Tensor::new(&[[1f32, 2., 3.]], &Device::Cpu).unwrap().repeat((64, 1)).unwrap();
+        let mut temperatures = vec![50.0; 64];
+        temperatures[0] = f32::NAN;
+        let tokens = greedy_only.forward(&logits, &temperatures).unwrap();
+        assert_eq!(tokens, vec![2; 64]);
+    }
+
+    #[test]
+    fn zero_temperature_is_greedy() {
+        let sampler = Sampler::new();
+        let logits = Tensor::new(&[[1f32, 5., 3.], [9., 2., 3.]], &Device::Cpu).unwrap();
+        assert_eq!(sampler.forward(&logits, &[0.0, 0.0]).unwrap(), vec![1, 0]);
+    }
+
+    #[test]
+    fn top_k_matches_full_sort() {
+        let vocab_size = 32_000;
+        // Coarse values so that many logits tie at the threshold.
+        let row = |seed: u64| -> Vec<f32> {
+            let mut state = seed;
+            (0..vocab_size).map(|_| (next_uniform(&mut state) * 64.0).floor()).collect()
+        };
+        let rows = vec![row(1), row(2), row(3)];
+        let top_k = [50, 0, 1];
+        let logits = Tensor::new(rows.clone(), &Device::Cpu).unwrap();
+        let masked = apply_top_k(&logits, &top_k).unwrap().to_vec2::<f32>().unwrap();
+
+        for ((row, masked), &k) in rows.iter().zip(&masked).zip(&top_k) {
+            let mut sorted = row.clone();
+            sorted.sort_by(|a, b| b.total_cmp(a));
+            let threshold = if k == 0 { f32::NEG_INFINITY } else { sorted[k - 1] };
+            let expected: Vec<f32> = row
+                .iter()
+                .map(|&logit| if logit < threshold { f32::NEG_INFINITY } else { logit })
+                .collect();
+            assert_eq!(masked, &expected);
+        }
+    }
+
+    #[test]
+    fn greedy_picks_max_logit_without_upcast() {
+        let logits = Tensor::new(&[[0.5f32, -2., 7.25, 7.], [3., 1., 2., -1.]], &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::BF16)
+            .unwrap();
+        assert_eq!(greedy(&logits).unwrap(), vec![2, 0]);
+    }
+
+    #[test]
+    fn greedy_breaks_ties_towards_lowest_token_id() {
+        let logits = Tensor::new(&[[1f32, 4., 0., 4., 4.], [2., 2., 2., 2., 2.]], &Device::Cpu).unwrap();
+        assert_eq!(greedy(&logits).unwrap(), vec![1, 0]);
+    }
+
+    #[test]
+    fn stable_softmax_handles_huge_logits() {
+        let logits = Tensor::new(&[[3e38f32, 1e38, -3e38], [60000., 60000., 0.]], &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::BF16)
+            .unwrap();
+        let probs = stable_softmax(&logits).unwrap();
+        assert_eq!(probs.dtype(), DType::F32);
+        for row in probs.to_vec2::<f32>().unwrap() {
+            assert!(row.iter().all(|p| p.is_finite()));
+            assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn log_softmax_matches_reference() {
+        let logits = Tensor::new(&[[1000f32, 1000., 1000., 1000.]], &Device::Cpu).unwrap();
+        let log_probs = log_softmax(&logits).unwrap().to_vec2::<f32>().unwrap();
+        for lp in &log_probs[0] {
+            assert!((lp - (0.25f32).ln()).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn seeded_rows_are_reproducible() {
+        let logits = Tensor::zeros((2, 64), DType::F32, &Device::Cpu).unwrap();
+        let seeds = [Some((1, sequence_seed(7, 0))), Some((2, sequence_seed(7, 0)))];
+        let run = || {
+            let sampler = Sampler::new();
+            (0..8)
+                .map(|_| sampler.forward_seeded(&logits, &[1.0, 1.0], &seeds).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let first = run();
+        assert_eq!(first, run());
+        // Equal seeds give equal streams even for different sequences.
+        assert!(first.iter().all(|tokens| tokens[0] == tokens[1]));
+        assert_ne!(sequence_seed(7, 0), sequence_seed(7, 1));
+    }
+
+    #[test]
+    fn fully_masked_row_is_an_error() {
+        let sampler = Sampler::new();
+        let logits = Tensor::new(&[[1f32, 5., 3.], [f32::NEG_INFINITY; 3]], &Device::Cpu).unwrap();
+        for temperature in [0.0, 1.0] {
+            let err = sampler.forward(&logits, &[temperature; 2]).unwrap_err();
+            assert!(err.to_string().contains("row 1 is masked"), "{}", err);
+        }
+
+        let masked = apply_top_k(&logits, &[1, 0]).unwrap();
+        assert!(check_unmasked_rows(&masked.narrow(0, 0, 1).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn mixed_params_batch_matches_rows_sampled_alone() {
+        let seq = |seq_id: usize, temperature: f32, top_p: f32| {
+            let params = SamplingParams { temperature, top_p, seed: Some(11), ..Default::default() };
+            let mut seq = Sequence::new(vec![1], params);
+            seq.seq_id = seq_id;
+            seq
+        };
+        let seqs = [seq(0, 0.0, 1.0), seq(1, 1.0, 0.9), seq(2, 5.0, 1.0)];
+        let rows: Vec<Vec<f32>> = (0..3u64)
+            .map(|row| {
+                let mut state = row;
+                (0..64).map(|_| next_uniform(&mut state) * 8.0).collect()
+            })
+            .collect();
+        let logits = Tensor::new(rows.clone(), &Device::Cpu).unwrap();
+
+        let batched = Sampler::new();
+        let alone = Sampler::new();
+        for _ in 0..8 {
+            let tokens = batched.forward_sequences(&logits, &seqs.iter().collect::<Vec<_>>()).unwrap();
+            for (i, seq) in seqs.iter().enumerate() {
+                let row = logits.narrow(0, i, 1).unwrap();
+                assert_eq!(alone.forward_sequences(&row, &[seq]).unwrap(), vec![tokens[i]]);
+            }
+            assert_eq!(tokens[0], greedy(&logits).unwrap()[0]);
+            let nucleus = apply_top_p(&logits.narrow(0, 1, 1).unwrap(), &[0.9], &[1.0]).unwrap();
+            assert!(nucleus.to_vec2::<f32>().unwrap()[0][tokens[1] as usize].is_finite());
+        }
+    }
+
+    #[test]
+    fn sequence_top_k_limits_the_candidates() {
+        let params = SamplingParams { temperature: 5.0, top_k: 2, seed: Some(3), ..Default::default() };
+        let seq = Sequence::new(vec![1], params);
+        let logits = Tensor::new(&[[0f32, 3., 1., 2.9, 0.5]], &Device::Cpu).unwrap();
+        let sampler = Sampler::new();
+        let tokens: HashSet<u32> = (0..64)
+            .map(|_| sampler.forward_sequences(&logits, &[&seq]).unwrap()[0])
+            .collect();
+        assert_eq!(tokens, HashSet::from([1, 3]));
+    }
+
+    #[test]
+    fn top_p_keeps_the_smallest_nucleus() {
+        let probs = [0.5f32, 0.3, 0.15, 0.05];
+        let logits = Tensor::new(&[probs.map(f32::ln), probs.map(f32::ln)], &Device::Cpu).unwrap();
+        let masked = apply_top_p(&logits, &[0.8, 0.8], &[1.0, 0.0]).unwrap().to_vec2::<f32>().unwrap();
+        let kept: Vec<bool> = masked[0].iter().map(|logit| logit.is_finite()).collect();
+        assert_eq!(kept, vec![true, true, false, false]);
+        // Greedy rows are not filtered.
+        assert!(masked[1].iter().all(|logit| logit.is_finite()));
+    }
+
+    #[test]
+    fn mismatched_temperatures_are_rejected() {
+        let sampler = Sampler::new();
+        let logits = Tensor::new(&[[1f32, 5., 3.]], &Device::Cpu).unwrap();
+        assert!(sampler.forward(&logits, &[0.5, 0.5]).is_err());
+    }
+
+    #[test]
+    fn uniform_draws_exclude_both_endpoints() {
+        assert!(unit_interval(0) > 0.0);
+        assert!(unit_interval(u64::MAX) < 1.0);
+        // The extreme draws give finite Gumbel noise.
+        assert!((-(-unit_interval(u64::MAX).ln()).ln()).is_finite());
+        assert!((-(-unit_interval(0).ln()).ln()).is_finite());
+    }
+
+    #[test]
+    fn gumbel_max_matches_the_softmax_distribution() {
+        let logits = [1f32, 2., 0.5, f32::NEG_INFINITY];
+        for temperature in [1.0, 2.0] {
+            let scaled = Tensor::new(&[logits.map(|logit| logit / temperature)], &Device::Cpu).unwrap();
+            let expected = stable_softmax(&scaled).unwrap().to_vec2::<f32>().unwrap().remove(0);
+
+            let num_seeds = 20_000;
+            let mut counts = [0usize; 4];
+            for seed in 0..num_seeds {
+                let mut state = sequence_seed(42, seed);
+                counts[gumbel_max(&logits, temperature, &mut state) as usize] += 1;
+            }
+            assert_eq!(counts[3], 0);
+            for (count, p) in counts.iter().zip(&expected) {
+                assert!((*count as f32 / num_seeds as f32 - p).abs() < 0.015, "{:?} vs {:?}", counts, expected);
+            }
+        }
+    }
+}
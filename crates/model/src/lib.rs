@@ -1,14 +1,39 @@
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
-}
+//! Model interfaces for the candle-nano-vllm project
+//!
+//! This crate defines the interface that language models implement so that
+//! the engine can drive them without knowing their architecture.
+
+use cache::KvCache;
+use candle_core::{Result, Tensor};
+
+/// A causal language model that can be driven by the engine
+///
+/// Implementations receive the flattened tokens of every scheduled sequence
+/// and return one row of logits per input token. Paged attention metadata
+/// (sequence boundaries, slot mapping, block tables) is published through the
+/// global execution context before `forward` is called.
+pub trait CausalLM {
+    /// Runs the model over a flattened batch of tokens
+    ///
+    /// # Arguments
+    ///
+    /// * `input_ids` - Token IDs of shape `[num_tokens]`
+    /// * `positions` - Position of each token within its sequence, shape `[num_tokens]`
+    ///
+    /// # Returns
+    ///
+    /// Logits of shape `[num_tokens, vocab_size]`
+    fn forward(&mut self, input_ids: &Tensor, positions: &Tensor) -> Result<Tensor>;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Returns the size of the model's vocabulary
+    fn vocab_size(&self) -> usize;
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+    /// Returns the model's paged KV cache, if it has one
+    ///
+    /// The engine copies blocks in and out of this cache when it swaps
+    /// preempted sequences to the CPU, so models with a KV cache must expose
+    /// it to be served with a swap pool. The default returns None.
+    fn kv_cache(&self) -> Option<&KvCache> {
+        None
     }
 }
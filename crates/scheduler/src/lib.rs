@@ -0,0 +1,12 @@
+//! Scheduling for the candle-nano-vllm project
+//!
+//! This crate decides which sequences are prefilled or decoded in each
+//! engine step, based on the batch limits and the available KV cache blocks.
+
+mod scheduler;
+
+/// Re-exports from the scheduler module
+///
+/// These exports provide the Scheduler, the batch type it produces, and the
+/// error it reports when the KV cache is full.
+pub use scheduler::{NothingScheduled, ScheduledBatch, Scheduler};
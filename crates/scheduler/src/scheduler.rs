@@ -0,0 +1,726 @@
+//! Request scheduling for continuous batching
+//!
+//! This module decides which sequences run in each engine step. Waiting
+//! sequences are admitted for prefill while KV cache blocks and the batch
+//! budget allow it, with long prompts prefilled in chunks over several
+//! steps; otherwise running sequences are batched for decode,
+//! preempting the most recently admitted ones when the cache is full.
+//! Preempted sequences are swapped out to the CPU pool when it has room and
+//! recomputed from their prompt otherwise.
+
+use anyhow::Result;
+use cache::BlockManager;
+use common::config::Config;
+use common::sequence::{FinishReason, Sequence, SequenceStatus, StopFn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::Instant;
+
+/// A batch of sequences selected for a single engine step
+///
+/// The sequences are moved out of the scheduler's queues while the batch
+/// is being executed and handed back through `Scheduler::postprocess`.
+#[derive(Debug)]
+pub struct ScheduledBatch {
+    /// Sequences to run in this step
+    pub seqs: Vec<Sequence>,
+
+    /// Whether this step processes prompts (prefill) or single tokens (decode)
+    pub is_prefill: bool,
+
+    /// Number of tokens processed for each sequence in this step
+    ///
+    /// A prefill entry smaller than the sequence's uncached tokens marks a
+    /// partial prefill; decode entries are always 1.
+    pub num_new_tokens: Vec<usize>,
+
+    /// `(cpu_block_id, gpu_block_id)` copies to perform before this step
+    pub blocks_to_swap_in: Vec<(usize, usize)>,
+
+    /// `(gpu_block_id, cpu_block_id)` copies to perform before this step
+    pub blocks_to_swap_out: Vec<(usize, usize)>,
+}
+
+/// Error returned by `Scheduler::schedule` when no sequence fits in the
+/// KV cache
+///
+/// Every other scheduling error is a bug, so callers can tell a full cache
+/// apart by downcasting to this type.
+#[derive(Debug)]
+pub struct NothingScheduled;
+
+impl fmt::Display for NothingScheduled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("No sequences could be scheduled")
+    }
+}
+
+impl std::error::Error for NothingScheduled {}
+
+/// Stop conditions of unfinished sequences, keyed by sequence ID
+#[derive(Default)]
+struct StopFns(HashMap<usize, StopFn>);
+
+impl fmt::Debug for StopFns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// Scheduler for waiting and running sequences
+///
+/// Owns the block manager so that admission and preemption decisions are
+/// always made against the current state of the KV cache.
+#[derive(Debug)]
+pub struct Scheduler {
+    /// Maximum number of sequences in a single batch
+    max_num_seqs: usize,
+
+    /// Maximum number of tokens processed in a single batch
+    max_num_batched_tokens: usize,
+
+    /// Maximum number of prompt tokens prefilled for a sequence in one step
+    max_num_partial_prefill_tokens: usize,
+
+    /// End-of-sequence token ID, if known
+    eos_token_id: Option<u32>,
+
+    /// Manager for the physical KV cache blocks
+    block_manager: BlockManager,
+
+    /// Sequences waiting for prefill, in admission order
+    waiting: VecDeque<Sequence>,
+
+    /// Sequences that have been prefilled and are decoding
+    running: VecDeque<Sequence>,
+
+    /// Preempted sequences whose KV cache is swapped out to the CPU
+    swapped: VecDeque<Sequence>,
+
+    /// Stop conditions evaluated after each token appended to a sequence
+    stop_fns: StopFns,
+}
+
+impl Scheduler {
+    /// Creates a new Scheduler from a resolved configuration
+    ///
+    /// The CPU swap pool is sized by `config.num_cpu_blocks`; when it is
+    /// unset, preempted sequences are always recomputed.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Engine configuration
+    /// * `num_kvcache_blocks` - Number of physical blocks in the KV cache
+    pub fn new(config: &Config, num_kvcache_blocks: usize) -> Self {
+        let block_manager = BlockManager::new(num_kvcache_blocks, config.kvcache_block_size)
+            .with_cpu_blocks(config.num_cpu_blocks.unwrap_or(0));
+        Self {
+            max_num_seqs: config.max_num_seqs,
+            max_num_batched_tokens: config.max_num_batched_tokens,
+            max_num_partial_prefill_tokens: config
+                .max_num_partial_prefill_tokens
+                .unwrap_or(config.max_num_batched_tokens),
+            eos_token_id: config.eos_token_id,
+            block_manager,
+            waiting: VecDeque::new(),
+            running: VecDeque::new(),
+            swapped: VecDeque::new(),
+            stop_fns: StopFns::default(),
+        }
+    }
+
+    /// Returns the block manager backing this scheduler
+    pub fn block_manager(&self) -> &BlockManager {
+        &self.block_manager
+    }
+
+    /// Returns the block manager for allocating blocks outside the queues
+    ///
+    /// Sequences allocated through it are not scheduled; the caller must
+    /// deallocate them when done, e.g. after a warmup pass.
+    pub fn block_manager_mut(&mut self) -> &mut BlockManager {
+        &mut self.block_manager
+    }
+
+    /// Returns true if there are no waiting, running, or swapped sequences
+    pub fn is_finished(&self) -> bool {
+        self.waiting.is_empty() && self.running.is_empty() && self.swapped.is_empty()
+    }
+
+    /// Returns the number of sequences waiting for (the rest of) their prefill
+    pub fn num_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Returns the number of sequences that are decoding
+    pub fn num_running(&self) -> usize {
+        self.running.len()
+    }
+
+    /// Returns the number of preempted sequences swapped out to the CPU
+    pub fn num_swapped(&self) -> usize {
+        self.swapped.len()
+    }
+
+    /// Returns the IDs of the decoding sequences, in batch order
+    pub fn running_seq_ids(&self) -> Vec<usize> {
+        self.running.iter().map(|seq| seq.seq_id).collect()
+    }
+
+    /// Adds a new sequence to the end of the waiting queue
+    pub fn add(&mut self, seq: Sequence) {
+        self.waiting.push_back(seq);
+    }
+
+    /// Registers a stop condition for a sequence
+    ///
+    /// The condition is evaluated after every token appended to the
+    /// sequence, once the EOS and length checks have not finished it.
+    /// Returning true finishes the sequence with `FinishReason::Custom`.
+    /// The condition is dropped when the sequence finishes.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq_id` - ID of the sequence
+    /// * `stop_fn` - Condition that returns true when the sequence should stop
+    pub fn set_stop_fn(&mut self, seq_id: usize, stop_fn: StopFn) {
+        self.stop_fns.0.insert(seq_id, stop_fn);
+    }
+
+    /// Appends a sampled token and evaluates the sequence's stop condition
+    ///
+    /// # Returns
+    ///
+    /// True if the sequence finished
+    fn append(&mut self, seq: &mut Sequence, token_id: u32) -> bool {
+        let finished = seq.try_append(token_id, self.eos_token_id)
+            || match self.stop_fns.0.get(&seq.seq_id) {
+                Some(stop_fn) if stop_fn(seq) => {
+                    seq.finish(FinishReason::Custom);
+                    true
+                }
+                _ => false,
+            };
+        if finished {
+            self.stop_fns.0.remove(&seq.seq_id);
+        }
+        finished
+    }
+
+    /// Removes a sequence from the queues and finishes it as aborted
+    ///
+    /// The sequence's KV cache blocks, or its CPU swap blocks if it is
+    /// swapped out, are released.
+    ///
+    /// # Returns
+    ///
+    /// The aborted sequence, or None if no waiting, running, or swapped
+    /// sequence has the ID
+    pub fn abort(&mut self, seq_id: usize) -> Option<Sequence> {
+        let take = |queue: &mut VecDeque<Sequence>| {
+            let index = queue.iter().position(|seq| seq.seq_id == seq_id)?;
+            queue.remove(index)
+        };
+        let mut seq = if let Some(mut seq) = take(&mut self.swapped) {
+            self.block_manager.deallocate_swapped(&mut seq);
+            seq
+        } else {
+            let mut seq = take(&mut self.waiting).or_else(|| take(&mut self.running))?;
+            self.block_manager.deallocate(&mut seq);
+            seq
+        };
+        self.stop_fns.0.remove(&seq_id);
+        seq.finish(FinishReason::Aborted);
+        Some(seq)
+    }
+
+    /// Finishes every waiting, running, and swapped sequence
+    ///
+    /// The sequences' KV cache blocks and CPU swap blocks are released.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Reason recorded on every finished sequence
+    ///
+    /// # Returns
+    ///
+    /// The finished sequences, swapped ones first, then running and waiting ones
+    pub fn finish_all(&mut self, reason: FinishReason) -> Vec<Sequence> {
+        let mut finished = Vec::new();
+        for mut seq in std::mem::take(&mut self.swapped) {
+            self.block_manager.deallocate_swapped(&mut seq);
+            finished.push(seq);
+        }
+        for mut seq in std::mem::take(&mut self.running).into_iter().chain(std::mem::take(&mut self.waiting)) {
+            self.block_manager.deallocate(&mut seq);
+            finished.push(seq);
+        }
+        for seq in &mut finished {
+            self.stop_fns.0.remove(&seq.seq_id);
+            seq.finish(reason);
+        }
+        finished
+    }
+
+    /// Finishes the sequences of a batch that could not be run
+    ///
+    /// Used when the model fails on a batch: its sequences are finished
+    /// with `FinishReason::Error` and their KV cache blocks are released.
+    ///
+    /// # Returns
+    ///
+    /// The failed sequences, in batch order
+    pub fn fail_batch(&mut self, batch: ScheduledBatch) -> Vec<Sequence> {
+        batch
+            .seqs
+            .into_iter()
+            .map(|mut seq| {
+                self.block_manager.deallocate(&mut seq);
+                self.stop_fns.0.remove(&seq.seq_id);
+                seq.finish(FinishReason::Error);
+                seq
+            })
+            .collect()
+    }
+
+    /// Finishes waiting sequences whose deadline has passed
+    ///
+    /// Only sequences that have not started are timed out: a prompt that is
+    /// partially prefilled, or a preempted sequence waiting to be recomputed,
+    /// has already made progress and keeps its place. Running and swapped
+    /// sequences are never timed out.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// The timed-out sequences, finished with `FinishReason::Timeout`
+    pub fn expire_waiting(&mut self, now: Instant) -> Vec<Sequence> {
+        let is_expired = |seq: &Sequence| {
+            seq.deadline.is_some_and(|deadline| deadline <= now)
+                && seq.block_table.is_empty()
+                && seq.num_completion_tokens() == 0
+        };
+        if !self.waiting.iter().any(is_expired) {
+            return Vec::new();
+        }
+        let (expired, waiting): (VecDeque<Sequence>, VecDeque<Sequence>) =
+            std::mem::take(&mut self.waiting).into_iter().partition(is_expired);
+        self.waiting = waiting;
+        expired
+            .into_iter()
+            .map(|mut seq| {
+                self.stop_fns.0.remove(&seq.seq_id);
+                seq.finish(FinishReason::Timeout);
+                seq
+            })
+            .collect()
+    }
+
+    /// Selects the sequences to run in the next step
+    ///
+    /// Swapped sequences are resumed first, in the order they were swapped
+    /// out, as long as their blocks fit while leaving a free block for the
+    /// next token of every running sequence. Prefill is preferred next: while no
+    /// sequence remains swapped, waiting sequences are admitted in order as
+    /// long as the batch limits and free KV blocks allow it. A prompt that
+    /// does not fit the remaining token budget or the partial prefill limit
+    /// is prefilled in part, ending the batch; it keeps its blocks and stays
+    /// at the front of the waiting queue until it is complete. When no sequence
+    /// can be admitted, running sequences are batched for decode. If a
+    /// running sequence cannot grow, the most recently admitted running
+    /// sequences are preempted to free their blocks.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NothingScheduled` if no sequence fits in the KV cache, or
+    /// another error if block allocation fails unexpectedly.
+    pub fn schedule(&mut self) -> Result<ScheduledBatch> {
+        // Swap in
+        let mut blocks_to_swap_in = Vec::new();
+        // A sequence swapped in here must not be preempted by this step's
+        // decode, since its blocks would be copied out before they are copied
+        // in. Swap-ins therefore leave a free block for the next token of
+        // every running sequence, so that decode never has to preempt.
+        let mut num_append_blocks = self.running.iter().filter(|seq| needs_new_block(seq)).count();
+        while let Some(seq) = self.swapped.front() {
+            let num_reserved = num_append_blocks + needs_new_block(seq) as usize;
+            if self.running.len() >= self.max_num_seqs || !self.block_manager.can_swap_in(seq, num_reserved) {
+                break;
+            }
+            // Safe to unwrap since front() returned a sequence.
+            let mut seq = self.swapped.pop_front().unwrap();
+            blocks_to_swap_in.extend(self.block_manager.swap_in(&mut seq)?);
+            seq.status = SequenceStatus::Running;
+            self.running.push_back(seq);
+            num_append_blocks = num_reserved;
+        }
+
+        // Prefill
+        let mut seqs = Vec::new();
+        let mut num_new_tokens = Vec::new();
+        let mut num_batched_tokens = 0;
+        while let Some(seq) = self.waiting.front() {
+            let is_allocated = !seq.block_table.is_empty();
+            if !self.swapped.is_empty()
+                || seqs.len() >= self.max_num_seqs
+                || num_batched_tokens >= self.max_num_batched_tokens
+                || (!is_allocated && !self.block_manager.can_allocate(seq))
+            {
+                break;
+            }
+            // Safe to unwrap since front() returned a sequence.
+            let mut seq = self.waiting.pop_front().unwrap();
+            if !is_allocated {
+                self.block_manager.allocate(&mut seq)?;
+            }
+            let num_remaining = seq.len() - seq.num_cached_tokens;
+            let num_tokens = seq.prefill_chunk(
+                self.max_num_partial_prefill_tokens.min(self.max_num_batched_tokens - num_batched_tokens),
+            );
+            num_batched_tokens += num_tokens;
+            seq.status = SequenceStatus::Running;
+            seqs.push(seq);
+            num_new_tokens.push(num_tokens);
+            if num_tokens < num_remaining {
+                break;
+            }
+        }
+        if !seqs.is_empty() {
+            return Ok(ScheduledBatch {
+                seqs,
+                is_prefill: true,
+                num_new_tokens,
+                blocks_to_swap_in,
+                blocks_to_swap_out: Vec::new(),
+            });
+        }
+
+        // Decode
+        let mut blocks_to_swap_out = Vec::new();
+        while seqs.len() < self.max_num_seqs {
+            let Some(mut seq) = self.running.pop_front() else {
+                break;
+            };
+            while !self.block_manager.can_append(&seq) {
+                match self.running.pop_back() {
+                    Some(victim) => self.preempt(victim, &mut blocks_to_swap_out),
+                    None => break,
+                }
+            }
+            if self.block_manager.can_append(&seq) {
+                self.block_manager.append_slot(&mut seq)?;
+                seqs.push(seq);
+            } else {
+                self.preempt(seq, &mut blocks_to_swap_out);
+            }
+        }
+        if seqs.is_empty() {
+            return Err(NothingScheduled.into());
+        }
+        Ok(ScheduledBatch {
+            num_new_tokens: vec![1; seqs.len()],
+            seqs,
+            is_prefill: false,
+            blocks_to_swap_in,
+            blocks_to_swap_out,
+        })
+    }
+
+    /// Frees the blocks of a running sequence
+    ///
+    /// The sequence is swapped out to the CPU pool if it has room, keeping
+    /// its progress; the block copies are appended to `blocks_to_swap_out`.
+    /// Otherwise it returns to the front of the waiting queue and will be
+    /// prefilled again when readmitted.
+    fn preempt(&mut self, mut seq: Sequence, blocks_to_swap_out: &mut Vec<(usize, usize)>) {
+        if let Ok(mapping) = self.block_manager.swap_out(&mut seq) {
+            blocks_to_swap_out.extend(mapping);
+            seq.status = SequenceStatus::Swapped;
+            self.swapped.push_back(seq);
+            return;
+        }
+        seq.status = SequenceStatus::Waiting;
+        self.block_manager.deallocate(&mut seq);
+        self.waiting.push_front(seq);
+    }
+
+    /// Applies the sampled tokens of a decode step and keeps the batch for
+    /// another step
+    ///
+    /// Used for multi-step scheduling. Each sequence receives its sampled
+    /// token as in `postprocess`, and finished sequences release their
+    /// blocks. When none finished and every sequence's next slot fits in the
+    /// free pool, the slots are reserved and the batch is returned to run
+    /// again without scheduling. Otherwise the unfinished sequences return
+    /// to the front of the running queue, so the next `schedule` call can
+    /// preempt or refill as usual.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - A decode batch returned by `schedule` or this method
+    /// * `token_ids` - The sampled token for each sequence in the batch
+    ///
+    /// # Returns
+    ///
+    /// The batch for the next step, if it can continue, and the sequences
+    /// that finished in this step
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `batch` is a prefill batch.
+    pub fn continue_decode(
+        &mut self,
+        batch: ScheduledBatch,
+        token_ids: &[u32],
+    ) -> Result<(Option<ScheduledBatch>, Vec<Sequence>)> {
+        if batch.is_prefill {
+            anyhow::bail!("Only decode batches can run several steps");
+        }
+        let mut finished = Vec::new();
+        let mut unfinished = Vec::new();
+        for (mut seq, &token_id) in batch.seqs.into_iter().zip(token_ids) {
+            seq.num_cached_tokens += 1;
+            if self.append(&mut seq, token_id) {
+                self.block_manager.deallocate(&mut seq);
+                finished.push(seq);
+            } else {
+                unfinished.push(seq);
+            }
+        }
+
+        let num_new_blocks = unfinished.iter().filter(|seq| seq.num_blocks() > seq.block_table.len()).count();
+        if !finished.is_empty() || num_new_blocks > self.block_manager.num_free_blocks() {
+            for seq in unfinished.into_iter().rev() {
+                self.running.push_front(seq);
+            }
+            return Ok((None, finished));
+        }
+        for seq in &mut unfinished {
+            self.block_manager.append_slot(seq)?;
+        }
+        let batch = ScheduledBatch {
+            num_new_tokens: vec![1; unfinished.len()],
+            seqs: unfinished,
+            is_prefill: false,
+            blocks_to_swap_in: Vec::new(),
+            blocks_to_swap_out: Vec::new(),
+        };
+        Ok((Some(batch), finished))
+    }
+
+    /// Applies the sampled tokens of a step and requeues the batch
+    ///
+    /// The tokens processed for each sequence are recorded as cached. A
+    /// sequence whose prompt was only partially prefilled discards its
+    /// sampled token and returns to the front of the waiting queue. Every
+    /// other sequence receives its sampled token. Sequences that produced an
+    /// EOS token (unless `ignore_eos` is set) or reached `max_tokens` are
+    /// marked finished and release their blocks; the rest return to the
+    /// running queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - The batch returned by `schedule`
+    /// * `token_ids` - The sampled token for each sequence in the batch
+    ///
+    /// # Returns
+    ///
+    /// The sequences that finished in this step
+    pub fn postprocess(&mut self, batch: ScheduledBatch, token_ids: &[u32]) -> Vec<Sequence> {
+        let mut finished = Vec::new();
+        let mut unfinished = Vec::new();
+        let mut partial = None;
+        let steps = batch.seqs.into_iter().zip(token_ids).zip(&batch.num_new_tokens);
+        for ((mut seq, &token_id), &num_new) in steps {
+            seq.num_cached_tokens += num_new;
+            if batch.is_prefill && seq.num_cached_tokens < seq.len() {
+                seq.status = SequenceStatus::Waiting;
+                partial = Some(seq);
+            } else if self.append(&mut seq, token_id) {
+                self.block_manager.deallocate(&mut seq);
+                finished.push(seq);
+            } else {
+                unfinished.push(seq);
+            }
+        }
+
+        if let Some(seq) = partial {
+            self.waiting.push_front(seq);
+        }
+        if batch.is_prefill {
+            self.running.extend(unfinished);
+        } else {
+            for seq in unfinished.into_iter().rev() {
+                self.running.push_front(seq);
+            }
+        }
+        finished
+    }
+}
+
+/// Returns true if the latest token of a sequence starts a block that is
+/// not in its block table yet
+fn needs_new_block(seq: &Sequence) -> bool {
+    seq.num_blocks() > seq.block_table.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::sampling::SamplingParams;
+    use common::sequence::FinishReason;
+
+    fn config() -> Config {
+        Config {
+            eos_token_id: Some(0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn prefill_then_decode_until_max_tokens() {
+        let mut scheduler = Scheduler::new(&config(), 8);
+        let params = SamplingParams { max_tokens: 2, ..Default::default() };
+        scheduler.add(Sequence::new(vec![1, 2, 3], params));
+
+        let batch = scheduler.schedule().unwrap();
+        assert!(batch.is_prefill);
+        assert!(scheduler.postprocess(batch, &[7]).is_empty());
+
+        let batch = scheduler.schedule().unwrap();
+        assert!(!batch.is_prefill);
+        assert_eq!(batch.seqs[0].position_ids(), vec![3]);
+        let finished = scheduler.postprocess(batch, &[8]);
+        assert_eq!(finished[0].completion_token_ids(), &[7, 8]);
+        assert_eq!(finished[0].finish_reason, Some(FinishReason::Length));
+        assert!(scheduler.is_finished());
+        assert_eq!(scheduler.block_manager().num_free_blocks(), 8);
+    }
+
+    #[test]
+    fn eos_finishes_sequence() {
+        let mut scheduler = Scheduler::new(&config(), 8);
+        scheduler.add(Sequence::new(vec![1, 2, 3], SamplingParams::default()));
+
+        let batch = scheduler.schedule().unwrap();
+        let finished = scheduler.postprocess(batch, &[0]);
+        assert_eq!(finished.len(), 1);
+        assert!(finished[0].is_finished());
+        assert_eq!(finished[0].finish_reason, Some(FinishReason::Stop));
+    }
+
+    #[test]
+    fn waiting_sequence_past_its_deadline_times_out() {
+        let mut scheduler = Scheduler::new(&config(), 1);
+        let blocking = Sequence::new(vec![1, 2, 3], SamplingParams::default());
+        let mut stalled = Sequence::new(vec![4, 5], SamplingParams::default());
+        let deadline = Instant::now();
+        stalled.deadline = Some(deadline);
+        let stalled_id = stalled.seq_id;
+        scheduler.add(blocking);
+        scheduler.add(stalled);
+
+        // The single block goes to the first sequence, which keeps running.
+        let mut batch = scheduler.schedule().unwrap();
+        assert_eq!(batch.seqs.len(), 1);
+        batch.seqs[0].deadline = Some(deadline);
+        scheduler.postprocess(batch, &[7]);
+
+        let expired = scheduler.expire_waiting(Instant::now());
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].seq_id, stalled_id);
+        assert_eq!(expired[0].finish_reason, Some(FinishReason::Timeout));
+        assert!(scheduler.waiting.is_empty());
+        assert_eq!(scheduler.running.len(), 1);
+        assert!(scheduler.expire_waiting(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn long_prompt_is_prefilled_in_chunks() {
+        let config = Config {
+            kvcache_block_size: 4,
+            max_num_partial_prefill_tokens: Some(4),
+            ..config()
+        };
+        let mut scheduler = Scheduler::new(&config, 8);
+        let prompt_len = 10;
+        scheduler.add(Sequence::with_block_size((1..=prompt_len).collect(), SamplingParams::default(), 4));
+
+        let mut num_prefill_steps = 0;
+        loop {
+            let batch = scheduler.schedule().unwrap();
+            if !batch.is_prefill {
+                // Only the token sampled after the last chunk is kept.
+                assert_eq!(batch.seqs[0].completion_token_ids(), &[7]);
+                break;
+            }
+            assert!(batch.num_new_tokens[0] <= 4);
+            num_prefill_steps += 1;
+            scheduler.postprocess(batch, &[7]);
+        }
+        assert_eq!(num_prefill_steps, (prompt_len as usize).div_ceil(4));
+        assert_eq!(scheduler.block_manager().num_free_blocks(), 5);
+    }
+
+    /// Prefills two single-block sequences that both need a second block
+    /// on their first decode step, with room for only one of them.
+    fn preempting_scheduler(num_cpu_blocks: usize) -> Scheduler {
+        let config = Config {
+            kvcache_block_size: 4,
+            num_cpu_blocks: Some(num_cpu_blocks),
+            ..config()
+        };
+        let mut scheduler = Scheduler::new(&config, 3);
+        scheduler.add(Sequence::with_block_size(vec![1, 2, 3, 4], SamplingParams::default(), 4));
+        scheduler.add(Sequence::with_block_size(vec![5, 6, 7, 8], SamplingParams::default(), 4));
+        let batch = scheduler.schedule().unwrap();
+        assert_eq!(batch.seqs.len(), 2);
+        scheduler.postprocess(batch, &[9, 9]);
+        scheduler
+    }
+
+    #[test]
+    fn preemption_swaps_out_when_swap_space_allows() {
+        let mut scheduler = preempting_scheduler(4);
+        let batch = scheduler.schedule().unwrap();
+        assert_eq!(batch.seqs.len(), 1);
+        assert_eq!(batch.blocks_to_swap_out.len(), 1);
+        assert_eq!(scheduler.swapped[0].status, SequenceStatus::Swapped);
+        assert!(scheduler.waiting.is_empty());
+    }
+
+    #[test]
+    fn swapped_in_sequence_is_not_swapped_out_in_the_same_step() {
+        let mut scheduler = preempting_scheduler(4);
+        let batch = scheduler.schedule().unwrap();
+        assert_eq!(scheduler.num_swapped(), 1);
+        assert!(scheduler.postprocess(batch, &[9]).is_empty());
+
+        // One block is free, but the swapped sequence needs it for its KV
+        // and one more for its next token, so it stays swapped out.
+        assert_eq!(scheduler.block_manager().num_free_blocks(), 1);
+        let batch = scheduler.schedule().unwrap();
+        assert!(batch.blocks_to_swap_in.is_empty());
+        assert!(batch.blocks_to_swap_out.is_empty());
+        assert_eq!((batch.seqs.len(), scheduler.num_swapped()), (1, 1));
+        assert_eq!(scheduler.postprocess(batch, &[0]).len(), 1);
+
+        let batch = scheduler.schedule().unwrap();
+        assert_eq!((batch.blocks_to_swap_in.len(), batch.seqs.len()), (1, 1));
+        assert!(batch.blocks_to_swap_out.is_empty());
+    }
+
+    #[test]
+    fn preemption_recomputes_when_swap_space_is_too_small() {
+        let mut scheduler = preempting_scheduler(0);
+        let batch = scheduler.schedule().unwrap();
+        assert_eq!(batch.seqs.len(), 1);
+        assert!(batch.blocks_to_swap_out.is_empty());
+        assert!(scheduler.swapped.is_empty());
+        assert_eq!(scheduler.waiting[0].status, SequenceStatus::Waiting);
+        assert!(scheduler.waiting[0].block_table.is_empty());
+    }
+}
@@ -1,4 +1,5 @@
-use candle_core::Tensor;
+use candle_core::{DType, Device, Result, Tensor};
+use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
 
 /// Context for model execution
@@ -81,6 +82,125 @@ impl Context {
     /// This is a convenience method that calls Default::default()
     /// to create a new Context instance with all default values.
     pub fn new() -> Self { Self::default() }
+
+    /// Captures a serializable copy of this context
+    ///
+    /// All tensors are copied to the CPU, so the snapshot can be saved to
+    /// disk and replayed later when debugging a problematic forward pass.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any tensor cannot be copied to the CPU
+    pub fn snapshot(&self) -> Result<ContextSnapshot> {
+        let snapshot_opt = |t: &Option<Tensor>| t.as_ref().map(TensorSnapshot::from_tensor).transpose();
+        Ok(ContextSnapshot {
+            is_prefill: self.is_prefill,
+            cu_seqlens_q: snapshot_opt(&self.cu_seqlens_q)?,
+            cu_seqlens_k: snapshot_opt(&self.cu_seqlens_k)?,
+            max_seqlen_q: self.max_seqlen_q,
+            max_seqlen_k: self.max_seqlen_k,
+            slot_mapping: snapshot_opt(&self.slot_mapping)?,
+            context_lens: snapshot_opt(&self.context_lens)?,
+            block_tables: self
+                .block_tables
+                .as_ref()
+                .map(|tables| tables.iter().map(TensorSnapshot::from_tensor).collect())
+                .transpose()?,
+        })
+    }
+
+    /// Rebuilds a context from a snapshot on the given device
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot` - The snapshot to restore
+    /// * `device` - Device on which the tensors are recreated
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a tensor snapshot is malformed
+    pub fn from_snapshot(snapshot: &ContextSnapshot, device: &Device) -> Result<Self> {
+        let restore_opt = |t: &Option<TensorSnapshot>| t.as_ref().map(|t| t.to_tensor(device)).transpose();
+        Ok(Self {
+            is_prefill: snapshot.is_prefill,
+            cu_seqlens_q: restore_opt(&snapshot.cu_seqlens_q)?,
+            cu_seqlens_k: restore_opt(&snapshot.cu_seqlens_k)?,
+            max_seqlen_q: snapshot.max_seqlen_q,
+            max_seqlen_k: snapshot.max_seqlen_k,
+            slot_mapping: restore_opt(&snapshot.slot_mapping)?,
+            context_lens: restore_opt(&snapshot.context_lens)?,
+            block_tables: snapshot
+                .block_tables
+                .as_ref()
+                .map(|tables| tables.iter().map(|t| t.to_tensor(device)).collect())
+                .transpose()?,
+        })
+    }
+}
+
+/// Serializable copy of a tensor
+///
+/// The values are flattened to `f64`, which represents every integer index
+/// used by the context (slot mappings, lengths, block numbers) exactly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TensorSnapshot {
+    /// Shape of the original tensor
+    pub shape: Vec<usize>,
+
+    /// Data type of the original tensor, e.g. "u32" or "i64"
+    pub dtype: String,
+
+    /// Tensor values in row-major order
+    pub data: Vec<f64>,
+}
+
+impl TensorSnapshot {
+    /// Copies a tensor into a snapshot
+    fn from_tensor(tensor: &Tensor) -> Result<Self> {
+        Ok(Self {
+            shape: tensor.dims().to_vec(),
+            dtype: tensor.dtype().as_str().to_string(),
+            data: tensor.flatten_all()?.to_dtype(DType::F64)?.to_vec1()?,
+        })
+    }
+
+    /// Recreates the tensor on the given device
+    fn to_tensor(&self, device: &Device) -> Result<Tensor> {
+        let dtype: DType = self.dtype.parse().map_err(candle_core::Error::wrap)?;
+        Tensor::from_slice(&self.data, self.shape.as_slice(), device)?.to_dtype(dtype)
+    }
+}
+
+/// Serializable snapshot of a Context
+///
+/// Mirrors the fields of `Context` with every tensor replaced by a
+/// `TensorSnapshot`. Created with `Context::snapshot` and restored with
+/// `Context::from_snapshot`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContextSnapshot {
+    /// Whether the captured execution was in prefill mode
+    pub is_prefill: bool,
+
+    /// Cumulative sequence lengths for query
+    pub cu_seqlens_q: Option<TensorSnapshot>,
+
+    /// Cumulative sequence lengths for key
+    pub cu_seqlens_k: Option<TensorSnapshot>,
+
+    /// Maximum sequence length for queries
+    pub max_seqlen_q: usize,
+
+    /// Maximum sequence length for keys
+    pub max_seqlen_k: usize,
+
+    /// Slot mapping tensor
+    pub slot_mapping: Option<TensorSnapshot>,
+
+    /// Context lengths tensor
+    pub context_lens: Option<TensorSnapshot>,
+
+    /// Block tables for paged attention
+    pub block_tables: Option<Vec<TensorSnapshot>>,
 }
 
 /// Global context instance protected by a mutex
@@ -113,6 +233,32 @@ pub fn get_context() -> Context {
     }
 }
 
+/// Get the current global context, failing if none has been set
+///
+/// Unlike `get_context`, this does not fall back to a default context, so
+/// code that requires batch metadata (e.g. attention) fails loudly when it
+/// runs before `set_context` instead of attending over empty metadata.
+///
+/// # Returns
+///
+/// A clone of the current global context
+///
+/// # Errors
+///
+/// Returns an error if `set_context` has not been called yet.
+///
+/// # Thread Safety
+///
+/// This function acquires a lock on the global context mutex, ensuring
+/// thread-safe access to the global context.
+pub fn get_context_checked() -> Result<Context> {
+    let context = CONTEXT.lock().unwrap();
+    match &*context {
+        Some(ctx) => Ok(ctx.clone()),
+        None => candle_core::bail!("no context has been set; call set_context before running the model"),
+    }
+}
+
 /// Set the global context with new values
 ///
 /// Updates the global context with the provided values. This function
@@ -154,4 +300,45 @@ pub fn set_context(
         context_lens,
         block_tables,
     });
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_context_requires_set_context() {
+        *CONTEXT.lock().unwrap() = None;
+        assert!(get_context_checked().is_err());
+        assert!(!get_context().is_prefill);
+
+        set_context(true, None, None, 4, 4, None, None, None);
+        let context = get_context_checked().unwrap();
+        assert!(context.is_prefill);
+        assert_eq!(context.max_seqlen_q, 4);
+    }
+
+    #[test]
+    fn snapshot_roundtrips_through_serde() {
+        let device = Device::Cpu;
+        let context = Context {
+            is_prefill: true,
+            cu_seqlens_q: Some(Tensor::new(&[0u32, 3, 8], &device).unwrap()),
+            max_seqlen_q: 5,
+            slot_mapping: Some(Tensor::new(&[7i64, 8, 9, 4_000_000_001], &device).unwrap()),
+            ..Context::default()
+        };
+
+        let json = serde_json::to_string(&context.snapshot().unwrap()).unwrap();
+        let snapshot: ContextSnapshot = serde_json::from_str(&json).unwrap();
+        let restored = Context::from_snapshot(&snapshot, &device).unwrap();
+
+        assert!(restored.is_prefill);
+        assert_eq!(restored.max_seqlen_q, 5);
+        let slot_mapping = restored.slot_mapping.unwrap();
+        assert_eq!(slot_mapping.dtype(), DType::I64);
+        assert_eq!(slot_mapping.to_vec1::<i64>().unwrap(), vec![7, 8, 9, 4_000_000_001]);
+        assert_eq!(restored.cu_seqlens_q.unwrap().to_vec1::<u32>().unwrap(), vec![0, 3, 8]);
+        assert!(restored.context_lens.is_none());
+    }
+}
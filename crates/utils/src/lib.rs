@@ -11,13 +11,17 @@ mod loader;
 ///
 /// These exports provide access to the Context struct and related functions
 /// for managing the global execution context in the model.
-pub use context::{Context, get_context, set_context};
+pub use context::{Context, ContextSnapshot, TensorSnapshot, get_context, get_context_checked, set_context};
 
 /// Re-exports from the loader module
 ///
 /// These exports provide functionality for loading weights from safetensors files
 /// into candle-based models.
-pub use loader::{SafeTensorLoadable, PackedModulesMapping, load_model};
+pub use loader::{
+    SafeTensorLoadable, PackedModulesMapping, ParameterSpec, QuantizedTensorKind, TensorInfo, apply_shard,
+    consolidate_safetensors, inspect_safetensors, layer_index, load_model, load_model_file, load_model_layers,
+    load_model_with_warnings, split_quantized_name,
+};
 
 /// Simple utility function that adds two numbers
 ///
@@ -3,13 +3,16 @@
 /// This module provides functionality for loading weights from safetensors files
 /// into candle-based models. It supports loading weights for both standard models
 /// and models with packed modules (where weights are split across multiple tensors).
-use std::path::Path;
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
 use anyhow::{Result, Context as _};
 use candle_core::{DType, Device, Tensor};
 use glob::glob;
 use safetensors::SafeTensors;
+use safetensors::tensor::Metadata;
 use std::fs;
+use std::ops::Range;
+use std::io::Read;
 
 /// Trait for models that can load weights from safetensors files
 ///
@@ -64,6 +67,101 @@ pub trait SafeTensorLoadable {
     /// how to apply the weight to the parameter. For example, different shards
     /// might need to be concatenated or applied to different parts of the parameter.
     fn load_weight(&mut self, name: &str, weight: Tensor, shard_id: Option<usize>) -> Result<bool>;
+
+    /// Load a tensor of a quantized linear layer
+    ///
+    /// Tensors whose name ends in `.qweight`, `.scales`, or `.qzeros` are
+    /// passed here instead of to `load_weight`, split into the layer prefix
+    /// and the tensor kind (see `split_quantized_name`). Models with
+    /// quantized layers look up the layer by `layer` and hand the tensor to
+    /// its `QuantizedLinear::load`.
+    ///
+    /// # Arguments
+    ///
+    /// * `layer` - Name of the layer, e.g. `"mlp.down_proj"`
+    /// * `kind` - Which of the layer's quantized tensors this is
+    /// * `tensor` - The tensor to load
+    /// * `shard_id` - Optional shard ID for packed modules
+    ///
+    /// # Returns
+    ///
+    /// Like `load_weight`, `Ok(false)` if the model has no such layer. The
+    /// default implementation returns `Ok(false)` for models without
+    /// quantized layers.
+    fn load_quantized(
+        &mut self,
+        _layer: &str,
+        _kind: QuantizedTensorKind,
+        _tensor: Tensor,
+        _shard_id: Option<usize>,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Get the parameters this model expects to be loaded
+    ///
+    /// This is the single source of truth for features that need to know a
+    /// model's parameters before or without loading them, such as strict
+    /// loading, dummy initialization, and dry-run validation of a checkpoint.
+    ///
+    /// # Returns
+    ///
+    /// The name, shape, and dtype of every parameter, using the names passed
+    /// to `load_weight`. The default implementation returns an empty list for
+    /// models that do not describe their parameters.
+    fn parameter_specs(&self) -> Vec<ParameterSpec> {
+        Vec::new()
+    }
+
+    /// Get the parameters that share their weight with another parameter
+    ///
+    /// Models with tied word embeddings (`HfConfig::tie_word_embeddings`)
+    /// should return `("lm_head.weight", "model.embed_tokens.weight")`, as
+    /// such checkpoints only store the embedding. After loading, every tied
+    /// parameter the checkpoint did not provide is loaded from its source.
+    ///
+    /// # Returns
+    ///
+    /// `(tied, source)` parameter name pairs. The default implementation
+    /// returns an empty list for models without tied parameters.
+    fn tied_parameters(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+/// Description of a single model parameter
+///
+/// Returned by `SafeTensorLoadable::parameter_specs` to describe the
+/// parameters a model expects without loading any weights.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterSpec {
+    /// Name of the parameter, as passed to `load_weight`
+    pub name: String,
+
+    /// Expected shape of the parameter
+    pub shape: Vec<usize>,
+
+    /// Expected data type of the parameter
+    pub dtype: DType,
+}
+
+/// Description of a tensor stored in a safetensors checkpoint
+///
+/// Returned by `inspect_safetensors`, which reads it from the file header
+/// without loading the tensor data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorInfo {
+    /// Name of the tensor in the checkpoint
+    pub name: String,
+
+    /// Shape of the tensor
+    pub shape: Vec<usize>,
+
+    /// Data type the tensor is loaded as
+    pub dtype: DType,
+
+    /// Path of the safetensors file holding the tensor
+    pub file: PathBuf,
 }
 
 /// Type for packed module mapping
@@ -103,6 +201,10 @@ fn convert_dtype(dtype: safetensors::tensor::Dtype, tensor_name: &str) -> Result
 
 /// Create a tensor from safetensors data
 ///
+/// BOOL tensors are read byte by byte into a `U8` tensor whose values are
+/// strictly 0 or 1, so a checkpoint that stores true as a nonzero byte
+/// other than 1 (such as 0xFF) still yields 1.
+///
 /// # Arguments
 ///
 /// * `view` - The safetensors tensor view
@@ -120,6 +222,10 @@ fn convert_dtype(dtype: safetensors::tensor::Dtype, tensor_name: &str) -> Result
 fn create_tensor(view: &impl safetensors::tensor::View, tensor_name: &str) -> Result<Tensor> {
     let shape = view.shape().to_vec();
     let dtype = convert_dtype(view.dtype(), tensor_name)?;
+    if view.dtype() == safetensors::tensor::Dtype::BOOL {
+        let values: Vec<u8> = view.data().iter().map(|&byte| u8::from(byte != 0)).collect();
+        return Ok(Tensor::from_vec(values, shape, &Device::Cpu)?);
+    }
     
     Ok(Tensor::from_raw_buffer(
         &view.data(),
@@ -152,6 +258,106 @@ fn find_packed_mapping(tensor_name: &str, mapping: &PackedModulesMapping) -> Opt
     None
 }
 
+/// Kind of tensor stored for a quantized linear layer
+///
+/// Quantized checkpoints (AWQ/GPTQ-style) replace a layer's `.weight` with
+/// several tensors distinguished by their name suffix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantizedTensorKind {
+    /// Quantized weight values, suffix `.qweight`
+    QWeight,
+
+    /// Dequantization scales, suffix `.scales`
+    Scales,
+
+    /// Zero points of asymmetric schemes, suffix `.qzeros`
+    QZeros,
+}
+
+/// Split a quantized tensor name into its layer prefix and kind
+///
+/// The loader uses this to route the tensors of a quantized layer to
+/// `SafeTensorLoadable::load_quantized`, e.g. `"mlp.down_proj.qweight"`
+/// becomes `("mlp.down_proj", QuantizedTensorKind::QWeight)`.
+///
+/// # Arguments
+///
+/// * `name` - The tensor name from the checkpoint
+///
+/// # Returns
+///
+/// The layer prefix and tensor kind, or None if the name has no quantized
+/// suffix
+pub fn split_quantized_name(name: &str) -> Option<(&str, QuantizedTensorKind)> {
+    [
+        (".qweight", QuantizedTensorKind::QWeight),
+        (".scales", QuantizedTensorKind::Scales),
+        (".qzeros", QuantizedTensorKind::QZeros),
+    ]
+    .into_iter()
+    .find_map(|(suffix, kind)| name.strip_suffix(suffix).map(|prefix| (prefix, kind)))
+}
+
+/// Copy a shard into its slice of a packed parameter
+///
+/// Packed modules (such as a fused QKV projection) store several logical
+/// weights in a single parameter, split evenly along one dimension. This
+/// helper writes `shard` into the `shard_id`-th of `num_shards` equal slices
+/// of `param` along `dim`, so models can call it from `load_weight` instead
+/// of reimplementing the slicing.
+///
+/// # Arguments
+///
+/// * `param` - The packed parameter to update
+/// * `shard` - The weight for a single shard
+/// * `shard_id` - Index of the slice that `shard` should be written to
+/// * `num_shards` - Number of equal slices the parameter is split into
+/// * `dim` - Dimension along which the parameter is split
+///
+/// # Returns
+///
+/// Result indicating success or an error
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - `dim` is out of range for the parameter
+/// - `shard_id` is not smaller than `num_shards`
+/// - The parameter size along `dim` is not divisible by `num_shards`
+/// - The shard shape does not match the shape of a single slice
+pub fn apply_shard(
+    param: &mut Tensor,
+    shard: &Tensor,
+    shard_id: usize,
+    num_shards: usize,
+    dim: usize,
+) -> Result<()> {
+    let param_dims = param.dims();
+    if dim >= param_dims.len() {
+        anyhow::bail!("Shard dimension {} out of range for parameter of rank {}", dim, param_dims.len());
+    }
+    if shard_id >= num_shards {
+        anyhow::bail!("Shard id {} out of range for {} shards", shard_id, num_shards);
+    }
+    if !param_dims[dim].is_multiple_of(num_shards) {
+        anyhow::bail!(
+            "Parameter size {} along dimension {} is not divisible by {} shards",
+            param_dims[dim], dim, num_shards
+        );
+    }
+
+    let shard_size = param_dims[dim] / num_shards;
+    let mut expected = param_dims.to_vec();
+    expected[dim] = shard_size;
+    if shard.dims() != expected.as_slice() {
+        anyhow::bail!("Expected shard of shape {:?}, got {:?}", expected, shard.dims());
+    }
+
+    let shard = shard.to_dtype(param.dtype())?.to_device(param.device())?;
+    *param = param.slice_scatter(&shard, dim, shard_id * shard_size)?;
+    Ok(())
+}
+
 /// Process a single tensor from a safetensors file
 ///
 /// # Arguments
@@ -160,23 +366,25 @@ fn find_packed_mapping(tensor_name: &str, mapping: &PackedModulesMapping) -> Opt
 /// * `tensors` - The safetensors file
 /// * `tensor_name` - The name of the tensor to process
 /// * `packed_modules_mapping` - Optional mapping for packed modules
+/// * `on_warning` - Receives a warning for a tensor the model does not have
 ///
 /// # Returns
 ///
-/// Result indicating success or an error
+/// The name of the parameter the tensor was passed to and the tensor itself
 ///
 /// # Errors
 ///
 /// Returns an error if:
 /// - The tensor cannot be retrieved from the safetensors file
 /// - The tensor cannot be converted to a candle-core Tensor
-/// - The model's `load_weight` method returns an error
+/// - The model's `load_weight` or `load_quantized` method returns an error
 fn process_tensor<M: SafeTensorLoadable>(
     model: &mut M,
     tensors: &SafeTensors,
     tensor_name: &str,
     packed_modules_mapping: &Option<PackedModulesMapping>,
-) -> Result<()> {
+    on_warning: &mut dyn FnMut(&str),
+) -> Result<(String, Tensor)> {
     // Check if this weight is part of a packed module
     let (param_name, shard_id) = if let Some(mapping) = packed_modules_mapping {
         if let Some((name, id)) = find_packed_mapping(tensor_name, mapping) {
@@ -192,13 +400,17 @@ fn process_tensor<M: SafeTensorLoadable>(
     let view = tensors.tensor(tensor_name)?;
     let tensor = create_tensor(&view, tensor_name)?;
     
-    // Load the weight into the parameter
-    if !model.load_weight(&param_name, tensor, shard_id)? {
-        // Parameter not found, log a warning
-        eprintln!("Warning: Parameter {} not found in model", param_name);
+    // Load the weight into the parameter, routing quantized tensors by suffix
+    let found = match split_quantized_name(&param_name) {
+        Some((layer, kind)) => model.load_quantized(layer, kind, tensor.clone(), shard_id)?,
+        None => model.load_weight(&param_name, tensor.clone(), shard_id)?,
+    };
+    if !found {
+        // Parameter not found, report a warning
+        on_warning(&format!("Parameter {} not found in model", param_name));
     }
     
-    Ok(())
+    Ok((param_name, tensor))
 }
 
 /// Load model weights from safetensors files
@@ -226,27 +438,122 @@ fn process_tensor<M: SafeTensorLoadable>(
 ///
 /// # Notes
 ///
-/// - This function will log warnings for parameters that are in the safetensors
-///   files but not found in the model.
+/// - This function prints warnings to stderr for parameters that are in the
+///   safetensors files but not found in the model. Use
+///   `load_model_with_warnings` to capture or suppress them instead.
+/// - Tied parameters (see `SafeTensorLoadable::tied_parameters`) missing
+///   from the checkpoint are loaded with the weight of their source.
 /// - It automatically handles data type conversions from safetensors types to
 ///   candle-core types.
 pub fn load_model<M: SafeTensorLoadable>(
     model: &mut M,
     path: impl AsRef<Path>,
+) -> Result<()> {
+    load_model_with_warnings(model, path, &mut |warning| eprintln!("Warning: {}", warning))
+}
+
+/// Load model weights from safetensors files, reporting warnings to a handler
+///
+/// Behaves like `load_model`, but every warning (e.g. a checkpoint tensor
+/// the model does not have) is passed to `on_warning` instead of being
+/// printed, so integrators can log, collect, or ignore them.
+///
+/// # Arguments
+///
+/// * `model` - The model to load weights into, must implement `SafeTensorLoadable`
+/// * `path` - Path to the directory containing safetensors files
+/// * `on_warning` - Called with the message of each warning
+///
+/// # Errors
+///
+/// Returns the same errors as `load_model`.
+pub fn load_model_with_warnings<M: SafeTensorLoadable>(
+    model: &mut M,
+    path: impl AsRef<Path>,
+    on_warning: &mut dyn FnMut(&str),
+) -> Result<()> {
+    load_model_layers(model, path, None, on_warning)
+}
+
+/// Load model weights from safetensors files, optionally only for some layers
+///
+/// Behaves like `load_model_with_warnings`, but when `layer_filter` is set,
+/// tensors of decoder layers whose index (see `layer_index`) is outside the
+/// range are skipped. Tensors outside the decoder layers, such as the
+/// embeddings and the final norm, are always loaded. This is meant for
+/// debugging, e.g. isolating an issue to the first few layers.
+///
+/// # Arguments
+///
+/// * `model` - The model to load weights into, must implement `SafeTensorLoadable`
+/// * `path` - Path to the directory containing safetensors files
+/// * `layer_filter` - Indices of the decoder layers to load, or None for all
+/// * `on_warning` - Called with the message of each warning
+///
+/// # Errors
+///
+/// Returns the same errors as `load_model`.
+pub fn load_model_layers<M: SafeTensorLoadable>(
+    model: &mut M,
+    path: impl AsRef<Path>,
+    layer_filter: Option<Range<usize>>,
+    on_warning: &mut dyn FnMut(&str),
 ) -> Result<()> {
     let path = path.as_ref();
     let pattern = path.join("*.safetensors");
     let pattern_str = pattern.to_string_lossy();
-    
+    let files = glob(&pattern_str)
+        .with_context(|| format!("Failed to read glob pattern {}", pattern_str))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    load_files(model, &files, layer_filter, on_warning)
+}
+
+/// Load model weights from a single safetensors file
+///
+/// Behaves like `load_model`, but reads the given file instead of every
+/// `*.safetensors` file in a directory.
+///
+/// # Arguments
+///
+/// * `model` - The model to load weights into, must implement `SafeTensorLoadable`
+/// * `file` - Path to the safetensors file
+///
+/// # Errors
+///
+/// Returns an error if `file` is not a file or has no `.safetensors`
+/// extension, and otherwise the same errors as `load_model`.
+pub fn load_model_file<M: SafeTensorLoadable>(
+    model: &mut M,
+    file: impl AsRef<Path>,
+) -> Result<()> {
+    let file = file.as_ref();
+    if !file.is_file() {
+        anyhow::bail!("{} is not a file", file.display());
+    }
+    if file.extension().is_none_or(|extension| extension != "safetensors") {
+        anyhow::bail!("{} is not a .safetensors file", file.display());
+    }
+    load_files(model, &[file.to_path_buf()], None, &mut |warning| eprintln!("Warning: {}", warning))
+}
+
+/// Loads the tensors of the given safetensors files into a model
+///
+/// Shared by `load_model_layers` and `load_model_file`; see
+/// `load_model_layers` for the meaning of `layer_filter`.
+fn load_files<M: SafeTensorLoadable>(
+    model: &mut M,
+    files: &[PathBuf],
+    layer_filter: Option<Range<usize>>,
+    on_warning: &mut dyn FnMut(&str),
+) -> Result<()> {
     // Get the packed modules mapping if available
     let packed_modules_mapping = model.get_packed_modules_mapping().cloned();
+    let tied_parameters = model.tied_parameters();
+    let mut loaded = HashSet::new();
+    let mut tie_sources = HashMap::new();
     
-    // Find all safetensors files in the directory
-    for entry in glob(&pattern_str)
-        .with_context(|| format!("Failed to read glob pattern {}", pattern_str))?
-    {
-        let file_path = entry?;
-        let data = fs::read(&file_path)
+    for file_path in files {
+        let data = fs::read(file_path)
             .with_context(|| format!("Failed to read file {}", file_path.display()))?;
         
         // Open the safetensors file
@@ -254,9 +561,428 @@ pub fn load_model<M: SafeTensorLoadable>(
         
         // Process each weight in the file
         for tensor_name in tensors.names() {
-            process_tensor(model, &tensors, tensor_name, &packed_modules_mapping)?;
+            if let (Some(filter), Some(layer)) = (&layer_filter, layer_index(tensor_name))
+                && !filter.contains(&layer)
+            {
+                continue;
+            }
+            let (param_name, tensor) = process_tensor(model, &tensors, tensor_name, &packed_modules_mapping, on_warning)?;
+            if tied_parameters.iter().any(|(_, source)| *source == param_name) {
+                tie_sources.insert(param_name.clone(), tensor);
+            }
+            loaded.insert(param_name);
+        }
+    }
+
+    // Share the source weight with tied parameters the checkpoint omits
+    for (tied, source) in &tied_parameters {
+        if let (false, Some(weight)) = (loaded.contains(tied), tie_sources.get(source)) {
+            model.load_weight(tied, weight.clone(), None)?;
         }
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Returns the decoder layer index encoded in a tensor name
+///
+/// Decoder layer tensors are named like `model.layers.3.mlp.up_proj.weight`;
+/// the index is the path segment following `layers`.
+///
+/// # Returns
+///
+/// The layer index, or None for tensors outside the decoder layers
+pub fn layer_index(name: &str) -> Option<usize> {
+    let mut segments = name.split('.');
+    segments.find(|&segment| segment == "layers")?;
+    segments.next()?.parse().ok()
+}
+
+/// Lists the tensors of a checkpoint without loading them
+///
+/// Only the JSON header of each safetensors file is read, so this is cheap
+/// even for large checkpoints. Sharded checkpoints are handled by reading
+/// every `*.safetensors` file in the directory.
+///
+/// # Arguments
+///
+/// * `path` - A model directory, or a single safetensors file
+///
+/// # Returns
+///
+/// The name, shape, dtype, and file of every tensor, sorted by name
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - A file cannot be opened or read
+/// - A header is truncated or is not valid safetensors metadata
+/// - A tensor has a dtype that cannot be loaded
+pub fn inspect_safetensors(path: impl AsRef<Path>) -> Result<Vec<TensorInfo>> {
+    let path = path.as_ref();
+    let files = if path.is_file() { vec![path.to_path_buf()] } else { shard_files(path)? };
+
+    let mut infos = Vec::new();
+    for file in files {
+        let metadata = read_header(&file)
+            .with_context(|| format!("Failed to read safetensors header of {}", file.display()))?;
+        for (name, info) in metadata.tensors() {
+            infos.push(TensorInfo {
+                dtype: convert_dtype(info.dtype, &name)?,
+                shape: info.shape.clone(),
+                file: file.clone(),
+                name,
+            });
+        }
+    }
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(infos)
+}
+
+/// Merges the shards of a checkpoint into a single safetensors file
+///
+/// Every tensor is copied with its name, dtype, shape, and raw data, so the
+/// merged file loads exactly like the shards it was built from.
+///
+/// # Arguments
+///
+/// * `input_dir` - Directory holding the checkpoint shards
+/// * `output_path` - Path of the merged safetensors file to write
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - The shards cannot be listed, read, or parsed
+/// - The same tensor name appears in more than one shard
+/// - The merged file cannot be written
+pub fn consolidate_safetensors(input_dir: impl AsRef<Path>, output_path: impl AsRef<Path>) -> Result<()> {
+    let files = shard_files(input_dir.as_ref())?;
+    let buffers = files
+        .iter()
+        .map(|file| fs::read(file).with_context(|| format!("Failed to read file {}", file.display())))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut merged = HashMap::new();
+    for (file, data) in files.iter().zip(&buffers) {
+        let shard = SafeTensors::deserialize(data)
+            .with_context(|| format!("Failed to parse safetensors file {}", file.display()))?;
+        for (name, view) in shard.tensors() {
+            if merged.contains_key(&name) {
+                anyhow::bail!("Tensor {} appears in more than one shard, again in {}", name, file.display());
+            }
+            merged.insert(name, view);
+        }
+    }
+
+    let output_path = output_path.as_ref();
+    safetensors::serialize_to_file(merged, None, output_path)
+        .with_context(|| format!("Failed to write {}", output_path.display()))
+}
+
+/// Lists the safetensors shards of a checkpoint directory
+///
+/// If the directory has a `model.safetensors.index.json`, the shards named
+/// in its `weight_map` are returned; otherwise every `*.safetensors` file
+/// in the directory is. Either way the files are sorted by path.
+fn shard_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let index_path = dir.join("model.safetensors.index.json");
+    if index_path.is_file() {
+        let index: serde_json::Value = serde_json::from_slice(&fs::read(&index_path)?)
+            .with_context(|| format!("Failed to parse {}", index_path.display()))?;
+        let Some(weight_map) = index["weight_map"].as_object() else {
+            anyhow::bail!("{} has no weight_map", index_path.display());
+        };
+        let mut files: Vec<PathBuf> = weight_map
+            .values()
+            .map(|file| file.as_str().map(|file| dir.join(file)))
+            .collect::<Option<_>>()
+            .with_context(|| format!("{} maps a tensor to a non-string file", index_path.display()))?;
+        files.sort();
+        files.dedup();
+        return Ok(files);
+    }
+
+    let pattern = dir.join("*.safetensors");
+    let pattern_str = pattern.to_string_lossy();
+    let mut files = glob(&pattern_str)
+        .with_context(|| format!("Failed to read glob pattern {}", pattern_str))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    files.sort();
+    Ok(files)
+}
+
+/// Reads the metadata header at the start of a safetensors file
+///
+/// The file starts with the header length as a little-endian `u64`,
+/// followed by the JSON header; the tensor data after it is not read.
+fn read_header(path: &Path) -> Result<Metadata> {
+    let mut file = fs::File::open(path)?;
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len)?;
+    let len = usize::try_from(u64::from_le_bytes(len))?;
+    let mut header = Vec::new();
+    file.take(len as u64).read_to_end(&mut header)?;
+    if header.len() != len {
+        anyhow::bail!("Header of {} bytes is truncated to {} bytes", len, header.len());
+    }
+    Ok(serde_json::from_slice(&header)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal model that stores its parameters by name
+    struct TinyModel {
+        params: HashMap<String, Tensor>,
+    }
+
+    impl TinyModel {
+        fn new() -> Self {
+            let device = Device::Cpu;
+            let params = HashMap::from([
+                ("embed.weight".to_string(), Tensor::zeros((8, 4), DType::F32, &device).unwrap()),
+                ("norm.weight".to_string(), Tensor::zeros(4, DType::BF16, &device).unwrap()),
+            ]);
+            Self { params }
+        }
+    }
+
+    impl SafeTensorLoadable for TinyModel {
+        fn load_weight(&mut self, name: &str, weight: Tensor, _shard_id: Option<usize>) -> Result<bool> {
+            match self.params.get_mut(name) {
+                Some(param) => {
+                    *param = weight;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+
+        fn parameter_specs(&self) -> Vec<ParameterSpec> {
+            self.params
+                .iter()
+                .map(|(name, param)| ParameterSpec {
+                    name: name.clone(),
+                    shape: param.dims().to_vec(),
+                    dtype: param.dtype(),
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn parameter_specs_match_model_parameters() {
+        let mut model = TinyModel::new();
+        let specs = model.parameter_specs();
+        assert_eq!(specs.len(), model.params.len());
+
+        for spec in specs {
+            let param = &model.params[&spec.name];
+            assert_eq!(spec.shape, param.dims());
+            assert_eq!(spec.dtype, param.dtype());
+            let weight = Tensor::ones(spec.shape.as_slice(), spec.dtype, &Device::Cpu).unwrap();
+            assert!(model.load_weight(&spec.name, weight, None).unwrap());
+        }
+    }
+
+    #[test]
+    fn inspect_lists_tensors_of_every_shard() {
+        let dir = std::env::temp_dir().join(format!("inspect_safetensors_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let device = Device::Cpu;
+        let shards = [
+            ("model-00001-of-00002.safetensors", vec![
+                ("norm.weight", Tensor::zeros(4, DType::BF16, &device).unwrap()),
+                ("embed.weight", Tensor::zeros((8, 4), DType::F32, &device).unwrap()),
+            ]),
+            ("model-00002-of-00002.safetensors", vec![
+                ("lm_head.weight", Tensor::zeros((8, 4), DType::F16, &device).unwrap()),
+            ]),
+        ];
+        for (file, tensors) in &shards {
+            let tensors: HashMap<_, _> = tensors.iter().cloned().collect();
+            candle_core::safetensors::save(&tensors, dir.join(file)).unwrap();
+        }
+
+        let infos = inspect_safetensors(&dir).unwrap();
+        let summary: Vec<_> = infos
+            .iter()
+            .map(|info| (info.name.as_str(), info.shape.clone(), info.dtype, info.file.file_name().unwrap()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("embed.weight", vec![8, 4], DType::F32, shards[0].0.as_ref()),
+            ("lm_head.weight", vec![8, 4], DType::F16, shards[1].0.as_ref()),
+            ("norm.weight", vec![4], DType::BF16, shards[0].0.as_ref()),
+        ]);
+        assert_eq!(inspect_safetensors(dir.join(shards[1].0)).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn consolidated_checkpoint_loads_like_its_shards() {
+        let dir = std::env::temp_dir().join(format!("consolidate_safetensors_{}", std::process::id()));
+        let shard_dir = dir.join("shards");
+        fs::create_dir_all(&shard_dir).unwrap();
+        let device = Device::Cpu;
+        let embed = Tensor::arange(0f32, 32., &device).unwrap().reshape((8, 4)).unwrap();
+        let norm = Tensor::new(&[0.5f32, 1., 1.5, 2.], &device).unwrap().to_dtype(DType::BF16).unwrap();
+        candle_core::safetensors::save(&HashMap::from([("embed.weight", embed)]), shard_dir.join("a.safetensors"))
+            .unwrap();
+        candle_core::safetensors::save(&HashMap::from([("norm.weight", norm)]), shard_dir.join("b.safetensors"))
+            .unwrap();
+        let index = r#"{"weight_map": {"embed.weight": "a.safetensors", "norm.weight": "b.safetensors"}}"#;
+        fs::write(shard_dir.join("model.safetensors.index.json"), index).unwrap();
+
+        let merged_dir = dir.join("merged");
+        fs::create_dir_all(&merged_dir).unwrap();
+        consolidate_safetensors(&shard_dir, merged_dir.join("model.safetensors")).unwrap();
+
+        let mut from_shards = TinyModel::new();
+        load_model(&mut from_shards, &shard_dir).unwrap();
+        let mut from_merged = TinyModel::new();
+        load_model(&mut from_merged, &merged_dir).unwrap();
+        let values = |t: &Tensor| t.to_dtype(DType::F32).unwrap().flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        for (name, param) in &from_shards.params {
+            let merged = &from_merged.params[name];
+            assert_eq!(merged.dtype(), param.dtype());
+            assert_eq!(values(merged), values(param));
+        }
+        assert_eq!(values(&from_merged.params["norm.weight"]), vec![0.5, 1., 1.5, 2.]);
+
+        // Merging the merged file with the shards repeats every tensor.
+        fs::copy(merged_dir.join("model.safetensors"), dir.join("model.safetensors")).unwrap();
+        fs::copy(shard_dir.join("a.safetensors"), dir.join("a.safetensors")).unwrap();
+        assert!(consolidate_safetensors(&dir, dir.join("out.safetensors")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn single_file_loads_without_a_directory() {
+        let dir = std::env::temp_dir().join(format!("load_model_file_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let device = Device::Cpu;
+        let embed = Tensor::arange(0f32, 32., &device).unwrap().reshape((8, 4)).unwrap();
+        let file = dir.join("weights.safetensors");
+        candle_core::safetensors::save(&HashMap::from([("embed.weight", embed)]), &file).unwrap();
+
+        let mut model = TinyModel::new();
+        load_model_file(&mut model, &file).unwrap();
+        let values = model.params["embed.weight"].flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(values, (0..32).map(|i| i as f32).collect::<Vec<_>>());
+
+        assert!(load_model_file(&mut model, &dir).is_err());
+        fs::copy(&file, dir.join("weights.bin")).unwrap();
+        assert!(load_model_file(&mut model, dir.join("weights.bin")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bool_tensors_are_normalized_to_zero_or_one() {
+        let data = [0u8, 0xFF, 1, 7];
+        let view = safetensors::tensor::TensorView::new(safetensors::tensor::Dtype::BOOL, vec![2, 2], &data).unwrap();
+        let tensor = create_tensor(&view, "mask").unwrap();
+        assert_eq!(tensor.dtype(), DType::U8);
+        assert_eq!(tensor.to_vec2::<u8>().unwrap(), vec![vec![0, 1], vec![1, 1]]);
+    }
+
+    #[test]
+    fn unknown_parameters_are_reported_to_the_warning_handler() {
+        let dir = std::env::temp_dir().join(format!("load_model_warnings_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let tensors = HashMap::from([
+            ("embed.weight", Tensor::ones((8, 4), DType::F32, &Device::Cpu).unwrap()),
+            ("rotary.inv_freq", Tensor::ones(2, DType::F32, &Device::Cpu).unwrap()),
+        ]);
+        candle_core::safetensors::save(&tensors, dir.join("model.safetensors")).unwrap();
+
+        let mut model = TinyModel::new();
+        let mut warnings = Vec::new();
+        load_model_with_warnings(&mut model, &dir, &mut |warning| warnings.push(warning.to_string())).unwrap();
+        assert_eq!(warnings, vec!["Parameter rotary.inv_freq not found in model"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn layer_filter_skips_layers_outside_the_range() {
+        let dir = std::env::temp_dir().join(format!("load_model_layers_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut model = TinyModel::new();
+        let embed = Tensor::ones((8, 4), DType::F32, &Device::Cpu).unwrap();
+        let mut tensors = HashMap::from([("embed.weight".to_string(), embed)]);
+        for layer in 0..4 {
+            let name = format!("model.layers.{}.mlp.up_proj.weight", layer);
+            model.params.insert(name.clone(), Tensor::zeros(4, DType::F32, &Device::Cpu).unwrap());
+            tensors.insert(name, Tensor::ones(4, DType::F32, &Device::Cpu).unwrap());
+        }
+        candle_core::safetensors::save(&tensors, dir.join("model.safetensors")).unwrap();
+
+        load_model_layers(&mut model, &dir, Some(0..2), &mut |warning| panic!("{}", warning)).unwrap();
+        let is_loaded = |name: &str| model.params[name].sum_all().unwrap().to_scalar::<f32>().unwrap() > 0.0;
+        let loaded: Vec<usize> = (0..4)
+            .filter(|layer| is_loaded(&format!("model.layers.{}.mlp.up_proj.weight", layer)))
+            .collect();
+        assert_eq!(loaded, vec![0, 1]);
+        assert!(is_loaded("embed.weight"));
+        assert_eq!(layer_index("model.norm.weight"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tied_lm_head_shares_the_embedding_weight() {
+        /// TinyModel whose LM head is tied to its embedding
+        struct TiedModel(TinyModel);
+
+        impl SafeTensorLoadable for TiedModel {
+            fn load_weight(&mut self, name: &str, weight: Tensor, shard_id: Option<usize>) -> Result<bool> {
+                self.0.load_weight(name, weight, shard_id)
+            }
+
+            fn tied_parameters(&self) -> Vec<(String, String)> {
+                vec![("lm_head.weight".to_string(), "embed.weight".to_string())]
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("load_model_tied_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let embed = Tensor::arange(0f32, 32., &Device::Cpu).unwrap().reshape((8, 4)).unwrap();
+        candle_core::safetensors::save(&HashMap::from([("embed.weight", embed)]), dir.join("model.safetensors")).unwrap();
+
+        let mut model = TiedModel(TinyModel::new());
+        let lm_head = Tensor::zeros((8, 4), DType::F32, &Device::Cpu).unwrap();
+        model.0.params.insert("lm_head.weight".to_string(), lm_head);
+        let mut warnings = Vec::new();
+        load_model_with_warnings(&mut model, &dir, &mut |warning| warnings.push(warning.to_string())).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        let values = |name: &str| model.0.params[name].flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(values("lm_head.weight"), values("embed.weight"));
+        assert_eq!(values("lm_head.weight")[31], 31.0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_shard_places_each_qkv_slice() {
+        let mut qkv = Tensor::zeros((6, 2), DType::F32, &Device::Cpu).unwrap();
+        for shard_id in 0..3 {
+            let shard = Tensor::full(shard_id as f32 + 1.0, (2, 2), &Device::Cpu).unwrap();
+            apply_shard(&mut qkv, &shard, shard_id, 3, 0).unwrap();
+        }
+
+        let rows = qkv.to_vec2::<f32>().unwrap();
+        assert_eq!(rows, vec![
+            vec![1.0, 1.0], vec![1.0, 1.0],
+            vec![2.0, 2.0], vec![2.0, 2.0],
+            vec![3.0, 3.0], vec![3.0, 3.0],
+        ]);
+    }
+
+    #[test]
+    fn apply_shard_rejects_bad_shapes() {
+        let mut qkv = Tensor::zeros((6, 2), DType::F32, &Device::Cpu).unwrap();
+        let shard = Tensor::zeros((3, 2), DType::F32, &Device::Cpu).unwrap();
+        assert!(apply_shard(&mut qkv, &shard, 0, 3, 0).is_err());
+        assert!(apply_shard(&mut qkv, &shard, 3, 3, 0).is_err());
+        assert!(apply_shard(&mut qkv, &shard, 0, 4, 0).is_err());
+        assert!(apply_shard(&mut qkv, &shard, 0, 2, 2).is_err());
+    }
+}